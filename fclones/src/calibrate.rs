@@ -0,0 +1,504 @@
+//! Measuring the performance of storage devices in order to tune the sizes of thread pools
+//! and the amount of data read in the grouping phases.
+//!
+//! The results are stored in the user config directory and loaded by the command line app
+//! on every run, so they don't need to be given each time.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::fs::{remove_file, File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::{CalibrateConfig, Parallelism};
+use crate::device::{DeviceProfile, DiskDevice, DiskDevices};
+use crate::error::Error;
+use crate::file::{FileLen, FilePos};
+use crate::hasher::evict_page_cache;
+use crate::log::{Log, LogExt};
+use crate::path::Path;
+
+/// Maximum time of a single measurement
+const MEASUREMENT_TIME: Duration = Duration::from_secs(1);
+/// Size of the reads in the sequential read test
+const SEQ_BLOCK_LEN: usize = 64 * 1024;
+/// Size of the reads in the random read test
+const RANDOM_BLOCK_LEN: usize = 4 * 1024;
+/// The number of threads is increased only if it gives at least such speedup
+const MIN_SPEEDUP: f64 = 1.1;
+/// Reading prefixes and suffixes shorter than this doesn't save any time
+const MIN_PREFIX_LEN: u64 = 4 * 1024;
+/// Longer prefixes and suffixes rarely tell more files apart
+const MAX_PREFIX_LEN: u64 = 1024 * 1024;
+/// Prefixes and suffixes are shorter than the seek length by this factor,
+/// so transferring them takes only a fraction of the time of locating them
+const SEEK_LEN_TO_PREFIX_LEN_RATIO: u64 = 8;
+/// Suffixes are read only from files longer than the seek length by this factor
+const SEEK_LEN_TO_SUFFIX_THRESHOLD_RATIO: u64 = 16;
+const MIN_SUFFIX_THRESHOLD: u64 = 64 * 1024;
+const MAX_SUFFIX_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Settings of a single device determined by `fclones calibrate`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviceCalibration {
+    pub parallelism: Parallelism,
+    #[serde(flatten)]
+    pub profile: DeviceProfile,
+    /// Measured throughput of sequential reads, in bytes per second
+    pub seq_read_rate: f64,
+    /// Measured number of random reads per second
+    pub random_iops: f64,
+}
+
+/// Calibrated settings of devices, keyed by device name
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    pub devices: BTreeMap<String, DeviceCalibration>,
+}
+
+impl Calibration {
+    /// Returns the location of the stored calibration:
+    /// `fclones/devices.json` in the user config dir.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("fclones").join("devices.json"))
+    }
+
+    /// Loads the calibration from the default location.
+    /// Returns an empty calibration if it wasn't stored yet.
+    pub fn load() -> io::Result<Calibration> {
+        match Self::default_path() {
+            Some(path) => Self::load_from(&path),
+            None => Ok(Calibration::default()),
+        }
+    }
+
+    /// Loads the calibration from the given file.
+    /// Returns an empty calibration if the file doesn't exist.
+    pub fn load_from(path: &std::path::Path) -> io::Result<Calibration> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Calibration::default()),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Saves the calibration to the given file, creating the parent directories if needed.
+    pub fn save_to(&self, path: &std::path::Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut out, self)?;
+        out.flush()
+    }
+
+    /// Returns the calibrated settings of the device with given name
+    pub fn get(&self, device_name: &OsStr) -> Option<&DeviceCalibration> {
+        self.devices.get(device_name.to_string_lossy().as_ref())
+    }
+}
+
+impl Display for Calibration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (name, c) in &self.devices {
+            writeln!(
+                f,
+                "{}: {}/s sequential, {:.0} IOPS random, \
+                 threads: {} sequential, {} random, \
+                 prefix: {}..{}, suffix: {} for files over {}",
+                name,
+                FileLen(c.seq_read_rate as u64),
+                c.random_iops,
+                c.parallelism.sequential,
+                c.parallelism.random,
+                c.profile.min_prefix_len,
+                c.profile.max_prefix_len,
+                c.profile.suffix_len,
+                c.profile.suffix_threshold,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A temporary file on the device being calibrated.
+/// Removed when dropped.
+struct TestFile {
+    path: PathBuf,
+    len: u64,
+}
+
+impl TestFile {
+    /// Writes a file filled with random data to the given directory.
+    fn create(dir: &Path, len: u64) -> io::Result<TestFile> {
+        let path = dir.to_path_buf().join(format!(
+            ".fclones-calibrate-{:032x}",
+            Uuid::new_v4().as_u128()
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let test_file = TestFile { path, len };
+        let mut out = BufWriter::new(&file);
+        let mut buf = vec![0; 1024 * 1024];
+        let mut rng = rand::thread_rng();
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(buf.len() as u64) as usize;
+            rng.fill_bytes(&mut buf[..n]);
+            out.write_all(&buf[..n])?;
+            remaining -= n as u64;
+        }
+        out.flush()?;
+        drop(out);
+        file.sync_all()?;
+        Ok(test_file)
+    }
+
+    fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+
+    /// Removes the file data from the page cache, so we measure the device, not the memory.
+    fn evict(&self) -> io::Result<()> {
+        evict_page_cache(&self.open()?, FilePos(0), FileLen(self.len));
+        Ok(())
+    }
+}
+
+impl Drop for TestFile {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// Reads the file sequentially with the given number of threads, each thread reading
+/// a separate fragment of the file. Returns the number of bytes read per second.
+fn measure_sequential(file: &TestFile, threads: usize) -> io::Result<f64> {
+    file.evict()?;
+    let fragment_len = file.len / threads as u64;
+    let start = Instant::now();
+    let bytes_read = run_threads(threads, |i| {
+        let mut f = file.open()?;
+        f.seek(SeekFrom::Start(i as u64 * fragment_len))?;
+        let mut f = f.take(fragment_len);
+        let mut buf = vec![0; SEQ_BLOCK_LEN];
+        let mut read = 0;
+        while start.elapsed() < MEASUREMENT_TIME {
+            match f.read(&mut buf)? {
+                0 => break,
+                n => read += n as u64,
+            }
+        }
+        Ok(read)
+    })?;
+    Ok(bytes_read as f64 / start.elapsed().as_secs_f64())
+}
+
+/// Reads small blocks at random offsets with the given number of threads.
+/// Returns the number of reads per second.
+fn measure_random(file: &TestFile, threads: usize) -> io::Result<f64> {
+    file.evict()?;
+    let block_count = (file.len / RANDOM_BLOCK_LEN as u64).max(1);
+    let start = Instant::now();
+    let ops = run_threads(threads, |_| {
+        let mut f = file.open()?;
+        let mut rng = rand::thread_rng();
+        let mut buf = vec![0; RANDOM_BLOCK_LEN];
+        let mut ops = 0;
+        while start.elapsed() < MEASUREMENT_TIME {
+            let offset = rng.gen_range(0..block_count) * RANDOM_BLOCK_LEN as u64;
+            f.seek(SeekFrom::Start(offset))?;
+            let n = f.read(&mut buf)?;
+            // Don't let the next reads of the same block hit the cache:
+            evict_page_cache(&f, FilePos(offset), FileLen(n as u64));
+            ops += 1;
+        }
+        Ok(ops)
+    })?;
+    Ok(ops as f64 / start.elapsed().as_secs_f64())
+}
+
+/// Runs the function in the given number of threads and returns the sum of the results.
+fn run_threads(threads: usize, f: impl Fn(usize) -> io::Result<u64> + Sync) -> io::Result<u64> {
+    let f = &f;
+    std::thread::scope(|s| {
+        let handles: Vec<_> = (0..threads).map(|i| s.spawn(move || f(i))).collect();
+        handles.into_iter().map(|h| h.join().unwrap()).sum()
+    })
+}
+
+/// Returns the smallest number of threads giving the best performance,
+/// and the best performance.
+/// Adding threads that increase the performance only slightly is not worth it.
+fn best_thread_count(results: &[(usize, f64)]) -> (usize, f64) {
+    let mut best = results[0];
+    for &(threads, value) in &results[1..] {
+        if value > best.1 * MIN_SPEEDUP {
+            best = (threads, value);
+        }
+    }
+    best
+}
+
+/// Computes the lengths of the prefixes and suffixes from the measured performance.
+///
+/// In the time of a single random read, the device can read `seq_read_rate / random_iops`
+/// bytes sequentially. We call it the seek length. Prefixes and suffixes much shorter
+/// than that cost almost the same as a single random read, so the slower the seeks are
+/// relative to the sequential throughput, the more data we read at once.
+/// Reading the suffix requires an additional seek, which pays off only for the files
+/// much longer than the seek length.
+fn device_profile(seq_read_rate: f64, random_iops: f64) -> DeviceProfile {
+    let seek_len = (seq_read_rate / random_iops.max(1.0)) as u64;
+    let prefix_len =
+        (seek_len / SEEK_LEN_TO_PREFIX_LEN_RATIO).clamp(MIN_PREFIX_LEN, MAX_PREFIX_LEN);
+    // Round down to a power of two, to keep the reads aligned to the blocks:
+    let prefix_len = 1 << (u64::BITS - 1 - prefix_len.leading_zeros());
+    let suffix_threshold = seek_len
+        .saturating_mul(SEEK_LEN_TO_SUFFIX_THRESHOLD_RATIO)
+        .clamp(MIN_SUFFIX_THRESHOLD, MAX_SUFFIX_THRESHOLD);
+    DeviceProfile {
+        min_prefix_len: FileLen(MIN_PREFIX_LEN),
+        max_prefix_len: FileLen(prefix_len),
+        suffix_len: FileLen(prefix_len),
+        suffix_threshold: FileLen(suffix_threshold),
+    }
+}
+
+/// Computes the device settings from the measured performance.
+fn device_calibration(seq: &[(usize, f64)], random: &[(usize, f64)]) -> DeviceCalibration {
+    let (seq_threads, seq_read_rate) = best_thread_count(seq);
+    let (random_threads, random_iops) = best_thread_count(random);
+    DeviceCalibration {
+        parallelism: Parallelism {
+            sequential: seq_threads,
+            random: random_threads,
+        },
+        profile: device_profile(seq_read_rate, random_iops),
+        seq_read_rate,
+        random_iops,
+    }
+}
+
+/// Measures the performance of the device by reading a test file created in `dir`.
+fn calibrate_device(
+    device: &DiskDevice,
+    dir: &Path,
+    len: FileLen,
+    log: &dyn Log,
+) -> io::Result<DeviceCalibration> {
+    let test_file = TestFile::create(dir, len.0)?;
+    let max_threads = 4 * num_cpus::get();
+    let thread_counts: Vec<usize> = std::iter::successors(Some(1), |&t| Some(t * 2))
+        .take_while(|&t| t <= max_threads)
+        .collect();
+
+    let mut seq = Vec::new();
+    let mut random = Vec::new();
+    for &threads in &thread_counts {
+        seq.push((threads, measure_sequential(&test_file, threads)?));
+        random.push((threads, measure_random(&test_file, threads)?));
+        log.info(format!(
+            "{} with {} threads: {}/s sequential, {:.0} IOPS random",
+            device.name.to_string_lossy(),
+            threads,
+            FileLen(seq.last().unwrap().1 as u64),
+            random.last().unwrap().1
+        ));
+    }
+    Ok(device_calibration(&seq, &random))
+}
+
+/// Benchmarks the devices holding the given directories
+/// and stores the results in the user config directory.
+/// Returns the calibration of the measured devices.
+pub fn calibrate(config: &CalibrateConfig, log: &dyn Log) -> Result<Calibration, Error> {
+    let path = Calibration::default_path()
+        .ok_or("Could not obtain user config directory from the system.")?;
+    if config.clear {
+        return match remove_file(&path) {
+            Ok(()) => {
+                log.info(format!("Removed calibration {}", path.display()));
+                Ok(Calibration::default())
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Calibration::default()),
+            Err(e) => Err(Error::new(format!(
+                "Failed to remove {}: {}",
+                path.display(),
+                e
+            ))),
+        };
+    }
+
+    // Writing test files to every mounted file system could fill up small or
+    // network file systems, so we calibrate only the devices chosen by the user
+    if config.paths.is_empty() {
+        return Err(Error::from("No directories to calibrate given"));
+    }
+    // Fail before running the benchmarks if the results can't be merged with the stored ones.
+    // Overwriting a corrupt file would lose the settings of the devices not calibrated now.
+    let mut stored = Calibration::load_from(&path).map_err(|e| {
+        format!(
+            "Failed to load calibration from {}: {}. Fix or remove the file \
+            with `fclones calibrate --clear`.",
+            path.display(),
+            e
+        )
+    })?;
+    let devices = DiskDevices::new(&Default::default());
+    let dirs: Vec<(Path, &DiskDevice)> = config
+        .paths
+        .iter()
+        .map(|p| {
+            let p = p.canonicalize();
+            let device = devices.get_by_path(&p);
+            (p, device)
+        })
+        .collect();
+
+    let mut calibrated = Calibration::default();
+    for (dir, device) in dirs {
+        let name = device.name.to_string_lossy().to_string();
+        if calibrated.devices.contains_key(&name) {
+            continue;
+        }
+        log.info(format!(
+            "Calibrating device {} using directory {}",
+            name,
+            dir.to_escaped_string()
+        ));
+        let c = calibrate_device(device, &dir, config.size, log).map_err(|e| {
+            format!(
+                "Failed to calibrate device {} using directory {}: {}",
+                name,
+                dir.to_escaped_string(),
+                e
+            )
+        })?;
+        calibrated.devices.insert(name, c);
+    }
+
+    stored.devices.extend(calibrated.devices.clone());
+    stored
+        .save_to(&path)
+        .map_err(|e| format!("Failed to save calibration to {}: {}", path.display(), e))?;
+    log.info(format!("Saved calibration to {}", path.display()));
+    Ok(calibrated)
+}
+
+#[cfg(test)]
+mod test {
+    use sysinfo::DiskKind;
+
+    use crate::util::test::with_dir;
+
+    use super::*;
+
+    #[test]
+    fn pick_smallest_thread_count_giving_significant_speedup() {
+        let results = [(1, 100.0), (2, 190.0), (4, 200.0), (8, 205.0)];
+        assert_eq!(best_thread_count(&results), (2, 190.0));
+        let results = [(1, 100.0), (2, 90.0), (4, 105.0)];
+        assert_eq!(best_thread_count(&results), (1, 100.0));
+    }
+
+    #[test]
+    fn tune_rotational_drive() {
+        let seq = [(1, 150e6), (2, 100e6)];
+        let random = [(1, 100.0), (2, 105.0)];
+        let c = device_calibration(&seq, &random);
+        assert_eq!(c.parallelism.sequential, 1);
+        assert_eq!(c.parallelism.random, 1);
+        // 1.5 MB can be read in the time of a seek
+        assert_eq!(c.profile.min_prefix_len, FileLen(4096));
+        assert_eq!(c.profile.max_prefix_len, FileLen(128 * 1024));
+        assert_eq!(c.profile.suffix_len, FileLen(128 * 1024));
+        assert_eq!(c.profile.suffix_threshold, FileLen(24_000_000));
+    }
+
+    #[test]
+    fn tune_ssd() {
+        let seq = [(1, 1e9), (2, 2e9), (4, 3e9)];
+        let random = [(1, 10000.0), (2, 20000.0), (4, 40000.0)];
+        let c = device_calibration(&seq, &random);
+        assert_eq!(c.parallelism.sequential, 4);
+        assert_eq!(c.parallelism.random, 4);
+        // 75 kB can be read in the time of a seek
+        assert_eq!(c.profile.max_prefix_len, FileLen(8 * 1024));
+        assert_eq!(c.profile.suffix_len, FileLen(8 * 1024));
+        assert_eq!(c.profile.suffix_threshold, FileLen(1_200_000));
+    }
+
+    #[test]
+    fn clamp_tuned_lengths() {
+        let p = device_profile(1e9, 1e6);
+        assert_eq!(p.max_prefix_len, FileLen(MIN_PREFIX_LEN));
+        assert_eq!(p.suffix_threshold, FileLen(MIN_SUFFIX_THRESHOLD));
+        let p = device_profile(1e9, 1.0);
+        assert_eq!(p.max_prefix_len, FileLen(MAX_PREFIX_LEN));
+        assert_eq!(p.suffix_threshold, FileLen(MAX_SUFFIX_THRESHOLD));
+    }
+
+    #[test]
+    fn measure_test_file() {
+        with_dir("calibrate/measure", |root| {
+            let file = TestFile::create(&Path::from(root), 1024 * 1024).unwrap();
+            let path = file.path.clone();
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), 1024 * 1024);
+            assert!(measure_sequential(&file, 2).unwrap() > 0.0);
+            assert!(measure_random(&file, 2).unwrap() > 0.0);
+            drop(file);
+            assert!(!path.exists());
+        });
+    }
+
+    #[test]
+    fn save_and_load_calibration() {
+        with_dir("calibrate/save_load", |root| {
+            let path = root.join("fclones").join("devices.json");
+            assert_eq!(
+                Calibration::load_from(&path).unwrap(),
+                Calibration::default()
+            );
+
+            let mut calibration = Calibration::default();
+            calibration.devices.insert(
+                "/dev/sda".to_owned(),
+                DeviceCalibration {
+                    parallelism: Parallelism {
+                        sequential: 1,
+                        random: 2,
+                    },
+                    profile: DeviceProfile::default_for(DiskKind::HDD),
+                    seq_read_rate: 150e6,
+                    random_iops: 120.0,
+                },
+            );
+            calibration.save_to(&path).unwrap();
+            let loaded = Calibration::load_from(&path).unwrap();
+            assert_eq!(loaded, calibration);
+            assert!(loaded.get(OsStr::new("/dev/sda")).is_some());
+        });
+    }
+
+    #[test]
+    fn fail_to_load_corrupt_calibration() {
+        with_dir("calibrate/corrupt", |root| {
+            let path = root.join("devices.json");
+            std::fs::write(&path, "{\"devices\": ").unwrap();
+            let err = Calibration::load_from(&path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        });
+    }
+}
@@ -0,0 +1,517 @@
+//! Computing and verifying checksums of files in the formats of `sha256sum` and `b3sum`.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{stdin, BufRead, BufReader, Write};
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use itertools::Itertools;
+
+use crate::config::{GroupConfig, HashConfig};
+use crate::error::Error;
+use crate::file::{FileAccess, FileChunk, FileHash, FileInfo, FileLen, FilePos};
+use crate::group::{rehash, scan_files, FileGroup, GroupCtx};
+use crate::hasher::HashFn;
+use crate::log::{Log, LogExt, ProgressBarLength};
+use crate::path::Path;
+use crate::phase::Phase;
+use crate::progress::ProgressTracker;
+
+/// Returns the name of the hash function used in the BSD tag format
+fn tag_name(hash_fn: HashFn) -> &'static str {
+    match hash_fn {
+        HashFn::Metro => "METRO",
+        #[cfg(feature = "xxhash")]
+        HashFn::Xxhash => "XXHASH3",
+        #[cfg(feature = "blake3")]
+        HashFn::Blake3 => "BLAKE3",
+        #[cfg(feature = "sha2")]
+        HashFn::Sha256 => "SHA256",
+        #[cfg(feature = "sha2")]
+        HashFn::Sha512 => "SHA512",
+        #[cfg(feature = "sha3")]
+        HashFn::Sha3_256 => "SHA3-256",
+        #[cfg(feature = "sha3")]
+        HashFn::Sha3_512 => "SHA3-512",
+    }
+}
+
+/// Escapes a file name the same way as `sha256sum` does.
+/// Returns true if the name needed escaping, in which case the line must start with a backslash.
+fn escape_name(name: &str) -> (bool, String) {
+    if !name.contains(['\\', '\n', '\r']) {
+        return (false, name.to_owned());
+    }
+    let escaped = name
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    (true, escaped)
+}
+
+fn unescape_name(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('\\')) => result.push('\\'),
+            ('\\', Some('n')) => result.push('\n'),
+            ('\\', Some('r')) => result.push('\r'),
+            _ => {
+                result.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    result
+}
+
+/// Formats a line of a checksum file.
+/// If `hash_fn` is given, the BSD tag format is used.
+pub fn format_checksum(hash: &FileHash, name: &str, hash_fn: Option<HashFn>) -> String {
+    let (escaped, name) = escape_name(name);
+    let prefix = if escaped { "\\" } else { "" };
+    match hash_fn {
+        Some(hash_fn) => format!("{prefix}{} ({name}) = {hash}", tag_name(hash_fn)),
+        None => format!("{prefix}{hash}  {name}"),
+    }
+}
+
+/// A single entry read from a checksum file
+#[derive(Debug, PartialEq, Eq)]
+pub struct Checksum {
+    pub name: String,
+    pub hash: FileHash,
+    /// Set if the hash function was given explicitly in the BSD tag format
+    pub hash_fn: Option<HashFn>,
+}
+
+/// Parses a line of a checksum file in the `sha256sum` or the BSD tag format.
+/// Returns `None` if the line is malformed.
+pub fn parse_checksum(line: &str) -> Option<Checksum> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (name, hash, hash_fn) = match line.split_once(" (") {
+        Some((tag, rest)) if !tag.contains(' ') => {
+            let (name, hash) = rest.rsplit_once(") = ")?;
+            let hash_fn = HashFn::value_variants()
+                .iter()
+                .find(|h| tag_name(**h).eq_ignore_ascii_case(tag))?;
+            (name, hash, Some(*hash_fn))
+        }
+        _ => {
+            let (hash, name) = line.split_once(' ')?;
+            let name = name.strip_prefix([' ', '*'])?;
+            (name, hash, None)
+        }
+    };
+    let name = if escaped {
+        unescape_name(name)
+    } else {
+        name.to_owned()
+    };
+    let hash = hash.parse().ok()?;
+    Some(Checksum {
+        name,
+        hash,
+        hash_fn,
+    })
+}
+
+/// Computes the hashes of the full contents of all files selected by the config.
+/// The files are hashed in parallel, using the thread-pools dedicated to their devices.
+/// Unreadable files are logged and skipped.
+pub fn hash_files(config: &GroupConfig, log: &dyn Log) -> Result<Vec<(FileInfo, FileHash)>, Error> {
+    let mut ctx = GroupCtx::new(config, log)?;
+    ctx.set_phases(vec![Phase::Walk, Phase::Hash]);
+    let files: Vec<_> = scan_files(&ctx).into_iter().flatten().collect();
+    let total_len = files.iter().map(|f| f.len).sum::<FileLen>();
+    let progress = &ctx.phase_progress_bar(Phase::Hash, ProgressBarLength::Bytes(total_len.0));
+
+    // Each file gets its own group, so all of them get hashed:
+    let groups = files
+        .into_iter()
+        .map(|f| FileGroup {
+            file_len: f.len,
+            file_hash: FileHash::default(),
+            files: vec![f],
+            ..Default::default()
+        })
+        .collect();
+    let groups = rehash(
+        groups,
+        |_| true,
+        |_| true,
+        &ctx.devices,
+        FileAccess::Sequential,
+        |(fi, _)| {
+            let chunk = FileChunk::new(&fi.path, FilePos(0), fi.len);
+            ctx.hasher
+                .hash_file_or_log_err(&chunk, |bytes_read| progress.inc(bytes_read as u64))
+        },
+    );
+
+    Ok(groups
+        .into_iter()
+        .flat_map(|g| {
+            let hash = g.file_hash;
+            g.files.into_iter().map(move |f| (f, hash.clone()))
+        })
+        .collect())
+}
+
+fn output_error(e: io::Error) -> Error {
+    Error::new(format!("Failed to write output: {e}"))
+}
+
+/// Returns the name of the file to be written to the checksum file.
+/// Files inside the base directory are written with relative paths.
+fn display_name(path: &Path, base_dir: &Path) -> String {
+    path.strip_prefix(base_dir)
+        .unwrap_or_else(|| path.clone())
+        .to_string_lossy()
+}
+
+/// Computes the checksums of the files given in the config and writes them to `out`.
+pub fn write_checksums(
+    config: &HashConfig,
+    log: &dyn Log,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let mut group_config = config.group_config(config.paths.clone(), config.hash_fn());
+    let base_dir = group_config
+        .resolve_base_dir()
+        .map_err(|e| e.to_string())?
+        .clone();
+    let files = hash_files(&group_config, log)?;
+    let tag = config.tag.then_some(config.hash_fn());
+    let mut lines: Vec<_> = files
+        .into_iter()
+        .map(|(f, hash)| (display_name(&f.path, &base_dir), hash))
+        .collect();
+    lines.sort_by(|a, b| a.0.cmp(&b.0));
+    for (name, hash) in lines {
+        writeln!(out, "{}", format_checksum(&hash, &name, tag)).map_err(output_error)?;
+    }
+    Ok(())
+}
+
+/// Reads the checksums from the given files, or from the standard input if none are given.
+/// Malformed lines are logged and skipped.
+fn read_checksums(files: &[Path], log: &dyn Log) -> Result<Vec<Checksum>, Error> {
+    let readers: Vec<(String, Box<dyn BufRead>)> = if files.is_empty() {
+        vec![("-".to_owned(), Box::new(BufReader::new(stdin())))]
+    } else {
+        files
+            .iter()
+            .map(|f| {
+                let name = f.to_escaped_string();
+                let file = File::open(f.to_path_buf())
+                    .map_err(|e| format!("Failed to open checksum file {name}: {e}"))?;
+                Ok((name, Box::new(BufReader::new(file)) as Box<dyn BufRead>))
+            })
+            .collect::<Result<_, Error>>()?
+    };
+
+    let mut checksums = Vec::new();
+    for (name, reader) in readers {
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| format!("Failed to read checksum file {name}: {e}"))?;
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_checksum(line) {
+                Some(checksum) => checksums.push(checksum),
+                None => log.warn(format!(
+                    "{}:{}: Improperly formatted checksum line",
+                    name,
+                    line_no + 1
+                )),
+            }
+        }
+    }
+    Ok(checksums)
+}
+
+/// Selects the hash function to verify the checksum with.
+///
+/// Lines in the BSD tag format name their hash function. For the other lines, the function
+/// given with `--hash-fn` is used. Without `--hash-fn`, the default function is used if its
+/// digests have the same length as the checksum, otherwise SHA-2, as written by `sha256sum`
+/// and `sha512sum`, or the only available function producing digests of that length.
+/// Returns an error if the length of the checksum doesn't match the selected function
+/// or if several functions produce digests of that length.
+fn checksum_hash_fn(c: &Checksum, config: &HashConfig) -> Result<HashFn, String> {
+    let len = c.hash.as_ref().len();
+    let (_, name) = escape_name(&c.name);
+    let explicit = c.hash_fn.or(config.hash_fn);
+    if let Some(hash_fn) = explicit {
+        if hash_fn.digest_len() != len {
+            return Err(format!(
+                "{name}: The checksum has {} bits, but {} checksums have {} bits",
+                len * 8,
+                tag_name(hash_fn),
+                hash_fn.digest_len() * 8
+            ));
+        }
+        return Ok(hash_fn);
+    }
+    if config.hash_fn().digest_len() == len {
+        return Ok(config.hash_fn());
+    }
+    #[cfg(feature = "sha2")]
+    if let Some(hash_fn) = [HashFn::Sha256, HashFn::Sha512]
+        .into_iter()
+        .find(|h| h.digest_len() == len)
+    {
+        return Ok(hash_fn);
+    }
+    let candidates: Vec<HashFn> = HashFn::value_variants()
+        .iter()
+        .copied()
+        .filter(|h| h.digest_len() == len)
+        .collect();
+    match candidates.as_slice() {
+        [hash_fn] => Ok(*hash_fn),
+        [] => Err(format!(
+            "{name}: No supported hash function produces {}-bit checksums",
+            len * 8
+        )),
+        _ => Err(format!(
+            "{name}: {}-bit checksums can be produced by {}. Select one with --hash-fn",
+            len * 8,
+            candidates.iter().map(|h| tag_name(*h)).join(", ")
+        )),
+    }
+}
+
+/// Verifies the checksums read from the files given in the config.
+/// Writes the status of each file to `out`.
+/// Returns an error if any file could not be read or its hash does not match.
+pub fn check_checksums(
+    config: &HashConfig,
+    log: &dyn Log,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let checksums = read_checksums(&config.paths, log)?;
+    let base_dir = Arc::new(
+        config
+            .group_config(vec![], config.hash_fn())
+            .resolve_base_dir()
+            .map_err(|e| e.to_string())?
+            .clone(),
+    );
+    let paths: Vec<Path> = checksums
+        .iter()
+        .map(|c| base_dir.resolve(Path::from(c.name.as_str())))
+        .collect();
+
+    let checksum_hash_fns: Vec<HashFn> = checksums
+        .iter()
+        .map(|c| checksum_hash_fn(c, config))
+        .collect::<Result<_, _>>()?;
+
+    // Files can be listed with different hash functions, so we need to hash them separately
+    let mut hash_fns: Vec<HashFn> = Vec::new();
+    for hash_fn in &checksum_hash_fns {
+        if !hash_fns.contains(hash_fn) {
+            hash_fns.push(*hash_fn);
+        }
+    }
+    let mut computed: HashMap<(Path, HashFn), FileHash> = HashMap::new();
+    for hash_fn in hash_fns {
+        let paths = checksum_hash_fns
+            .iter()
+            .zip(paths.iter())
+            .filter(|(h, _)| **h == hash_fn)
+            .map(|(_, p)| p.clone())
+            .collect();
+        let mut group_config = config.group_config(paths, hash_fn);
+        // Check exactly the listed files:
+        group_config.depth = Some(0);
+        group_config.hidden = true;
+        group_config.no_ignore = true;
+        group_config.follow_links = true;
+        group_config.resolve_base_dir().map_err(|e| e.to_string())?;
+        for (f, hash) in hash_files(&group_config, log)? {
+            computed.insert((f.path, hash_fn), hash);
+        }
+    }
+
+    let mut failed = 0;
+    let mut unreadable = 0;
+    for ((c, path), hash_fn) in checksums.iter().zip(paths).zip(checksum_hash_fns) {
+        let (_, name) = escape_name(&c.name);
+        match computed.get(&(path, hash_fn)) {
+            Some(hash) if *hash == c.hash => {
+                if !config.quiet {
+                    writeln!(out, "{name}: OK").map_err(output_error)?;
+                }
+            }
+            Some(_) => {
+                failed += 1;
+                writeln!(out, "{name}: FAILED").map_err(output_error)?;
+            }
+            None => {
+                unreadable += 1;
+                writeln!(out, "{name}: FAILED open or read").map_err(output_error)?;
+            }
+        }
+    }
+    out.flush().map_err(output_error)?;
+
+    if unreadable > 0 {
+        log.warn(format!("{unreadable} listed files could not be read"));
+    }
+    if failed > 0 {
+        log.warn(format!("{failed} computed checksums did NOT match"));
+    }
+    if failed + unreadable > 0 {
+        return Err(Error::new(format!(
+            "{} of {} files failed verification",
+            failed + unreadable,
+            checksums.len()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::write;
+
+    use crate::log::StdLog;
+    use crate::util::test::with_dir;
+
+    use super::*;
+
+    fn test_log() -> StdLog {
+        let mut log = StdLog::new();
+        log.no_progress = true;
+        log
+    }
+
+    #[test]
+    fn format_and_parse_checksum_lines() {
+        let hash: FileHash = "00ff".parse().unwrap();
+        assert_eq!(format_checksum(&hash, "a b", None), "00ff  a b");
+        assert_eq!(format_checksum(&hash, "a\\b\n", None), "\\00ff  a\\\\b\\n");
+        assert_eq!(
+            format_checksum(&hash, "a", Some(HashFn::Metro)),
+            "METRO (a) = 00ff"
+        );
+
+        for line in ["00ff  a b", "00ff *a b"] {
+            let c = parse_checksum(line).unwrap();
+            assert_eq!(c.name, "a b");
+            assert_eq!(c.hash, hash);
+            assert_eq!(c.hash_fn, None);
+        }
+        let c = parse_checksum("\\00ff  a\\\\b\\n").unwrap();
+        assert_eq!(c.name, "a\\b\n");
+        let c = parse_checksum("METRO (a (1).txt) = 00ff").unwrap();
+        assert_eq!(c.name, "a (1).txt");
+        assert_eq!(c.hash_fn, Some(HashFn::Metro));
+
+        assert!(parse_checksum("00ff a").is_none());
+        assert!(parse_checksum("xyz  a").is_none());
+        assert!(parse_checksum("FOO (a) = 00ff").is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "sha2")]
+    fn hash_is_compatible_with_sha256sum() {
+        with_dir("checksum/sha256", |root| {
+            let file = root.join("file");
+            write(&file, b"hello\n").unwrap();
+            let config = HashConfig {
+                hash_fn: Some(HashFn::Sha256),
+                paths: vec![Path::from(&file)],
+                ..HashConfig::default()
+            };
+            let mut out = Vec::new();
+            write_checksums(&config, &test_log(), &mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(out
+                .starts_with("5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  "));
+        });
+    }
+
+    #[test]
+    fn check_checksums_detects_modified_and_missing_files() {
+        with_dir("checksum/check", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            let file3 = root.join("file3");
+            write(&file1, b"foo").unwrap();
+            write(&file2, b"bar").unwrap();
+            write(&file3, b"").unwrap();
+            let log = test_log();
+
+            let config = HashConfig {
+                tag: true,
+                paths: vec![Path::from(root)],
+                ..HashConfig::default()
+            };
+            let mut manifest = Vec::new();
+            write_checksums(&config, &log, &mut manifest).unwrap();
+            let manifest_file = root.join("manifest");
+            write(&manifest_file, &manifest).unwrap();
+
+            let config = HashConfig {
+                check: true,
+                paths: vec![Path::from(&manifest_file)],
+                ..HashConfig::default()
+            };
+            let mut out = Vec::new();
+            check_checksums(&config, &log, &mut out).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap().matches(": OK\n").count(), 3);
+
+            write(&file1, b"baz").unwrap();
+            std::fs::remove_file(&file2).unwrap();
+            let mut out = Vec::new();
+            assert!(check_checksums(&config, &log, &mut out).is_err());
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.contains("file1: FAILED\n"));
+            assert!(out.contains("file2: FAILED open or read\n"));
+            assert!(out.contains("file3: OK\n"));
+        });
+    }
+
+    #[test]
+    #[cfg(all(feature = "sha2", feature = "blake3"))]
+    fn hash_fn_is_selected_by_checksum_length() {
+        let sha256 =
+            parse_checksum("5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  a")
+                .unwrap();
+        let metro = parse_checksum("00112233445566778899aabbccddeeff  a").unwrap();
+        let config = HashConfig::default();
+        assert_eq!(checksum_hash_fn(&metro, &config), Ok(HashFn::Metro));
+        assert_eq!(checksum_hash_fn(&sha256, &config), Ok(HashFn::Sha256));
+
+        let config = HashConfig {
+            hash_fn: Some(HashFn::Blake3),
+            ..HashConfig::default()
+        };
+        assert_eq!(checksum_hash_fn(&sha256, &config), Ok(HashFn::Blake3));
+        let err = checksum_hash_fn(&metro, &config).unwrap_err();
+        assert!(err.contains("128 bits"), "{err}");
+
+        let config = HashConfig {
+            hash_fn: Some(HashFn::Sha256),
+            ..HashConfig::default()
+        };
+        assert_eq!(checksum_hash_fn(&sha256, &config), Ok(HashFn::Sha256));
+        let err = checksum_hash_fn(&metro, &config).unwrap_err();
+        assert!(err.contains("128 bits"), "{err}");
+
+        let tagged = parse_checksum("SHA256 (a) = 00ff").unwrap();
+        assert!(checksum_hash_fn(&tagged, &HashConfig::default()).is_err());
+    }
+}
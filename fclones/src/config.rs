@@ -8,20 +8,31 @@ use std::io::{stdin, BufRead, BufReader, ErrorKind};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use chrono::{DateTime, FixedOffset, Local};
 use clap::builder::{TypedValueParser, ValueParserFactory};
 
-use clap::{command, Arg, Error};
+use clap::{Arg, Error};
+use serde::{Deserialize, Serialize};
 
+use crate::calibrate::Calibration;
+use crate::expr::FileExpr;
 use crate::file::FileLen;
+use crate::filetype::{TypeFilter, TypeSpec};
 use crate::group::FileGroupFilter;
 use crate::group::Replication::{Overreplicated, Underreplicated};
 use crate::hasher::HashFn;
+use crate::log::LogFormat;
+#[cfg(unix)]
+use crate::metadata::{resolve_group, resolve_user};
+use crate::metadata::{MetadataFilter, PermSpec};
+use crate::normalize::{NormalizerSpec, Normalizers};
 use crate::path::Path;
 use crate::pattern::{Pattern, PatternError, PatternOpts};
+use crate::report::FileMarks;
 use crate::selector::PathSelector;
-use crate::transform::Transform;
+use crate::transform::{Transform, TransformLimits};
 
 #[derive(Debug, Clone, Copy, clap::ValueEnum, Default)]
 pub enum OutputFormat {
@@ -30,11 +41,24 @@ pub enum OutputFormat {
     Fdupes,
     Csv,
     Json,
+    Ndjson,
+    Html,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
 }
 
 impl OutputFormat {
     pub fn variants() -> Vec<&'static str> {
-        vec!["default", "fdupes", "csv", "json"]
+        vec![
+            "default",
+            "fdupes",
+            "csv",
+            "json",
+            "ndjson",
+            "html",
+            #[cfg(feature = "sqlite")]
+            "sqlite",
+        ]
     }
 }
 
@@ -45,6 +69,10 @@ impl Display for OutputFormat {
             OutputFormat::Fdupes => f.pad("fdupes"),
             OutputFormat::Csv => f.pad("csv"),
             OutputFormat::Json => f.pad("json"),
+            OutputFormat::Ndjson => f.pad("ndjson"),
+            OutputFormat::Html => f.pad("html"),
+            #[cfg(feature = "sqlite")]
+            OutputFormat::Sqlite => f.pad("sqlite"),
         }
     }
 }
@@ -58,6 +86,10 @@ impl FromStr for OutputFormat {
             "fdupes" => Ok(OutputFormat::Fdupes),
             "csv" => Ok(OutputFormat::Csv),
             "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "html" => Ok(OutputFormat::Html),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(OutputFormat::Sqlite),
             s => Err(format!("Unrecognized output format: {s}")),
         }
     }
@@ -90,7 +122,7 @@ impl ValueParserFactory for Path {
 }
 
 /// Parses date time string, accepts wide range of human-readable formats
-fn parse_date_time(s: &str) -> Result<DateTime<FixedOffset>, String> {
+pub(crate) fn parse_date_time(s: &str) -> Result<DateTime<FixedOffset>, String> {
     match dtparse::parse(s) {
         Ok((dt, Some(offset))) => Ok(DateTime::from_naive_utc_and_offset(dt, offset)),
         Ok((dt, None)) => {
@@ -101,15 +133,53 @@ fn parse_date_time(s: &str) -> Result<DateTime<FixedOffset>, String> {
     }
 }
 
-/// Parses string with format: `<device>:<seq parallelism>[,<rand parallelism>]`
-fn parse_thread_count_option(s: &str) -> Result<(OsString, Parallelism), String> {
+/// Converts raw bytes read from a stream to an OS string.
+#[cfg(unix)]
+pub(crate) fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+/// Converts raw bytes read from a stream to an OS string.
+/// Invalid UTF-8 sequences are replaced.
+#[cfg(not(unix))]
+pub(crate) fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    OsString::from(String::from_utf8_lossy(&bytes).to_string())
+}
+
+/// Reads patterns from the given files, one pattern per line.
+/// Skips empty lines and comments starting with `#`.
+fn read_pattern_files(files: &[PathBuf]) -> Result<Vec<String>, String> {
+    let mut patterns = Vec::new();
+    for file in files {
+        let content = std::fs::read_to_string(file)
+            .map_err(|e| format!("Failed to read patterns from {}: {}", file.display(), e))?;
+        patterns.extend(
+            content
+                .lines()
+                .map(|line| line.trim_end_matches('\r'))
+                .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                .map(|line| line.to_owned()),
+        );
+    }
+    Ok(patterns)
+}
+
+/// Splits a string with format `[<device>:]<value>` into the device key and the value.
+/// If the device is not given, the key is `default`.
+fn split_device_option(s: &str) -> (OsString, &str) {
     let (key, value) = if s.contains(':') {
         let index = s.rfind(':').unwrap();
         (&s[0..index], &s[(index + 1)..])
     } else {
         ("default", s)
     };
-    let key = OsString::from(key);
+    (OsString::from(key), value)
+}
+
+/// Parses string with format: `<device>:<seq parallelism>[,<rand parallelism>]`
+fn parse_thread_count_option(s: &str) -> Result<(OsString, Parallelism), String> {
+    let (key, value) = split_device_option(s);
     let value = value.to_string();
     let mut pool_sizes = value
         .split(',')
@@ -126,7 +196,48 @@ fn parse_thread_count_option(s: &str) -> Result<(OsString, Parallelism), String>
     Ok((key, Parallelism { random, sequential }))
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Parses string with format: `[<device>:]<bytes per second>`
+fn parse_read_rate_option(s: &str) -> Result<(OsString, FileLen), String> {
+    let (key, value) = split_device_option(s);
+    match value.parse::<FileLen>() {
+        Ok(rate) if rate.0 > 0 => Ok((key, rate)),
+        Ok(_) => Err(String::from("Read rate must be greater than zero")),
+        Err(e) => Err(format!("{e}: {value}")),
+    }
+}
+
+/// Parses a positive, possibly fractional number of seconds
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs > 0.0 => Duration::try_from_secs_f64(secs).map_err(|e| e.to_string()),
+        Ok(_) => Err(String::from(
+            "The number of seconds must be greater than zero",
+        )),
+        Err(e) => Err(format!("{e}: {s}")),
+    }
+}
+
+/// Parses string with format: `<glob>=<command>`
+fn parse_transform_rule(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((glob, command)) if !glob.is_empty() && !command.trim().is_empty() => {
+            Ok((glob.to_owned(), command.to_owned()))
+        }
+        _ => Err(format!("Expected <GLOB>=<COMMAND>: {s}")),
+    }
+}
+
+/// Parses string with format: `[<device>:]<operations per second>`
+fn parse_iops_option(s: &str) -> Result<(OsString, u64), String> {
+    let (key, value) = split_device_option(s);
+    match value.parse::<u64>() {
+        Ok(iops) if iops > 0 => Ok((key, iops)),
+        Ok(_) => Err(String::from("IOPS limit must be greater than zero")),
+        Err(e) => Err(format!("{e}: {value}")),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Parallelism {
     pub random: usize,
     pub sequential: usize,
@@ -155,6 +266,17 @@ pub struct GroupConfig {
     #[arg(long)]
     pub stdin: bool,
 
+    /// Separate paths with NUL characters instead of newlines.
+    ///
+    /// Applies to the paths read from the standard input with `--stdin` and to the paths
+    /// written in the `default` and `fdupes` formats. In the `fdupes` format, groups are
+    /// separated by an extra NUL. Paths are written verbatim, without escaping special characters.
+    /// Other output formats escape the paths on their own and don't allow this flag
+    /// without `--stdin`.
+    /// Allows to process file names containing newlines with `find -print0` and `xargs -0`.
+    #[arg(short = '0', long)]
+    pub null: bool,
+
     /// Limit the recursion depth.
     ///
     /// 0 disables descending into directories.
@@ -167,7 +289,8 @@ pub struct GroupConfig {
     #[arg(short = '.', long)]
     pub hidden: bool,
 
-    /// Do not ignore files matching patterns listed in `.gitignore` and `.fdignore`.
+    /// Do not ignore files matching patterns listed in `.gitignore`, `.fdignore`,
+    /// `.fclonesignore` and the global `fclones/ignore` file in the user config directory.
     #[arg(short = 'A', long)]
     pub no_ignore: bool,
 
@@ -182,6 +305,8 @@ pub struct GroupConfig {
     ///
     /// If `--symbolic-links` is not set, only hard links are matched.
     /// If `--symbolic-links` is set, both hard and symbolic links are matched.
+    /// On Linux, files reflinked together so that they share all their data extents
+    /// are matched as well.
     #[arg(short = 'H', long)]
     pub match_links: bool,
 
@@ -246,6 +371,75 @@ pub struct GroupConfig {
     #[arg(long)]
     pub no_copy: bool,
 
+    /// Transform the files with names matching the glob by the specified program before matching.
+    ///
+    /// The value has the form `<GLOB>=<COMMAND>`, where the command has the same syntax as
+    /// the one given to `--transform`. Can be given multiple times to use different programs
+    /// for different kinds of files. The first rule matching the file name wins.
+    /// Files not matching any rule are compared by their raw contents.
+    ///
+    /// Files transformed by different programs are never put in the same group.
+    /// The command used to compute the hash of each group is recorded in the
+    /// JSON and CSV reports.
+    #[arg(
+        long,
+        value_name("GLOB=COMMAND"),
+        value_parser = parse_transform_rule,
+        conflicts_with_all(["transform", "normalize"])
+    )]
+    pub transform_for: Vec<(String, String)>,
+
+    /// Kill the transform program if it doesn't finish processing a file in the given time.
+    ///
+    /// The file is skipped, a warning is logged and the file is listed in the `json` report.
+    /// Applies to `--transform` and `--transform-for`.
+    #[arg(long, value_name("SECONDS"), value_parser = parse_seconds)]
+    pub transform_timeout: Option<Duration>,
+
+    /// Kill the transform program if it outputs more data than the given limit for a single file.
+    ///
+    /// The file is skipped, a warning is logged and the file is listed in the `json` report.
+    /// Applies to `--transform` and `--transform-for`.
+    #[arg(long, value_name("BYTES"))]
+    pub transform_max_output: Option<FileLen>,
+
+    /// Limit the virtual memory available to the transform program.
+    ///
+    /// Memory allocations above the limit fail, which usually makes the program exit with
+    /// an error. Applies to `--transform` and `--transform-for`. Unix only.
+    #[arg(long, value_name("BYTES"))]
+    pub transform_max_memory: Option<FileLen>,
+
+    /// Kill the transform program if it uses more CPU time than the given limit
+    /// for a single file.
+    ///
+    /// Applies to `--transform` and `--transform-for`. Unix only.
+    #[arg(long, value_name("SECONDS"))]
+    pub transform_max_cpu: Option<u64>,
+
+    /// Normalize file contents with a built-in normalizer before matching.
+    ///
+    /// The value has the form `<NAME>[:<GLOB>]`, where the glob selects the names of the files
+    /// the normalizer applies to. If the glob is not given, a default one is used.
+    /// Can be given multiple times. Files not matching any glob are compared as they are.
+    ///
+    /// Available normalizers:
+    ///
+    /// - `exif`: strips EXIF, XMP, IPTC metadata and comments from JPEG and PNG images
+    ///   (default glob: `*.{jpg,jpeg,png}`)
+    ///
+    /// - `audio-tags`: strips ID3 and APE tags from MP3 files and metadata blocks
+    ///   from FLAC files (default glob: `*.{mp3,flac}`)
+    ///
+    /// - `text`: converts line endings to LF and removes trailing whitespace
+    ///   (default glob: `*.{txt,md,rst,csv,tsv,log}`)
+    ///
+    /// - `gzip`, `zstd`: decompress the file (default globs: `*.gz`, `*.zst`)
+    ///
+    /// Unlike `--transform`, normalizers run in-process and don't spawn external programs.
+    #[arg(long, value_name("NAME[:GLOB]"), conflicts_with("transform"))]
+    pub normalize: Vec<NormalizerSpec>,
+
     /// Search for over-replicated files with replication factor above the specified value.
     ///
     /// Specifying neither `--rf-over` nor `--rf-under` is equivalent to `--rf-over 1` which would
@@ -275,6 +469,72 @@ pub struct GroupConfig {
     #[arg(long("max"), value_name("BYTES"))]
     pub max_size: Option<FileLen>,
 
+    /// Include only files modified after the given time.
+    #[arg(long, value_name = "TIMESTAMP", value_parser(parse_date_time))]
+    pub modified_after: Option<DateTime<FixedOffset>>,
+
+    /// Include only files modified before the given time.
+    #[arg(long, value_name = "TIMESTAMP", value_parser(parse_date_time))]
+    pub modified_before: Option<DateTime<FixedOffset>>,
+
+    /// Include only files with status (ctime) changed after the given time.
+    #[arg(long, value_name = "TIMESTAMP", value_parser(parse_date_time))]
+    pub changed_after: Option<DateTime<FixedOffset>>,
+
+    /// Include only files with status (ctime) changed before the given time.
+    #[arg(long, value_name = "TIMESTAMP", value_parser(parse_date_time))]
+    pub changed_before: Option<DateTime<FixedOffset>>,
+
+    /// Include only files owned by any of the given users.
+    ///
+    /// Accepts user names or numeric user ids.
+    #[arg(long = "user", value_name = "USER")]
+    pub users: Vec<String>,
+
+    /// Include only files belonging to any of the given groups.
+    ///
+    /// Accepts group names or numeric group ids.
+    #[arg(long = "group", value_name = "GROUP")]
+    pub groups: Vec<String>,
+
+    /// Include only files with permission bits matching the given mode.
+    ///
+    /// The mode syntax is the same as in `find -perm`:
+    /// `644` matches exactly these bits, `-644` requires all of these bits to be set,
+    /// `/022` requires any of these bits to be set.
+    /// Symbolic modes like `o+r` or `u+s` are accepted as well.
+    #[arg(long = "perm", value_name = "MODE", allow_hyphen_values = true)]
+    pub perms: Vec<PermSpec>,
+
+    /// Ignore files with permission bits matching the given mode.
+    ///
+    /// Uses the same syntax as `--perm`, e.g. `--exclude-perm /6000` skips
+    /// setuid and setgid files.
+    #[arg(long = "exclude-perm", value_name = "MODE", allow_hyphen_values = true)]
+    pub excluded_perms: Vec<PermSpec>,
+
+    /// Include only files of the given content type.
+    ///
+    /// The type is detected from the first bytes of the file, not from its name,
+    /// and is recorded in the CSV and JSON reports, as with `--report-types`.
+    /// Accepts a category: `app`, `archive`, `audio`, `book`, `doc`, `font`, `image`,
+    /// `text`, `video`, `unknown`, or an exact MIME type like `image/png`.
+    #[arg(long = "type", value_name = "TYPE")]
+    pub types: Vec<TypeSpec>,
+
+    /// Ignore files of the given content type.
+    ///
+    /// Uses the same syntax as `--type`, e.g. `--type-not archive`.
+    #[arg(long = "type-not", value_name = "TYPE")]
+    pub excluded_types: Vec<TypeSpec>,
+
+    /// Record the content type of each group in the report, even if not filtering by type.
+    ///
+    /// The type is written to the JSON reports and to an extra `type` column of the CSV
+    /// reports, after the file paths. This is implied by `--type` and `--type-not`.
+    #[arg(long)]
+    pub report_types: bool,
+
     /// Maximum prefix size to check in bytes
     ///
     /// Units like KB, KiB, MB, MiB, GB, GiB are supported.
@@ -303,6 +563,32 @@ pub struct GroupConfig {
     #[arg(long = "exclude", value_name("PATTERN"))]
     pub exclude_patterns: Vec<String>,
 
+    /// Include only paths matched fully by any of the patterns listed in the given file.
+    ///
+    /// The file must contain one pattern per line, in the same syntax as `--path`.
+    /// Empty lines and lines starting with `#` are skipped.
+    #[arg(long, value_name("FILE"))]
+    pub include_from: Vec<PathBuf>,
+
+    /// Ignore paths matched fully by any of the patterns listed in the given file.
+    ///
+    /// The file must contain one pattern per line, in the same syntax as `--exclude`.
+    /// Empty lines and lines starting with `#` are skipped.
+    #[arg(long, value_name("FILE"))]
+    pub exclude_from: Vec<PathBuf>,
+
+    /// Include only files matching the given expression.
+    ///
+    /// The expression compares file attributes with values and combines the comparisons
+    /// with `and`, `or` and `not`. Supported attributes are:
+    /// `size`, `name`, `path`, `ext`, `mtime`, `owner`, `group`, `depth` and `links`.
+    /// Supported operators are `=`, `!=`, `<`, `<=`, `>`, `>=`, `in (...)`,
+    /// and `~`, `!~` for matching a glob pattern or an `r"regex"`.
+    ///
+    /// Example: `ext in (jpg, heic) and size > 500K and not path ~ "**/thumbs/**"`
+    #[arg(long = "where", value_name("EXPR"))]
+    pub where_expr: Option<String>,
+
     /// Make pattern matching case-insensitive.
     #[arg(short = 'i', long)]
     pub ignore_case: bool,
@@ -328,6 +614,27 @@ pub struct GroupConfig {
     #[arg(long)]
     pub skip_content_hash: bool,
 
+    /// Compare large files by a hash of N evenly distributed blocks instead of their full contents.
+    ///
+    /// The blocks are read in addition to the prefix and suffix and have the size of the suffix.
+    /// Files too short for sampling to save much I/O are still hashed entirely.
+    /// This speeds up searching for duplicates among very large files, at the risk of reporting
+    /// files that differ only outside the sampled blocks.
+    ///
+    /// The report is marked as sampled and the `link`, `move`, `remove` and `dedupe`
+    /// commands refuse to process it unless given `--allow-sampled`.
+    #[arg(long, value_name = "N", conflicts_with_all = ["skip_content_hash", "transform", "normalize", "transform_for"])]
+    pub sample_hash: Option<usize>,
+
+    /// Don't report groups of files containing only zero bytes.
+    ///
+    /// Preallocated, zero-filled or sparse files, e.g. virtual machine disk images
+    /// or placeholders of files being downloaded, often form large groups of bogus duplicates.
+    /// Without this flag, such groups are reported, but marked as zero-filled,
+    /// so they can be handled separately, e.g. by `fclones sparsify`.
+    #[arg(long)]
+    pub exclude_zero_filled: bool,
+
     /// Enable caching of file hashes.
     ///
     /// Caching can significantly speed up subsequent runs of `fclones group` by avoiding
@@ -360,6 +667,41 @@ pub struct GroupConfig {
       verbatim_doc_comment)]
     pub threads: Vec<(OsString, Parallelism)>,
 
+    /// Limit the rate of reading data from the disks
+    ///
+    /// The spec has the following format: `[<name>:]<rate>`.
+    /// The name can be one of:
+    /// (1) a physical block device when prefixed with `dev:` e.g. `dev:/dev/sda`;
+    /// (2) a type of device - `ssd`, `hdd`, `removable` or `unknown`.
+    /// If the name is not given, the limit applies to the total rate of reads
+    /// from all devices together.
+    ///
+    /// The rate is the number of bytes per second.
+    /// Units like KB, KiB, MB, MiB, GB, GiB are supported.
+    /// Reading directories and file metadata counts as reading 4 KiB.
+    ///
+    /// This parameter can be used multiple times to limit multiple devices.
+    #[arg(
+      long,
+      value_name = "SPEC",
+      value_parser = parse_read_rate_option,
+      verbatim_doc_comment)]
+    pub max_read_rate: Vec<(OsString, FileLen)>,
+
+    /// Limit the number of I/O operations per second
+    ///
+    /// The spec has the following format: `[<name>:]<iops>`,
+    /// where the name is interpreted the same way as in `--max-read-rate`.
+    ///
+    /// Each read of a block of file data, each directory read and each metadata
+    /// read counts as one operation.
+    #[arg(
+      long,
+      value_name = "SPEC",
+      value_parser = parse_iops_option,
+      verbatim_doc_comment)]
+    pub max_iops: Vec<(OsString, u64)>,
+
     /// Base directory to use when resolving relative input paths.
     #[arg(long, value_name = "PATH", default_value("."))]
     pub base_dir: Path,
@@ -371,10 +713,29 @@ pub struct GroupConfig {
     /// limit is specified with `--depth`.
     #[arg(required_unless_present("stdin"))]
     pub paths: Vec<Path>,
+
+    /// Settings of the devices measured by `fclones calibrate`.
+    /// Not loaded automatically, so the results don't depend on the settings stored
+    /// on the machine, unless the caller loads them with [`Calibration::load`].
+    #[arg(skip)]
+    pub calibration: Calibration,
 }
 
 impl GroupConfig {
     fn validate(&self) -> Result<(), String> {
+        if self.null
+            && !self.stdin
+            && !matches!(self.format, OutputFormat::Default | OutputFormat::Fdupes)
+        {
+            return Err(format!(
+                "The --null flag requires --stdin or the default or fdupes output format, \
+                 but the output format is {}",
+                self.format
+            ));
+        }
+        if self.sample_hash == Some(0) {
+            return Err("The --sample-hash option requires at least one block".to_owned());
+        }
         if self.isolate && self.paths.len() <= self.rf_over() {
             return Err(format!(
                 "The --isolate flag requires that the number of input paths ({}) \
@@ -401,6 +762,32 @@ impl GroupConfig {
         Ok(())
     }
 
+    #[cfg(target_os = "linux")]
+    fn validate_watch(&self) -> Result<(), String> {
+        if self.stdin {
+            return Err("The watch command doesn't support --stdin".to_owned());
+        }
+        if self.transform.is_some() {
+            return Err("The watch command doesn't support --transform".to_owned());
+        }
+        if !self.normalize.is_empty() {
+            return Err("The watch command doesn't support --normalize".to_owned());
+        }
+        if !self.transform_for.is_empty() {
+            return Err("The watch command doesn't support --transform-for".to_owned());
+        }
+        if self.skip_content_hash {
+            return Err("The watch command doesn't support --skip-content-hash".to_owned());
+        }
+        if self.sample_hash.is_some() {
+            return Err("The watch command doesn't support --sample-hash".to_owned());
+        }
+        if self.exclude_zero_filled {
+            return Err("The watch command doesn't support --exclude-zero-filled".to_owned());
+        }
+        self.validate()
+    }
+
     fn compile_pattern(&self, s: &str) -> Result<Pattern, PatternError> {
         let pattern_opts = if self.ignore_case {
             PatternOpts::case_insensitive()
@@ -414,27 +801,80 @@ impl GroupConfig {
         }
     }
 
-    pub fn path_selector(&self, base_dir: &Path) -> Result<PathSelector, PatternError> {
-        let include_names: Result<Vec<Pattern>, PatternError> = self
-            .name_patterns
-            .iter()
-            .map(|p| self.compile_pattern(p))
-            .collect();
-        let include_paths: Result<Vec<Pattern>, PatternError> = self
-            .path_patterns
-            .iter()
-            .map(|p| self.compile_pattern(p))
-            .collect();
-        let exclude_paths: Result<Vec<Pattern>, PatternError> = self
-            .exclude_patterns
-            .iter()
-            .map(|p| self.compile_pattern(p))
-            .collect();
+    pub fn path_selector(&self, base_dir: &Path) -> Result<PathSelector, String> {
+        let path_patterns = read_pattern_files(&self.include_from)?;
+        let exclude_patterns = read_pattern_files(&self.exclude_from)?;
+        let compile = |patterns: &mut dyn Iterator<Item = &String>| {
+            patterns
+                .map(|p| self.compile_pattern(p))
+                .collect::<Result<Vec<Pattern>, PatternError>>()
+                .map_err(|e| format!("Invalid pattern: {e}"))
+        };
+        let include_names = compile(&mut self.name_patterns.iter())?;
+        let include_paths = compile(&mut self.path_patterns.iter().chain(path_patterns.iter()))?;
+        let exclude_paths =
+            compile(&mut self.exclude_patterns.iter().chain(exclude_patterns.iter()))?;
 
         Ok(PathSelector::new(base_dir.clone())
-            .include_names(include_names?)
-            .include_paths(include_paths?)
-            .exclude_paths(exclude_paths?))
+            .include_names(include_names)
+            .include_paths(include_paths)
+            .exclude_paths(exclude_paths))
+    }
+
+    /// Returns the compiled `--where` expression, if given.
+    pub fn file_expr(&self, base_dir: &Path) -> Result<Option<FileExpr>, String> {
+        self.where_expr
+            .as_ref()
+            .map(|e| FileExpr::new(e, base_dir, self.ignore_case))
+            .transpose()
+    }
+
+    /// Returns the filter selecting files by metadata such as modification time or owner.
+    pub fn metadata_filter(&self) -> Result<MetadataFilter, String> {
+        #[cfg(unix)]
+        let (uids, gids) = (
+            self.users
+                .iter()
+                .map(|u| resolve_user(u))
+                .collect::<Result<_, _>>()?,
+            self.groups
+                .iter()
+                .map(|g| resolve_group(g))
+                .collect::<Result<_, _>>()?,
+        );
+        #[cfg(not(unix))]
+        let (uids, gids) = {
+            if !self.users.is_empty()
+                || !self.groups.is_empty()
+                || !self.perms.is_empty()
+                || !self.excluded_perms.is_empty()
+                || self.changed_after.is_some()
+                || self.changed_before.is_some()
+            {
+                return Err("Filtering by owner, permissions or status change time \
+                     is not supported on this platform"
+                    .to_owned());
+            }
+            (vec![], vec![])
+        };
+        Ok(MetadataFilter {
+            modified_after: self.modified_after,
+            modified_before: self.modified_before,
+            changed_after: self.changed_after,
+            changed_before: self.changed_before,
+            uids,
+            gids,
+            perms: self.perms.clone(),
+            excluded_perms: self.excluded_perms.clone(),
+        })
+    }
+
+    /// Returns the filter selecting files by the type of their contents.
+    pub fn type_filter(&self) -> TypeFilter {
+        TypeFilter {
+            include: self.types.clone(),
+            exclude: self.excluded_types.clone(),
+        }
     }
 
     pub fn group_filter(&self) -> FileGroupFilter {
@@ -507,7 +947,15 @@ impl GroupConfig {
     /// Input paths may be provided as arguments or from standard input.
     pub fn input_paths(&self) -> Box<dyn Iterator<Item = Path> + Send> {
         let base_dir = Arc::new(self.base_dir.clone());
-        if self.stdin {
+        if self.stdin && self.null {
+            Box::new(
+                BufReader::new(stdin())
+                    .split(b'\0')
+                    .map(|s| s.unwrap())
+                    .filter(|s| !s.is_empty())
+                    .map(move |s| base_dir.resolve(Path::from(os_string_from_bytes(s)))),
+            )
+        } else if self.stdin {
             Box::new(
                 BufReader::new(stdin())
                     .lines()
@@ -528,6 +976,12 @@ impl GroupConfig {
         if self.no_copy {
             tr.copy = false
         };
+        tr.limits = TransformLimits {
+            timeout: self.transform_timeout,
+            max_output: self.transform_max_output.map(|l| l.0),
+            max_memory: self.transform_max_memory.map(|l| l.0),
+            max_cpu_secs: self.transform_max_cpu,
+        };
         Ok(tr)
     }
 
@@ -539,6 +993,39 @@ impl GroupConfig {
             .map(|command| self.build_transform(command))
     }
 
+    /// Constructs the transforms given by `--transform-for`,
+    /// together with the patterns selecting the files they apply to.
+    pub fn transform_rules(&self) -> Result<Vec<(Pattern, Transform)>, String> {
+        self.transform_for
+            .iter()
+            .map(|(glob, command)| {
+                let pattern = self
+                    .compile_pattern(glob)
+                    .map_err(|e| format!("Invalid --transform-for pattern {glob}: {e}"))?;
+                let transform = self
+                    .build_transform(command)
+                    .map_err(|e| format!("Invalid transform {command}: {e}"))?;
+                Ok((pattern, transform))
+            })
+            .collect()
+    }
+
+    /// Constructs the normalizers.
+    /// Returns None if no normalizers were given.
+    pub fn normalizers(&self) -> Result<Option<Normalizers>, PatternError> {
+        if self.normalize.is_empty() {
+            return Ok(None);
+        }
+        Normalizers::new(&self.normalize, |s| self.compile_pattern(s)).map(Some)
+    }
+
+    /// Returns true if file contents are changed before matching,
+    /// either by the `--transform` program or by the built-in normalizers.
+    /// In that case the data lengths in the report don't have to match the file sizes.
+    pub fn transforms_data(&self) -> bool {
+        self.transform.is_some() || !self.normalize.is_empty() || !self.transform_for.is_empty()
+    }
+
     pub fn thread_pool_sizes(&self) -> HashMap<OsString, Parallelism> {
         let mut map = HashMap::new();
         for (k, v) in self.threads.iter() {
@@ -546,6 +1033,19 @@ impl GroupConfig {
         }
         map
     }
+
+    /// Returns the limits of bytes read per second, by device name or type
+    pub fn read_rate_limits(&self) -> HashMap<OsString, u64> {
+        self.max_read_rate
+            .iter()
+            .map(|(k, v)| (k.clone(), v.0))
+            .collect()
+    }
+
+    /// Returns the limits of I/O operations per second, by device name or type
+    pub fn iops_limits(&self) -> HashMap<OsString, u64> {
+        self.max_iops.iter().cloned().collect()
+    }
 }
 
 /// Controls which files in a group should be removed / moved / replaced by links.
@@ -662,8 +1162,196 @@ pub struct DedupeConfig {
     /// this flag is set automatically if `--transform` was used.
     #[arg(long)]
     pub no_check_size: bool,
+
+    /// Allow processing a report produced with `fclones group --sample-hash`.
+    ///
+    /// Large files in such a report were compared only by a sample of their contents,
+    /// so they might not be identical.
+    #[arg(long)]
+    pub allow_sampled: bool,
+
+    /// Files marked in the report to be kept or dropped
+    #[arg(skip)]
+    pub marks: FileMarks,
+}
+
+/// Configuration of the `verify` subcommand
+#[derive(clap::Args, Debug, Default)]
+pub struct VerifyConfig {
+    /// Write the verified report to a file instead of the standard output
+    #[arg(short = 'o', long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+
+    /// Set output file format
+    #[arg(
+        value_enum,
+        short = 'f',
+        long,
+        ignore_case = true,
+        default_value = "default"
+    )]
+    pub format: OutputFormat,
+
+    /// Hash the files again and drop the ones with contents different than recorded
+    /// in the report.
+    ///
+    /// Uses the same hash function as the run that produced the report.
+    /// Without this flag, only file sizes and modification times are checked.
+    #[arg(long)]
+    pub rehash: bool,
+
+    /// Set the sizes of thread-pools used for rehashing.
+    ///
+    /// Accepts the same specs as `fclones group --threads`.
+    /// Defaults to the thread-pool configuration of the run that produced the report.
+    #[arg(short, long, value_name = "SPEC", value_parser = parse_thread_count_option)]
+    pub threads: Vec<(OsString, Parallelism)>,
+
+    /// The report to verify.
+    ///
+    /// If not given, the report is read from the standard input.
+    #[arg(value_name = "REPORT")]
+    pub report: Option<PathBuf>,
+}
+
+/// Configuration of the `sparsify` subcommand
+#[derive(clap::Args, Debug, Default)]
+pub struct SparsifyConfig {
+    /// Don't perform any changes on the file-system, but write a shell script
+    /// that would release the space of the zero-filled files.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Write the script to a file instead of the standard output.
+    /// Used only with `--dry-run`.
+    #[arg(short = 'o', long, value_name = "PATH", requires = "dry_run")]
+    pub output: Option<PathBuf>,
+
+    /// Don't lock files before punching holes in them.
+    #[arg(long)]
+    pub no_lock: bool,
+}
+
+/// Configuration of the `calibrate` subcommand
+#[derive(clap::Args, Debug)]
+pub struct CalibrateConfig {
+    /// Size of the test file written to each device.
+    ///
+    /// Larger files give more accurate results on devices with large caches,
+    /// but take longer to write.
+    #[arg(long, value_name = "BYTES", default_value = "64MiB")]
+    pub size: FileLen,
+
+    /// Remove the stored calibration of all devices and return to the default settings.
+    #[arg(long, conflicts_with_all = ["size", "paths"])]
+    pub clear: bool,
+
+    /// Directories on the devices to calibrate. Test files are temporarily created in them.
+    #[arg(value_name = "DIR", required_unless_present = "clear")]
+    pub paths: Vec<Path>,
+}
+
+/// Configuration of the `hash` subcommand
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct HashConfig {
+    /// Write the checksums to a file instead of the standard output
+    #[arg(short = 'o', long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+
+    /// Read checksums from the given files and verify them.
+    ///
+    /// Accepts both the `sha256sum` and the BSD tag format. If no files are given,
+    /// the checksums are read from the standard input.
+    #[arg(short = 'c', long)]
+    pub check: bool,
+
+    /// Write checksums in the BSD tag format, e.g. `SHA256 (file) = <hash>`.
+    #[arg(long, conflicts_with = "check")]
+    pub tag: bool,
+
+    /// Don't print OK for each successfully verified file.
+    #[arg(short = 'q', long, requires = "check")]
+    pub quiet: bool,
+
+    /// A hash function to use for computing file digests [default: metro].
+    ///
+    /// When checking, the hash functions named in BSD tag lines take precedence.
+    /// If not given, the function for the other lines is inferred from the checksum length:
+    /// `metro` for 128-bit, `sha256` for 256-bit and `sha512` for 512-bit checksums.
+    #[arg(value_enum, long, value_name = "NAME")]
+    pub hash_fn: Option<HashFn>,
+
+    /// Limit the recursion depth.
+    ///
+    /// 0 disables descending into directories.
+    /// 1 descends into directories specified explicitly as input paths,
+    /// but does not descend into subdirectories.
+    #[arg(short = 'd', long, value_name = "NUMBER")]
+    pub depth: Option<usize>,
+
+    /// Include hidden files.
+    #[arg(short = '.', long)]
+    pub hidden: bool,
+
+    /// Do not ignore files matching patterns listed in `.gitignore`, `.fdignore`,
+    /// `.fclonesignore` and the global `fclones/ignore` file in the user config directory.
+    #[arg(short = 'A', long)]
+    pub no_ignore: bool,
+
+    /// Follow symbolic links.
+    #[arg(short = 'L', long)]
+    pub follow_links: bool,
+
+    /// Enable caching of file hashes.
+    #[arg(long)]
+    pub cache: bool,
+
+    /// Set the sizes of thread-pools.
+    ///
+    /// Accepts the same specs as `fclones group --threads`.
+    #[arg(short, long, value_name = "SPEC", value_parser = parse_thread_count_option)]
+    pub threads: Vec<(OsString, Parallelism)>,
+
+    /// A list of input paths, or the files with checksums to verify if `--check` is set.
+    ///
+    /// Accepts files and directories.
+    /// By default descends into directories recursively, unless a recursion depth
+    /// limit is specified with `--depth`.
+    #[arg(required_unless_present("check"))]
+    pub paths: Vec<Path>,
+
+    /// Settings of the devices measured by `fclones calibrate`
+    #[arg(skip)]
+    pub calibration: Calibration,
 }
 
+impl HashConfig {
+    pub fn hash_fn(&self) -> HashFn {
+        self.hash_fn.unwrap_or_default()
+    }
+
+    /// Returns the configuration for scanning and hashing the given files
+    /// with the given hash function.
+    /// All files are selected, including the empty ones.
+    pub fn group_config(&self, paths: Vec<Path>, hash_fn: HashFn) -> GroupConfig {
+        GroupConfig {
+            depth: self.depth,
+            hidden: self.hidden,
+            no_ignore: self.no_ignore,
+            follow_links: self.follow_links,
+            hash_fn,
+            cache: self.cache,
+            threads: self.threads.clone(),
+            base_dir: Path::from("."),
+            paths,
+            calibration: self.calibration.clone(),
+            ..GroupConfig::default()
+        }
+    }
+}
+
+// Parsed only once, so the size difference between variants doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(clap::Subcommand, Debug)]
 pub enum Command {
     /// Produce a list of groups of identical files.
@@ -698,7 +1386,10 @@ pub enum Command {
     /// After successful deduplication, all file clones would still be visible as distinct files,
     /// but the data would be stored only once, hence taking up possibly less space than before.
     /// Unlike with hard links, modifying a file does not modify any of its clones.
-    /// The result is not visible to userland applications, so repeated runs
+    /// On Linux, files stored in the same data extents are treated like hard links,
+    /// so repeated runs of `fclones group` don't count them as redundant, unless `--match-links`
+    /// is set, and repeated runs of `fclones dedupe` don't clone them again.
+    /// On other systems, the result is not visible to userland applications, so repeated runs
     /// will find the same files again. This also applies to `fclones dedupe` itself:
     /// The options `--priority` and `--rf-over` do not detect earlier deduplications.
     ///
@@ -730,6 +1421,77 @@ pub enum Command {
         target: PathBuf,
     },
 
+    /// Find copies of the given files.
+    ///
+    /// Scans the given directories recursively and reports the groups of files identical
+    /// to any of the reference files given with `--ref`. Only the files of the same size
+    /// as the reference files are hashed, so this is much faster than `group`
+    /// followed by filtering the report.
+    /// The reference files are listed first in each group.
+    /// Accepts the same options as `group`.
+    Find {
+        /// File to look for copies of; may be given multiple times
+        #[arg(long = "ref", value_name = "FILE", required = true)]
+        references: Vec<Path>,
+
+        #[clap(flatten)]
+        config: GroupConfig,
+    },
+
+    /// Compute or verify checksums of files.
+    ///
+    /// Prints the hash of each file in the format of `sha256sum` (or `b3sum`, depending on
+    /// the hash function), or in the BSD tag format if `--tag` is given.
+    /// Directories are scanned recursively. Files are hashed in parallel,
+    /// using thread-pools dedicated to each device, just like in `group`.
+    ///
+    /// With `--check`, reads the checksums from the given files and verifies them.
+    /// Exits with an error if any of the files are missing or don't match.
+    Hash(HashConfig),
+
+    /// Check if a report is still valid.
+    ///
+    /// Reads the report produced by `fclones group` and checks that the files still exist
+    /// and have not been modified since the report was produced. With `--rehash`, the contents
+    /// of the files are hashed again instead of relying on modification times.
+    /// Writes a new report without the files that changed and without the groups
+    /// that don't contain enough replicas anymore.
+    Verify(VerifyConfig),
+
+    /// Measure the performance of storage devices and tune the settings used for them.
+    ///
+    /// Writes a temporary test file to each given directory and benchmarks sequential and random
+    /// reads with increasing numbers of threads. The sizes of the thread-pools and the amounts
+    /// of data read in the prefix and suffix grouping phases are then chosen for each device
+    /// and stored in the user config directory. Subsequent runs use them automatically,
+    /// unless overridden with `--threads`.
+    Calibrate(CalibrateConfig),
+
+    /// Watch directories for changes and report new duplicates as they appear.
+    ///
+    /// Scans the given directories first, then keeps monitoring them for created, modified,
+    /// moved and removed files. Whenever a file becomes a duplicate of another file,
+    /// an event is written to the standard output as a JSON object on a single line.
+    /// If the output file is given, the report is rewritten after each batch of changes.
+    ///
+    /// Accepts the same options as `group`, except `--stdin`, `--transform` and
+    /// `--skip-content-hash`. Hashes of files are always cached.
+    /// Supported only on Linux.
+    #[cfg(target_os = "linux")]
+    Watch(GroupConfig),
+
+    /// Release the disk space taken by zero-filled files by punching holes in them.
+    ///
+    /// The list of groups earlier produced by `fclones group` should be submitted
+    /// on the standard input. Only the groups marked as zero-filled are processed,
+    /// and all files in each group are sparsified, so no file needs to be kept.
+    /// Unlike linking, the files stay independent of each other.
+    /// Each file is read again and only the blocks that still contain zeros are released,
+    /// so files modified after grouping are not damaged.
+    /// Supported only on Linux.
+    #[cfg(target_os = "linux")]
+    Sparsify(SparsifyConfig),
+
     /// Print shell completion script to stdout.
     Complete {
         /// Shell for which the completion script is generated.
@@ -741,6 +1503,9 @@ impl Command {
     pub fn validate(&self) -> Result<(), String> {
         match self {
             Command::Group(c) => c.validate(),
+            Command::Find { config, .. } => config.validate(),
+            #[cfg(target_os = "linux")]
+            Command::Watch(c) => c.validate_watch(),
             _ => Ok(()),
         }
     }
@@ -767,6 +1532,15 @@ pub struct Config {
     #[arg(short('q'), long, hide = true)]
     pub quiet: bool,
 
+    /// Sets the format of log messages and progress written to the standard error.
+    ///
+    /// The `json` format writes one JSON object per line for each event: log messages,
+    /// start and end of each phase, and periodic progress updates. Progress updates
+    /// can be turned off with `--progress=false`.
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = LogFormat::Text,
+          ignore_case = true)]
+    pub log_format: LogFormat,
+
     /// Find files
     #[command(subcommand)]
     pub command: Command,
@@ -774,6 +1548,8 @@ pub struct Config {
 
 #[cfg(test)]
 mod test {
+    use std::ffi::OsStr;
+
     use crate::config::{Command, Config};
     use crate::path::Path;
 
@@ -790,6 +1566,208 @@ mod test {
             Command::Group(g) if g.paths == vec![Path::from("dir1"), Path::from("dir2")]);
     }
 
+    #[test]
+    fn test_find_command() {
+        let config: Config =
+            Config::try_parse_from(vec!["fclones", "find", "--ref", "a", "--ref", "b", "dir"])
+                .unwrap();
+        assert_matches!(
+            config.command,
+            Command::Find { references, config }
+                if references == vec![Path::from("a"), Path::from("b")]
+                    && config.paths == vec![Path::from("dir")]);
+        assert!(Config::try_parse_from(vec!["fclones", "find", "dir"]).is_err());
+    }
+
+    #[test]
+    fn test_normalize() {
+        let config: Config = Config::try_parse_from(vec![
+            "fclones",
+            "group",
+            "--normalize",
+            "exif",
+            "--normalize",
+            "text:*.c",
+            "dir",
+        ])
+        .unwrap();
+        assert_matches!(
+            config.command,
+            Command::Group(g) if g.normalize.len() == 2
+                && g.normalize[1].pattern.as_deref() == Some("*.c")
+                && g.transforms_data());
+        assert!(
+            Config::try_parse_from(vec!["fclones", "group", "--normalize", "foo", "dir"]).is_err()
+        );
+        assert!(Config::try_parse_from(vec![
+            "fclones",
+            "group",
+            "--normalize",
+            "text",
+            "--transform",
+            "cat",
+            "dir",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_transform_for() {
+        let config: Config = Config::try_parse_from(vec![
+            "fclones",
+            "group",
+            "--transform-for",
+            "*.jpg=exiv2 -d a $IN",
+            "--transform-for",
+            "*.mp3=id3v2 -D $IN",
+            "dir",
+        ])
+        .unwrap();
+        assert_matches!(
+            config.command,
+            Command::Group(g) if g.transform_for == vec![
+                ("*.jpg".to_owned(), "exiv2 -d a $IN".to_owned()),
+                ("*.mp3".to_owned(), "id3v2 -D $IN".to_owned()),
+            ] && g.transforms_data());
+        assert!(Config::try_parse_from(vec![
+            "fclones",
+            "group",
+            "--transform-for",
+            "*.jpg",
+            "dir"
+        ])
+        .is_err());
+        assert!(Config::try_parse_from(vec![
+            "fclones",
+            "group",
+            "--transform-for",
+            "*.jpg=cat",
+            "--transform",
+            "cat",
+            "dir",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_sample_hash() {
+        let config: Config =
+            Config::try_parse_from(vec!["fclones", "group", "--sample-hash", "8", "dir"]).unwrap();
+        assert_matches!(config.command, Command::Group(g) if g.sample_hash == Some(8));
+        let config: Config =
+            Config::try_parse_from(vec!["fclones", "group", "--sample-hash", "0", "dir"]).unwrap();
+        assert!(config.command.validate().is_err());
+        assert!(Config::try_parse_from(vec![
+            "fclones",
+            "group",
+            "--sample-hash",
+            "8",
+            "--skip-content-hash",
+            "dir"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_null_requires_text_output_or_stdin() {
+        let validate = |args: &[&str]| {
+            let args = ["fclones", "group"].iter().chain(args);
+            Config::try_parse_from(args).unwrap().command.validate()
+        };
+        assert!(validate(&["-0", "dir"]).is_ok());
+        assert!(validate(&["-0", "-f", "fdupes", "dir"]).is_ok());
+        assert!(validate(&["-0", "-f", "json", "dir"]).is_err());
+        assert!(validate(&["-0", "--stdin", "-f", "json"]).is_ok());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_sparsify_command() {
+        let config: Config = Config::try_parse_from(vec!["fclones", "sparsify"]).unwrap();
+        assert_matches!(config.command, Command::Sparsify(c) if !c.dry_run && !c.no_lock);
+        assert!(Config::try_parse_from(vec!["fclones", "sparsify", "-o", "script.sh"]).is_err());
+    }
+
+    #[test]
+    fn test_hash_command() {
+        let config: Config = Config::try_parse_from(vec!["fclones", "hash", "-c"]).unwrap();
+        assert_matches!(config.command, Command::Hash(h) if h.check && h.paths.is_empty());
+        assert!(Config::try_parse_from(vec!["fclones", "hash"]).is_err());
+        assert!(Config::try_parse_from(vec!["fclones", "hash", "--tag", "-c", "sums"]).is_err());
+    }
+
+    #[test]
+    fn test_verify_command() {
+        let config: Config =
+            Config::try_parse_from(vec!["fclones", "verify", "--rehash", "report.json"]).unwrap();
+        assert_matches!(
+            config.command,
+            Command::Verify(v) if v.rehash && v.report.as_ref().is_some_and(|p| p == "report.json"));
+    }
+
+    #[test]
+    fn test_calibrate_command() {
+        let config: Config =
+            Config::try_parse_from(vec!["fclones", "calibrate", "--size", "16MiB", "/mnt"])
+                .unwrap();
+        assert_matches!(
+            config.command,
+            Command::Calibrate(c) if c.size == crate::file::FileLen(16 * 1024 * 1024) && c.paths == vec![Path::from("/mnt")]);
+        assert!(Config::try_parse_from(vec!["fclones", "calibrate"]).is_err());
+        let config: Config =
+            Config::try_parse_from(vec!["fclones", "calibrate", "--clear"]).unwrap();
+        assert_matches!(config.command, Command::Calibrate(c) if c.clear && c.paths.is_empty());
+        assert!(Config::try_parse_from(vec!["fclones", "calibrate", "--clear", "/mnt"]).is_err());
+    }
+
+    #[test]
+    fn test_exclude_from_file() {
+        use std::io::Write;
+        let mut patterns = tempfile::NamedTempFile::new().unwrap();
+        writeln!(patterns, "# build outputs\r\n**/target/**\n\n**/*.o").unwrap();
+        let config: Config = Config::try_parse_from(vec![
+            "fclones".as_ref(),
+            "group".as_ref(),
+            "--exclude-from".as_ref(),
+            patterns.path().as_os_str(),
+            "/".as_ref(),
+        ])
+        .unwrap();
+        let Command::Group(g) = config.command else {
+            panic!("Expected group command")
+        };
+        let selector = g.path_selector(&Path::from("/")).unwrap();
+        assert!(!selector.matches_full_path(&Path::from("/foo/target/bar")));
+        assert!(!selector.matches_full_path(&Path::from("/foo/main.o")));
+        assert!(selector.matches_full_path(&Path::from("/foo/main.c")));
+    }
+
+    #[test]
+    fn test_max_read_rate() {
+        let config: Config = Config::try_parse_from(vec![
+            "fclones",
+            "group",
+            "--max-read-rate",
+            "50MB",
+            "--max-read-rate",
+            "dev:/dev/sda:1MiB",
+            "--max-iops",
+            "hdd:100",
+            ".",
+        ])
+        .unwrap();
+        let Command::Group(g) = config.command else {
+            panic!("Expected group command")
+        };
+        let read_rate = g.read_rate_limits();
+        assert_eq!(read_rate.get(OsStr::new("default")), Some(&50_000_000));
+        assert_eq!(read_rate.get(OsStr::new("dev:/dev/sda")), Some(&1_048_576));
+        assert_eq!(g.iops_limits().get(OsStr::new("hdd")), Some(&100));
+
+        let result = Config::try_parse_from(vec!["fclones", "group", "--max-iops", "0", "."]);
+        assert!(result.is_err());
+    }
+
     #[test]
     fn test_dedupe_command() {
         let config: Config = Config::try_parse_from(vec!["fclones", "dedupe"]).unwrap();
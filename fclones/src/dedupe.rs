@@ -535,7 +535,7 @@ fn was_modified(files: &[PathAndMetadata], after: DateTime<FixedOffset>, log: &d
     result
 }
 
-/// Returns true if given path matches any of the `keep` patterns
+/// Returns true if given path matches any of the `keep` patterns or is marked to be kept
 fn should_keep(path: &Path, config: &DedupeConfig) -> bool {
     let matches_any_name = config
         .keep_name_patterns
@@ -551,12 +551,16 @@ fn should_keep(path: &Path, config: &DedupeConfig) -> bool {
             .any(|p| p.matches_path(&path.to_path_buf()))
     };
 
-    matches_any_name || matches_any_path()
+    matches_any_name || matches_any_path() || config.marks.keep.contains(path)
 }
 
-/// Returns true if given path matches all of the `drop` patterns.
-/// If there are no `drop` patterns, returns true.
+/// Returns true if given path matches all of the `drop` patterns
+/// and is marked to be dropped, if any files were marked in the report.
+/// If there are no `drop` patterns nor marks, returns true.
 fn may_drop(path: &Path, config: &DedupeConfig) -> bool {
+    if !config.marks.drop.is_empty() && !config.marks.drop.contains(path) {
+        return false;
+    }
     let matches_any_name = || {
         config
             .name_patterns
@@ -746,7 +750,17 @@ impl PartitionedFileGroup {
         );
         let mut commands = Vec::new();
         let retained_file = Arc::new(self.to_keep.swap_remove(0));
+        // Files already stored in the same data extents as the retained file need no cloning
+        let retained_extents_id = match strategy {
+            DedupeOp::RefLink => shared_extents_id(&retained_file.path),
+            _ => None,
+        };
         for dropped_file in self.to_drop {
+            if retained_extents_id.is_some()
+                && shared_extents_id(&dropped_file.path) == retained_extents_id
+            {
+                continue;
+            }
             match strategy {
                 DedupeOp::SymbolicLink => commands.push(FsCommand::SoftLink {
                     target: retained_file.clone(),
@@ -778,6 +792,18 @@ impl PartitionedFileGroup {
     }
 }
 
+/// Returns the identifier of the data extents of the file, if all of them are shared
+/// with other files. See [`crate::file::shared_extents_id`].
+#[cfg(target_os = "linux")]
+fn shared_extents_id(path: &Path) -> Option<u128> {
+    crate::file::shared_extents_id(path).ok().flatten()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn shared_extents_id(_path: &Path) -> Option<u128> {
+    None
+}
+
 /// Attempts to retrieve the metadata of all the files in the file group.
 /// If metadata is inaccessible for a file, a warning is emitted to the log, and None gets returned.
 fn fetch_files_metadata<P>(group: FileGroup<P>, log: &dyn Log) -> Option<FileGroup<PathAndMetadata>>
@@ -1248,6 +1274,7 @@ mod test {
                 Path::from(&file_2),
                 Path::from(&file_3),
             ],
+            ..Default::default()
         }
     }
 
@@ -1420,6 +1447,7 @@ mod test {
                 file_len: group1.file_len,
                 file_hash: group1.file_hash,
                 files: group1.files.into_iter().chain(group2.files).collect(),
+                ..Default::default()
             };
 
             let config = DedupeConfig {
@@ -1469,6 +1497,7 @@ mod test {
                     Path::from(&file_a1),
                     Path::from(&file_b2),
                 ],
+                ..Default::default()
             };
 
             let config = DedupeConfig::default();
@@ -1630,4 +1659,69 @@ mod test {
             assert_eq!(read_file(&file_a2), "foo");
         })
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_remove_removes_reflinked_copies() {
+        with_dir("dedupe/remove_reflinked", |root| {
+            let mut log = StdLog::new();
+            log.no_progress = true;
+            log.log_stderr_to_stdout = true;
+
+            let file_1 = root.join("file_1");
+            let file_2 = root.join("file_2");
+            write_file(&file_1, "foo");
+            // Falls back to copying if the file system doesn't support reflinks:
+            ::reflink::reflink_or_copy(&file_1, &file_2).unwrap();
+
+            let group_config = GroupConfig {
+                paths: vec![Path::from(root)],
+                ..GroupConfig::default()
+            };
+            let groups = group_files(&group_config, &log).unwrap();
+            let dedupe_config = DedupeConfig::default();
+            let script = dedupe(groups, DedupeOp::Remove, &dedupe_config, &log);
+            let dedupe_result = run_script(script, false, &log);
+            assert_eq!(dedupe_result.processed_count, 1);
+            assert!(file_1.exists() != file_2.exists());
+        })
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_reflink_skips_files_sharing_extents_with_retained_file() {
+        with_dir("dedupe/reflink_reflinked", |root| {
+            let mut log = StdLog::new();
+            log.no_progress = true;
+            log.log_stderr_to_stdout = true;
+
+            let file_1 = root.join("file_1");
+            let file_2 = root.join("file_2");
+            let file_3 = root.join("file_3");
+            write_file(&file_1, "foo");
+            write_file(&file_3, "foo");
+            let reflinked = ::reflink::reflink(&file_1, &file_2).is_ok();
+            if !reflinked {
+                write_file(&file_2, "foo");
+            }
+
+            let group = FileGroup {
+                file_len: FileLen(3),
+                file_hash: FileHash::from(1),
+                files: vec![
+                    Path::from(&file_1),
+                    Path::from(&file_2),
+                    Path::from(&file_3),
+                ],
+                ..Default::default()
+            };
+            let dedupe_config = DedupeConfig::default();
+            let script: Vec<_> = dedupe(vec![group], DedupeOp::RefLink, &dedupe_config, &log)
+                .flat_map(|(_, commands)| commands)
+                .collect();
+            // Without reflink support the files don't share any extents
+            let expected_count = if reflinked { 1 } else { 2 };
+            assert_eq!(script.len(), expected_count);
+        })
+    }
 }
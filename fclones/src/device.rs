@@ -2,15 +2,20 @@ use core::cmp;
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::ops::Index;
+use std::sync::Arc;
 
 use itertools::Itertools;
 use lazy_init::Lazy;
 use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
 use sysinfo::{DiskExt, DiskKind, System, SystemExt};
 
+use crate::calibrate::Calibration;
 use crate::config::Parallelism;
 use crate::file::FileLen;
 use crate::path::Path;
+use crate::stats::IoCounters;
+use crate::throttle::{set_thread_throttle, RateLimiter, Throttle};
 
 impl Parallelism {
     pub fn default_for(disk_kind: DiskKind) -> Parallelism {
@@ -44,6 +49,79 @@ impl Parallelism {
             },
         }
     }
+
+    /// Returns the default parallelism for a device made of `disk_count` disks of the same kind,
+    /// e.g. a RAID array, an LVM volume group or a multi-device btrfs file system.
+    /// Each rotational disk can serve a request independently of the others.
+    pub fn default_for_disks(disk_kind: DiskKind, disk_count: usize) -> Parallelism {
+        let p = Parallelism::default_for(disk_kind);
+        match disk_kind {
+            DiskKind::SSD => p,
+            DiskKind::HDD => Parallelism {
+                random: p.random * disk_count,
+                sequential: p.sequential * disk_count,
+            },
+            DiskKind::Unknown(_) => Parallelism {
+                random: p.random,
+                sequential: p.sequential * disk_count,
+            },
+        }
+    }
+}
+
+/// Physical storage a mounted file system is stored on
+#[derive(Debug, PartialEq, Eq)]
+struct PhysicalDevice {
+    /// Name of the disk, or names of all the disks joined with `+`
+    /// if the file system spans multiple disks
+    name: OsString,
+    /// Kind of the disks, if it could be determined from their rotational flags
+    disk_kind: Option<DiskKind>,
+    /// Number of disks
+    disk_count: usize,
+}
+
+impl PhysicalDevice {
+    fn new(name: OsString) -> PhysicalDevice {
+        PhysicalDevice {
+            name,
+            disk_kind: None,
+            disk_count: 1,
+        }
+    }
+}
+
+/// Sizes of the file fragments read when grouping files stored on a device
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceProfile {
+    /// Minimum number of bytes read from the beginning of a file in the prefix grouping phase
+    pub min_prefix_len: FileLen,
+    /// Maximum number of bytes read from the beginning of a file in the prefix grouping phase
+    pub max_prefix_len: FileLen,
+    /// Number of bytes read from the end of a file in the suffix grouping phase
+    pub suffix_len: FileLen,
+    /// Files smaller than this are not grouped by suffix
+    pub suffix_threshold: FileLen,
+}
+
+impl DeviceProfile {
+    pub fn default_for(disk_kind: DiskKind) -> DeviceProfile {
+        let max_prefix_len = FileLen(match disk_kind {
+            DiskKind::SSD => 4 * 1024,
+            DiskKind::HDD => 16 * 1024,
+            DiskKind::Unknown(_) => 16 * 1024,
+        });
+        DeviceProfile {
+            min_prefix_len: FileLen(4 * 1024),
+            max_prefix_len,
+            suffix_len: max_prefix_len,
+            suffix_threshold: FileLen(match disk_kind {
+                DiskKind::HDD => 64 * 1024 * 1024, // 64 MB
+                DiskKind::SSD => 64 * 1024,        // 64 kB
+                DiskKind::Unknown(_) => 64 * 1024 * 1024,
+            }),
+        }
+    }
 }
 
 pub struct DiskDevice {
@@ -52,6 +130,11 @@ pub struct DiskDevice {
     pub disk_kind: DiskKind,
     pub file_system: String,
     pub parallelism: Parallelism,
+    pub profile: DeviceProfile,
+    /// Amount of data read from this device while hashing
+    pub(crate) io: Arc<IoCounters>,
+    /// Limits the rate of I/O on this device, applied to the threads of its thread pools
+    pub(crate) throttle: Throttle,
     seq_thread_pool: Lazy<ThreadPool>,
     rand_thread_pool: Lazy<ThreadPool>,
 }
@@ -63,6 +146,7 @@ impl DiskDevice {
         disk_kind: DiskKind,
         file_system: String,
         parallelism: Parallelism,
+        profile: DeviceProfile,
     ) -> DiskDevice {
         DiskDevice {
             index,
@@ -70,54 +154,47 @@ impl DiskDevice {
             disk_kind,
             file_system,
             parallelism,
+            profile,
+            io: Arc::new(IoCounters::default()),
+            throttle: Throttle::default(),
             seq_thread_pool: Lazy::new(),
             rand_thread_pool: Lazy::new(),
         }
     }
 
-    fn build_thread_pool(num_threads: usize) -> ThreadPool {
+    fn build_thread_pool(&self, num_threads: usize) -> ThreadPool {
+        let throttle = self.throttle.clone();
         ThreadPoolBuilder::default()
             .num_threads(num_threads)
+            .start_handler(move |_| set_thread_throttle(throttle.clone()))
             .build()
             .unwrap()
     }
 
     pub fn seq_thread_pool(&self) -> &ThreadPool {
         self.seq_thread_pool
-            .get_or_create(|| Self::build_thread_pool(self.parallelism.sequential))
+            .get_or_create(|| self.build_thread_pool(self.parallelism.sequential))
     }
 
     pub fn rand_thread_pool(&self) -> &ThreadPool {
         self.rand_thread_pool
-            .get_or_create(|| Self::build_thread_pool(self.parallelism.random))
+            .get_or_create(|| self.build_thread_pool(self.parallelism.random))
     }
 
     pub fn min_prefix_len(&self) -> FileLen {
-        FileLen(match self.disk_kind {
-            DiskKind::SSD => 4 * 1024,
-            DiskKind::HDD => 4 * 1024,
-            DiskKind::Unknown(_) => 4 * 1024,
-        })
+        self.profile.min_prefix_len
     }
 
     pub fn max_prefix_len(&self) -> FileLen {
-        FileLen(match self.disk_kind {
-            DiskKind::SSD => 4 * 1024,
-            DiskKind::HDD => 16 * 1024,
-            DiskKind::Unknown(_) => 16 * 1024,
-        })
+        self.profile.max_prefix_len
     }
 
     pub fn suffix_len(&self) -> FileLen {
-        self.max_prefix_len()
+        self.profile.suffix_len
     }
 
     pub fn suffix_threshold(&self) -> FileLen {
-        FileLen(match self.disk_kind {
-            DiskKind::HDD => 64 * 1024 * 1024, // 64 MB
-            DiskKind::SSD => 64 * 1024,        // 64 kB
-            DiskKind::Unknown(_) => 64 * 1024 * 1024,
-        })
+        self.profile.suffix_threshold
     }
 }
 
@@ -139,6 +216,7 @@ impl DiskDevices {
                 random: parallelism,
                 sequential: parallelism,
             },
+            DeviceProfile::default_for(disk_kind),
         );
         DiskDevices {
             devices: vec![device],
@@ -146,35 +224,76 @@ impl DiskDevices {
         }
     }
 
+    /// Looks up the setting for the device by the device name prefixed with `dev:`,
+    /// and if not found, by the device type (ssd/hdd).
+    fn get_device_setting<'a, T>(
+        name: &OsStr,
+        disk_kind: DiskKind,
+        settings: &'a HashMap<OsString, T>,
+    ) -> Option<&'a T> {
+        let mut dev_key = OsString::new();
+        dev_key.push("dev:");
+        dev_key.push(name);
+        settings.get(&dev_key).or_else(|| match disk_kind {
+            DiskKind::SSD => settings.get(OsStr::new("ssd")),
+            DiskKind::HDD => settings.get(OsStr::new("hdd")),
+            DiskKind::Unknown(_) => settings.get(OsStr::new("unknown")),
+        })
+    }
+
     /// Reads the preferred parallelism level for the device based on the
     /// device name or the device type (ssd/hdd) from `pool_sizes` map.
     /// Returns the value under the "default" key if device was not found,
-    /// or 0 if "default" doesn't exist in the map.
-    /// If found, the device key is removed from the map.
+    /// then the value measured by `fclones calibrate`,
+    /// or the default for the device type and the number of disks if none of these exist.
     fn get_parallelism(
         name: &OsStr,
         disk_kind: DiskKind,
+        disk_count: usize,
         pool_sizes: &HashMap<OsString, Parallelism>,
+        calibration: &Calibration,
     ) -> Parallelism {
-        let mut dev_key = OsString::new();
-        dev_key.push("dev:");
-        dev_key.push(name);
-        match pool_sizes.get(&dev_key) {
-            Some(p) => *p,
-            None => {
-                let p = match disk_kind {
-                    DiskKind::SSD => pool_sizes.get(OsStr::new("ssd")),
-                    DiskKind::HDD => pool_sizes.get(OsStr::new("hdd")),
-                    DiskKind::Unknown(_) => pool_sizes.get(OsStr::new("unknown")),
-                };
-                match p {
-                    Some(p) => *p,
-                    None => pool_sizes
-                        .get(OsStr::new("default"))
-                        .copied()
-                        .unwrap_or_else(|| Parallelism::default_for(disk_kind)),
-                }
+        Self::get_device_setting(name, disk_kind, pool_sizes)
+            .or_else(|| pool_sizes.get(OsStr::new("default")))
+            .copied()
+            .or_else(|| calibration.get(name).map(|c| c.parallelism))
+            .unwrap_or_else(|| Parallelism::default_for_disks(disk_kind, disk_count))
+    }
+
+    /// Sets up throttling of I/O on the devices.
+    ///
+    /// The maps give the limits of bytes read per second and I/O operations per second.
+    /// They are keyed by the device name prefixed with `dev:` or by the device type.
+    /// The limit under the "default" key is shared by all devices.
+    ///
+    /// Must be called before any thread pools are used.
+    pub fn set_rate_limits(
+        &mut self,
+        read_rate: &HashMap<OsString, u64>,
+        iops: &HashMap<OsString, u64>,
+    ) {
+        let global_read_rate = read_rate
+            .get(OsStr::new("default"))
+            .map(|&r| Arc::new(RateLimiter::new(r)));
+        let global_iops = iops
+            .get(OsStr::new("default"))
+            .map(|&r| Arc::new(RateLimiter::new(r)));
+
+        for device in self.devices.iter_mut() {
+            let mut throttle = Throttle::default();
+            if let Some(limiter) = &global_read_rate {
+                throttle.limit_bytes(limiter.clone());
+            }
+            if let Some(limiter) = &global_iops {
+                throttle.limit_ops(limiter.clone());
             }
+            if let Some(&r) = Self::get_device_setting(&device.name, device.disk_kind, read_rate) {
+                throttle.limit_bytes(Arc::new(RateLimiter::new(r)));
+            }
+            if let Some(&r) = Self::get_device_setting(&device.name, device.disk_kind, iops) {
+                throttle.limit_ops(Arc::new(RateLimiter::new(r)));
+            }
+            device.throttle = throttle;
         }
     }
 
@@ -185,19 +304,27 @@ impl DiskDevices {
         name: OsString,
         disk_kind: DiskKind,
         file_system: String,
+        disk_count: usize,
         pool_sizes: &HashMap<OsString, Parallelism>,
+        calibration: &Calibration,
     ) -> usize {
         if let Some((index, _)) = self.devices.iter().find_position(|d| d.name == name) {
             index
         } else {
             let index = self.devices.len();
-            let parallelism = Self::get_parallelism(&name, disk_kind, pool_sizes);
+            let parallelism =
+                Self::get_parallelism(&name, disk_kind, disk_count, pool_sizes, calibration);
+            let profile = calibration
+                .get(&name)
+                .map(|c| c.profile)
+                .unwrap_or_else(|| DeviceProfile::default_for(disk_kind));
             self.devices.push(DiskDevice::new(
                 index,
                 name,
                 disk_kind,
                 file_system,
                 parallelism,
+                profile,
             ));
             index
         }
@@ -218,14 +345,47 @@ impl DiskDevices {
         }
     }
 
+    /// Returns the physical storage of the file system mounted from the device `name`.
+    ///
+    /// Datasets of a ZFS pool share the same storage, so they are mapped to the pool.
+    fn physical_device(name: &OsStr, file_system: &[u8]) -> PhysicalDevice {
+        if file_system == b"zfs" {
+            let name = name.to_string_lossy();
+            let pool = name.split('/').next().unwrap_or_default();
+            return PhysicalDevice::new(OsString::from(pool));
+        }
+        Self::resolve_block_device(name, file_system == b"btrfs")
+    }
+
+    /// Resolves the block device through the stack of device-mapper (LVM, dm-crypt)
+    /// and md RAID devices down to the disks it is stored on.
+    /// Falls back to guessing the disk from the device name if sysfs can't tell.
+    #[cfg(target_os = "linux")]
+    fn resolve_block_device(name: &OsStr, btrfs: bool) -> PhysicalDevice {
+        let sys = std::path::Path::new("/sys");
+        kernel_device_name(name)
+            .and_then(|kernel_name| sysfs_physical_device(sys, &kernel_name, btrfs))
+            .unwrap_or_else(|| PhysicalDevice::new(Self::physical_device_name(name)))
+    }
+
     #[cfg(not(target_os = "linux"))]
-    fn physical_device_name(name: &OsStr) -> OsString {
-        name.to_os_string()
+    fn resolve_block_device(name: &OsStr, _btrfs: bool) -> PhysicalDevice {
+        PhysicalDevice::new(name.to_os_string())
     }
 
     /// Reads the list of partitions and disks from the system and builds the `DiskDevices`
     /// structure from that information.
+    /// Uses the default settings of the devices.
     pub fn new(pool_sizes: &HashMap<OsString, Parallelism>) -> DiskDevices {
+        Self::with_calibration(pool_sizes, &Calibration::default())
+    }
+
+    /// Like [`DiskDevices::new`], but uses the device settings from the given calibration,
+    /// e.g. the one stored by `fclones calibrate`.
+    pub fn with_calibration(
+        pool_sizes: &HashMap<OsString, Parallelism>,
+        calibration: &Calibration,
+    ) -> DiskDevices {
         let mut sys = System::new();
         sys.refresh_disks_list();
         let mut result = DiskDevices {
@@ -238,15 +398,19 @@ impl DiskDevices {
             OsString::from("default"),
             DiskKind::Unknown(-1),
             String::from("unknown"),
+            1,
             pool_sizes,
+            calibration,
         );
         for d in sys.disks() {
-            let device_name = Self::physical_device_name(d.name());
+            let device = Self::physical_device(d.name(), d.file_system());
             let index = result.add_device(
-                device_name,
-                d.kind(),
+                device.name,
+                device.disk_kind.unwrap_or(d.kind()),
                 String::from_utf8_lossy(d.file_system()).to_string(),
+                device.disk_count,
                 pool_sizes,
+                calibration,
             );
 
             // On macOS APFS disk users' data is mounted in '/System/Volumes/Data'
@@ -337,12 +501,131 @@ impl Index<usize> for DiskDevices {
     }
 }
 
+/// Returns the kernel name of the block device, e.g. `dm-0` for `/dev/mapper/vg-root`
+#[cfg(target_os = "linux")]
+fn kernel_device_name(name: &OsStr) -> Option<String> {
+    let path = std::path::Path::new(name);
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if !path.starts_with("/dev") {
+        return None;
+    }
+    Some(path.file_name()?.to_string_lossy().into_owned())
+}
+
+/// Returns the names of the block devices listed in a sysfs directory,
+/// e.g. in `/sys/block/dm-0/slaves`. Returns an empty list if the directory doesn't exist.
+#[cfg(target_os = "linux")]
+fn sysfs_list_devices(dir: &std::path::Path) -> Vec<String> {
+    let mut names: Vec<String> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names
+}
+
+/// Returns the name of the whole disk the block device belongs to.
+/// Partitions are listed in sysfs as subdirectories of their disks, e.g. `/sys/block/sda/sda1`.
+#[cfg(target_os = "linux")]
+fn sysfs_whole_disk(sys_block: &std::path::Path, name: &str) -> Option<String> {
+    if sys_block.join(name).is_dir() {
+        return Some(name.to_owned());
+    }
+    sysfs_list_devices(sys_block)
+        .into_iter()
+        .find(|disk| sys_block.join(disk).join(name).is_dir())
+}
+
+/// Collects the disks the block device is stored on, following the slaves
+/// of device-mapper and md devices down to the devices that have no slaves.
+#[cfg(target_os = "linux")]
+fn sysfs_collect_disks(
+    sys_block: &std::path::Path,
+    name: &str,
+    depth: usize,
+    disks: &mut std::collections::BTreeSet<String>,
+) -> bool {
+    // Protects from cycles in case sysfs contents is not what we expect
+    const MAX_DEPTH: usize = 16;
+    let Some(disk) = sysfs_whole_disk(sys_block, name) else {
+        return false;
+    };
+    let slaves = sysfs_list_devices(&sys_block.join(&disk).join("slaves"));
+    if slaves.is_empty() || depth >= MAX_DEPTH {
+        disks.insert(disk);
+        return true;
+    }
+    slaves
+        .iter()
+        .all(|slave| sysfs_collect_disks(sys_block, slave, depth + 1, disks))
+}
+
+/// Returns all block devices of the btrfs file system the device belongs to.
+/// Btrfs doesn't register its devices as slaves of each other, but lists them
+/// in `/sys/fs/btrfs/<uuid>/devices`.
+#[cfg(target_os = "linux")]
+fn sysfs_btrfs_devices(sys: &std::path::Path, name: &str) -> Vec<String> {
+    let btrfs = sys.join("fs").join("btrfs");
+    sysfs_list_devices(&btrfs)
+        .into_iter()
+        .map(|fs| sysfs_list_devices(&btrfs.join(fs).join("devices")))
+        .find(|devices| devices.iter().any(|d| d == name))
+        .unwrap_or_else(|| vec![name.to_owned()])
+}
+
+/// Finds the disks the block device with given kernel name is stored on, using the sysfs
+/// mounted at `sys`. Returns `None` if the device or any of the devices below it is not found.
+#[cfg(target_os = "linux")]
+fn sysfs_physical_device(
+    sys: &std::path::Path,
+    kernel_name: &str,
+    btrfs: bool,
+) -> Option<PhysicalDevice> {
+    let sys_block = sys.join("block");
+    let devices = if btrfs {
+        sysfs_btrfs_devices(sys, kernel_name)
+    } else {
+        vec![kernel_name.to_owned()]
+    };
+    let mut disks = std::collections::BTreeSet::new();
+    for d in devices {
+        if !sysfs_collect_disks(&sys_block, &d, 0, &mut disks) {
+            return None;
+        }
+    }
+
+    let rotational: Option<Vec<bool>> = disks
+        .iter()
+        .map(|disk| {
+            let flag = std::fs::read_to_string(sys_block.join(disk).join("queue/rotational"));
+            flag.ok().map(|f| f.trim() == "1")
+        })
+        .collect();
+    // If any of the disks is rotational, it slows down the whole device
+    let disk_kind = rotational.map(|r| {
+        if r.iter().any(|&r| r) {
+            DiskKind::HDD
+        } else {
+            DiskKind::SSD
+        }
+    });
+
+    Some(PhysicalDevice {
+        name: OsString::from(disks.iter().map(|d| format!("/dev/{d}")).join("+")),
+        disk_kind,
+        disk_count: disks.len(),
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     #[test]
-    #[cfg_attr(not(target_os = "linux"), ignore)]
+    #[cfg(target_os = "linux")]
     fn test_physical_device_name() {
         assert_eq!(
             DiskDevices::physical_device_name(OsStr::new("/dev/sda")),
@@ -365,4 +648,87 @@ mod test {
             OsString::from("/dev/unknown")
         );
     }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_sysfs_physical_device() {
+        use crate::util::test::{create_file, with_dir};
+        with_dir("device/sysfs", |root| {
+            let block = root.join("block");
+            for dir in [
+                "sda/sda1",
+                "sda/sda2",
+                "sdb/sdb1",
+                "nvme0n1/nvme0n1p1",
+                "dm-0/slaves/sda2",
+                "dm-1/slaves/md0",
+                "md0/slaves/sda1",
+                "md0/slaves/sdb1",
+            ] {
+                std::fs::create_dir_all(block.join(dir)).unwrap();
+            }
+            for (disk, rotational) in [("sda", "1\n"), ("sdb", "1\n"), ("nvme0n1", "0\n")] {
+                std::fs::create_dir_all(block.join(disk).join("queue")).unwrap();
+                std::fs::write(block.join(disk).join("queue/rotational"), rotational).unwrap();
+            }
+            let btrfs_devices = root.join("fs/btrfs/8f3e1c2a/devices");
+            std::fs::create_dir_all(&btrfs_devices).unwrap();
+            create_file(&btrfs_devices.join("sda1"));
+            create_file(&btrfs_devices.join("nvme0n1p1"));
+
+            let resolve = |name: &str, btrfs: bool| sysfs_physical_device(root, name, btrfs);
+            // partition
+            assert_eq!(
+                resolve("nvme0n1p1", false),
+                Some(PhysicalDevice {
+                    name: OsString::from("/dev/nvme0n1"),
+                    disk_kind: Some(DiskKind::SSD),
+                    disk_count: 1,
+                })
+            );
+            // LVM volume on a partition
+            assert_eq!(
+                resolve("dm-0", false),
+                Some(PhysicalDevice {
+                    name: OsString::from("/dev/sda"),
+                    disk_kind: Some(DiskKind::HDD),
+                    disk_count: 1,
+                })
+            );
+            // dm-crypt on md RAID
+            assert_eq!(
+                resolve("dm-1", false),
+                Some(PhysicalDevice {
+                    name: OsString::from("/dev/sda+/dev/sdb"),
+                    disk_kind: Some(DiskKind::HDD),
+                    disk_count: 2,
+                })
+            );
+            // multi-device btrfs
+            assert_eq!(
+                resolve("sda1", true),
+                Some(PhysicalDevice {
+                    name: OsString::from("/dev/nvme0n1+/dev/sda"),
+                    disk_kind: Some(DiskKind::HDD),
+                    disk_count: 2,
+                })
+            );
+            assert_eq!(resolve("sdc1", false), None);
+        });
+    }
+
+    #[test]
+    fn test_zfs_dataset_maps_to_pool() {
+        let device = DiskDevices::physical_device(OsStr::new("tank/home/user"), b"zfs");
+        assert_eq!(device.name, OsString::from("tank"));
+    }
+
+    #[test]
+    fn test_default_parallelism_for_multiple_disks() {
+        let p = Parallelism::default_for_disks(DiskKind::HDD, 3);
+        assert_eq!(p.random, 3);
+        assert_eq!(p.sequential, 3);
+        let p = Parallelism::default_for_disks(DiskKind::SSD, 3);
+        assert_eq!(p, Parallelism::default_for(DiskKind::SSD));
+    }
 }
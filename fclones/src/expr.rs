@@ -0,0 +1,624 @@
+//! Expression language for selecting files with `--where`.
+//!
+//! Expressions are parsed and compiled once, and then evaluated for every file
+//! visited by the directory walker.
+//!
+//! # Grammar
+//! ```text
+//! expr       := and_expr ("or" and_expr)*
+//! and_expr   := unary ("and" unary)*
+//! unary      := "not" unary | "(" expr ")" | comparison
+//! comparison := field op value | field "in" "(" value ("," value)* ")"
+//! field      := "size" | "name" | "path" | "ext" | "mtime" | "owner" | "group" | "depth" | "links"
+//! op         := "=" | "==" | "!=" | "<" | "<=" | ">" | ">=" | "~" | "!~"
+//! value      := word | "quoted string" | 'quoted string' | r"regex"
+//! ```
+//!
+//! The `~` operator matches a string field against a glob pattern, or against a regular
+//! expression if the value is given as `r"..."`.
+//!
+//! # Example
+//! ```text
+//! ext in (jpg, heic) and size > 500K and not path ~ "**/thumbs/**"
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::fs::Metadata;
+
+use chrono::{DateTime, FixedOffset, Utc};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, tag_no_case};
+use nom::character::complete::{char, multispace0, satisfy};
+use nom::combinator::{all_consuming, map, not, value};
+use nom::multi::{many0, separated_list1};
+use nom::sequence::{delimited, pair, preceded, terminated, tuple};
+use nom::IResult;
+use regex::Regex;
+
+use crate::config::parse_date_time;
+use crate::file::FileLen;
+use crate::path::Path;
+use crate::pattern::{Pattern, PatternOpts};
+use crate::selector::PathSelector;
+use crate::transform::re_find;
+
+/// Comparison operator as written in the expression
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+    NotMatch,
+    In,
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Match => "~",
+            Op::NotMatch => "!~",
+            Op::In => "in",
+        })
+    }
+}
+
+/// A literal value on the right side of a comparison
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Literal {
+    Str(String),
+    Regex(String),
+}
+
+/// Expression tree produced by the parser, before checking the types of fields and values
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum RawExpr {
+    And(Box<RawExpr>, Box<RawExpr>),
+    Or(Box<RawExpr>, Box<RawExpr>),
+    Not(Box<RawExpr>),
+    Cmp(String, Op, Vec<Literal>),
+}
+
+fn ws<'a, O>(
+    inner: impl FnMut(&'a str) -> IResult<&'a str, O>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    delimited(multispace0, inner, multispace0)
+}
+
+/// Matches a keyword not followed immediately by another word character
+fn keyword<'a>(kw: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    ws(terminated(
+        tag_no_case(kw),
+        not(satisfy(|c| c.is_alphanumeric() || c == '_')),
+    ))
+}
+
+/// Parses a quoted string. Only the quote character can be escaped with a backslash;
+/// other backslashes are passed as-is, so they can be used for escaping in globs and regexes.
+fn p_quoted(i: &str) -> IResult<&str, String> {
+    let r_double = Regex::new(r#"^"(?:[^"\\]|\\.)*""#).unwrap();
+    let r_single = Regex::new(r#"^'(?:[^'\\]|\\.)*'"#).unwrap();
+    alt((
+        map(re_find(r_double), |s: &str| {
+            s[1..s.len() - 1].replace("\\\"", "\"")
+        }),
+        map(re_find(r_single), |s: &str| {
+            s[1..s.len() - 1].replace("\\'", "'")
+        }),
+    ))(i)
+}
+
+fn p_literal(i: &str) -> IResult<&str, Literal> {
+    let r_word = Regex::new(r#"^[^\s(),"'=!<>~]+"#).unwrap();
+    ws(alt((
+        map(preceded(tag("r"), p_quoted), Literal::Regex),
+        map(p_quoted, Literal::Str),
+        map(re_find(r_word), |s: &str| Literal::Str(s.to_owned())),
+    )))(i)
+}
+
+fn p_op(i: &str) -> IResult<&str, Op> {
+    alt((
+        ws(alt((
+            value(Op::Eq, tag("==")),
+            value(Op::Ne, tag("!=")),
+            value(Op::Le, tag("<=")),
+            value(Op::Ge, tag(">=")),
+            value(Op::NotMatch, tag("!~")),
+            value(Op::Eq, tag("=")),
+            value(Op::Lt, tag("<")),
+            value(Op::Gt, tag(">")),
+            value(Op::Match, tag("~")),
+        ))),
+        value(Op::In, keyword("in")),
+    ))(i)
+}
+
+fn p_comparison(i: &str) -> IResult<&str, RawExpr> {
+    let r_field = Regex::new(r"^[A-Za-z_]+").unwrap();
+    let (i, field) = ws(re_find(r_field))(i)?;
+    let (i, op) = p_op(i)?;
+    let (i, values) = if op == Op::In {
+        delimited(
+            ws(char('(')),
+            separated_list1(ws(char(',')), p_literal),
+            ws(char(')')),
+        )(i)?
+    } else {
+        map(p_literal, |v| vec![v])(i)?
+    };
+    Ok((i, RawExpr::Cmp(field.to_lowercase(), op, values)))
+}
+
+fn p_unary(i: &str) -> IResult<&str, RawExpr> {
+    alt((
+        map(preceded(keyword("not"), p_unary), |e| {
+            RawExpr::Not(Box::new(e))
+        }),
+        delimited(ws(char('(')), p_expr, ws(char(')'))),
+        p_comparison,
+    ))(i)
+}
+
+fn p_and(i: &str) -> IResult<&str, RawExpr> {
+    map(
+        pair(p_unary, many0(preceded(keyword("and"), p_unary))),
+        |(first, rest)| {
+            rest.into_iter()
+                .fold(first, |a, b| RawExpr::And(Box::new(a), Box::new(b)))
+        },
+    )(i)
+}
+
+fn p_expr(i: &str) -> IResult<&str, RawExpr> {
+    map(
+        pair(p_and, many0(preceded(keyword("or"), p_and))),
+        |(first, rest)| {
+            rest.into_iter()
+                .fold(first, |a, b| RawExpr::Or(Box::new(a), Box::new(b)))
+        },
+    )(i)
+}
+
+fn parse(expr: &str) -> Result<RawExpr, String> {
+    match all_consuming(tuple((multispace0, p_expr)))(expr) {
+        Ok((_, (_, e))) => Ok(e),
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) if e.input.trim().is_empty() => {
+            Err("Unexpected end of expression".to_owned())
+        }
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+            let pos = expr.len() - e.input.len();
+            Err(format!("Syntax error at position {pos}: '{}'", e.input))
+        }
+        Err(nom::Err::Incomplete(_)) => Err("Incomplete expression".to_owned()),
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum NumField {
+    Size,
+    Depth,
+    Links,
+    Uid,
+    Gid,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum StrField {
+    Name,
+    Path,
+    Ext,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum CmpOp {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    fn eval<T: PartialOrd>(&self, left: T, right: T) -> bool {
+        match self {
+            CmpOp::Eq => left == right,
+            CmpOp::Lt => left < right,
+            CmpOp::Le => left <= right,
+            CmpOp::Gt => left > right,
+            CmpOp::Ge => left >= right,
+        }
+    }
+}
+
+/// Compiled expression with typed values
+#[derive(Clone, Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Num(NumField, CmpOp, u64),
+    Mtime(CmpOp, DateTime<FixedOffset>),
+    StrEq(StrField, String),
+    StrMatch(StrField, Pattern),
+}
+
+/// Information about a file needed to evaluate a [`FileExpr`]
+pub struct FileAttrs<'a> {
+    pub path: &'a Path,
+    /// Nesting level of the file relative to the input path it was found in
+    pub depth: usize,
+    /// Must be given if [`FileExpr::needs_metadata`] returns true
+    pub metadata: Option<&'a Metadata>,
+}
+
+impl FileAttrs<'_> {
+    fn num(&self, field: NumField) -> Option<u64> {
+        match field {
+            NumField::Depth => Some(self.depth as u64),
+            NumField::Size => self.metadata.map(|m| m.len()),
+            #[cfg(unix)]
+            NumField::Links => {
+                use std::os::unix::fs::MetadataExt;
+                self.metadata.map(|m| m.nlink())
+            }
+            #[cfg(unix)]
+            NumField::Uid => {
+                use std::os::unix::fs::MetadataExt;
+                self.metadata.map(|m| m.uid() as u64)
+            }
+            #[cfg(unix)]
+            NumField::Gid => {
+                use std::os::unix::fs::MetadataExt;
+                self.metadata.map(|m| m.gid() as u64)
+            }
+            #[cfg(not(unix))]
+            NumField::Links | NumField::Uid | NumField::Gid => None,
+        }
+    }
+
+    fn str(&self, field: StrField) -> String {
+        let name = || {
+            self.path
+                .file_name_cstr()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        match field {
+            StrField::Name => name(),
+            StrField::Path => self.path.to_string_lossy(),
+            StrField::Ext => std::path::Path::new(&name())
+                .extension()
+                .map(|e| e.to_string_lossy().to_lowercase())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Expr {
+    fn needs_metadata(&self) -> bool {
+        match self {
+            Expr::And(a, b) | Expr::Or(a, b) => a.needs_metadata() || b.needs_metadata(),
+            Expr::Not(e) => e.needs_metadata(),
+            Expr::Num(NumField::Depth, _, _) => false,
+            Expr::Num(_, _, _) | Expr::Mtime(_, _) => true,
+            Expr::StrEq(_, _) | Expr::StrMatch(_, _) => false,
+        }
+    }
+
+    fn eval(&self, file: &FileAttrs) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(file) && b.eval(file),
+            Expr::Or(a, b) => a.eval(file) || b.eval(file),
+            Expr::Not(e) => !e.eval(file),
+            Expr::Num(field, op, value) => file.num(*field).is_some_and(|v| op.eval(v, *value)),
+            Expr::Mtime(op, value) => file
+                .metadata
+                .and_then(|m| m.modified().ok())
+                .is_some_and(|t| op.eval(DateTime::<Utc>::from(t), value.with_timezone(&Utc))),
+            Expr::StrEq(field, value) => file.str(*field) == *value,
+            Expr::StrMatch(field, pattern) => pattern.matches(&file.str(*field)),
+        }
+    }
+}
+
+/// Settings affecting compilation of patterns in the expression
+struct CompileCtx<'a> {
+    base_dir: &'a Path,
+    ignore_case: bool,
+}
+
+impl CompileCtx<'_> {
+    fn compile(&self, e: RawExpr) -> Result<Expr, String> {
+        Ok(match e {
+            RawExpr::And(a, b) => {
+                Expr::And(Box::new(self.compile(*a)?), Box::new(self.compile(*b)?))
+            }
+            RawExpr::Or(a, b) => Expr::Or(Box::new(self.compile(*a)?), Box::new(self.compile(*b)?)),
+            RawExpr::Not(e) => Expr::Not(Box::new(self.compile(*e)?)),
+            RawExpr::Cmp(field, Op::In, values) => values
+                .into_iter()
+                .map(|v| self.compile_cmp(&field, Op::Eq, v))
+                .reduce(|a, b| Ok(Expr::Or(Box::new(a?), Box::new(b?))))
+                .unwrap()?,
+            RawExpr::Cmp(field, Op::Ne, mut values) => Expr::Not(Box::new(self.compile_cmp(
+                &field,
+                Op::Eq,
+                values.remove(0),
+            )?)),
+            RawExpr::Cmp(field, Op::NotMatch, mut values) => Expr::Not(Box::new(
+                self.compile_cmp(&field, Op::Match, values.remove(0))?,
+            )),
+            RawExpr::Cmp(field, op, mut values) => {
+                self.compile_cmp(&field, op, values.remove(0))?
+            }
+        })
+    }
+
+    fn compile_cmp(&self, field: &str, op: Op, value: Literal) -> Result<Expr, String> {
+        let unsupported = || format!("Operator {op} is not supported for {field}");
+        let cmp_op = match op {
+            Op::Eq => Some(CmpOp::Eq),
+            Op::Lt => Some(CmpOp::Lt),
+            Op::Le => Some(CmpOp::Le),
+            Op::Gt => Some(CmpOp::Gt),
+            Op::Ge => Some(CmpOp::Ge),
+            _ => None,
+        };
+        let str_value = match &value {
+            Literal::Str(s) => Ok(s.as_str()),
+            Literal::Regex(_) => Err(format!(
+                "Regular expression can be used only with ~ operator in {field} {op} ..."
+            )),
+        };
+
+        match field {
+            "size" | "depth" | "links" | "owner" | "group" => {
+                let cmp_op = cmp_op.ok_or_else(unsupported)?;
+                let s = str_value?;
+                let (field, value) = match field {
+                    "size" => (NumField::Size, parse_size(s)?),
+                    "depth" => (NumField::Depth, parse_u64(s)?),
+                    "links" => (NumField::Links, Self::check_unix(field, parse_u64(s))?),
+                    "owner" => (NumField::Uid, Self::check_unix(field, resolve_user(s))?),
+                    _ => (NumField::Gid, Self::check_unix(field, resolve_group(s))?),
+                };
+                Ok(Expr::Num(field, cmp_op, value))
+            }
+            "mtime" => {
+                let cmp_op = cmp_op.ok_or_else(unsupported)?;
+                Ok(Expr::Mtime(cmp_op, parse_date_time(str_value?)?))
+            }
+            "name" | "path" | "ext" => {
+                let field = match field {
+                    "name" => StrField::Name,
+                    "path" => StrField::Path,
+                    _ => StrField::Ext,
+                };
+                match op {
+                    Op::Eq => {
+                        let s = str_value?;
+                        let s = match field {
+                            StrField::Ext => s.trim_start_matches('.').to_lowercase(),
+                            _ => s.to_owned(),
+                        };
+                        Ok(Expr::StrEq(field, s))
+                    }
+                    Op::Match => Ok(Expr::StrMatch(field, self.compile_pattern(field, value)?)),
+                    _ => Err(unsupported()),
+                }
+            }
+            _ => Err(format!(
+                "Unknown field: {field}. \
+                 Supported fields are: size, name, path, ext, mtime, owner, group, depth, links"
+            )),
+        }
+    }
+
+    fn compile_pattern(&self, field: StrField, value: Literal) -> Result<Pattern, String> {
+        let opts = if self.ignore_case || matches!(field, StrField::Ext) {
+            PatternOpts::case_insensitive()
+        } else {
+            PatternOpts::default()
+        };
+        let pattern = match value {
+            Literal::Str(s) => Pattern::glob_with(&s, &opts),
+            Literal::Regex(s) => Pattern::regex_with(&s, &opts),
+        }
+        .map_err(|e| e.to_string())?;
+        Ok(match field {
+            StrField::Path => PathSelector::abs_pattern(self.base_dir, pattern),
+            _ => pattern,
+        })
+    }
+
+    #[cfg(unix)]
+    fn check_unix<T>(_field: &str, value: Result<T, String>) -> Result<T, String> {
+        value
+    }
+
+    #[cfg(not(unix))]
+    fn check_unix<T>(field: &str, _value: Result<T, String>) -> Result<T, String> {
+        Err(format!("Field {field} is not supported on this platform"))
+    }
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    s.parse::<FileLen>()
+        .map(|l| l.0)
+        .map_err(|e| format!("Invalid size {s}: {e}"))
+}
+
+fn parse_u64(s: &str) -> Result<u64, String> {
+    s.parse().map_err(|e| format!("Invalid number {s}: {e}"))
+}
+
+#[cfg(unix)]
+fn resolve_user(s: &str) -> Result<u64, String> {
+    crate::metadata::resolve_user(s).map(|uid| uid as u64)
+}
+
+#[cfg(unix)]
+fn resolve_group(s: &str) -> Result<u64, String> {
+    crate::metadata::resolve_group(s).map(|gid| gid as u64)
+}
+
+#[cfg(not(unix))]
+fn resolve_user(_s: &str) -> Result<u64, String> {
+    Ok(0)
+}
+
+#[cfg(not(unix))]
+fn resolve_group(_s: &str) -> Result<u64, String> {
+    Ok(0)
+}
+
+/// A compiled `--where` expression.
+#[derive(Clone, Debug)]
+pub struct FileExpr {
+    src: String,
+    expr: Expr,
+}
+
+impl FileExpr {
+    /// Parses and compiles the expression.
+    /// Relative path patterns are resolved against `base_dir`.
+    pub fn new(src: &str, base_dir: &Path, ignore_case: bool) -> Result<FileExpr, String> {
+        let ctx = CompileCtx {
+            base_dir,
+            ignore_case,
+        };
+        let expr = ctx.compile(parse(src)?)?;
+        Ok(FileExpr {
+            src: src.to_owned(),
+            expr,
+        })
+    }
+
+    /// Returns true if evaluating this expression requires file metadata.
+    pub fn needs_metadata(&self) -> bool {
+        self.expr.needs_metadata()
+    }
+
+    /// Returns true if the file is selected by this expression.
+    pub fn matches(&self, file: &FileAttrs) -> bool {
+        self.expr.eval(file)
+    }
+}
+
+impl Display for FileExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(&self.src)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+
+    use crate::util::test::with_dir;
+
+    use super::*;
+
+    fn cmp(field: &str, op: Op, value: &str) -> RawExpr {
+        RawExpr::Cmp(field.to_owned(), op, vec![Literal::Str(value.to_owned())])
+    }
+
+    #[test]
+    fn parse_precedence() {
+        let e = parse("size > 1 or name = a and not ext = b").unwrap();
+        assert_eq!(
+            e,
+            RawExpr::Or(
+                Box::new(cmp("size", Op::Gt, "1")),
+                Box::new(RawExpr::And(
+                    Box::new(cmp("name", Op::Eq, "a")),
+                    Box::new(RawExpr::Not(Box::new(cmp("ext", Op::Eq, "b"))))
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn parse_literals() {
+        let e = parse(r#"ext in (jpg, 'he ic') and path !~ r"a\"b\d""#).unwrap();
+        assert_eq!(
+            e,
+            RawExpr::And(
+                Box::new(RawExpr::Cmp(
+                    "ext".to_owned(),
+                    Op::In,
+                    vec![
+                        Literal::Str("jpg".to_owned()),
+                        Literal::Str("he ic".to_owned())
+                    ]
+                )),
+                Box::new(RawExpr::Cmp(
+                    "path".to_owned(),
+                    Op::NotMatch,
+                    vec![Literal::Regex(r#"a"b\d"#.to_owned())]
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse("size >").is_err());
+        assert!(parse("size > 1 and").is_err());
+        assert!(parse("(size > 1").is_err());
+        let base_dir = Path::from("/");
+        assert!(FileExpr::new("color = red", &base_dir, false).is_err());
+        assert!(FileExpr::new("size ~ 1", &base_dir, false).is_err());
+        assert!(FileExpr::new("name = r\"a\"", &base_dir, false).is_err());
+        assert!(FileExpr::new("size > 1X", &base_dir, false).is_err());
+    }
+
+    #[test]
+    fn evaluate() {
+        with_dir("target/test/expr/evaluate", |root| {
+            let file_path = root.join("Photo.JPG");
+            let mut file = File::create(&file_path).unwrap();
+            file.write_all(&[0; 1000]).unwrap();
+            let metadata = file_path.metadata().unwrap();
+            let path = Path::from(&file_path);
+            let attrs = FileAttrs {
+                path: &path,
+                depth: 2,
+                metadata: Some(&metadata),
+            };
+            let base_dir = Path::from(root);
+            let matches = |e: &str| FileExpr::new(e, &base_dir, false).unwrap().matches(&attrs);
+
+            assert!(matches("ext in (jpg, heic) and size > 500"));
+            assert!(!matches("ext in (png, heic) and size > 500"));
+            assert!(matches("size = 1000 and size >= 1K and size < 1KiB"));
+            assert!(matches("name ~ '*.JPG' and not name ~ '*.jpg'"));
+            assert!(matches("name ~ r\"[A-Z]\\w+\\.JPG\""));
+            assert!(matches("path ~ 'Photo.*' and not path ~ \"**/thumbs/**\""));
+            assert!(matches("depth = 2 and (depth > 5 or mtime > 2000-01-01)"));
+            assert!(matches("mtime < '2100-01-01 12:00'"));
+            #[cfg(unix)]
+            assert!(matches("links = 1"));
+        });
+    }
+
+    #[test]
+    fn depth_and_names_do_not_need_metadata() {
+        let base_dir = Path::from("/");
+        let e = FileExpr::new("depth < 3 and name = foo", &base_dir, false).unwrap();
+        assert!(!e.needs_metadata());
+        let e = FileExpr::new("depth < 3 or size > 0", &base_dir, false).unwrap();
+        assert!(e.needs_metadata());
+    }
+}
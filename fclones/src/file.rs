@@ -14,7 +14,7 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use bytesize::ByteSize;
 use hex::FromHexError;
 use itertools::{EitherOrBoth, Itertools};
-use rayon::iter::{IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator};
+use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
 use serde::*;
 use smallvec::alloc::fmt::Formatter;
 use smallvec::alloc::str::FromStr;
@@ -359,19 +359,28 @@ const OFFSET_MASK: u64 = 0x0000FFFFFFFFFFFF;
 const DEVICE_MASK: u64 = 0xFFFF000000000000;
 
 impl FileInfo {
-    fn new(path: Path, devices: &DiskDevices) -> io::Result<FileInfo> {
-        let device_index = devices.get_by_path(&path).index as u64;
+    pub(crate) fn new(path: Path, devices: &DiskDevices) -> io::Result<FileInfo> {
         let metadata = FileMetadata::new(&path)?;
+        Ok(Self::with_metadata(path, &metadata, devices))
+    }
+
+    /// Creates the file information from already fetched metadata
+    pub(crate) fn with_metadata(
+        path: Path,
+        metadata: &FileMetadata,
+        devices: &DiskDevices,
+    ) -> FileInfo {
+        let device_index = devices.get_by_path(&path).index as u64;
         let file_len = metadata.len();
         let id = metadata.id;
         let inode_id = metadata.inode_id();
         #[allow(clippy::unnecessary_cast)] // cast needed when inode_id > 64 bits on some platforms
-        Ok(FileInfo {
+        FileInfo {
             path,
             id,
             len: file_len,
             location: (device_index << 48) | (inode_id as u64) & OFFSET_MASK,
-        })
+        }
     }
 
     /// Returns the device index into the `DiskDevices` instance passed at creation
@@ -414,11 +423,11 @@ pub(crate) fn file_info_or_log_err(
     devices: &DiskDevices,
     log: &dyn Log,
 ) -> Option<FileInfo> {
-    match FileInfo::new(file, devices) {
+    match FileInfo::new(file.clone(), devices) {
         Ok(info) => Some(info),
         Err(e) if e.kind() == ErrorKind::NotFound => None,
         Err(e) => {
-            log.warn(e);
+            log.warn_file(&file, &e, &e);
             None
         }
     }
@@ -438,6 +447,112 @@ pub(crate) fn get_physical_file_location(path: &Path) -> io::Result<Option<u64>>
     }
 }
 
+/// Returns true if the file has no data extents, i.e. it consists only of holes
+/// or of preallocated space that was never written.
+#[cfg(target_os = "linux")]
+pub(crate) fn has_no_data(path: &Path) -> io::Result<bool> {
+    use crate::rlimit::RLIMIT_OPEN_FILES;
+    use fiemap::FiemapExtentFlags;
+    let _open_files_guard = RLIMIT_OPEN_FILES.clone().access_owned();
+    for extent in fiemap::fiemap(path.to_path_buf())? {
+        if !extent?.fe_flags.contains(FiemapExtentFlags::UNWRITTEN) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Returns the amount of disk space occupied by the file that removing it would release,
+/// provided no other hard links to it exist.
+///
+/// Unlike the logical file length, this does not count the holes of sparse files and,
+/// on Linux, the data extents shared with other files, e.g. by reflinking or snapshots.
+#[cfg(unix)]
+pub(crate) fn exclusive_len(path: &Path, metadata: &fs::Metadata) -> FileLen {
+    use std::os::unix::fs::MetadataExt;
+    // Unix always reports the number of blocks in 512-byte units
+    let allocated = metadata.blocks() * 512;
+    #[cfg(target_os = "linux")]
+    let allocated = allocated.saturating_sub(shared_len(path).unwrap_or(0));
+    #[cfg(not(target_os = "linux"))]
+    let _ = path;
+    FileLen(allocated)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn exclusive_len(_path: &Path, metadata: &fs::Metadata) -> FileLen {
+    FileLen(metadata.len())
+}
+
+/// Returns the number of hard links to the file
+#[cfg(unix)]
+pub(crate) fn link_count(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn link_count(_metadata: &fs::Metadata) -> u64 {
+    1
+}
+
+/// Returns the total length of the data extents of the file shared with other files
+#[cfg(target_os = "linux")]
+fn shared_len(path: &Path) -> io::Result<u64> {
+    use crate::rlimit::RLIMIT_OPEN_FILES;
+    use fiemap::FiemapExtentFlags;
+    let _open_files_guard = RLIMIT_OPEN_FILES.clone().access_owned();
+    let mut len = 0;
+    for extent in fiemap::fiemap(path.to_path_buf())? {
+        let extent = extent?;
+        if extent.fe_flags.contains(FiemapExtentFlags::SHARED) {
+            len += extent.fe_length;
+        }
+    }
+    Ok(len)
+}
+
+/// Returns an identifier of the data extents of the file, if all of them are shared with
+/// other files, e.g. because the file was reflinked.
+/// Files stored in exactly the same extents on the same device get the same identifier,
+/// so they can be treated like hard links to the same file.
+/// Returns `None` for files without any data extents or with extents not shared.
+#[cfg(target_os = "linux")]
+pub(crate) fn shared_extents_id(path: &Path) -> io::Result<Option<u128>> {
+    use crate::rlimit::RLIMIT_OPEN_FILES;
+    use fiemap::FiemapExtentFlags;
+    use metrohash::MetroHash128;
+    use std::hash::Hasher;
+
+    let _open_files_guard = RLIMIT_OPEN_FILES.clone().access_owned();
+    let device = FileId::new(path)?.device;
+    let mut hasher = MetroHash128::new();
+    hasher.write_u64(device);
+    let mut empty = true;
+    for extent in fiemap::fiemap(path.to_path_buf())? {
+        let extent = extent?;
+        // Physical locations of such extents are not reliable:
+        let unreliable = FiemapExtentFlags::UNKNOWN
+            | FiemapExtentFlags::DELALLOC
+            | FiemapExtentFlags::DATA_INLINE
+            | FiemapExtentFlags::NOT_ALIGNED;
+        if !extent.fe_flags.contains(FiemapExtentFlags::SHARED)
+            || extent.fe_flags.intersects(unreliable)
+        {
+            return Ok(None);
+        }
+        hasher.write_u64(extent.fe_logical);
+        hasher.write_u64(extent.fe_physical);
+        hasher.write_u64(extent.fe_length);
+        empty = false;
+    }
+    if empty {
+        return Ok(None);
+    }
+    let (a, b) = hasher.finish128();
+    Ok(Some(((a as u128) << 64) | (b as u128)))
+}
+
 #[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct FileHash(Box<[u8]>);
 
@@ -450,6 +565,12 @@ impl FileHash {
     }
 }
 
+impl AsRef<[u8]> for FileHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl Display for FileHash {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.pad(hex::encode(&self.0).as_str())
@@ -523,8 +644,6 @@ impl<'de> Deserialize<'de> for FileHash {
 pub(crate) trait FileCollection {
     /// Returns the number of files in the collection
     fn count(&self) -> usize;
-    /// Returns the total size of files in the collection
-    fn total_size(&self) -> FileLen;
     /// Performs given action on each file in the collection
     fn for_each_mut<OP>(&mut self, op: OP)
     where
@@ -536,10 +655,6 @@ impl FileCollection for Vec<FileInfo> {
         self.len()
     }
 
-    fn total_size(&self) -> FileLen {
-        self.par_iter().map(|f| f.len).sum()
-    }
-
     fn for_each_mut<OP>(&mut self, op: OP)
     where
         OP: Fn(&mut FileInfo) + Sync + Send,
@@ -553,10 +668,6 @@ impl FileCollection for Vec<FileGroup<FileInfo>> {
         self.iter().map(|g| g.file_count()).sum()
     }
 
-    fn total_size(&self) -> FileLen {
-        self.par_iter().map(|g| g.total_size()).sum()
-    }
-
     fn for_each_mut<OP>(&mut self, op: OP)
     where
         OP: Fn(&mut FileInfo) + Sync + Send,
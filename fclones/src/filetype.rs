@@ -0,0 +1,165 @@
+//! Detecting types of file contents from magic bytes.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::str::FromStr;
+
+use infer::MatcherType;
+
+use crate::path::Path;
+use crate::stats::record_thread_read;
+use crate::throttle::throttle_thread_read;
+
+/// How many bytes from the beginning of the file are needed to detect its type
+pub const SNIFF_LEN: usize = 8192;
+
+/// Names of the categories that can be given to `--type` and `--type-not`
+pub const CATEGORIES: [&str; 10] = [
+    "app", "archive", "audio", "book", "doc", "font", "image", "text", "video", "unknown",
+];
+
+/// Detects the type of the file by looking at its first bytes.
+/// Returns `None` if the type is not recognized.
+pub fn detect(path: &Path) -> io::Result<Option<infer::Type>> {
+    let file = File::open(path.to_path_buf())?;
+    let mut buf = Vec::with_capacity(SNIFF_LEN);
+    file.take(SNIFF_LEN as u64).read_to_end(&mut buf)?;
+    throttle_thread_read(buf.len() as u64);
+    record_thread_read(buf.len() as u64);
+    Ok(detect_bytes(&buf))
+}
+
+/// Detects the type of the file from the data at its beginning, already read.
+/// Returns `None` if the type is not recognized.
+pub fn detect_bytes(prefix: &[u8]) -> Option<infer::Type> {
+    infer::get(prefix)
+}
+
+/// Returns the name of the category of the detected type
+fn category(file_type: Option<&infer::Type>) -> &'static str {
+    match file_type.map(|t| t.matcher_type()) {
+        Some(MatcherType::App) => "app",
+        Some(MatcherType::Archive) => "archive",
+        Some(MatcherType::Audio) => "audio",
+        Some(MatcherType::Book) => "book",
+        Some(MatcherType::Doc) => "doc",
+        Some(MatcherType::Font) => "font",
+        Some(MatcherType::Image) => "image",
+        Some(MatcherType::Text) => "text",
+        Some(MatcherType::Video) => "video",
+        Some(MatcherType::Custom) | None => "unknown",
+    }
+}
+
+/// Selects files of a type category like `image`, or of an exact MIME type like `image/png`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeSpec(String);
+
+impl TypeSpec {
+    pub fn matches(&self, file_type: Option<&infer::Type>) -> bool {
+        if self.0.contains('/') {
+            file_type.is_some_and(|t| t.mime_type().eq_ignore_ascii_case(&self.0))
+        } else {
+            category(file_type) == self.0
+        }
+    }
+}
+
+impl FromStr for TypeSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        if s.contains('/') || CATEGORIES.contains(&s.as_str()) {
+            Ok(TypeSpec(s))
+        } else {
+            Err(format!(
+                "Unknown file type: {s}. Expected a MIME type or one of: {}",
+                CATEGORIES.join(", ")
+            ))
+        }
+    }
+}
+
+impl Display for TypeSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(&self.0)
+    }
+}
+
+/// Selects files by detected content type.
+/// A file is selected if it matches any of the included types (or the list is empty)
+/// and doesn't match any of the excluded types.
+#[derive(Clone, Debug, Default)]
+pub struct TypeFilter {
+    pub include: Vec<TypeSpec>,
+    pub exclude: Vec<TypeSpec>,
+}
+
+impl TypeFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches(&self, file_type: Option<&infer::Type>) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|t| t.matches(file_type)))
+            && !self.exclude.iter().any(|t| t.matches(file_type))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::write;
+
+    use crate::util::test::with_dir;
+
+    use super::*;
+
+    #[test]
+    fn detect_type_from_contents() {
+        with_dir("target/test/filetype/detect", |root| {
+            let png = root.join("image.dat");
+            write(&png, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+            let html = root.join("image.jpg");
+            write(&html, b"<!DOCTYPE html><html><body>Not found</body></html>").unwrap();
+            let unknown = root.join("file.txt");
+            write(&unknown, b"foo").unwrap();
+
+            let png_type = detect(&Path::from(png)).unwrap();
+            assert_eq!(png_type.unwrap().mime_type(), "image/png");
+            let html_type = detect(&Path::from(html)).unwrap();
+            assert_eq!(html_type.unwrap().mime_type(), "text/html");
+            assert!(detect(&Path::from(unknown)).unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn filter_by_category_and_mime_type() {
+        let png = infer::get(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR");
+        let zip = infer::get(b"PK\x03\x04\0\0\0\0");
+        let filter = TypeFilter {
+            include: vec!["image".parse().unwrap(), "application/zip".parse().unwrap()],
+            exclude: vec![],
+        };
+        assert!(filter.matches(png.as_ref()));
+        assert!(filter.matches(zip.as_ref()));
+        assert!(!filter.matches(None));
+
+        let filter = TypeFilter {
+            include: vec![],
+            exclude: vec!["archive".parse().unwrap(), "unknown".parse().unwrap()],
+        };
+        assert!(filter.matches(png.as_ref()));
+        assert!(!filter.matches(zip.as_ref()));
+        assert!(!filter.matches(None));
+    }
+
+    #[test]
+    fn parse_type_spec() {
+        assert!("Image".parse::<TypeSpec>().is_ok());
+        assert!("image/jpeg".parse::<TypeSpec>().is_ok());
+        assert!("picture".parse::<TypeSpec>().is_err());
+    }
+}
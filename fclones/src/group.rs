@@ -3,7 +3,7 @@
 use std::cell::RefCell;
 use std::cmp::{max, min, Reverse};
 use std::collections::BTreeMap;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env::{args_os, current_dir};
 use std::ffi::{OsStr, OsString};
 use std::fmt::Debug;
@@ -20,6 +20,7 @@ use std::sync::Arc;
 use chrono::{DateTime, Local};
 use console::Term;
 use crossbeam_utils::thread;
+use dashmap::DashMap;
 use indexmap::IndexMap;
 use itertools::Itertools;
 use rayon::prelude::*;
@@ -31,15 +32,21 @@ use crate::arg::Arg;
 use crate::config::*;
 use crate::device::{DiskDevice, DiskDevices};
 use crate::error::Error;
+use crate::expr::FileExpr;
 use crate::file::*;
+use crate::filetype;
+use crate::filetype::TypeFilter;
 use crate::hasher::FileHasher;
 use crate::log::{Log, LogExt, ProgressBarLength};
+use crate::metadata::MetadataFilter;
 use crate::path::Path;
 use crate::phase::{Phase, Phases};
+use crate::progress::ProgressTracker;
 use crate::report::{FileStats, ReportHeader, ReportWriter};
 use crate::rlimit::RLIMIT_OPEN_FILES;
 use crate::selector::PathSelector;
 use crate::semaphore::Semaphore;
+use crate::stats::{take_thread_reads, PhaseStart, RunStats, StatsCollector};
 use crate::walk::Walk;
 
 /// Groups items by key.
@@ -100,15 +107,49 @@ where
     }
 }
 
+/// Progress bar of a phase that records the phase statistics when dropped.
+pub(crate) struct PhaseProgress<'a> {
+    ctx: &'a GroupCtx<'a>,
+    start: Option<PhaseStart>,
+    progress: Arc<dyn ProgressTracker>,
+}
+
+impl ProgressTracker for PhaseProgress<'_> {
+    fn inc(&self, delta: u64) {
+        self.progress.inc(delta)
+    }
+}
+
+impl Drop for PhaseProgress<'_> {
+    fn drop(&mut self) {
+        if let Some(start) = self.start.take() {
+            let ctx = self.ctx;
+            ctx.stats.end_phase(start, &ctx.hasher.io, &ctx.devices);
+        }
+    }
+}
+
 /// Holds stuff needed globally by the whole application
-struct GroupCtx<'a> {
+pub(crate) struct GroupCtx<'a> {
     pub config: &'a GroupConfig,
     pub log: &'a dyn Log,
     phases: Phases,
-    group_filter: FileGroupFilter,
-    devices: DiskDevices,
+    pub group_filter: FileGroupFilter,
+    pub devices: DiskDevices,
     path_selector: PathSelector,
-    hasher: FileHasher<'a>,
+    metadata_filter: MetadataFilter,
+    file_expr: Option<FileExpr>,
+    type_filter: TypeFilter,
+    /// Set if the types of file contents are needed for filtering and the report
+    detect_types: bool,
+    /// Types of the files detected so far
+    file_types: DashMap<FileId, Option<infer::Type>>,
+    /// If not empty, only the groups containing any of these files are reported
+    reference_paths: HashSet<Path>,
+    /// If not empty, only the files of these sizes are considered
+    reference_lens: HashSet<FileLen>,
+    pub hasher: FileHasher<'a>,
+    stats: StatsCollector,
 }
 
 impl<'a> GroupCtx<'a> {
@@ -120,18 +161,30 @@ impl<'a> GroupCtx<'a> {
                 Phase::TransformAndGroup,
             ])
         } else {
-            Phases::new(vec![
+            let mut phases = vec![
                 Phase::Walk,
                 Phase::GroupBySize,
                 Phase::FetchExtents,
                 Phase::GroupByPrefix,
                 Phase::GroupBySuffix,
-                Phase::GroupByContents,
-            ])
+            ];
+            if config.sample_hash.is_some() {
+                phases.push(Phase::GroupBySamples);
+            }
+            phases.push(Phase::GroupByContents);
+            // Only the files matched by the normalizers or transform rules get transformed,
+            // the remaining ones go through the usual stages:
+            if config.transforms_data() {
+                phases.push(Phase::TransformAndGroup);
+            }
+            Phases::new(phases)
         };
 
         let thread_pool_sizes = config.thread_pool_sizes();
-        let devices = DiskDevices::new(&thread_pool_sizes);
+        let mut devices = DiskDevices::with_calibration(&thread_pool_sizes, &config.calibration);
+        let read_rate_limits = config.read_rate_limits();
+        let iops_limits = config.iops_limits();
+        devices.set_rate_limits(&read_rate_limits, &iops_limits);
         let transform = match config.transform() {
             None => None,
             Some(Ok(transform)) => Some(transform),
@@ -139,16 +192,34 @@ impl<'a> GroupCtx<'a> {
         };
         let base_dir = Path::from(current_dir().unwrap_or_default());
         let group_filter = config.group_filter();
-        let path_selector = config
-            .path_selector(&base_dir)
-            .map_err(|e| format!("Invalid pattern: {e}"))?;
-        let hasher = if config.cache {
-            FileHasher::new_cached(config.hash_fn, transform, log)?
-        } else {
-            FileHasher::new(config.hash_fn, transform, log)
+        let path_selector = config.path_selector(&base_dir)?;
+        let metadata_filter = config.metadata_filter()?;
+        let file_expr = config
+            .file_expr(&base_dir)
+            .map_err(|e| format!("Invalid --where expression: {e}"))?;
+        let type_filter = config.type_filter();
+        let detect_types = !type_filter.is_empty() || config.report_types;
+        let normalizers = config
+            .normalizers()
+            .map_err(|e| format!("Invalid --normalize pattern: {e}"))?;
+        let transform_rules = config.transform_rules()?;
+        let hasher = match normalizers {
+            Some(normalizers) => {
+                FileHasher::new_normalized(config.hash_fn, normalizers, config.cache, log)?
+            }
+            None if !transform_rules.is_empty() => FileHasher::new_with_transform_rules(
+                config.hash_fn,
+                transform_rules,
+                config.cache,
+                log,
+            )?,
+            None if config.cache => FileHasher::new_cached(config.hash_fn, transform, log)?,
+            None => FileHasher::new(config.hash_fn, transform, log),
         };
 
         Self::check_pool_config(thread_pool_sizes, &devices)?;
+        Self::check_rate_limit_config(read_rate_limits.keys(), &devices)?;
+        Self::check_rate_limit_config(iops_limits.keys(), &devices)?;
 
         Ok(GroupCtx {
             config,
@@ -157,10 +228,122 @@ impl<'a> GroupCtx<'a> {
             group_filter,
             devices,
             path_selector,
+            metadata_filter,
+            file_expr,
+            type_filter,
+            detect_types,
+            file_types: DashMap::new(),
+            reference_paths: HashSet::new(),
+            reference_lens: HashSet::new(),
             hasher,
+            stats: StatsCollector::new(),
         })
     }
 
+    /// Overrides the phases reported in the progress bars.
+    pub fn set_phases(&mut self, phases: Vec<Phase>) {
+        self.phases = Phases::new(phases);
+    }
+
+    /// Returns a walk configured to select the files according to the config.
+    pub fn walk(&self) -> Walk<'_> {
+        let config = &self.config;
+        let mut walk = Walk::new();
+        walk.depth = config.depth.unwrap_or(usize::MAX);
+        walk.hidden = config.hidden;
+        walk.follow_links = config.follow_links;
+        walk.report_links = config.symbolic_links;
+        walk.no_ignore = config.no_ignore;
+        walk.one_fs = config.one_fs;
+        walk.path_selector = self.path_selector.clone();
+        walk.metadata_filter = self.metadata_filter.clone();
+        walk.file_expr = self.file_expr.clone();
+        walk.log = Some(self.log);
+        walk
+    }
+
+    /// Returns true if the file size is within the limits given by `--min-size`
+    /// and `--max-size`.
+    pub fn matches_size(&self, len: FileLen) -> bool {
+        len >= self.config.min_size
+            && len <= self.config.max_size.unwrap_or(FileLen::MAX)
+            && (self.reference_lens.is_empty() || self.reference_lens.contains(&len))
+    }
+
+    /// Restricts the search to the copies of the given files.
+    fn set_references(&mut self, references: &[FileInfo]) {
+        self.reference_paths = references.iter().map(|f| f.path.clone()).collect();
+        self.reference_lens = references.iter().map(|f| f.len).collect();
+    }
+
+    /// Returns true if the group contains any of the reference files,
+    /// or if we're not looking for copies of specific files.
+    fn contains_reference(&self, g: &FileGroup<FileInfo>) -> bool {
+        self.reference_paths.is_empty()
+            || g.files
+                .iter()
+                .any(|f| self.reference_paths.contains(&f.path))
+    }
+
+    /// Returns the statistics of all the phases completed so far,
+    /// together with the files that had to be skipped.
+    fn finish_stats(&self) -> RunStats {
+        let mut stats = self.stats.finish();
+        stats.skipped_files = self.hasher.skipped_files.lock().unwrap().clone();
+        stats.skipped_files.sort_by(|a, b| a.path.cmp(&b.path));
+        stats
+    }
+
+    /// Installs a progress bar for tracking the given phase of work.
+    /// Statistics of the phase are recorded when the returned progress bar is dropped.
+    pub fn phase_progress_bar(&self, phase: Phase, len: ProgressBarLength) -> PhaseProgress<'_> {
+        let start = self
+            .stats
+            .begin_phase(phase, &self.hasher.io, &self.devices);
+        let progress = self
+            .log
+            .phase_progress_bar(phase, &self.phases.format(phase), len);
+        PhaseProgress {
+            ctx: self,
+            start: Some(start),
+            progress,
+        }
+    }
+
+    /// Detects the type of the file contents from the `prefix` read from the beginning
+    /// of the file and remembers it for the report. If `prefix` is `None`, because the hash
+    /// of the prefix was found in the cache, the beginning of the file is read again.
+    /// Returns `None` if the type is unknown or the file could not be read.
+    fn detect_file_type(&self, fi: &FileInfo, prefix: Option<&[u8]>) -> Option<infer::Type> {
+        if let Some(t) = self.file_types.get(&fi.id) {
+            return *t;
+        }
+        let file_type = match prefix {
+            Some(prefix) => filetype::detect_bytes(prefix),
+            None => match filetype::detect(&fi.path) {
+                Ok(t) => t,
+                Err(e) => {
+                    self.log
+                        .warn_file(&fi.path, &e, "Failed to detect file type");
+                    None
+                }
+            },
+        };
+        self.file_types.insert(fi.id, file_type);
+        file_type
+    }
+
+    /// Returns true if the file passes the `--type` and `--type-not` filters.
+    /// Files of unknown type are treated as the `unknown` category.
+    /// See [`GroupCtx::detect_file_type`] for the meaning of `prefix`.
+    fn matches_file_type(&self, fi: &FileInfo, prefix: Option<&[u8]>) -> bool {
+        if !self.detect_types {
+            return true;
+        }
+        let file_type = self.detect_file_type(fi, prefix);
+        self.type_filter.matches(file_type.as_ref())
+    }
+
     /// Checks if all thread pool names refer to existing pools or devices
     fn check_pool_config(
         thread_pool_sizes: HashMap<OsString, Parallelism>,
@@ -185,6 +368,28 @@ impl<'a> GroupCtx<'a> {
         }
         Ok(())
     }
+
+    /// Checks if all device names given in rate limits refer to existing devices
+    fn check_rate_limit_config<'k>(
+        names: impl Iterator<Item = &'k OsString>,
+        devices: &DiskDevices,
+    ) -> Result<(), Error> {
+        let mut allowed_names = DiskDevices::device_types();
+        allowed_names.push("default");
+        for name in names {
+            let name = name.to_string_lossy();
+            match name.strip_prefix("dev:") {
+                Some(name) if devices.get_by_name(OsStr::new(name)).is_none() => {
+                    return Err(Error::new(format!("Unknown device: {name}")));
+                }
+                None if !allowed_names.contains(&name.as_ref()) => {
+                    return Err(Error::new(format!("Unknown device type: {name}")));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
 }
 
 /// A group of files that have something in common, e.g. same size or same hash
@@ -194,10 +399,40 @@ pub struct FileGroup<F> {
     pub file_len: FileLen,
     /// Hash of a part or the whole of the file
     pub file_hash: FileHash,
+    /// MIME type of the contents detected from the first bytes of the files, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_type: Option<String>,
+    /// Transform or normalizer applied to the files before computing the hash, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<String>,
+    /// Set if the files contain only zero bytes, e.g. when they are preallocated or sparse
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub zero_filled: bool,
+    /// Identifiers of the files stored in the same data extents as another file of the group,
+    /// mapped to the identifier of that file, e.g. because the files were reflinked.
+    /// Such files are counted as a single replica, like hard links.
+    #[serde(skip)]
+    pub shared_extents: HashMap<FileId, FileId>,
     /// Group of files with the same length and hash
     pub files: Vec<F>,
 }
 
+/// An empty group without any additional attributes.
+/// Allows to set only the interesting fields with the struct update syntax.
+impl<F> Default for FileGroup<F> {
+    fn default() -> Self {
+        FileGroup {
+            file_len: FileLen(0),
+            file_hash: FileHash::from(0),
+            file_type: None,
+            transform: None,
+            zero_filled: false,
+            shared_extents: HashMap::new(),
+            files: Vec::new(),
+        }
+    }
+}
+
 /// Controls the type of search by determining the number of replicas
 /// allowed in a group of identical files.
 #[derive(Debug)]
@@ -248,6 +483,10 @@ impl<F> FileGroup<F> {
         FileGroup {
             file_len: self.file_len,
             file_hash: self.file_hash,
+            file_type: self.file_type,
+            transform: self.transform,
+            zero_filled: self.zero_filled,
+            shared_extents: self.shared_extents,
             files: self.files.into_iter().map(f).collect(),
         }
     }
@@ -257,6 +496,10 @@ impl<F> FileGroup<F> {
         FileGroup {
             file_len: self.file_len,
             file_hash: self.file_hash,
+            file_type: self.file_type,
+            transform: self.transform,
+            zero_filled: self.zero_filled,
+            shared_extents: self.shared_extents,
             files: self.files.into_iter().filter_map(f).collect(),
         }
     }
@@ -273,6 +516,10 @@ impl<F> FileGroup<F> {
             Ok(FileGroup {
                 file_len: self.file_len,
                 file_hash: self.file_hash,
+                file_type: self.file_type,
+                transform: self.transform,
+                zero_filled: self.zero_filled,
+                shared_extents: self.shared_extents,
                 files: ok.into_iter().map(Result::unwrap).collect(),
             })
         } else {
@@ -289,6 +536,10 @@ impl<F> FileGroup<F> {
         FileGroup {
             file_len: self.file_len,
             file_hash: self.file_hash,
+            file_type: self.file_type,
+            transform: self.transform,
+            zero_filled: self.zero_filled,
+            shared_extents: self.shared_extents,
             files: self.files.into_iter().flat_map(f).collect(),
         }
     }
@@ -308,6 +559,10 @@ impl<F> FileGroup<F> {
             .map(|files| FileGroup {
                 file_len: self.file_len,
                 file_hash: self.file_hash.clone(),
+                file_type: self.file_type.clone(),
+                transform: self.transform.clone(),
+                zero_filled: self.zero_filled,
+                shared_extents: self.shared_extents.clone(),
                 files,
             })
             .collect()
@@ -394,6 +649,9 @@ impl<F: AsRef<Path> + AsRef<FileId>> FileGroup<F> {
     /// than the allowed number of replicas r, the last N - r subgroups are considered
     /// redundant. The total number of files in redundant subgroups is returned.
     ///
+    /// Files stored in the same data extents as another file of the group
+    /// (see [`FileGroup::shared_extents`]) are never counted as redundant.
+    ///
     /// If the result would be negative in any of the above cases or if `filter.replication`
     /// is set to `Replication::Underreplicated`, 0 is returned.
     pub fn redundant_count(&self, filter: &FileGroupFilter) -> usize {
@@ -401,15 +659,20 @@ impl<F: AsRef<Path> + AsRef<FileId>> FileGroup<F> {
             Replication::Underreplicated(_) => 0,
             Replication::Overreplicated(rf) => {
                 let rf = max(rf, 1);
+                let is_counted = |f: &&F| !self.shared_extents.contains_key(&FileId::of(f));
                 if filter.root_paths.is_empty() {
                     // fast-path, equivalent to the code in the else branch, but way faster
-                    self.file_count().saturating_sub(rf)
+                    let count = if self.shared_extents.is_empty() {
+                        self.file_count()
+                    } else {
+                        self.files.iter().filter(is_counted).count()
+                    };
+                    count.saturating_sub(rf)
                 } else {
-                    let sub_groups =
-                        FileSubGroup::group(&self.files, &filter.root_paths, filter.group_by_id);
-                    let sub_group_lengths = sub_groups
+                    let sub_group_lengths = self
+                        .sub_groups(filter)
                         .into_iter()
-                        .map(|sg| sg.files.len())
+                        .map(|sg| sg.files.iter().copied().filter(is_counted).count())
                         .collect_vec();
                     let cutoff_index = min(rf, sub_group_lengths.len());
                     sub_group_lengths[cutoff_index..].iter().sum()
@@ -428,7 +691,37 @@ impl<F: AsRef<Path> + AsRef<FileId>> FileGroup<F> {
 
     /// The number of subgroups of paths with distinct root prefix.
     fn subgroup_count(&self, filter: &FileGroupFilter) -> usize {
-        FileSubGroup::group(&self.files, &filter.root_paths, filter.group_by_id).len()
+        self.sub_groups(filter).len()
+    }
+
+    /// Splits the files into subgroups with [`FileSubGroup::group`].
+    /// If `filter.group_by_id` is set, the subgroups of files sharing their data extents
+    /// are additionally merged, like the subgroups of hard links.
+    fn sub_groups(&self, filter: &FileGroupFilter) -> Vec<FileSubGroup<&F>> {
+        let roots = &filter.root_paths;
+        let sub_groups = FileSubGroup::group(&self.files, roots, filter.group_by_id);
+        if !filter.group_by_id || self.shared_extents.is_empty() {
+            return sub_groups;
+        }
+        let mut result: Vec<FileSubGroup<&F>> = Vec::with_capacity(sub_groups.len());
+        let mut id_index: HashMap<FileId, usize> = HashMap::new();
+        for sg in sub_groups {
+            let path: &Path = sg.files[0].as_ref();
+            if roots.iter().any(|r| r.is_prefix_of(path)) {
+                result.push(sg);
+                continue;
+            }
+            let id = FileId::of(sg.files[0]);
+            let id = *self.shared_extents.get(&id).unwrap_or(&id);
+            match id_index.get(&id) {
+                Some(&index) => result[index].files.extend(sg.files),
+                None => {
+                    id_index.insert(id, result.len());
+                    result.push(sg)
+                }
+            }
+        }
+        result
     }
 
     /// Sorts the files by their path names.
@@ -515,6 +808,63 @@ impl<F: AsRef<Path> + AsRef<FileId>> FileSubGroup<F> {
     }
 }
 
+impl<F: AsRef<Path>> FileSubGroup<F> {
+    /// Merges the subgroups of files stored in exactly the same data extents,
+    /// e.g. because they were reflinked, so they are treated like hard links to the same file.
+    ///
+    /// As in [`FileSubGroup::group`], grouping by prefixes takes precedence,
+    /// so the subgroups of files under any of the roots are never merged.
+    /// Subgroups of files whose extents cannot be read are left intact.
+    #[cfg(target_os = "linux")]
+    pub fn merge_shared_extents(
+        sub_groups: Vec<FileSubGroup<F>>,
+        roots: &[Path],
+    ) -> Vec<FileSubGroup<F>> {
+        Self::merge_by_extents_id(sub_groups, roots, |path| {
+            shared_extents_id(path).ok().flatten()
+        })
+    }
+
+    /// Like [`FileSubGroup::merge_shared_extents`], but obtains the identifiers of the extents
+    /// of the files from the given function.
+    #[cfg(target_os = "linux")]
+    fn merge_by_extents_id(
+        sub_groups: Vec<FileSubGroup<F>>,
+        roots: &[Path],
+        extents_id: impl Fn(&Path) -> Option<u128>,
+    ) -> Vec<FileSubGroup<F>> {
+        let mut result: Vec<FileSubGroup<F>> = Vec::with_capacity(sub_groups.len());
+        let mut extents_index: HashMap<u128, usize> = HashMap::new();
+        for sg in sub_groups {
+            let extents_id = sg.files.first().and_then(|f| {
+                let path: &Path = f.as_ref();
+                if roots.iter().any(|r| r.is_prefix_of(path)) {
+                    None
+                } else {
+                    extents_id(path)
+                }
+            });
+            match extents_id.map(|id| (id, extents_index.get(&id))) {
+                Some((_, Some(&index))) => result[index].files.extend(sg.files),
+                Some((id, None)) => {
+                    extents_index.insert(id, result.len());
+                    result.push(sg)
+                }
+                None => result.push(sg),
+            }
+        }
+        result
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn merge_shared_extents(
+        sub_groups: Vec<FileSubGroup<F>>,
+        _roots: &[Path],
+    ) -> Vec<FileSubGroup<F>> {
+        sub_groups
+    }
+}
+
 /// Helper struct to preserve the original file hash and keep it together with file information
 /// Sometimes the old hash must be taken into account, e.g. when combining the prefix hash with
 /// the suffix hash.
@@ -560,7 +910,7 @@ fn flat_iter(files: &[FileGroup<FileInfo>]) -> impl ParallelIterator<Item = &Fil
 /// were in the different groups to end up in the same group if they have the same length
 /// and they hash to the same value. If you don't want this, you need to combine the old
 /// hash with the new hash in the provided `hash_fn`.
-fn rehash<'a, F1, F2, H>(
+pub(crate) fn rehash<'a, F1, F2, H>(
     groups: Vec<FileGroup<FileInfo>>,
     group_pre_filter: F1,
     group_post_filter: F2,
@@ -573,9 +923,43 @@ where
     F2: Fn(&FileGroup<FileInfo>) -> bool,
     H: Fn((&mut FileInfo, FileHash)) -> Option<FileHash> + Sync + Send + 'a,
 {
-    // Allow sharing the hash function between threads:
+    rehash_prefetched(
+        groups,
+        group_pre_filter,
+        group_post_filter,
+        devices,
+        access_type,
+        1,
+        |_| {},
+        hash_fn,
+    )
+}
+
+/// Like `rehash`, but processes files in batches of up to `max_batch_len` files.
+/// Each batch is passed to `prefetch_fn` first, so it can read the data of all the files at once,
+/// and then the files are passed to `hash_fn` one by one, on the same thread.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn rehash_prefetched<'a, F1, F2, P, H>(
+    groups: Vec<FileGroup<FileInfo>>,
+    group_pre_filter: F1,
+    group_post_filter: F2,
+    devices: &DiskDevices,
+    access_type: FileAccess,
+    max_batch_len: usize,
+    prefetch_fn: P,
+    hash_fn: H,
+) -> Vec<FileGroup<FileInfo>>
+where
+    F1: Fn(&FileGroup<FileInfo>) -> bool,
+    F2: Fn(&FileGroup<FileInfo>) -> bool,
+    P: Fn(&[&FileInfo]) + Sync + Send + 'a,
+    H: Fn((&mut FileInfo, FileHash)) -> Option<FileHash> + Sync + Send + 'a,
+{
+    // Allow sharing the hash and prefetch functions between threads:
     type HashFn<'a> = dyn Fn((&mut FileInfo, FileHash)) -> Option<FileHash> + Sync + Send + 'a;
+    type PrefetchFn<'a> = dyn Fn(&[&FileInfo]) + Sync + Send + 'a;
     let hash_fn: &HashFn<'a> = &hash_fn;
+    let prefetch_fn: &PrefetchFn<'a> = &prefetch_fn;
 
     let (tx, rx): (Sender<HashedFileInfo>, Receiver<HashedFileInfo>) = channel();
 
@@ -627,10 +1011,19 @@ where
                 // when processing 1M of files.
                 let semaphore = Arc::new(Semaphore::new(8 * thread_count));
 
+                // Batches must not be so large that some threads are left without work
+                let batch_len =
+                    (files.len() / thread_count as usize).clamp(1, max_batch_len.max(1));
+
                 // Run hashing on the thread-pool dedicated to the device.
                 // Group files by their identifiers so we hash only one file per unique id.
-                for (_, fg) in &files.into_iter().group_by(|f| f.file_info.id) {
-                    let mut fg = fg.collect_vec();
+                let file_groups = files.into_iter().group_by(|f| f.file_info.id);
+                let batches = file_groups
+                    .into_iter()
+                    .map(|(_, fg)| fg.collect_vec())
+                    .chunks(batch_len);
+                for batch in &batches {
+                    let batch = batch.collect_vec();
                     let tx = tx.clone();
                     let guard = semaphore.clone().access_owned();
 
@@ -644,15 +1037,27 @@ where
                     // to safely block inside the scope, because that leads to deadlock
                     // when the pool has only one thread.
                     let hash_fn: &HashFn<'static> = unsafe { std::mem::transmute(hash_fn) };
+                    let prefetch_fn: &PrefetchFn<'static> =
+                        unsafe { std::mem::transmute(prefetch_fn) };
+                    let device_io = device.io.clone();
                     thread_pool.spawn_fifo(move || {
                         let _open_files_guard = RLIMIT_OPEN_FILES.clone().access_owned();
-                        let old_hash = fg[0].file_hash.clone();
-                        if let Some(hash) = hash_fn((&mut fg[0].file_info, old_hash)) {
-                            for mut f in fg {
-                                f.file_hash = hash.clone();
-                                tx.send(f).unwrap();
+                        take_thread_reads();
+                        if batch.len() > 1 {
+                            prefetch_fn(&batch.iter().map(|fg| &fg[0].file_info).collect_vec());
+                        }
+                        for mut fg in batch {
+                            let old_hash = fg[0].file_hash.clone();
+                            let hash = hash_fn((&mut fg[0].file_info, old_hash));
+                            if let Some(hash) = hash {
+                                for mut f in fg {
+                                    f.file_hash = hash.clone();
+                                    tx.send(f).unwrap();
+                                }
                             }
                         }
+                        let (files_read, bytes_read) = take_thread_reads();
+                        device_io.add_read(files_read, bytes_read);
                         // This forces moving the guard into this task and be released when
                         // the task is done
                         drop(guard);
@@ -679,6 +1084,7 @@ where
             file_len: len,
             file_hash: hash,
             files: files.to_vec(),
+            ..Default::default()
         })
         .chain(groups_to_pass)
         .filter(group_post_filter)
@@ -686,38 +1092,30 @@ where
 }
 
 /// Walks the directory tree and collects matching files in parallel into a vector
-fn scan_files(ctx: &GroupCtx<'_>) -> Vec<Vec<FileInfo>> {
+pub(crate) fn scan_files(ctx: &GroupCtx<'_>) -> Vec<Vec<FileInfo>> {
     let file_collector = ThreadLocal::new();
     let file_count = AtomicUsize::new(0);
-    let spinner = ctx
-        .log
-        .progress_bar(&ctx.phases.format(Phase::Walk), ProgressBarLength::Unknown);
+    let spinner = ctx.phase_progress_bar(Phase::Walk, ProgressBarLength::Unknown);
     let spinner_tick = &|_: &Path| {
         file_count.fetch_add(1, Ordering::Relaxed);
         spinner.inc(1);
     };
 
-    let config = &ctx.config;
-    let min_size = config.min_size;
-    let max_size = config.max_size.unwrap_or(FileLen::MAX);
-
-    let mut walk = Walk::new();
-    walk.depth = config.depth.unwrap_or(usize::MAX);
-    walk.hidden = config.hidden;
-    walk.follow_links = config.follow_links;
-    walk.report_links = config.symbolic_links;
-    walk.no_ignore = config.no_ignore;
-    walk.one_fs = config.one_fs;
-    walk.path_selector = ctx.path_selector.clone();
-    walk.log = Some(ctx.log);
+    let mut walk = ctx.walk();
     walk.on_visit = spinner_tick;
+    // Don't waste time on looking up the device of each path if there are no limits:
+    let throttle: &(dyn Fn(&Path) + Sync + Send) =
+        if ctx.devices.iter().any(|d| !d.throttle.is_empty()) {
+            &|path| ctx.devices.get_by_path(path).throttle.metadata_op()
+        } else {
+            &|_| {}
+        };
+    walk.throttle = throttle;
     walk.run(ctx.config.input_paths(), |path| {
+        throttle(&path);
         file_info_or_log_err(path, &ctx.devices, ctx.log)
             .into_iter()
-            .filter(|info| {
-                let l = info.len;
-                l >= min_size && l <= max_size
-            })
+            .filter(|info| ctx.matches_size(info.len))
             .for_each(|info| {
                 let vec = file_collector.get_or(|| RefCell::new(Vec::new()));
                 vec.borrow_mut().push(info);
@@ -792,8 +1190,8 @@ fn stage_stats(groups: &[FileGroup<FileInfo>], filter: &FileGroupFilter) -> (usi
 
 fn group_by_size(ctx: &GroupCtx<'_>, files: Vec<Vec<FileInfo>>) -> Vec<FileGroup<FileInfo>> {
     let file_count: usize = files.iter().map(|v| v.len()).sum();
-    let progress = ctx.log.progress_bar(
-        &ctx.phases.format(Phase::GroupBySize),
+    let progress = ctx.phase_progress_bar(
+        Phase::GroupBySize,
         ProgressBarLength::Items(file_count as u64),
     );
 
@@ -811,8 +1209,9 @@ fn group_by_size(ctx: &GroupCtx<'_>, files: Vec<Vec<FileInfo>>) -> Vec<FileGroup
             file_len: l,
             file_hash: FileHash::from(0),
             files: files.into_vec(),
+            ..Default::default()
         })
-        .filter(|g| g.matches(&ctx.group_filter))
+        .filter(|g| g.matches(&ctx.group_filter) && ctx.contains_reference(g))
         .collect();
 
     let stats = stage_stats(&groups, &ctx.group_filter);
@@ -854,7 +1253,7 @@ fn remove_same_files(
     let groups: Vec<_> = groups
         .into_par_iter()
         .update(|g| deduplicate(&mut g.files, |_| {}))
-        .filter(|g| g.matches(&ctx.group_filter))
+        .filter(|g| g.matches(&ctx.group_filter) && ctx.contains_reference(g))
         .collect();
 
     let stats = stage_stats(&groups, &ctx.group_filter);
@@ -877,9 +1276,8 @@ fn atomic_counter_vec(len: usize) -> Vec<std::sync::atomic::AtomicU32> {
 #[cfg(target_os = "linux")]
 fn update_file_locations(ctx: &GroupCtx<'_>, groups: &mut (impl FileCollection + ?Sized)) {
     let count = groups.count();
-    let progress = ctx
-        .log
-        .progress_bar("Fetching extents", ProgressBarLength::Items(count as u64));
+    let progress =
+        ctx.phase_progress_bar(Phase::FetchExtents, ProgressBarLength::Items(count as u64));
 
     let err_counters = atomic_counter_vec(ctx.devices.len());
     groups.for_each_mut(|fi| {
@@ -928,12 +1326,16 @@ fn handle_fetch_physical_location_err(
             ));
         }
     } else if counter.load(Ordering::Acquire) < MAX_ERR_COUNT_TO_LOG {
-        ctx.log.warn(format!(
-            "Failed to fetch file extents mapping for file {}: {}. \
-            This is generally harmless, but it might decrease random access performance.",
-            file_info.path.display(),
-            error
-        ));
+        ctx.log.warn_file(
+            &file_info.path,
+            &error,
+            format!(
+                "Failed to fetch file extents mapping for file {}: {}. \
+                This is generally harmless, but it might decrease random access performance.",
+                file_info.path.display(),
+                error
+            ),
+        );
         let err_count = counter.fetch_add(1, Ordering::AcqRel);
         if err_count == MAX_ERR_COUNT_TO_LOG {
             ctx.log.warn(format!(
@@ -945,7 +1347,8 @@ fn handle_fetch_physical_location_err(
     }
 }
 
-/// Transforms files by piping them to an external program and groups them by their hashes
+/// Transforms files by piping them to an external program and groups them by their hashes.
+/// Files transformed differently are put in separate groups.
 fn group_transformed(ctx: &GroupCtx<'_>, files: Vec<FileInfo>) -> Vec<FileGroup<FileInfo>> {
     let mut files = files;
     files.par_sort_unstable_by_key(|f| FileId::of(f)); // need to sort so we know unique_file_count
@@ -954,18 +1357,23 @@ fn group_transformed(ctx: &GroupCtx<'_>, files: Vec<FileInfo>) -> Vec<FileGroup<
         file_len: FileLen(0),         // doesn't matter, will be computed
         file_hash: FileHash::from(0), // doesn't matter, will be computed
         files,
+        ..Default::default()
     }];
-    let progress = ctx.log.progress_bar(
-        &ctx.phases.format(Phase::TransformAndGroup),
+    let progress = ctx.phase_progress_bar(
+        Phase::TransformAndGroup,
         ProgressBarLength::Items(unique_file_count(&groups) as u64),
     );
     let groups = rehash(
         groups,
         |_| true,
-        |g| g.matches(&ctx.group_filter),
+        |_| true,
         &ctx.devices,
         FileAccess::Sequential,
         |(fi, _)| {
+            if !ctx.matches_file_type(fi, None) {
+                progress.inc(1);
+                return None;
+            }
             let chunk = FileChunk::new(&fi.path, FilePos(0), fi.len);
             let result =
                 ctx.hasher
@@ -978,6 +1386,26 @@ fn group_transformed(ctx: &GroupCtx<'_>, files: Vec<FileInfo>) -> Vec<FileGroup<
             result
         },
     );
+    let groups: Vec<_> = groups
+        .into_iter()
+        .flat_map(|g| {
+            let (file_len, file_hash) = (g.file_len, g.file_hash);
+            g.files
+                .into_iter()
+                .into_group_map_by(|f| ctx.hasher.transform_name(&f.path))
+                .into_iter()
+                .map(move |(transform, files)| FileGroup {
+                    file_len,
+                    file_hash: file_hash.clone(),
+                    file_type: None,
+                    transform,
+                    zero_filled: false,
+                    shared_extents: HashMap::new(),
+                    files,
+                })
+        })
+        .filter(|g| g.matches(&ctx.group_filter) && ctx.contains_reference(g))
+        .collect();
 
     let stats = stage_stats(&groups, &ctx.group_filter);
     ctx.log.info(format!(
@@ -1022,28 +1450,54 @@ fn group_by_prefix(
     let mut groups = groups;
     sort_files_by_id(&mut groups);
 
-    let pre_filter = |g: &FileGroup<FileInfo>| g.unique_count() > 1;
+    // Files in groups that are skipped here would never get their types checked,
+    // so all groups must be processed if filtering by type:
+    let pre_filter = |g: &FileGroup<FileInfo>| g.unique_count() > 1 || !ctx.type_filter.is_empty();
     let file_count = unique_file_count(groups.iter().filter(|g| pre_filter(g)));
-    let progress = ctx.log.progress_bar(
-        &ctx.phases.format(Phase::GroupByPrefix),
+    let progress = ctx.phase_progress_bar(
+        Phase::GroupByPrefix,
         ProgressBarLength::Items(file_count as u64),
     );
 
-    let groups = rehash(
+    let file_prefix_len = |fi: &FileInfo| {
+        if fi.len <= prefix_len {
+            prefix_len
+        } else {
+            ctx.devices[fi.get_device_index()].min_prefix_len()
+        }
+    };
+
+    let groups = rehash_prefetched(
         groups,
         pre_filter,
-        |g| g.matches(&ctx.group_filter),
+        |g| g.matches(&ctx.group_filter) && ctx.contains_reference(g),
         &ctx.devices,
         FileAccess::Random,
+        ctx.hasher.max_prefetch_len(),
+        |files| {
+            ctx.hasher.prefetch(files.iter().map(|fi| {
+                let chunk = FileChunk::new(&fi.path, FilePos(0), file_prefix_len(fi));
+                (chunk, fi.len)
+            }))
+        },
         |(fi, _)| {
             progress.inc(1);
-            let prefix_len = if fi.len <= prefix_len {
-                prefix_len
-            } else {
-                ctx.devices[fi.get_device_index()].min_prefix_len()
-            };
-            let chunk = FileChunk::new(&fi.path, FilePos(0), prefix_len);
-            ctx.hasher.hash_file_or_log_err(&chunk, |_| {})
+            let chunk = FileChunk::new(&fi.path, FilePos(0), file_prefix_len(fi));
+            if !ctx.detect_types {
+                return ctx.hasher.hash_file_or_log_err(&chunk, |_| {});
+            }
+            // The type is detected from the same data that is hashed, to avoid reading twice:
+            let mut prefix: Option<Vec<u8>> = None;
+            let hash = ctx.hasher.hash_file_and_inspect_or_log_err(
+                &chunk,
+                |_| {},
+                |buf| {
+                    let prefix = prefix.get_or_insert_with(Vec::new);
+                    let len = min(buf.len(), filetype::SNIFF_LEN - prefix.len());
+                    prefix.extend_from_slice(&buf[..len]);
+                },
+            )?;
+            ctx.matches_file_type(fi, prefix.as_deref()).then_some(hash)
         },
     );
 
@@ -1087,17 +1541,24 @@ fn group_by_suffix(
     let pre_filter =
         |g: &FileGroup<FileInfo>| g.file_len >= suffix_threshold && g.unique_count() > 1;
     let file_count = unique_file_count(groups.iter().filter(|g| pre_filter(g)));
-    let progress = ctx.log.progress_bar(
-        &ctx.phases.format(Phase::GroupBySuffix),
+    let progress = ctx.phase_progress_bar(
+        Phase::GroupBySuffix,
         ProgressBarLength::Items(file_count as u64),
     );
 
-    let groups = rehash(
+    let groups = rehash_prefetched(
         groups,
         pre_filter,
-        |g| g.matches(&ctx.group_filter),
+        |g| g.matches(&ctx.group_filter) && ctx.contains_reference(g),
         &ctx.devices,
         FileAccess::Random,
+        ctx.hasher.max_prefetch_len(),
+        |files| {
+            ctx.hasher.prefetch(files.iter().map(|fi| {
+                let chunk = FileChunk::new(&fi.path, fi.len.as_pos() - suffix_len, suffix_len);
+                (chunk, fi.len)
+            }))
+        },
         |(fi, old_hash)| {
             progress.inc(1);
             let chunk = FileChunk::new(&fi.path, fi.len.as_pos() - suffix_len, suffix_len);
@@ -1115,8 +1576,27 @@ fn group_by_suffix(
     groups
 }
 
-fn group_by_contents(
+/// Files shorter than this many times the total length of the samples are hashed in full,
+/// because sampling them wouldn't save much I/O.
+const MIN_SAMPLED_FILE_LEN_RATIO: u64 = 16;
+
+/// Returns the positions of `count` blocks of `block_len` bytes,
+/// evenly distributed between the beginning and the end of a file of length `file_len`.
+fn sample_positions(file_len: FileLen, block_len: FileLen, count: usize) -> Vec<FilePos> {
+    let range = file_len.0.saturating_sub(block_len.0) as u128;
+    let count = count as u128;
+    (1..=count)
+        .map(|i| FilePos((range * i / (count + 1)) as u64))
+        .collect()
+}
+
+/// Groups files by a hash of `sample_count` blocks evenly distributed across each file.
+/// Only files at least `min_file_len` long are sampled. The shorter files are left
+/// for [`group_by_contents`].
+fn group_by_samples(
     ctx: &GroupCtx<'_>,
+    sample_count: usize,
+    block_len: FileLen,
     min_file_len: FileLen,
     groups: Vec<FileGroup<FileInfo>>,
 ) -> Vec<FileGroup<FileInfo>> {
@@ -1124,33 +1604,129 @@ fn group_by_contents(
     sort_files_by_id(&mut groups);
 
     let pre_filter = |g: &FileGroup<FileInfo>| g.unique_count() > 1 && g.file_len >= min_file_len;
-    let bytes_to_scan = unique_file_size(groups.iter().filter(|g| pre_filter(g)));
-    let progress = &ctx.log.progress_bar(
-        &ctx.phases.format(Phase::GroupByContents),
-        ProgressBarLength::Bytes(bytes_to_scan.0),
+    let file_count = unique_file_count(groups.iter().filter(|g| pre_filter(g)));
+    let progress = ctx.phase_progress_bar(
+        Phase::GroupBySamples,
+        ProgressBarLength::Items(file_count as u64),
     );
 
     let groups = rehash(
         groups,
         pre_filter,
-        |g| g.matches_strictly(&ctx.group_filter),
+        |g| g.matches(&ctx.group_filter) && ctx.contains_reference(g),
         &ctx.devices,
-        FileAccess::Sequential,
-        |(fi, _)| {
-            let chunk = FileChunk::new(&fi.path, FilePos(0), fi.len);
+        FileAccess::Random,
+        |(fi, old_hash)| {
+            progress.inc(1);
+            let positions = sample_positions(fi.len, block_len, sample_count);
             ctx.hasher
-                .hash_file_or_log_err(&chunk, |bytes_read| progress.inc(bytes_read as u64))
+                .hash_file_samples_or_log_err(&fi.path, &positions, block_len, |_| {})
+                .map(|new_hash| old_hash ^ new_hash)
         },
     );
 
     let stats = stage_stats(&groups, &ctx.group_filter);
+    ctx.log.info(format!(
+        "Found {} ({}) candidates after grouping by samples",
+        stats.0, stats.1
+    ));
+    groups
+}
+
+/// Groups files by a hash of their full contents.
+/// Only the files at least `min_file_len` and less than `max_file_len` bytes long are hashed.
+/// The shorter files have been already hashed entirely when grouping by prefix and
+/// the longer ones have been compared by samples.
+///
+/// The input groups are processed in batches of `batch_len` groups and the resulting groups
+/// of each batch are passed to `sink` as soon as the batch is complete.
+fn group_by_contents(
+    ctx: &GroupCtx<'_>,
+    min_file_len: FileLen,
+    max_file_len: FileLen,
+    groups: Vec<FileGroup<FileInfo>>,
+    batch_len: usize,
+    sink: &mut dyn FnMut(Vec<FileGroup<FileInfo>>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut groups = groups;
+    sort_files_by_id(&mut groups);
+
+    let pre_filter = |g: &FileGroup<FileInfo>| {
+        g.unique_count() > 1 && g.file_len >= min_file_len && g.file_len < max_file_len
+    };
+    let bytes_to_scan = unique_file_size(groups.iter().filter(|g| pre_filter(g)));
+    let progress = &ctx.phase_progress_bar(
+        Phase::GroupByContents,
+        ProgressBarLength::Bytes(bytes_to_scan.0),
+    );
+
+    let mut stats = (0, FileLen(0));
+    let mut groups = groups.into_iter().peekable();
+    while groups.peek().is_some() {
+        let batch = groups.by_ref().take(batch_len).collect_vec();
+        let batch = rehash_prefetched(
+            batch,
+            pre_filter,
+            |g| g.matches_strictly(&ctx.group_filter) && ctx.contains_reference(g),
+            &ctx.devices,
+            FileAccess::Sequential,
+            ctx.hasher.max_prefetch_len(),
+            |files| {
+                ctx.hasher.prefetch(
+                    files
+                        .iter()
+                        .map(|fi| (FileChunk::new(&fi.path, FilePos(0), fi.len), fi.len)),
+                )
+            },
+            |(fi, _)| {
+                let chunk = FileChunk::new(&fi.path, FilePos(0), fi.len);
+                ctx.hasher
+                    .hash_file_or_log_err(&chunk, |bytes_read| progress.inc(bytes_read as u64))
+            },
+        );
+        let batch_stats = stage_stats(&batch, &ctx.group_filter);
+        stats = (stats.0 + batch_stats.0, stats.1 + batch_stats.1);
+        sink(batch)?;
+    }
+
     ctx.log.info(format!(
         "Found {} ({}) {} files",
         stats.0,
         stats.1,
         ctx.config.search_type()
     ));
-    groups
+    Ok(())
+}
+
+/// Marks the groups of files containing only zero bytes.
+/// Removes them if `config.exclude_zero_filled` is set.
+/// Requires the hashes of the groups to be computed from the full contents of the files.
+/// Returns the number and the total size of the zero-filled files.
+///
+/// Single files are checked only when looking for unique or under-replicated files,
+/// because otherwise they are not redundant and checking them would cost an extra read.
+fn mark_zero_filled(ctx: &GroupCtx<'_>, groups: &mut Vec<FileGroup<FileInfo>>) -> (usize, FileLen) {
+    let check_single_files = matches!(
+        ctx.group_filter.replication,
+        Replication::Underreplicated(_)
+    );
+    groups.par_iter_mut().for_each(|g| {
+        if g.files.len() < 2 && !check_single_files {
+            return;
+        }
+        if let Some(fi) = g.files.first() {
+            g.zero_filled = ctx
+                .hasher
+                .is_zero_filled(&fi.path, g.file_len, &g.file_hash)
+                .unwrap_or(false);
+        }
+    });
+    let zero_filled = groups.iter().filter(|g| g.zero_filled);
+    let (count, size) = (file_count(zero_filled.clone()), total_size(zero_filled));
+    if ctx.config.exclude_zero_filled {
+        groups.retain(|g| !g.zero_filled);
+    }
+    (count, size)
 }
 
 /// Groups identical files together by 128-bit hash of their contents.
@@ -1199,7 +1775,8 @@ fn group_by_contents(
 /// 4. In each group, remove duplicate files with the same identifier.
 /// 5. Group files by hash of the prefix.
 /// 6. Group files by hash of the suffix.
-/// 7. Group files by hash of their full contents.
+/// 7. If `config.sample_hash` is set, group large files by hash of evenly distributed blocks.
+/// 8. Group files by hash of their full contents.
 ///
 /// # Example
 /// ```
@@ -1219,41 +1796,283 @@ fn group_by_contents(
 /// write_report(&config, &log, &groups).unwrap();
 /// ```
 pub fn group_files(config: &GroupConfig, log: &dyn Log) -> Result<Vec<FileGroup<FileInfo>>, Error> {
+    group_files_with_stats(config, log).map(|(groups, _)| groups)
+}
+
+/// Like [`group_files`], but additionally returns the timing and I/O statistics of each phase.
+pub fn group_files_with_stats(
+    config: &GroupConfig,
+    log: &dyn Log,
+) -> Result<(Vec<FileGroup<FileInfo>>, RunStats), Error> {
     let spinner = log.progress_bar("Initializing", ProgressBarLength::Unknown);
     let ctx = GroupCtx::new(config, log)?;
 
     drop(spinner);
     let matching_files = scan_files(&ctx);
+    let groups = group_scanned_files(&ctx, matching_files);
+    Ok((groups, ctx.finish_stats()))
+}
 
-    let mut groups = match &ctx.hasher.transform {
-        Some(_transform) => {
-            let mut files = matching_files.into_iter().flatten().collect_vec();
-            deduplicate(&mut files, |_| {});
-            update_file_locations(&ctx, &mut files);
-            group_transformed(&ctx, files)
-        }
-        _ => {
-            let size_groups = group_by_size(&ctx, matching_files);
-            let mut size_groups_pruned = remove_same_files(&ctx, size_groups);
-            update_file_locations(&ctx, &mut size_groups_pruned);
-            let prefix_len = ctx
-                .config
-                .max_prefix_size
-                .unwrap_or_else(|| prefix_len(&ctx.devices, flat_iter(&size_groups_pruned)));
-            let prefix_groups = group_by_prefix(&ctx, prefix_len, size_groups_pruned);
-            let suffix_groups = group_by_suffix(&ctx, prefix_groups);
-            if !ctx.config.skip_content_hash {
-                group_by_contents(&ctx, prefix_len, suffix_groups)
-            } else {
-                suffix_groups
-            }
+/// Number of groups compared by contents before writing them in [`group_files_to_ndjson`].
+const NDJSON_BATCH_LEN: usize = 1024;
+
+/// Groups identical files like [`group_files_with_stats`] and writes them in the
+/// newline-delimited JSON format to `config.output` or the standard output.
+///
+/// Unlike [`write_report`], this doesn't wait until all files are grouped.
+/// The header is written first, then the groups are written in batches as soon as
+/// they are found, so the next command in a pipeline can start processing them early.
+/// Therefore the header contains neither the file statistics nor the run statistics,
+/// and the groups are not sorted.
+///
+/// # Errors
+/// Returns an error if the configuration is invalid or the report cannot be written.
+pub fn group_files_to_ndjson(config: &GroupConfig, log: &dyn Log) -> Result<RunStats, Error> {
+    let spinner = log.progress_bar("Initializing", ProgressBarLength::Unknown);
+    let ctx = GroupCtx::new(config, log)?;
+
+    drop(spinner);
+    let header = report_header(config, None, None);
+    let write_error = |e: io::Error| Error::new(format!("Failed to write report: {e}"));
+    let mut reporter: ReportWriter<Box<dyn io::Write>> = match &config.output {
+        Some(path) => {
+            let file = File::create(path).map_err(write_error)?;
+            ReportWriter::new(Box::new(BufWriter::new(file)), false)
         }
+        None => ReportWriter::new(Box::new(BufWriter::new(Term::stdout())), false),
     };
+    reporter.write_ndjson_header(&header).map_err(write_error)?;
+    reporter.flush().map_err(write_error)?;
+
+    let matching_files = scan_files(&ctx);
+    group_scanned_files_in_batches(&ctx, matching_files, NDJSON_BATCH_LEN, &mut |batch| {
+        for g in batch.iter() {
+            reporter.write_ndjson_group(g)?;
+        }
+        reporter.flush()
+    })
+    .map_err(write_error)?;
+    Ok(ctx.finish_stats())
+}
+
+/// Finds the copies of the given reference files in the directories given in the config.
+///
+/// Only the files of the same size as any of the reference files are taken into account
+/// after scanning the directories, so this is much faster than grouping all files.
+/// Each returned group contains at least one reference file.
+/// The reference files are listed first in each group.
+pub fn find_copies(
+    config: &GroupConfig,
+    references: &[Path],
+    log: &dyn Log,
+) -> Result<(Vec<FileGroup<FileInfo>>, RunStats), Error> {
+    let spinner = log.progress_bar("Initializing", ProgressBarLength::Unknown);
+    let mut ctx = GroupCtx::new(config, log)?;
+    let base_dir = Arc::new(config.base_dir.clone());
+    let references = references
+        .iter()
+        .map(|p| {
+            let path = base_dir.resolve(p).canonicalize();
+            FileInfo::new(path, &ctx.devices).map_err(|e| {
+                Error::new(format!(
+                    "Cannot read reference file {}: {}",
+                    p.to_escaped_string(),
+                    e
+                ))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    ctx.set_references(&references);
+
+    drop(spinner);
+    let mut matching_files = scan_files(&ctx);
+    matching_files.push(references);
+    let mut groups = group_scanned_files(&ctx, matching_files);
+    groups.par_iter_mut().for_each(|g| {
+        g.files
+            .sort_by_key(|f| !ctx.reference_paths.contains(&f.path))
+    });
+
+    let found: HashSet<&Path> = groups
+        .iter()
+        .flat_map(|g| g.files.iter().map(|f| &f.path))
+        .collect();
+    for path in ctx.reference_paths.iter().filter(|p| !found.contains(p)) {
+        log.info(format!("No copies of {} found", path.display()));
+    }
+    Ok((groups, ctx.finish_stats()))
+}
+
+/// Runs the grouping phases on the files found by [`scan_files`].
+/// Returns the groups sorted descending by file size.
+fn group_scanned_files(
+    ctx: &GroupCtx<'_>,
+    matching_files: Vec<Vec<FileInfo>>,
+) -> Vec<FileGroup<FileInfo>> {
+    let mut groups = Vec::new();
+    group_scanned_files_in_batches(ctx, matching_files, usize::MAX, &mut |batch| {
+        groups.extend(batch);
+        Ok(())
+    })
+    .expect("collecting groups into a vector never fails");
     groups.par_sort_by_key(|g| Reverse((g.file_len, g.file_hash.u128_prefix())));
     groups
-        .par_iter_mut()
-        .for_each(|g| g.sort_by_path(&ctx.group_filter.root_paths));
-    Ok(groups)
+}
+
+/// Runs the grouping phases on the files found by [`scan_files`] and passes the found
+/// groups to `sink`. The last phase processes the groups in batches of `batch_len` groups
+/// and passes the groups of each batch to `sink` as soon as the batch is complete.
+/// The groups are not sorted.
+fn group_scanned_files_in_batches(
+    ctx: &GroupCtx<'_>,
+    matching_files: Vec<Vec<FileInfo>>,
+    batch_len: usize,
+    sink: &mut dyn FnMut(Vec<FileGroup<FileInfo>>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut zero_filled = (0, FileLen(0));
+    let mut excluded_shared_extents = 0;
+    let mut finish = |mut groups: Vec<FileGroup<FileInfo>>, transformed: bool| {
+        if !transformed && !ctx.config.skip_content_hash {
+            let (count, size) = mark_zero_filled(ctx, &mut groups);
+            zero_filled = (zero_filled.0 + count, zero_filled.1 + size);
+        }
+        if ctx.group_filter.group_by_id {
+            let count = groups.len();
+            groups = mark_shared_extents(ctx, groups);
+            excluded_shared_extents += count - groups.len();
+        }
+        groups
+            .par_iter_mut()
+            .for_each(|g| g.sort_by_path(&ctx.group_filter.root_paths));
+        if ctx.detect_types {
+            groups.par_iter_mut().for_each(|g| {
+                // Types of the files in the groups not grouped by prefix are detected here
+                g.file_type = g
+                    .files
+                    .first()
+                    .and_then(|f| ctx.detect_file_type(f, None))
+                    .map(|t| t.mime_type().to_owned())
+            });
+        }
+        sink(groups)
+    };
+
+    // Files matched by the normalizers or transform rules must be read whole to be compared,
+    // but the remaining ones can still be pruned by size, prefix and suffix first:
+    let (transformed_files, matching_files) = if ctx.hasher.transforms_data() {
+        let (transformed, other): (Vec<_>, Vec<_>) = matching_files
+            .into_iter()
+            .flatten()
+            .partition(|f| ctx.hasher.transforms(&f.path));
+        (transformed, vec![other])
+    } else {
+        (Vec::new(), matching_files)
+    };
+
+    if !ctx.hasher.transforms_data() || matching_files.iter().any(|files| !files.is_empty()) {
+        let mut finish = |groups| finish(groups, false);
+        let size_groups = group_by_size(ctx, matching_files);
+        let mut size_groups_pruned = remove_same_files(ctx, size_groups);
+        update_file_locations(ctx, &mut size_groups_pruned);
+        let prefix_len = ctx
+            .config
+            .max_prefix_size
+            .unwrap_or_else(|| prefix_len(&ctx.devices, flat_iter(&size_groups_pruned)));
+        let prefix_groups = group_by_prefix(ctx, prefix_len, size_groups_pruned);
+        let suffix_groups = group_by_suffix(ctx, prefix_groups);
+        if ctx.config.skip_content_hash {
+            finish(suffix_groups)?;
+        } else if let Some(sample_count) = ctx.config.sample_hash {
+            let block_len = ctx
+                .config
+                .max_suffix_size
+                .unwrap_or_else(|| suffix_len(&ctx.devices, flat_iter(&suffix_groups)));
+            let min_sampled_len = block_len * (sample_count as u64 * MIN_SAMPLED_FILE_LEN_RATIO);
+            let sampled_groups =
+                group_by_samples(ctx, sample_count, block_len, min_sampled_len, suffix_groups);
+            group_by_contents(
+                ctx,
+                prefix_len,
+                min_sampled_len,
+                sampled_groups,
+                batch_len,
+                &mut finish,
+            )?;
+        } else {
+            group_by_contents(
+                ctx,
+                prefix_len,
+                FileLen::MAX,
+                suffix_groups,
+                batch_len,
+                &mut finish,
+            )?;
+        }
+    }
+
+    if !transformed_files.is_empty() {
+        let mut files = transformed_files;
+        deduplicate(&mut files, |_| {});
+        update_file_locations(ctx, &mut files);
+        finish(group_transformed(ctx, files), true)?;
+    }
+
+    let (count, size) = zero_filled;
+    if count > 0 && ctx.config.exclude_zero_filled {
+        ctx.log
+            .info(format!("Excluded {count} ({size}) zero-filled files"));
+    } else if count > 0 {
+        ctx.log
+            .info(format!("Found {count} ({size}) zero-filled files"));
+    }
+    if excluded_shared_extents > 0 {
+        ctx.log.info(format!(
+            "Excluded {excluded_shared_extents} groups of files already sharing their data extents"
+        ));
+    }
+    Ok(())
+}
+
+/// Records the files stored in the same data extents as another file of the same group
+/// in [`FileGroup::shared_extents`], and then removes the groups left with too few replicas.
+/// This way the files already reflinked together are not reported as redundant again.
+fn mark_shared_extents(
+    ctx: &GroupCtx<'_>,
+    mut groups: Vec<FileGroup<FileInfo>>,
+) -> Vec<FileGroup<FileInfo>> {
+    if let Replication::Underreplicated(_) = ctx.group_filter.replication {
+        return groups;
+    }
+    let roots = &ctx.group_filter.root_paths;
+    groups.par_iter_mut().for_each(|g| {
+        g.shared_extents = shared_extents(&g.files, roots, FileSubGroup::merge_shared_extents)
+    });
+    groups.retain(|g| g.matches(&ctx.group_filter));
+    groups
+}
+
+/// Returns the identifiers of the files stored in the same data extents as another file,
+/// mapped to the identifier of the first of such files. Hard links of the first file
+/// and files under any of the roots are not included.
+///
+/// The subgroups of files sharing the extents are found by the `merge` function.
+fn shared_extents<'a>(
+    files: &'a [FileInfo],
+    roots: &[Path],
+    merge: impl Fn(Vec<FileSubGroup<&'a FileInfo>>, &[Path]) -> Vec<FileSubGroup<&'a FileInfo>>,
+) -> HashMap<FileId, FileId> {
+    let sub_groups = FileSubGroup::group(files, roots, true);
+    merge(sub_groups, roots)
+        .into_iter()
+        .filter(|sg| !roots.iter().any(|r| r.is_prefix_of(&sg.files[0].path)))
+        .flat_map(|sg| {
+            let id = sg.files[0].id;
+            sg.files
+                .into_iter()
+                .filter(move |f| f.id != id)
+                .map(move |f| (f.id, id))
+        })
+        .collect()
 }
 
 /// Writes the list of groups to a file or the standard output.
@@ -1271,8 +2090,41 @@ pub fn write_report(
     log: &dyn Log,
     groups: &[FileGroup<FileInfo>],
 ) -> io::Result<()> {
+    write_report_with_stats(config, log, groups, None)
+}
+
+/// Like [`write_report`], but additionally stores the run statistics in the report header.
+/// Only the JSON format includes the run statistics.
+pub fn write_report_with_stats(
+    config: &GroupConfig,
+    log: &dyn Log,
+    groups: &[FileGroup<FileInfo>],
+    run_stats: Option<&RunStats>,
+) -> io::Result<()> {
+    let header = report_header(config, Some(file_stats(config, groups)), run_stats);
+    write_report_with_header(config, log, &header, groups)
+}
+
+/// Creates the report header describing the current run.
+fn report_header(
+    config: &GroupConfig,
+    stats: Option<FileStats>,
+    run_stats: Option<&RunStats>,
+) -> ReportHeader {
     let now = Local::now();
+    ReportHeader {
+        timestamp: DateTime::from_naive_utc_and_offset(now.naive_utc(), *now.offset()),
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        command: args_os().map(Arg::from).collect(),
+        base_dir: config.base_dir.clone(),
+        stats,
+        sample_count: config.sample_hash,
+        run_stats: run_stats.cloned(),
+    }
+}
 
+/// Computes the statistics of the reported groups, written in the report header.
+pub fn file_stats(config: &GroupConfig, groups: &[FileGroup<FileInfo>]) -> FileStats {
     let total_count = file_count(groups.iter());
     let total_size = total_size(groups.iter());
 
@@ -1280,27 +2132,67 @@ pub fn write_report(
         let count = g.redundant_count(&config.group_filter());
         (res.0 + count, res.1 + g.file_len * count as u64)
     });
+    let reclaimable_size = groups
+        .par_iter()
+        .map(|g| reclaimable_size(g, g.redundant_count(&config.group_filter())))
+        .sum();
     let (missing_count, missing_size) = groups.iter().fold((0, FileLen(0)), |res, g| {
         let count = g.missing_count(&config.group_filter());
         (res.0 + count, res.1 + g.file_len * count as u64)
     });
 
-    let header = ReportHeader {
-        timestamp: DateTime::from_naive_utc_and_offset(now.naive_utc(), *now.offset()),
-        version: env!("CARGO_PKG_VERSION").to_owned(),
-        command: args_os().map(Arg::from).collect(),
-        base_dir: config.base_dir.clone(),
-        stats: Some(FileStats {
-            group_count: groups.len(),
-            total_file_count: total_count,
-            total_file_size: total_size,
-            redundant_file_count: redundant_count,
-            redundant_file_size: redundant_size,
-            missing_file_count: missing_count,
-            missing_file_size: missing_size,
-        }),
-    };
+    FileStats {
+        group_count: groups.len(),
+        total_file_count: total_count,
+        total_file_size: total_size,
+        redundant_file_count: redundant_count,
+        redundant_file_size: redundant_size,
+        reclaimable_size: Some(reclaimable_size),
+        missing_file_count: missing_count,
+        missing_file_size: missing_size,
+    }
+}
+
+/// Returns the amount of disk space that removing `redundant_count` files of the group
+/// would actually release.
+///
+/// Unlike `file_len * redundant_count`, this accounts for the files that are already
+/// hard-linked, reflinked or sparse. Files that have hard links outside of the group
+/// release nothing. The smallest files are assumed to be removed, so the result
+/// is a lower bound.
+fn reclaimable_size(group: &FileGroup<FileInfo>, redundant_count: usize) -> FileLen {
+    if redundant_count == 0 {
+        return FileLen(0);
+    }
+    let mut links: IndexMap<FileId, (&Path, usize)> = IndexMap::new();
+    for f in &group.files {
+        links.entry(f.id).or_insert((&f.path, 0)).1 += 1;
+    }
+    let mut sizes = links
+        .values()
+        .map(
+            |&(path, count)| match std::fs::metadata(path.to_path_buf()) {
+                Ok(m) if link_count(&m) <= count as u64 => exclusive_len(path, &m),
+                _ => FileLen(0),
+            },
+        )
+        .collect_vec();
+    sizes.sort();
+    let retained_count = group.file_count().saturating_sub(redundant_count);
+    let dropped_count = sizes.len().saturating_sub(retained_count);
+    sizes[..dropped_count].iter().copied().sum()
+}
 
+/// Writes the report with the given header to the output file set in the config,
+/// or to the standard output, if the output file is not set.
+pub fn write_report_with_header(
+    config: &GroupConfig,
+    log: &dyn Log,
+    header: &ReportHeader,
+    groups: &[FileGroup<FileInfo>],
+) -> io::Result<()> {
+    let type_column = !config.type_filter().is_empty() || config.report_types;
+    let transform_column = !config.normalize.is_empty() || !config.transform_for.is_empty();
     match &config.output {
         Some(path) => {
             let progress = log.progress_bar(
@@ -1309,14 +2201,18 @@ pub fn write_report(
             );
             let iter = groups.iter().inspect(|_g| progress.inc(1));
             let file = BufWriter::new(File::create(path)?);
-            let mut reporter = ReportWriter::new(file, false);
-            reporter.write(config.format, &header, iter)
+            let mut reporter = ReportWriter::new(file, false)
+                .null_separated(config.null)
+                .csv_columns(type_column, transform_column);
+            reporter.write(config.format, header, iter)
         }
         None => {
             let term = Term::stdout();
             let color = term.is_term();
-            let mut reporter = ReportWriter::new(BufWriter::new(term), color);
-            reporter.write(config.format, &header, groups.iter())
+            let mut reporter = ReportWriter::new(BufWriter::new(term), color)
+                .null_separated(config.null)
+                .csv_columns(type_column, transform_column);
+            reporter.write(config.format, header, groups.iter())
         }
     }
 }
@@ -1331,10 +2227,12 @@ mod test {
     use std::sync::Mutex;
 
     use crate::log::StdLog;
+    use fallible_iterator::FallibleIterator;
     use rand::seq::SliceRandom;
     use sysinfo::DiskKind;
 
     use crate::path::Path;
+    use crate::report::open_report;
     use crate::util::test::*;
 
     use super::*;
@@ -1387,6 +2285,7 @@ mod test {
                     path: Path::from("file2"),
                 },
             ],
+            ..Default::default()
         }];
 
         let result = rehash(
@@ -1430,6 +2329,7 @@ mod test {
                     path: Path::from("file2"),
                 },
             ],
+            ..Default::default()
         }];
 
         let hash_call_count = AtomicUsize::new(0);
@@ -1468,6 +2368,7 @@ mod test {
                     location: 0,
                     path: Path::from("file1"),
                 }],
+                ..Default::default()
             },
             FileGroup {
                 file_len: FileLen(500),
@@ -1481,6 +2382,7 @@ mod test {
                     location: 35847587,
                     path: Path::from("file2"),
                 }],
+                ..Default::default()
             },
         ];
 
@@ -1512,6 +2414,7 @@ mod test {
                 location: 0,
                 path: Path::from("file1"),
             }],
+            ..Default::default()
         }];
 
         let called = AtomicBool::new(false);
@@ -1557,6 +2460,7 @@ mod test {
                     path: Path::from("file2"),
                 },
             ],
+            ..Default::default()
         }];
 
         let result = rehash(
@@ -1590,6 +2494,7 @@ mod test {
                     location: i as u64,
                     path: Path::from(format!("file{i}")),
                 }],
+                ..Default::default()
             })
         }
         input.shuffle(&mut rand::thread_rng());
@@ -1639,6 +2544,108 @@ mod test {
         });
     }
 
+    #[test]
+    fn filter_by_file_type() {
+        with_dir("main/filter_by_file_type", |root| {
+            let png1 = root.join("png1");
+            let png2 = root.join("png2");
+            let zip1 = root.join("zip1");
+            let zip2 = root.join("zip2");
+            write_test_file(&png1, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", b"", b"");
+            write_test_file(&png2, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", b"", b"");
+            write_test_file(&zip1, b"PK\x03\x04\0\0\0\0", b"", b"");
+            write_test_file(&zip2, b"PK\x03\x04\0\0\0\0", b"", b"");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                types: vec!["image".parse().unwrap()],
+                format: OutputFormat::Json,
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+            assert_eq!(results[0].file_type.as_deref(), Some("image/png"));
+
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                excluded_types: vec!["image".parse().unwrap()],
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+            assert_eq!(results[0].file_type.as_deref(), Some("application/zip"));
+        });
+    }
+
+    #[test]
+    fn report_file_types() {
+        with_dir("main/report_file_types", |root| {
+            let png1 = root.join("png1");
+            let png2 = root.join("png2");
+            write_test_file(&png1, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", b"", b"");
+            write_test_file(&png2, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", b"", b"");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].file_type, None);
+
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                report_types: true,
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].file_type.as_deref(), Some("image/png"));
+        });
+    }
+
+    #[test]
+    fn find_copies_of_reference_files() {
+        with_dir("main/find_copies", |root| {
+            let dir = root.join("dir");
+            create_dir(&dir).unwrap();
+            let reference = root.join("reference");
+            let copy = dir.join("copy");
+            let other1 = dir.join("other1");
+            let other2 = dir.join("other2");
+            write_test_file(&reference, b"foo", b"", b"");
+            write_test_file(&copy, b"foo", b"", b"");
+            write_test_file(&other1, b"bar", b"", b"");
+            write_test_file(&other2, b"bar", b"", b"");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![dir.into()],
+                ..GroupConfig::default()
+            };
+            let (results, _) = find_copies(&config, &[reference.clone().into()], &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+            assert_eq!(results[0].files[0].path, Path::from(&reference));
+            assert_eq!(results[0].files[1].path, Path::from(&copy));
+
+            // The reference file may be also located in the scanned directory:
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                ..GroupConfig::default()
+            };
+            let (results, _) = find_copies(&config, &[copy.clone().into()], &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+            assert_eq!(results[0].files[0].path, Path::from(&copy));
+            assert_eq!(results[0].files[1].path, Path::from(&reference));
+        });
+    }
+
     #[test]
     fn identical_large_files() {
         with_dir("main/identical_large_files", |root| {
@@ -1658,6 +2665,152 @@ mod test {
         });
     }
 
+    #[test]
+    fn large_files_compared_by_samples() {
+        with_dir("main/large_files_compared_by_samples", |root| {
+            // With 16-byte blocks and 2 samples, files of at least 512 bytes are sampled.
+            // In a 1 MiB file, the samples are taken at offsets 349520 and 699040.
+            let write = |name: &str, len: usize, changed_byte: Option<usize>| {
+                let path = root.join(name);
+                let mut data = vec![0; len];
+                if let Some(pos) = changed_byte {
+                    data[pos] = 1;
+                }
+                write_test_file(&path, &data, b"", b"");
+                Path::from(path)
+            };
+            let large1 = write("large1", 1 << 20, None);
+            let large2 = write("large2", 1 << 20, Some(500000));
+            let large3 = write("large3", 1 << 20, Some(349525));
+            let small1 = write("small1", 300, None);
+            let small2 = write("small2", 300, Some(150));
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![large1.clone(), large2.clone(), large3, small1, small2],
+                max_prefix_size: Some(FileLen(16)),
+                max_suffix_size: Some(FileLen(16)),
+                sample_hash: Some(2),
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(
+                results[0].paths(),
+                vec![large1.canonicalize(), large2.canonicalize()]
+            );
+        });
+    }
+
+    #[test]
+    fn groups_written_as_ndjson_while_grouping() {
+        with_dir("main/groups_written_as_ndjson_while_grouping", |root| {
+            let mut paths = Vec::new();
+            for (i, contents) in [b"aaa", b"bbb", b"ccc", b"ddd"].iter().enumerate() {
+                for copy in 0..2 {
+                    let file = root.join(format!("file{i}_{copy}"));
+                    write_test_file(&file, *contents, b"", b"");
+                    paths.push(Path::from(&file));
+                }
+            }
+            let report = root.join("report.ndjson");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths,
+                output: Some(report.clone()),
+                format: OutputFormat::Ndjson,
+                ..GroupConfig::default()
+            };
+            group_files_to_ndjson(&config, &log).unwrap();
+
+            let mut reader = open_report(File::open(&report).unwrap()).unwrap();
+            assert_eq!(reader.read_header().unwrap().stats, None);
+            let mut written: Vec<FileGroup<Path>> =
+                reader.read_groups().unwrap().collect().unwrap();
+            let mut expected: Vec<FileGroup<Path>> = group_files(&config, &log)
+                .unwrap()
+                .into_iter()
+                .map(|g| g.map(|f| f.path))
+                .collect();
+            written.sort_by_key(|g| g.file_hash.u128_prefix());
+            expected.sort_by_key(|g| g.file_hash.u128_prefix());
+            assert_eq!(written, expected);
+        });
+    }
+
+    #[test]
+    fn zero_filled_files() {
+        with_dir("main/zero_filled_files", |root| {
+            let zeros1 = root.join("zeros1");
+            let zeros2 = root.join("zeros2");
+            let sparse1 = root.join("sparse1");
+            let sparse2 = root.join("sparse2");
+            let data1 = root.join("data1");
+            let data2 = root.join("data2");
+            write_test_file(&zeros1, &[0; 100000], b"", b"");
+            write_test_file(&zeros2, &[0; 100000], b"", b"");
+            File::create(&sparse1).unwrap().set_len(1 << 20).unwrap();
+            File::create(&sparse2).unwrap().set_len(1 << 20).unwrap();
+            write_test_file(&data1, &[0; 100000], b"data", b"");
+            write_test_file(&data2, &[0; 100000], b"data", b"");
+
+            let log = test_log();
+            let paths: Vec<Path> = [&zeros1, &zeros2, &sparse1, &sparse2, &data1, &data2]
+                .into_iter()
+                .map(Path::from)
+                .collect();
+            let mut config = GroupConfig {
+                paths: paths.clone(),
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 3);
+            assert!(results[0].zero_filled);
+            assert_eq!(results[0].file_len, FileLen(1 << 20));
+            assert!(!results[1].zero_filled);
+            assert!(results[2].zero_filled);
+            assert_eq!(results[2].file_len, FileLen(100000));
+
+            config.exclude_zero_filled = true;
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(
+                results[0].paths(),
+                vec![
+                    Path::from(&data1).canonicalize(),
+                    Path::from(&data2).canonicalize()
+                ]
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn single_zero_filled_files_checked_only_if_unique() {
+        with_dir("main/single_zero_filled_files", |root| {
+            // Single files are not hashed, so only the files without any data can be detected
+            let zeros = root.join("zeros");
+            File::create(&zeros).unwrap().set_len(1 << 20).unwrap();
+
+            let log = test_log();
+            let mut config = GroupConfig {
+                paths: vec![Path::from(&zeros)],
+                rf_over: Some(0),
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert!(!results[0].zero_filled);
+
+            config.rf_over = None;
+            config.unique = true;
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert!(results[0].zero_filled);
+        });
+    }
+
     #[test]
     fn files_differing_by_size() {
         with_dir("main/files_differing_by_size", |root| {
@@ -1774,6 +2927,35 @@ mod test {
         });
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn reclaimable_size_excludes_hard_links() {
+        use std::os::unix::fs::MetadataExt;
+        with_dir("main/reclaimable_size", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            let file3 = root.join("file3");
+            let data = vec![b'a'; 65536];
+            write_test_file(&file1, &data, b"", b"");
+            hard_link(&file1, &file2).unwrap();
+            write_test_file(&file3, &data, b"", b"");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![file1.into(), file2.into(), file3.clone().into()],
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+
+            // Removing two files releases the space of only one copy:
+            let stats = file_stats(&config, &results);
+            assert_eq!(stats.redundant_file_size, FileLen(2 * 65536));
+            let allocated = std::fs::metadata(&file3).unwrap().blocks() * 512;
+            assert_eq!(stats.reclaimable_size, Some(FileLen(allocated)));
+        });
+    }
+
     #[test]
     #[cfg(unix)]
     fn report_symbolic_links_to_files() {
@@ -1918,6 +3100,171 @@ mod test {
         })
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn transformed_by_pattern() {
+        with_dir("group/transform/by_pattern", |root| {
+            // after taking the first 2 bytes, the txt files are identical to each other
+            // and to the dat files, but the dat files are compared by raw contents
+            write_file(&root.join("a1.txt"), "aa|1");
+            write_file(&root.join("a2.txt"), "aa|23");
+            write_file(&root.join("b1.dat"), "aa");
+            write_file(&root.join("b2.dat"), "aa");
+            write_file(&root.join("c1.dat"), "aa|1");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                transform_for: vec![("*.txt".to_owned(), "dd count=2 bs=1".to_owned())],
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 2);
+            let transformed = results.iter().find(|g| g.transform.is_some()).unwrap();
+            let raw = results.iter().find(|g| g.transform.is_none()).unwrap();
+            assert_eq!(transformed.transform.as_deref(), Some("dd count=2 bs=1"));
+            assert_eq!(transformed.file_hash, raw.file_hash);
+            assert_eq!(
+                transformed.files.iter().map(|f| &f.path).collect_vec(),
+                vec![
+                    &Path::from(root.join("a1.txt")),
+                    &Path::from(root.join("a2.txt"))
+                ]
+            );
+            assert_eq!(
+                raw.files.iter().map(|f| &f.path).collect_vec(),
+                vec![
+                    &Path::from(root.join("b1.dat")),
+                    &Path::from(root.join("b2.dat"))
+                ]
+            );
+        })
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn files_not_transformed_are_grouped_by_size_first() {
+        with_dir("group/transform/size_first", |root| {
+            write_file(&root.join("a1.txt"), "aa|1");
+            write_file(&root.join("a2.txt"), "aa|23");
+            write_file(&root.join("b1.dat"), "bb");
+            write_file(&root.join("b2.dat"), "bb");
+            write_file(&root.join("c1.dat"), &"c".repeat(100000));
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                transform_for: vec![("*.txt".to_owned(), "dd count=2 bs=1".to_owned())],
+                ..GroupConfig::default()
+            };
+            let (results, stats) = group_files_with_stats(&config, &log).unwrap();
+            assert_eq!(results.len(), 2);
+            let raw = results.iter().find(|g| g.transform.is_none()).unwrap();
+            assert_eq!(
+                raw.files.iter().map(|f| &f.path).collect_vec(),
+                vec![
+                    &Path::from(root.join("b1.dat")),
+                    &Path::from(root.join("b2.dat"))
+                ]
+            );
+            // the file of unique size must not be read at all
+            let bytes_read: u64 = stats.phases.iter().map(|p| p.io.bytes_read.0).sum();
+            assert!(bytes_read < 100000, "read {bytes_read} bytes");
+        })
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn files_killed_by_transform_timeout_are_reported() {
+        with_dir("group/transform/timeout", |root| {
+            write_file(&root.join("a1.txt"), "aa");
+            write_file(&root.join("a2.txt"), "aa");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                transform: Some("sleep 10".to_owned()),
+                transform_timeout: Some(std::time::Duration::from_millis(100)),
+                ..GroupConfig::default()
+            };
+            let (results, stats) = group_files_with_stats(&config, &log).unwrap();
+            assert!(results.is_empty());
+            assert_eq!(
+                stats.skipped_files.iter().map(|f| &f.path).collect_vec(),
+                vec![
+                    &Path::from(root.join("a1.txt")),
+                    &Path::from(root.join("a2.txt"))
+                ]
+            );
+            assert!(stats.skipped_files[0].error.contains("timed out"));
+        })
+    }
+
+    #[test]
+    fn normalized_text() {
+        with_dir("group/normalize/text", |root| {
+            let file1 = root.join("file1.txt");
+            let file2 = root.join("file2.txt");
+            let file3 = root.join("file3.dat");
+            let file4 = root.join("file4.dat");
+            write_file(&file1, "foo\nbar\n");
+            write_file(&file2, "foo \r\nbar\r\n");
+            write_file(&file3, "foo\nbar\n");
+            write_file(&file4, "foo \r\nbar\r\n");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                normalize: vec!["text".parse().unwrap()],
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(
+                results[0].files.iter().map(|f| &f.path).collect_vec(),
+                vec![&Path::from(file1), &Path::from(file2)]
+            );
+        })
+    }
+
+    #[test]
+    fn files_not_normalized_are_grouped_by_size_first() {
+        with_dir("group/normalize/size_first", |root| {
+            let file1 = root.join("file1.txt");
+            let file2 = root.join("file2.txt");
+            let file3 = root.join("file3.dat");
+            let file4 = root.join("file4.dat");
+            let file5 = root.join("file5.dat");
+            write_file(&file1, "foo\nbar\n");
+            write_file(&file2, "foo \r\nbar\r\n");
+            write_file(&file3, "foo\nbar\n");
+            write_file(&file4, "foo\nbar\n");
+            write_file(&file5, &"x".repeat(100000));
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                normalize: vec!["text".parse().unwrap()],
+                ..GroupConfig::default()
+            };
+            let (results, stats) = group_files_with_stats(&config, &log).unwrap();
+            assert_eq!(results.len(), 2);
+            let normalized = results.iter().find(|g| g.transform.is_some()).unwrap();
+            let raw = results.iter().find(|g| g.transform.is_none()).unwrap();
+            assert_eq!(
+                normalized.files.iter().map(|f| &f.path).collect_vec(),
+                vec![&Path::from(file1), &Path::from(file2)]
+            );
+            assert_eq!(
+                raw.files.iter().map(|f| &f.path).collect_vec(),
+                vec![&Path::from(file3), &Path::from(file4)]
+            );
+            // the file of unique size must not be read at all
+            let bytes_read: u64 = stats.phases.iter().map(|p| p.io.bytes_read.0).sum();
+            assert!(bytes_read < 100000, "read {bytes_read} bytes");
+        })
+    }
+
     #[test]
     fn unique_files() {
         with_dir("main/unique_files", |root| {
@@ -2012,12 +3359,104 @@ mod test {
         )
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn merge_sub_groups_of_reflinked_files() {
+        with_dir("main/merge_shared_extents", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            let file3 = root.join("file3");
+            let data = vec![b'a'; 65536];
+            write_test_file(&file1, &data, b"", b"");
+            write_test_file(&file2, &data, b"", b"");
+            let reflinked = reflink::reflink(&file1, &file3).is_ok();
+            if !reflinked {
+                write_test_file(&file3, &data, b"", b"");
+            }
+
+            let files = [&file1, &file2, &file3].map(|f| FileSubGroup::single(Path::from(f)));
+            let sub_groups = FileSubGroup::merge_shared_extents(files.into(), &[]);
+            if reflinked {
+                assert_eq!(sub_groups.len(), 2);
+                assert_eq!(
+                    sub_groups[0].files,
+                    vec![Path::from(&file1), Path::from(&file3)]
+                );
+            } else {
+                // Copies stored in distinct extents must not be merged
+                assert_eq!(sub_groups.len(), 3);
+            }
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn count_files_sharing_extents_as_single_replica() {
+        let file = |name: &str, inode| FileInfo {
+            id: FileId { device: 1, inode },
+            len: FileLen(4096),
+            location: 0,
+            path: Path::from(name),
+        };
+        // file2 is a hard link to file1, file3 is a reflink of file1, file4 is a copy
+        fn merge<F: AsRef<Path>>(
+            sub_groups: Vec<FileSubGroup<F>>,
+            roots: &[Path],
+        ) -> Vec<FileSubGroup<F>> {
+            FileSubGroup::merge_by_extents_id(sub_groups, roots, |path| {
+                match path.to_string_lossy().as_ref() {
+                    "file4" => Some(2),
+                    _ => Some(1),
+                }
+            })
+        }
+        let filter = FileGroupFilter {
+            replication: Replication::Overreplicated(1),
+            root_paths: vec![],
+            group_by_id: true,
+        };
+        let group = |files: Vec<FileInfo>| {
+            let shared_extents = shared_extents(&files, &[], merge);
+            FileGroup {
+                file_len: FileLen(4096),
+                file_hash: FileHash::from(1u128),
+                shared_extents,
+                files,
+                ..Default::default()
+            }
+        };
+
+        let g = group(vec![file("file1", 1), file("file2", 1), file("file3", 2)]);
+        assert_eq!(
+            g.shared_extents,
+            HashMap::from([(g.files[2].id, g.files[0].id)])
+        );
+        assert!(!g.matches(&filter));
+
+        let g = group(vec![
+            file("file1", 1),
+            file("file2", 1),
+            file("file3", 2),
+            file("file4", 3),
+        ]);
+        assert!(g.matches(&filter));
+        assert_eq!(g.file_count(), 4);
+        // The reflink is not counted, the hard link still is
+        assert_eq!(g.redundant_count(&filter), 2);
+
+        // Files under an isolated root are distinct replicas, even if reflinked
+        let files = vec![file("file1", 1), file("file3", 2)];
+        let roots = [Path::from("file1"), Path::from("file3")];
+        assert!(shared_extents(&files, &roots, merge).is_empty());
+    }
+
     #[test]
     fn partition() {
         let fg = FileGroup {
             file_len: FileLen::from(1u64),
             file_hash: FileHash::from(1u128),
             files: vec!["a1", "b1", "a2", "b2", "b3"],
+            ..Default::default()
         };
         let mut partitions = fg.partition_by_key(|f| f.chars().next().unwrap());
         assert_eq!(partitions.len(), 2);
@@ -2032,6 +3471,7 @@ mod test {
             file_len: FileLen::from(1u64),
             file_hash: FileHash::from(1u128),
             files: vec!["a", "b"],
+            ..Default::default()
         };
         let fg = fg.map(|f| format!("{f}.txt"));
         assert_eq!(fg.files, vec![String::from("a.txt"), String::from("b.txt")]);
@@ -2043,6 +3483,7 @@ mod test {
             file_len: FileLen::from(1u64),
             file_hash: FileHash::from(1u128),
             files: vec!["a", "b"],
+            ..Default::default()
         };
         let fg = fg.try_map_all(|f| Result::<_, ()>::Ok(format!("{f}.txt")));
         assert!(fg.is_ok());
@@ -2058,6 +3499,7 @@ mod test {
             file_len: FileLen::from(1u64),
             file_hash: FileHash::from(1u128),
             files: vec!["a", "b"],
+            ..Default::default()
         };
         let fg = fg.try_map_all(|f| Result::<(), _>::Err(format!("error {f}")));
         assert!(fg.is_err());
@@ -2073,6 +3515,7 @@ mod test {
             file_len: FileLen::from(1u64),
             file_hash: FileHash::from(1u128),
             files: vec!["a1", "b1", "a2", "b2", "b3"],
+            ..Default::default()
         };
         let fg = fg.flat_map(|f| if f.starts_with('a') { Some(f) } else { None });
         assert_eq!(fg.files, vec!["a1", "a2"]);
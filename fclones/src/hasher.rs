@@ -1,10 +1,12 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::cmp::{max, min};
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::hash::Hasher;
 use std::io;
 use std::io::{Read, Seek};
 use std::str::FromStr;
+use std::sync::Mutex;
 
 use metrohash::MetroHash128;
 use serde::{Deserialize, Serialize};
@@ -18,11 +20,19 @@ use xxhash_rust::xxh3::Xxh3;
 use crate::cache::{HashCache, Key};
 use crate::file::{FileAccess, FileChunk, FileHash, FileLen, FileMetadata, FilePos};
 use crate::log::{Log, LogExt};
+use crate::normalize::Normalizers;
 use crate::path::Path;
+use crate::pattern::Pattern;
+use crate::stats::{record_thread_read, IoCounters, SkippedFile};
+use crate::throttle::throttle_thread_read;
 use crate::transform::Transform;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring;
 use crate::Error;
 
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[derive(
+    Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize, clap::ValueEnum,
+)]
 pub enum HashFn {
     #[default]
     Metro,
@@ -58,6 +68,25 @@ impl HashFn {
             "sha3-512",
         ]
     }
+
+    /// Returns the length of the digests produced by this function, in bytes
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashFn::Metro => 16,
+            #[cfg(feature = "xxhash")]
+            HashFn::Xxhash => 16,
+            #[cfg(feature = "blake3")]
+            HashFn::Blake3 => 32,
+            #[cfg(feature = "sha2")]
+            HashFn::Sha256 => 32,
+            #[cfg(feature = "sha2")]
+            HashFn::Sha512 => 64,
+            #[cfg(feature = "sha3")]
+            HashFn::Sha3_256 => 32,
+            #[cfg(feature = "sha3")]
+            HashFn::Sha3_512 => 64,
+        }
+    }
 }
 
 impl FromStr for HashFn {
@@ -209,7 +238,13 @@ pub struct FileHasher<'a> {
     pub(crate) buf_len: usize,
     pub(crate) cache: Option<HashCache>,
     pub(crate) transform: Option<Transform>,
+    pub(crate) normalizers: Option<Normalizers>,
+    pub(crate) transform_rules: Vec<(Pattern, Transform)>,
     pub(crate) log: &'a dyn Log,
+    pub(crate) io: IoCounters,
+    pub(crate) skipped_files: Mutex<Vec<SkippedFile>>,
+    /// Hashes of zero-filled files computed so far, by file length
+    zeros_hashes: Mutex<HashMap<FileLen, FileHash>>,
 }
 
 impl FileHasher<'_> {
@@ -220,7 +255,12 @@ impl FileHasher<'_> {
             buf_len: 65536,
             cache: None,
             transform,
+            normalizers: None,
+            transform_rules: Vec::new(),
             log,
+            io: IoCounters::default(),
+            skipped_files: Mutex::default(),
+            zeros_hashes: Mutex::default(),
         }
     }
 
@@ -237,16 +277,213 @@ impl FileHasher<'_> {
             buf_len: 65536,
             cache: Some(cache),
             transform,
+            normalizers: None,
+            transform_rules: Vec::new(),
+            log,
+            io: IoCounters::default(),
+            skipped_files: Mutex::default(),
+            zeros_hashes: Mutex::default(),
+        })
+    }
+
+    /// Creates a hasher that normalizes file contents with the built-in normalizers
+    /// before hashing. If `cached` is set, the hashes are cached separately
+    /// for each set of normalizers.
+    pub fn new_normalized(
+        algorithm: HashFn,
+        normalizers: Normalizers,
+        cached: bool,
+        log: &dyn Log,
+    ) -> Result<FileHasher<'_>, Error> {
+        let cache = if cached {
+            Some(HashCache::open_default(Some(normalizers.id()), algorithm)?)
+        } else {
+            None
+        };
+        Ok(FileHasher {
+            algorithm,
+            buf_len: 65536,
+            cache,
+            transform: None,
+            normalizers: Some(normalizers),
+            transform_rules: Vec::new(),
+            log,
+            io: IoCounters::default(),
+            skipped_files: Mutex::default(),
+            zeros_hashes: Mutex::default(),
+        })
+    }
+
+    /// Creates a hasher that transforms the files matching the patterns by the
+    /// associated programs before hashing. Other files are hashed as they are.
+    /// If `cached` is set, the hashes are cached separately for each set of rules.
+    pub fn new_with_transform_rules(
+        algorithm: HashFn,
+        transform_rules: Vec<(Pattern, Transform)>,
+        cached: bool,
+        log: &dyn Log,
+    ) -> Result<FileHasher<'_>, Error> {
+        let cache = if cached {
+            let rules = transform_rules
+                .iter()
+                .map(|(p, t)| format!("{}={}", p, t.command_str))
+                .collect::<Vec<_>>();
+            let id = format!("<transform-for:{}>", rules.join(";"));
+            Some(HashCache::open_default(Some(&id), algorithm)?)
+        } else {
+            None
+        };
+        Ok(FileHasher {
+            algorithm,
+            buf_len: 65536,
+            cache,
+            transform: None,
+            normalizers: None,
+            transform_rules,
             log,
+            io: IoCounters::default(),
+            skipped_files: Mutex::default(),
+            zeros_hashes: Mutex::default(),
         })
     }
 
+    /// Returns true if the hasher transforms the file contents before hashing,
+    /// either with an external program or with the built-in normalizers.
+    pub fn transforms_data(&self) -> bool {
+        self.transform.is_some() || self.normalizers.is_some() || !self.transform_rules.is_empty()
+    }
+
+    /// Returns the transform program that applies to the file, if any.
+    fn transform_for(&self, path: &Path) -> Option<&Transform> {
+        self.transform.as_ref().or_else(|| {
+            let name = path.file_name()?;
+            let name = name.to_string_lossy();
+            self.transform_rules
+                .iter()
+                .find(|(pattern, _)| pattern.matches(&name))
+                .map(|(_, transform)| transform)
+        })
+    }
+
+    /// Returns a description of how the contents of the file are transformed
+    /// by `hash_transformed` before hashing, or `None` if they are hashed as they are.
+    /// Files with different descriptions must not be considered identical.
+    pub fn transform_name(&self, path: &Path) -> Option<String> {
+        match &self.normalizers {
+            Some(normalizers) => normalizers
+                .find(path)
+                .map(|n| format!("normalize:{}", n.name())),
+            None => self.transform_for(path).map(|t| t.command_str.clone()),
+        }
+    }
+
+    /// Returns true if the contents of the file are transformed or normalized before hashing.
+    /// The other files can be compared by their raw contents.
+    pub fn transforms(&self, path: &Path) -> bool {
+        match &self.normalizers {
+            Some(normalizers) => normalizers.find(path).is_some(),
+            None => self.transform_for(path).is_some(),
+        }
+    }
+
+    /// Returns the maximum number of files worth passing to `prefetch` at once.
+    /// Returns 1 if reading files ahead is not supported.
+    pub fn max_prefetch_len(&self) -> usize {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if self.cache.is_none() && uring::is_supported() {
+            return uring::MAX_BATCH_LEN;
+        }
+        1
+    }
+
+    /// Reads the given fragments of small files in batches, so that `hash_file`
+    /// called later on the same thread doesn't need to open and read the files one by one.
+    /// Each fragment is given together with the length of its file.
+    ///
+    /// Fragments longer than the read buffer are not read ahead. Does nothing if io_uring
+    /// is not available, or if the hashes are cached, because then most files
+    /// don't need to be read at all.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn prefetch<'a>(&self, chunks: impl IntoIterator<Item = (FileChunk<'a>, FileLen)>) {
+        if self.cache.is_some() || !uring::is_supported() {
+            return;
+        }
+        // The number of bytes we get if we read the chunk the usual way,
+        // assuming the file hasn't changed since it was scanned:
+        let chunks: Vec<_> = chunks
+            .into_iter()
+            .filter(|(chunk, _)| chunk.len.0 <= self.buf_len as u64)
+            .map(|(chunk, file_len)| {
+                let remaining = file_len.0.saturating_sub(chunk.pos.0);
+                let expected_len = min(chunk.len.0, remaining);
+                (chunk, expected_len)
+            })
+            .collect();
+        let paths: Vec<_> = chunks.iter().map(|(c, _)| c.path.to_path_buf()).collect();
+        // Reading one byte more than expected detects files that have grown
+        let requests: Vec<_> = chunks
+            .iter()
+            .zip(&paths)
+            .map(|((chunk, expected_len), path)| uring::ReadRequest {
+                path,
+                offset: chunk.pos.0,
+                len: min(chunk.len.0, expected_len + 1) as usize,
+            })
+            .collect();
+
+        PREFETCHED.with(|prefetched| {
+            let mut prefetched = prefetched.borrow_mut();
+            prefetched.clear();
+            for (chunks, requests) in chunks
+                .chunks(uring::MAX_BATCH_LEN)
+                .zip(requests.chunks(uring::MAX_BATCH_LEN))
+            {
+                // The data are hashed from memory later, so they must be accounted for now
+                for r in requests {
+                    throttle_thread_read(r.len as u64);
+                }
+                let Some(results) = uring::read_chunks(requests) else {
+                    return;
+                };
+                // Files that failed to read or changed are read again the usual way,
+                // so the errors are reported consistently
+                for ((chunk, expected_len), result) in chunks.iter().zip(results) {
+                    if let Ok(data) = result {
+                        if data.len() as u64 == *expected_len {
+                            prefetched.push(PrefetchedChunk {
+                                path: chunk.path.clone(),
+                                pos: chunk.pos,
+                                len: chunk.len,
+                                data,
+                            });
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    pub fn prefetch<'a>(&self, _chunks: impl IntoIterator<Item = (FileChunk<'a>, FileLen)>) {}
+
     /// Computes the file hash or logs an error and returns none if failed.
     /// If file is not found, no error is logged and `None` is returned.
     pub fn hash_file(
         &self,
         chunk: &FileChunk<'_>,
         progress: impl Fn(usize),
+    ) -> io::Result<FileHash> {
+        self.hash_file_and_inspect(chunk, progress, |_| {})
+    }
+
+    /// Like [`FileHasher::hash_file`], but additionally passes the data read from the file
+    /// to `inspect`, so it can be examined without reading the file again.
+    /// If the hash is found in the cache, nothing is read and `inspect` is not called.
+    pub fn hash_file_and_inspect(
+        &self,
+        chunk: &FileChunk<'_>,
+        progress: impl Fn(usize),
+        mut inspect: impl FnMut(&[u8]),
     ) -> io::Result<FileHash> {
         let cache = self.cache.as_ref();
         let metadata = cache.and_then(|_| FileMetadata::new(chunk.path).ok());
@@ -260,21 +497,30 @@ impl FileHasher<'_> {
             progress(chunk.len.0 as usize);
             return Ok(hash);
         }
+        let bytes_read = Cell::new(0);
+        let consumer = |buf: &[u8]| {
+            bytes_read.set(bytes_read.get() + buf.len() as u64);
+            progress(buf.len());
+            inspect(buf)
+        };
+        let buf_len = self.buf_len;
         let hash = match self.algorithm {
-            HashFn::Metro => file_hash::<MetroHash128>(chunk, self.buf_len, progress),
+            HashFn::Metro => inspect_file_hash::<MetroHash128>(chunk, buf_len, consumer),
             #[cfg(feature = "xxhash")]
-            HashFn::Xxhash => file_hash::<Xxh3>(chunk, self.buf_len, progress),
+            HashFn::Xxhash => inspect_file_hash::<Xxh3>(chunk, buf_len, consumer),
             #[cfg(feature = "blake3")]
-            HashFn::Blake3 => file_hash::<blake3::Hasher>(chunk, self.buf_len, progress),
+            HashFn::Blake3 => inspect_file_hash::<blake3::Hasher>(chunk, buf_len, consumer),
             #[cfg(feature = "sha2")]
-            HashFn::Sha256 => file_hash::<Sha256>(chunk, self.buf_len, progress),
+            HashFn::Sha256 => inspect_file_hash::<Sha256>(chunk, buf_len, consumer),
             #[cfg(feature = "sha2")]
-            HashFn::Sha512 => file_hash::<Sha512>(chunk, self.buf_len, progress),
+            HashFn::Sha512 => inspect_file_hash::<Sha512>(chunk, buf_len, consumer),
             #[cfg(feature = "sha3")]
-            HashFn::Sha3_256 => file_hash::<Sha3_256>(chunk, self.buf_len, progress),
+            HashFn::Sha3_256 => inspect_file_hash::<Sha3_256>(chunk, buf_len, consumer),
             #[cfg(feature = "sha3")]
-            HashFn::Sha3_512 => file_hash::<Sha3_512>(chunk, self.buf_len, progress),
-        }?;
+            HashFn::Sha3_512 => inspect_file_hash::<Sha3_512>(chunk, buf_len, consumer),
+        };
+        self.record_read(bytes_read.get());
+        let hash = hash?;
         self.store_hash(key, metadata, chunk.len, hash.clone());
         Ok(hash)
     }
@@ -284,30 +530,172 @@ impl FileHasher<'_> {
         chunk: &FileChunk<'_>,
         progress: impl Fn(usize),
     ) -> Option<FileHash> {
-        match self.hash_file(chunk, progress) {
+        self.ok_or_log_err(chunk, self.hash_file(chunk, progress))
+    }
+
+    /// Like [`FileHasher::hash_file_or_log_err`], but additionally passes the data read
+    /// from the file to `inspect`. See [`FileHasher::hash_file_and_inspect`].
+    pub fn hash_file_and_inspect_or_log_err(
+        &self,
+        chunk: &FileChunk<'_>,
+        progress: impl Fn(usize),
+        inspect: impl FnMut(&[u8]),
+    ) -> Option<FileHash> {
+        self.ok_or_log_err(chunk, self.hash_file_and_inspect(chunk, progress, inspect))
+    }
+
+    /// Logs the error of hashing the chunk, unless the file doesn't exist.
+    fn ok_or_log_err(&self, chunk: &FileChunk<'_>, hash: io::Result<FileHash>) -> Option<FileHash> {
+        match hash {
             Ok(hash) => Some(hash),
             Err(e) if e.kind() == io::ErrorKind::NotFound => None,
             Err(e) => {
-                self.log.warn(format!(
-                    "Failed to compute hash of file {}: {}",
-                    chunk.path.to_escaped_string(),
-                    e
-                ));
+                self.log.warn_file(
+                    chunk.path,
+                    &e,
+                    format!(
+                        "Failed to compute hash of file {}: {}",
+                        chunk.path.to_escaped_string(),
+                        e
+                    ),
+                );
                 None
             }
         }
     }
 
-    /// Just like `hash_file`, but transforms the file before hashing.
+    /// Computes a hash of `block_len` bytes read at each of the given positions in the file.
+    /// Used to compare large files without reading them entirely.
+    /// Hashes of samples are not cached.
+    pub fn hash_file_samples(
+        &self,
+        path: &Path,
+        positions: &[FilePos],
+        block_len: FileLen,
+        progress: impl Fn(usize),
+    ) -> io::Result<FileHash> {
+        let bytes_read = Cell::new(0);
+        let progress = |delta: usize| {
+            bytes_read.set(bytes_read.get() + delta as u64);
+            progress(delta)
+        };
+        let buf_len = self.buf_len;
+        let hash = match self.algorithm {
+            HashFn::Metro => {
+                samples_hash::<MetroHash128>(path, positions, block_len, buf_len, progress)
+            }
+            #[cfg(feature = "xxhash")]
+            HashFn::Xxhash => samples_hash::<Xxh3>(path, positions, block_len, buf_len, progress),
+            #[cfg(feature = "blake3")]
+            HashFn::Blake3 => {
+                samples_hash::<blake3::Hasher>(path, positions, block_len, buf_len, progress)
+            }
+            #[cfg(feature = "sha2")]
+            HashFn::Sha256 => samples_hash::<Sha256>(path, positions, block_len, buf_len, progress),
+            #[cfg(feature = "sha2")]
+            HashFn::Sha512 => samples_hash::<Sha512>(path, positions, block_len, buf_len, progress),
+            #[cfg(feature = "sha3")]
+            HashFn::Sha3_256 => {
+                samples_hash::<Sha3_256>(path, positions, block_len, buf_len, progress)
+            }
+            #[cfg(feature = "sha3")]
+            HashFn::Sha3_512 => {
+                samples_hash::<Sha3_512>(path, positions, block_len, buf_len, progress)
+            }
+        };
+        self.record_read(bytes_read.get());
+        hash
+    }
+
+    /// Like [`FileHasher::hash_file_samples`], but logs errors and returns `None` on failure.
+    /// If file is not found, no error is logged.
+    pub fn hash_file_samples_or_log_err(
+        &self,
+        path: &Path,
+        positions: &[FilePos],
+        block_len: FileLen,
+        progress: impl Fn(usize),
+    ) -> Option<FileHash> {
+        match self.hash_file_samples(path, positions, block_len, progress) {
+            Ok(hash) => Some(hash),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                self.log.warn_file(
+                    path,
+                    &e,
+                    format!(
+                        "Failed to compute hash of file {}: {}",
+                        path.to_escaped_string(),
+                        e
+                    ),
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns the hash of `len` zero bytes, as it would be computed by [`FileHasher::hash_file`].
+    /// The hashes are remembered, so each length is hashed only once.
+    pub fn zeros_hash(&self, len: FileLen) -> FileHash {
+        if let Some(hash) = self.zeros_hashes.lock().unwrap().get(&len) {
+            return hash.clone();
+        }
+        let hash = match self.algorithm {
+            HashFn::Metro => zeros_hash::<MetroHash128>(len, self.buf_len),
+            #[cfg(feature = "xxhash")]
+            HashFn::Xxhash => zeros_hash::<Xxh3>(len, self.buf_len),
+            #[cfg(feature = "blake3")]
+            HashFn::Blake3 => zeros_hash::<blake3::Hasher>(len, self.buf_len),
+            #[cfg(feature = "sha2")]
+            HashFn::Sha256 => zeros_hash::<Sha256>(len, self.buf_len),
+            #[cfg(feature = "sha2")]
+            HashFn::Sha512 => zeros_hash::<Sha512>(len, self.buf_len),
+            #[cfg(feature = "sha3")]
+            HashFn::Sha3_256 => zeros_hash::<Sha3_256>(len, self.buf_len),
+            #[cfg(feature = "sha3")]
+            HashFn::Sha3_512 => zeros_hash::<Sha3_512>(len, self.buf_len),
+        };
+        self.zeros_hashes.lock().unwrap().insert(len, hash.clone());
+        hash
+    }
+
+    /// Checks if the file contains only zero bytes.
+    /// The `hash` must be the hash of the full contents of the file, computed by this hasher.
+    ///
+    /// The first block of the file is read and if it is not zero, the file is rejected early.
+    /// If the file has no data extents, i.e. it consists only of holes or of preallocated space
+    /// that was never written, it is accepted without computing the hash of zeros.
+    /// Empty files are not considered zero-filled.
+    pub fn is_zero_filled(&self, path: &Path, len: FileLen, hash: &FileHash) -> io::Result<bool> {
+        if len == FileLen(0) {
+            return Ok(false);
+        }
+        let block_len = min(len, FileLen(self.buf_len as u64));
+        let mut file = open(path, FilePos(0), block_len, FileAccess::Random)?;
+        let mut zero = true;
+        let bytes_read = scan(&mut file, block_len, self.buf_len, |buf| {
+            zero &= buf.iter().all(|&b| b == 0)
+        })?;
+        self.record_read(bytes_read);
+        if !zero {
+            return Ok(false);
+        }
+        #[cfg(target_os = "linux")]
+        if crate::file::has_no_data(path).unwrap_or(false) {
+            return Ok(true);
+        }
+        Ok(self.zeros_hash(len) == *hash)
+    }
+
+    /// Just like `hash_file`, but transforms or normalizes the file before hashing.
     pub fn hash_transformed(
         &self,
         chunk: &FileChunk<'_>,
         progress: impl Fn(usize),
     ) -> io::Result<(FileLen, FileHash)> {
         assert_eq!(chunk.pos, FilePos::zero());
-        assert!(self.transform.is_some());
+        assert!(self.transforms_data());
 
-        let transform = self.transform.as_ref().unwrap();
         let cache = self.cache.as_ref();
         let metadata = cache.and_then(|_| FileMetadata::new(chunk.path).ok());
         let metadata = metadata.as_ref();
@@ -321,6 +709,86 @@ impl FileHasher<'_> {
             return Ok(hash);
         }
 
+        let hash = match (&self.normalizers, self.transform_for(chunk.path)) {
+            (Some(normalizers), _) => self.normalized_hash(normalizers, chunk),
+            (None, Some(transform)) => self.transformed_hash(transform, chunk),
+            (None, None) => self.raw_hash(chunk),
+        };
+        progress(chunk.len.0 as usize);
+        let hash = hash?;
+        self.store_hash(key, metadata, hash.0, hash.1.clone());
+        Ok(hash)
+    }
+
+    /// Computes the hash of the file contents passed through the built-in normalizers.
+    fn normalized_hash(
+        &self,
+        normalizers: &Normalizers,
+        chunk: &FileChunk<'_>,
+    ) -> io::Result<(FileLen, FileHash)> {
+        fn hash<H: StreamHasher>(
+            normalizers: &Normalizers,
+            path: &Path,
+        ) -> io::Result<(FileLen, FileHash)> {
+            let mut hasher = H::new();
+            let mut len = FileLen(0);
+            normalizers.normalize(path, &mut |buf| {
+                hasher.update(buf);
+                len += FileLen(buf.len() as u64);
+            })?;
+            Ok((len, hasher.finish()))
+        }
+
+        let path = chunk.path;
+        let result = match self.algorithm {
+            HashFn::Metro => hash::<MetroHash128>(normalizers, path),
+            #[cfg(feature = "xxhash")]
+            HashFn::Xxhash => hash::<Xxh3>(normalizers, path),
+            #[cfg(feature = "blake3")]
+            HashFn::Blake3 => hash::<blake3::Hasher>(normalizers, path),
+            #[cfg(feature = "sha2")]
+            HashFn::Sha256 => hash::<Sha256>(normalizers, path),
+            #[cfg(feature = "sha2")]
+            HashFn::Sha512 => hash::<Sha512>(normalizers, path),
+            #[cfg(feature = "sha3")]
+            HashFn::Sha3_256 => hash::<Sha3_256>(normalizers, path),
+            #[cfg(feature = "sha3")]
+            HashFn::Sha3_512 => hash::<Sha3_512>(normalizers, path),
+        };
+        throttle_thread_read(chunk.len.0);
+        self.record_read(chunk.len.0);
+        result
+    }
+
+    /// Computes the hash of the file contents, for the files not matched by any transform rule.
+    fn raw_hash(&self, chunk: &FileChunk<'_>) -> io::Result<(FileLen, FileHash)> {
+        let bytes_read = Cell::new(0);
+        let progress = |delta: usize| bytes_read.set(bytes_read.get() + delta as u64);
+        let hash = match self.algorithm {
+            HashFn::Metro => file_hash::<MetroHash128>(chunk, self.buf_len, progress),
+            #[cfg(feature = "xxhash")]
+            HashFn::Xxhash => file_hash::<Xxh3>(chunk, self.buf_len, progress),
+            #[cfg(feature = "blake3")]
+            HashFn::Blake3 => file_hash::<blake3::Hasher>(chunk, self.buf_len, progress),
+            #[cfg(feature = "sha2")]
+            HashFn::Sha256 => file_hash::<Sha256>(chunk, self.buf_len, progress),
+            #[cfg(feature = "sha2")]
+            HashFn::Sha512 => file_hash::<Sha512>(chunk, self.buf_len, progress),
+            #[cfg(feature = "sha3")]
+            HashFn::Sha3_256 => file_hash::<Sha3_256>(chunk, self.buf_len, progress),
+            #[cfg(feature = "sha3")]
+            HashFn::Sha3_512 => file_hash::<Sha3_512>(chunk, self.buf_len, progress),
+        };
+        self.record_read(bytes_read.get());
+        Ok((chunk.len, hash?))
+    }
+
+    /// Computes the hash of the output of the external transform program.
+    fn transformed_hash(
+        &self,
+        transform: &Transform,
+        chunk: &FileChunk<'_>,
+    ) -> io::Result<(FileLen, FileHash)> {
         let mut transform_output = transform.run(chunk.path)?;
         let stream = &mut transform_output.out_stream;
         let buf_len = self.buf_len;
@@ -343,10 +811,26 @@ impl FileHasher<'_> {
             #[cfg(feature = "sha3")]
             HashFn::Sha3_512 => stream_hash::<Sha3_512>(stream, chunk.len, buf_len, |_| {}),
         };
-        progress(chunk.len.0 as usize);
-
-        let hash = hash?;
-        let exit_status = transform_output.child.lock().unwrap().wait()?;
+        // The transform program reads the whole input file
+        self.record_read(chunk.len.0);
+
+        // Read the remaining output, so we know if it exceeded the limit:
+        let hash = hash
+            .and_then(|hash| io::copy(stream, &mut io::sink()).map(|_| hash))
+            .map_err(|e| {
+                io::Error::new(e.kind(), format!("{} failed: {}", transform.program, e))
+            })?;
+        let exit_status = transform_output.wait()?;
+        if transform_output.timed_out() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "{} timed out after {:?} and was killed",
+                    transform.program,
+                    transform.limits.timeout.unwrap_or_default()
+                ),
+            ));
+        }
         if !exit_status.success() {
             let captured_err = transform_output
                 .err_stream
@@ -365,15 +849,20 @@ impl FileHasher<'_> {
                 )),
                 None => Err(io::Error::new(
                     io::ErrorKind::Other,
-                    format!("{} failed{}", transform.program, captured_err),
+                    format!(
+                        "{} {}{}",
+                        transform.program,
+                        describe_termination(exit_status),
+                        captured_err
+                    ),
                 )),
             };
         }
-
-        self.store_hash(key, metadata, hash.0, hash.1.clone());
         Ok(hash)
     }
 
+    /// Like [`FileHasher::hash_transformed`], but logs the error and returns `None` on failure.
+    /// The files that failed are remembered, so they can be listed in the report.
     pub fn hash_transformed_or_log_err(
         &self,
         chunk: &FileChunk<'_>,
@@ -383,11 +872,19 @@ impl FileHasher<'_> {
             Ok(hash) => Some(hash),
             Err(e) if e.kind() == io::ErrorKind::NotFound => None,
             Err(e) => {
-                self.log.warn(format!(
-                    "Failed to compute hash of file {}: {}",
-                    chunk.path.to_escaped_string(),
-                    e
-                ));
+                self.skipped_files.lock().unwrap().push(SkippedFile {
+                    path: chunk.path.clone(),
+                    error: e.to_string(),
+                });
+                self.log.warn_file(
+                    chunk.path,
+                    &e,
+                    format!(
+                        "Failed to compute hash of file {}: {}",
+                        chunk.path.to_escaped_string(),
+                        e
+                    ),
+                );
                 None
             }
         }
@@ -406,7 +903,14 @@ impl FileHasher<'_> {
             .zip(key)
             .zip(metadata)
             .and_then(|((cache, key), metadata)| match cache.get(key, metadata) {
-                Ok(len_and_hash) => len_and_hash,
+                Ok(Some(len_and_hash)) => {
+                    self.io.add_cache_hit();
+                    Some(len_and_hash)
+                }
+                Ok(None) => {
+                    self.io.add_cache_miss();
+                    None
+                }
                 Err(e) => {
                     self.log.warn(format!(
                         "Failed to load hash of file id = {key} from the cache: {e}"
@@ -416,6 +920,12 @@ impl FileHasher<'_> {
             })
     }
 
+    /// Records reading data of a file in the I/O statistics.
+    fn record_read(&self, bytes: u64) {
+        self.io.add_read(1, bytes);
+        record_thread_read(bytes);
+    }
+
     /// Stores the hash in the cache.
     /// If the operation fails (e.g. no space on drive), logs a warning.
     fn store_hash(
@@ -495,7 +1005,7 @@ fn configure_readahead(file: &File, offset: FilePos, len: FileLen, access: FileA
 /// Tells the system to remove given file fragment from the page cache.
 /// On non-Unix systems, does nothing.
 #[allow(unused)]
-fn evict_page_cache(file: &File, offset: FilePos, len: FileLen) {
+pub(crate) fn evict_page_cache(file: &File, offset: FilePos, len: FileLen) {
     #[cfg(target_os = "linux")]
     {
         use nix::fcntl::*;
@@ -564,10 +1074,55 @@ fn open_noatime(path: &Path) -> io::Result<File> {
     }
 }
 
+/// Describes why the process terminated without an exit code.
+#[cfg(unix)]
+fn describe_termination(status: std::process::ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+    match status.signal() {
+        Some(libc::SIGXCPU) => "exceeded the CPU time limit and was killed".to_owned(),
+        Some(libc::SIGKILL) => "was killed".to_owned(),
+        Some(signal) => format!("was terminated by signal {signal}"),
+        None => "failed".to_owned(),
+    }
+}
+
+#[cfg(not(unix))]
+fn describe_termination(_status: std::process::ExitStatus) -> String {
+    "failed".to_owned()
+}
+
 thread_local! {
     static BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) }
 }
 
+/// Data of a file fragment read ahead by `FileHasher::prefetch`
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+struct PrefetchedChunk {
+    path: Path,
+    pos: FilePos,
+    len: FileLen,
+    data: Vec<u8>,
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+thread_local! {
+    /// File fragments read ahead, waiting to be hashed by this thread
+    static PREFETCHED: RefCell<Vec<PrefetchedChunk>> = const { RefCell::new(Vec::new()) }
+}
+
+/// Removes the data of the chunk read ahead by `FileHasher::prefetch` and returns it.
+/// Returns `None` if the chunk was not read ahead.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn take_prefetched(chunk: &FileChunk<'_>) -> Option<Vec<u8>> {
+    PREFETCHED.with(|prefetched| {
+        let mut prefetched = prefetched.borrow_mut();
+        let index = prefetched
+            .iter()
+            .position(|p| &p.path == chunk.path && p.pos == chunk.pos && p.len == chunk.len)?;
+        Some(prefetched.swap_remove(index).data)
+    })
+}
+
 /// Scans up to `len` bytes in a file and sends data to the given consumer.
 /// Returns the number of bytes successfully read.
 fn scan<F: FnMut(&[u8])>(
@@ -590,6 +1145,7 @@ fn scan<F: FnMut(&[u8])>(
                 Ok(0) => break,
                 Ok(actual_read) => {
                     read += actual_read as u64;
+                    throttle_thread_read(actual_read as u64);
                     (consumer)(&buf[..actual_read]);
                 }
                 Err(e) => {
@@ -603,18 +1159,19 @@ fn scan<F: FnMut(&[u8])>(
 
 /// Computes the hash value over at most `len` bytes of the stream.
 /// Returns the number of the bytes read and a 128-bit hash value.
+/// Each chunk of data read is also passed to `consumer`.
 fn stream_hash<H: StreamHasher>(
     stream: &mut impl Read,
     len: FileLen,
     buf_len: usize,
-    progress: impl Fn(usize),
+    mut consumer: impl FnMut(&[u8]),
 ) -> io::Result<(FileLen, FileHash)> {
     let mut hasher = H::new();
     let mut read_len: FileLen = FileLen(0);
     scan(stream, len, buf_len, |buf| {
         hasher.update(buf);
         read_len += FileLen(buf.len() as u64);
-        (progress)(buf.len());
+        (consumer)(buf);
     })?;
     Ok((read_len, hasher.finish()))
 }
@@ -627,17 +1184,70 @@ fn file_hash<H: StreamHasher>(
     buf_len: usize,
     progress: impl Fn(usize),
 ) -> io::Result<FileHash> {
+    inspect_file_hash::<H>(chunk, buf_len, |buf| progress(buf.len()))
+}
+
+/// Like `file_hash`, but passes each chunk of data read from the file to `consumer`.
+fn inspect_file_hash<H: StreamHasher>(
+    chunk: &FileChunk<'_>,
+    buf_len: usize,
+    consumer: impl FnMut(&[u8]),
+) -> io::Result<FileHash> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if let Some(data) = take_prefetched(chunk) {
+        // Not passed through `scan`, because `prefetch` already throttled the reads
+        let mut consumer = consumer;
+        let mut hasher = H::new();
+        hasher.update(&data);
+        consumer(&data);
+        return Ok(hasher.finish());
+    }
     let access = if chunk.len.0 < 64 * 1024 {
         FileAccess::Random
     } else {
         FileAccess::Sequential
     };
     let mut file = open(chunk.path, chunk.pos, chunk.len, access)?;
-    let hash = stream_hash::<H>(&mut file, chunk.len, buf_len, progress)?.1;
+    let hash = stream_hash::<H>(&mut file, chunk.len, buf_len, consumer)?.1;
     evict_page_cache_if_low_mem(&mut file, chunk.len);
     Ok(hash)
 }
 
+/// Computes a hash of `len` zero bytes, without reading anything.
+fn zeros_hash<H: StreamHasher>(len: FileLen, buf_len: usize) -> FileHash {
+    let zeros = vec![0; buf_len];
+    let mut hasher = H::new();
+    let mut remaining = len.0;
+    while remaining > 0 {
+        let n = min(remaining, buf_len as u64) as usize;
+        hasher.update(&zeros[..n]);
+        remaining -= n as u64;
+    }
+    hasher.finish()
+}
+
+/// Computes a hash of `block_len` bytes read at each of the given positions in the file.
+/// All blocks are read through a single open file handle and fed into one hasher.
+fn samples_hash<H: StreamHasher>(
+    path: &Path,
+    positions: &[FilePos],
+    block_len: FileLen,
+    buf_len: usize,
+    progress: impl Fn(usize),
+) -> io::Result<FileHash> {
+    let mut file = open_noatime(path)?;
+    let mut hasher = H::new();
+    for &pos in positions {
+        configure_readahead(&file, pos, block_len, FileAccess::Random);
+        file.seek(pos.into())?;
+        scan(&mut file, block_len, buf_len, |buf| {
+            hasher.update(buf);
+            (progress)(buf.len());
+        })?;
+    }
+    Ok(hasher.finish())
+}
+
 #[cfg(test)]
 mod test {
     use metrohash::MetroHash128;
@@ -709,4 +1319,93 @@ mod test {
     fn test_file_hash_sha3_512() {
         test_file_hash::<sha3::Sha3_512>()
     }
+
+    #[test]
+    fn test_is_zero_filled() {
+        use crate::hasher::{FileHasher, HashFn};
+        use crate::log::StdLog;
+
+        let mut zeros = NamedTempFile::new().unwrap();
+        zeros.write_all(&[0; 100000]).unwrap();
+        let mut data = NamedTempFile::new().unwrap();
+        let mut buf = vec![0; 100000];
+        buf[99999] = 1;
+        data.write_all(&buf).unwrap();
+        let zeros = Path::from(&zeros);
+        let data = Path::from(&data);
+
+        let log = StdLog::new();
+        let hasher = FileHasher::new(HashFn::Metro, None, &log);
+        let len = FileLen(100000);
+        let hash = |path| {
+            hasher
+                .hash_file(&FileChunk::new(path, FilePos(0), len), |_| {})
+                .unwrap()
+        };
+        assert_eq!(hasher.zeros_hash(len), hash(&zeros));
+        assert!(hasher.zeros_hashes.lock().unwrap().contains_key(&len));
+        assert_eq!(hasher.zeros_hash(len), hash(&zeros));
+        assert!(hasher.is_zero_filled(&zeros, len, &hash(&zeros)).unwrap());
+        assert!(!hasher.is_zero_filled(&data, len, &hash(&data)).unwrap());
+    }
+
+    #[test]
+    fn test_samples_hash() {
+        use crate::hasher::samples_hash;
+
+        let mut file1 = NamedTempFile::new().unwrap();
+        file1.write_all(b"aaaa-bbbb-cccc").unwrap();
+        let mut file2 = NamedTempFile::new().unwrap();
+        file2.write_all(b"aaaaXbbbbXcccc").unwrap();
+        let mut file3 = NamedTempFile::new().unwrap();
+        file3.write_all(b"aaaa-bxbb-cccc").unwrap();
+        let file1 = Path::from(&file1);
+        let file2 = Path::from(&file2);
+        let file3 = Path::from(&file3);
+
+        let positions = [FilePos(0), FilePos(5), FilePos(10)];
+        let len = FileLen(4);
+        let hash = |path| samples_hash::<MetroHash128>(path, &positions, len, 4096, |_| {});
+        // Bytes between the sampled blocks are not compared:
+        assert_eq!(hash(&file1).unwrap(), hash(&file2).unwrap());
+        assert_ne!(hash(&file1).unwrap(), hash(&file3).unwrap());
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn test_prefetched_file_hash() {
+        use crate::hasher::{take_prefetched, FileHasher, HashFn};
+        use crate::log::StdLog;
+        use crate::uring;
+
+        if !uring::is_supported() {
+            return;
+        }
+        let mut file1 = NamedTempFile::new().unwrap();
+        file1.write_all(b"Test file 1").unwrap();
+        let mut file2 = NamedTempFile::new().unwrap();
+        file2.write_all(b"Test file 2").unwrap();
+        let file1 = Path::from(&file1);
+        let file2 = Path::from(&file2);
+        let chunk1 = || FileChunk::new(&file1, FilePos(0), FileLen(4096));
+        let chunk2 = || FileChunk::new(&file2, FilePos(5), FileLen(4));
+
+        let hash1 = file_hash::<MetroHash128>(&chunk1(), 4096, |_| {}).unwrap();
+        let hash2 = file_hash::<MetroHash128>(&chunk2(), 4096, |_| {}).unwrap();
+
+        let log = StdLog::new();
+        let hasher = FileHasher::new(HashFn::Metro, None, &log);
+        hasher.prefetch([(chunk1(), FileLen(11)), (chunk2(), FileLen(11))]);
+        assert_eq!(hasher.hash_file(&chunk2(), |_| {}).unwrap(), hash2);
+        assert_eq!(hasher.hash_file(&chunk1(), |_| {}).unwrap(), hash1);
+        // The chunks must be consumed by hashing:
+        assert!(take_prefetched(&chunk1()).is_none());
+        assert!(take_prefetched(&chunk2()).is_none());
+
+        // A file that is longer than expected must not be read ahead:
+        hasher.prefetch([(chunk1(), FileLen(5))]);
+        assert!(take_prefetched(&chunk1()).is_none());
+        hasher.prefetch([(chunk1(), FileLen(11))]);
+        assert!(take_prefetched(&chunk1()).is_some());
+    }
 }
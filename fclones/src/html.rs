@@ -0,0 +1,283 @@
+//! Self-contained HTML report for browsing duplicates in a web browser.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::path::{Component, PathBuf};
+
+use crate::arg;
+use crate::file::FileLen;
+use crate::group::FileGroup;
+use crate::path::Path;
+use crate::report::ReportHeader;
+use crate::TIMESTAMP_FMT;
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; margin: 2em; color: #222; }
+h1 { font-size: 1.6em; }
+h2 { font-size: 1.3em; margin-top: 1.5em; }
+table { border-collapse: collapse; }
+th, td { padding: 0.3em 0.8em; border-bottom: 1px solid #ddd; text-align: left; vertical-align: top; }
+th[data-sort] { cursor: pointer; user-select: none; background: #f0f0f0; }
+th[data-order="asc"]::after { content: " \25B2"; }
+th[data-order="desc"]::after { content: " \25BC"; }
+td.num { text-align: right; white-space: nowrap; }
+.stats td:first-child { font-weight: bold; }
+code, .path { font-family: monospace; }
+summary { cursor: pointer; }
+ul.files { margin: 0.3em 0; padding-left: 1.5em; }
+ul.tree { list-style: none; padding-left: 1.2em; }
+.size { color: #a33; margin-left: 0.5em; }
+.count { color: #777; margin-left: 0.5em; }
+"#;
+
+const SCRIPT: &str = r#"
+document.querySelectorAll("th[data-sort]").forEach(function (th) {
+  th.addEventListener("click", function () {
+    var tbody = th.closest("table").tBodies[0];
+    var index = th.cellIndex;
+    var numeric = th.dataset.sort === "num";
+    var asc = th.dataset.order !== "asc";
+    th.closest("tr").querySelectorAll("th").forEach(function (h) { delete h.dataset.order; });
+    th.dataset.order = asc ? "asc" : "desc";
+    var rows = Array.prototype.slice.call(tbody.rows);
+    rows.sort(function (a, b) {
+      var x = a.cells[index].dataset.value, y = b.cells[index].dataset.value;
+      var c = numeric ? x - y : x.localeCompare(y);
+      return asc ? c : -c;
+    });
+    rows.forEach(function (r) { tbody.appendChild(r); });
+  });
+});
+"#;
+
+/// Escapes characters that have special meaning in HTML.
+fn escape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// A directory in the tree of directories containing duplicate files.
+#[derive(Default)]
+struct DirNode {
+    /// Total size of duplicate files in this directory and all its subdirectories
+    bytes: u64,
+    /// Number of duplicate files in this directory and all its subdirectories
+    file_count: usize,
+    /// Number of duplicate files directly in this directory
+    direct_file_count: usize,
+    children: BTreeMap<String, DirNode>,
+}
+
+impl DirNode {
+    fn add_file(&mut self, path: &Path, len: FileLen) {
+        let path = path.to_path_buf();
+        let dir_names = path
+            .parent()
+            .into_iter()
+            .flat_map(|p| p.components())
+            .map(|c| match c {
+                Component::RootDir => String::from(std::path::MAIN_SEPARATOR),
+                c => c.as_os_str().to_string_lossy().to_string(),
+            });
+
+        let mut node = self;
+        node.bytes += len.0;
+        node.file_count += 1;
+        for name in dir_names {
+            node = node.children.entry(name).or_default();
+            node.bytes += len.0;
+            node.file_count += 1;
+        }
+        node.direct_file_count += 1;
+    }
+
+    /// Writes the subdirectories as nested lists.
+    /// Chains of directories with a single subdirectory and no files are collapsed into one item.
+    fn write_children(&self, out: &mut impl Write, open: bool) -> io::Result<()> {
+        let mut children = self.children.iter().collect::<Vec<_>>();
+        children.sort_by_key(|(_, node)| Reverse(node.bytes));
+        writeln!(out, "<ul class=\"tree\">")?;
+        for (name, node) in children {
+            let mut label = PathBuf::from(name);
+            let mut node = node;
+            while node.direct_file_count == 0 && node.children.len() == 1 {
+                let (name, child) = node.children.iter().next().unwrap();
+                label.push(name);
+                node = child;
+            }
+            let summary = format!(
+                "<span class=\"path\">{}</span><span class=\"size\">{}</span>\
+                 <span class=\"count\">{} files</span>",
+                escape(&label.to_string_lossy()),
+                FileLen(node.bytes),
+                node.file_count
+            );
+            if node.children.is_empty() {
+                writeln!(out, "<li>{summary}</li>")?;
+            } else {
+                let open_attr = if open { " open" } else { "" };
+                writeln!(out, "<li><details{open_attr}><summary>{summary}</summary>")?;
+                node.write_children(out, false)?;
+                writeln!(out, "</details></li>")?;
+            }
+        }
+        writeln!(out, "</ul>")
+    }
+}
+
+fn write_stats(out: &mut impl Write, header: &ReportHeader) -> io::Result<()> {
+    writeln!(out, "<table class=\"stats\">")?;
+    let mut row = |name: &str, value: String| {
+        writeln!(out, "<tr><td>{}</td><td>{}</td></tr>", name, escape(&value))
+    };
+    row("Version", format!("fclones {}", header.version))?;
+    row(
+        "Timestamp",
+        header.timestamp.format(TIMESTAMP_FMT).to_string(),
+    )?;
+    row("Command", arg::join(&header.command))?;
+    row("Base dir", header.base_dir.to_escaped_string())?;
+    if let Some(stats) = &header.stats {
+        row(
+            "Total",
+            format!(
+                "{} in {} files in {} groups",
+                stats.total_file_size, stats.total_file_count, stats.group_count
+            ),
+        )?;
+        row(
+            "Redundant",
+            format!(
+                "{} in {} files",
+                stats.redundant_file_size, stats.redundant_file_count
+            ),
+        )?;
+        if let Some(reclaimable_size) = stats.reclaimable_size {
+            row("Reclaimable", format!("{reclaimable_size} on disk"))?;
+        }
+        row(
+            "Missing",
+            format!(
+                "{} in {} files",
+                stats.missing_file_size, stats.missing_file_count
+            ),
+        )?;
+    }
+    writeln!(out, "</table>")
+}
+
+fn write_groups<P: AsRef<Path>>(out: &mut impl Write, groups: &[&FileGroup<P>]) -> io::Result<()> {
+    writeln!(out, "<table class=\"groups\">")?;
+    writeln!(
+        out,
+        "<thead><tr><th data-sort=\"str\">Hash and files</th><th data-sort=\"num\">File size</th>\
+         <th data-sort=\"num\">Copies</th><th data-sort=\"num\">Wasted space</th></tr></thead>"
+    )?;
+    writeln!(out, "<tbody>")?;
+    for g in groups {
+        let hash = g.file_hash.to_string();
+        let wasted = g.file_len * g.files.len().saturating_sub(1) as u64;
+        write!(
+            out,
+            "<tr><td data-value=\"{hash}\"><details><summary><code>{hash}</code></summary>\
+             <ul class=\"files\">"
+        )?;
+        for f in &g.files {
+            write!(
+                out,
+                "<li class=\"path\">{}</li>",
+                escape(&f.as_ref().display())
+            )?;
+        }
+        writeln!(
+            out,
+            "</ul></details></td>\
+             <td class=\"num\" data-value=\"{}\">{}</td>\
+             <td class=\"num\" data-value=\"{}\">{}</td>\
+             <td class=\"num\" data-value=\"{}\">{}</td></tr>",
+            g.file_len.0,
+            g.file_len,
+            g.files.len(),
+            g.files.len(),
+            wasted.0,
+            wasted,
+        )?;
+    }
+    writeln!(out, "</tbody></table>")
+}
+
+/// Writes a standalone HTML page with the report.
+/// The page does not reference any external resources.
+/// Groups are listed from the one wasting the most space.
+pub(crate) fn write_html<P: AsRef<Path>>(
+    out: &mut impl Write,
+    header: &ReportHeader,
+    groups: &[&FileGroup<P>],
+) -> io::Result<()> {
+    let mut groups = groups.to_vec();
+    groups.sort_by_key(|g| Reverse(g.file_len * g.files.len().saturating_sub(1) as u64));
+
+    let mut tree = DirNode::default();
+    for g in groups.iter().filter(|g| g.files.len() > 1) {
+        for f in &g.files {
+            tree.add_file(f.as_ref(), g.file_len);
+        }
+    }
+
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html lang=\"en\">")?;
+    writeln!(out, "<head>")?;
+    writeln!(out, "<meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>fclones report</title>")?;
+    writeln!(out, "<style>{STYLE}</style>")?;
+    writeln!(out, "</head>")?;
+    writeln!(out, "<body>")?;
+    writeln!(out, "<h1>fclones report</h1>")?;
+    write_stats(out, header)?;
+    writeln!(out, "<h2>Directories</h2>")?;
+    tree.write_children(out, true)?;
+    writeln!(out, "<h2>Groups</h2>")?;
+    write_groups(out, &groups)?;
+    writeln!(out, "<script>{SCRIPT}</script>")?;
+    writeln!(out, "</body>")?;
+    writeln!(out, "</html>")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn escape_special_chars() {
+        assert_eq!(
+            escape("<a href=\"x\">&'"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;"
+        );
+    }
+
+    #[test]
+    fn dir_tree_sums_file_sizes() {
+        let mut tree = DirNode::default();
+        tree.add_file(&Path::from("/a/b/file1"), FileLen(100));
+        tree.add_file(&Path::from("/a/c/file2"), FileLen(100));
+        tree.add_file(&Path::from("/a/c/file3"), FileLen(10));
+        let a = &tree.children["/"].children["a"];
+        assert_eq!(a.bytes, 210);
+        assert_eq!(a.file_count, 3);
+        assert_eq!(a.children["b"].bytes, 100);
+        assert_eq!(a.children["c"].bytes, 110);
+        assert_eq!(a.children["c"].direct_file_count, 2);
+    }
+}
@@ -5,13 +5,20 @@ pub mod report;
 
 mod arg;
 mod cache;
+mod calibrate;
+mod checksum;
 mod dedupe;
 mod device;
 mod error;
+mod expr;
 mod file;
+mod filetype;
 mod group;
 mod hasher;
+mod html;
 mod lock;
+mod metadata;
+mod normalize;
 mod path;
 mod pattern;
 mod phase;
@@ -20,10 +27,23 @@ mod regex;
 mod rlimit;
 mod selector;
 mod semaphore;
+#[cfg(target_os = "linux")]
+mod sparsify;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+mod throttle;
 mod transform;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 mod util;
+mod verify;
 mod walk;
+#[cfg(target_os = "linux")]
+mod watch;
 
+pub use calibrate::{calibrate, Calibration, DeviceCalibration};
+pub use checksum::{check_checksums, hash_files, write_checksums};
 pub use config::{DedupeConfig, GroupConfig, Priority};
 pub use dedupe::{
     dedupe, log_script, run_script, sort_by_priority, DedupeOp, DedupeResult, PartitionedFileGroup,
@@ -32,7 +52,18 @@ pub use dedupe::{
 pub use device::DiskDevices;
 pub use error::Error;
 pub use file::{FileHash, FileId, FileInfo, FileLen};
-pub use group::{group_files, write_report, FileGroup, FileSubGroup};
+pub use group::{
+    file_stats, find_copies, group_files, group_files_to_ndjson, group_files_with_stats,
+    write_report, write_report_with_header, write_report_with_stats, FileGroup, FileSubGroup,
+};
+pub use metadata::{MetadataFilter, PermSpec};
 pub use path::Path;
+pub use phase::Phase;
+#[cfg(target_os = "linux")]
+pub use sparsify::{sparsify_file, sparsify_files, zero_filled_files};
+pub use stats::{log_run_stats, DeviceStats, IoStats, PhaseStats, RunStats, SkippedFile};
+pub use verify::{verify_groups, VerifyStats};
+#[cfg(target_os = "linux")]
+pub use watch::{WatchEvent, Watcher};
 
 const TIMESTAMP_FMT: &str = "%Y-%m-%d %H:%M:%S.%3f %z";
@@ -1,12 +1,19 @@
 //! Logging and progress reporting.
 
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
 
 use console::style;
 use nom::lib::std::fmt::Display;
+use serde::Serialize;
 
+use crate::error::error_kind;
+use crate::path::Path;
+use crate::phase::Phase;
 use crate::progress::{ProgressBar, ProgressTracker};
-use chrono::Local;
+use chrono::{DateTime, Local};
 
 /// Determines the size of the task tracked by ProgressTracker.
 #[derive(Debug, Clone, Copy)]
@@ -16,20 +23,67 @@ pub enum ProgressBarLength {
     Unknown,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl ProgressBarLength {
+    /// Returns the name of the unit the progress is measured in.
+    pub fn unit(&self) -> &'static str {
+        match self {
+            ProgressBarLength::Bytes(_) => "bytes",
+            ProgressBarLength::Items(_) | ProgressBarLength::Unknown => "items",
+        }
+    }
+
+    /// Returns the expected final value of the progress counter, if known.
+    pub fn total(&self) -> Option<u64> {
+        match self {
+            ProgressBarLength::Items(len) | ProgressBarLength::Bytes(len) => Some(*len),
+            ProgressBarLength::Unknown => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum LogLevel {
     Info,
     Warn,
     Error,
 }
 
+/// Controls how log messages and progress are written to the standard error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable messages and animated progress bars
+    #[default]
+    Text,
+    /// One JSON object per line for each event
+    Json,
+}
+
 /// Common interface for logging diagnostics and progress.
 pub trait Log: Sync + Send {
     /// Clears any previous progress bar or spinner and installs a new progress bar.
     fn progress_bar(&self, msg: &str, len: ProgressBarLength) -> Arc<dyn ProgressTracker>;
 
+    /// Like `progress_bar`, but additionally identifies the phase of work the progress bar
+    /// belongs to. The phase is considered finished when the returned tracker is dropped.
+    fn phase_progress_bar(
+        &self,
+        _phase: Phase,
+        msg: &str,
+        len: ProgressBarLength,
+    ) -> Arc<dyn ProgressTracker> {
+        self.progress_bar(msg, len)
+    }
+
     /// Logs a message.
     fn log(&self, level: LogLevel, msg: String);
+
+    /// Logs a message about a failed operation on a file.
+    /// The message should already include the path and the error description;
+    /// the path and the error are passed separately for loggers that emit structured events.
+    fn log_file_error(&self, level: LogLevel, _path: &Path, _error: &io::Error, msg: String) {
+        self.log(level, msg)
+    }
 }
 
 /// Additional convenience methods for logging.
@@ -40,6 +94,8 @@ pub trait LogExt {
     fn warn(&self, msg: impl Display);
     /// Logs an error.
     fn err(&self, msg: impl Display);
+    /// Logs a warning caused by a failed operation on a file.
+    fn warn_file(&self, path: &Path, error: &io::Error, msg: impl Display);
 }
 
 /// Additional convenience methods for logging.
@@ -58,6 +114,11 @@ impl<L: Log + ?Sized> LogExt for L {
     fn err(&self, msg: impl Display) {
         self.log(LogLevel::Error, msg.to_string())
     }
+
+    /// Logs a warning caused by a failed operation on a file.
+    fn warn_file(&self, path: &Path, error: &io::Error, msg: impl Display) {
+        self.log_file_error(LogLevel::Warn, path, error, msg.to_string())
+    }
 }
 
 /// A logger that uses standard error stream to communicate with the user.
@@ -66,6 +127,7 @@ pub struct StdLog {
     progress_bar: Mutex<Weak<ProgressBar>>,
     pub log_stderr_to_stdout: bool,
     pub no_progress: bool,
+    pub log_format: LogFormat,
 }
 
 impl StdLog {
@@ -80,6 +142,7 @@ impl StdLog {
                 .to_string(),
             log_stderr_to_stdout: false,
             no_progress: false,
+            log_format: LogFormat::Text,
         }
     }
 
@@ -140,11 +203,30 @@ impl StdLog {
         }
     }
 
+    /// Creates a progress tracker that reports the progress as JSON events.
+    fn json_progress(
+        &self,
+        phase: Option<Phase>,
+        msg: &str,
+        len: ProgressBarLength,
+    ) -> Arc<dyn ProgressTracker> {
+        Arc::new(JsonProgress::new(
+            phase,
+            msg,
+            len,
+            !self.no_progress,
+            self.log_stderr_to_stdout,
+        ))
+    }
+
     const TIMESTAMP_FMT: &'static str = "[%Y-%m-%d %H:%M:%S.%3f]";
 }
 
 impl Log for StdLog {
     fn progress_bar(&self, msg: &str, len: ProgressBarLength) -> Arc<dyn ProgressTracker> {
+        if self.log_format == LogFormat::Json {
+            return self.json_progress(None, msg, len);
+        }
         match len {
             ProgressBarLength::Items(count) => self.progress_bar(msg, count),
             ProgressBarLength::Bytes(count) => self.bytes_progress_bar(msg, count),
@@ -152,7 +234,28 @@ impl Log for StdLog {
         }
     }
 
+    fn phase_progress_bar(
+        &self,
+        phase: Phase,
+        msg: &str,
+        len: ProgressBarLength,
+    ) -> Arc<dyn ProgressTracker> {
+        match self.log_format {
+            LogFormat::Text => Log::progress_bar(self, msg, len),
+            LogFormat::Json => self.json_progress(Some(phase), msg, len),
+        }
+    }
+
     fn log(&self, level: LogLevel, msg: String) {
+        if self.log_format == LogFormat::Json {
+            let event = EventKind::Log {
+                level,
+                message: &msg,
+                path: None,
+                error_kind: None,
+            };
+            return emit_event(event, self.log_stderr_to_stdout);
+        }
         let timestamp = Local::now();
         let level = match level {
             LogLevel::Info => style(" info:").for_stderr().green(),
@@ -171,6 +274,21 @@ impl Log for StdLog {
         );
         self.eprintln(msg);
     }
+
+    fn log_file_error(&self, level: LogLevel, path: &Path, error: &io::Error, msg: String) {
+        match self.log_format {
+            LogFormat::Text => self.log(level, msg),
+            LogFormat::Json => {
+                let event = EventKind::Log {
+                    level,
+                    message: &msg,
+                    path: Some(path),
+                    error_kind: Some(format!("{:?}", error_kind(error))),
+                };
+                emit_event(event, self.log_stderr_to_stdout)
+            }
+        }
+    }
 }
 
 impl Default for StdLog {
@@ -178,3 +296,184 @@ impl Default for StdLog {
         StdLog::new()
     }
 }
+
+/// State of a progress tracker, reported in the JSON events related to progress.
+#[derive(Serialize)]
+struct ProgressEvent<'a> {
+    phase: Option<Phase>,
+    message: &'a str,
+    unit: &'static str,
+    value: u64,
+    total: Option<u64>,
+}
+
+/// A structured event written by [`StdLog`] when configured with [`LogFormat::Json`].
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum EventKind<'a> {
+    Log {
+        level: LogLevel,
+        message: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        path: Option<&'a Path>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error_kind: Option<String>,
+    },
+    PhaseStart(ProgressEvent<'a>),
+    Progress(ProgressEvent<'a>),
+    PhaseEnd {
+        #[serde(flatten)]
+        progress: ProgressEvent<'a>,
+        elapsed_ms: u64,
+    },
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    timestamp: DateTime<Local>,
+    #[serde(flatten)]
+    kind: EventKind<'a>,
+}
+
+/// Formats the event as a single line of JSON, with the current timestamp.
+fn format_event(kind: EventKind<'_>) -> String {
+    let event = Event {
+        timestamp: Local::now(),
+        kind,
+    };
+    serde_json::to_string(&event).expect("Failed to serialize a log event")
+}
+
+fn emit_event(kind: EventKind<'_>, to_stdout: bool) {
+    let line = format_event(kind);
+    if to_stdout {
+        println!("{line}")
+    } else {
+        eprintln!("{line}")
+    }
+}
+
+/// Tracks progress of a task and reports it as a sequence of JSON events.
+/// Emits `phase_start` on creation, `progress` at most once per
+/// `PROGRESS_REPORT_PERIOD_MS` and `phase_end` when dropped.
+struct JsonProgress {
+    phase: Option<Phase>,
+    msg: String,
+    len: ProgressBarLength,
+    value: AtomicU64,
+    start_time: Instant,
+    last_report_ms: AtomicU64,
+    report_progress: bool,
+    to_stdout: bool,
+}
+
+const PROGRESS_REPORT_PERIOD_MS: u64 = 250;
+
+impl JsonProgress {
+    fn new(
+        phase: Option<Phase>,
+        msg: &str,
+        len: ProgressBarLength,
+        report_progress: bool,
+        to_stdout: bool,
+    ) -> JsonProgress {
+        let progress = JsonProgress {
+            phase,
+            msg: msg.to_owned(),
+            len,
+            value: AtomicU64::new(0),
+            start_time: Instant::now(),
+            last_report_ms: AtomicU64::new(0),
+            report_progress,
+            to_stdout,
+        };
+        emit_event(EventKind::PhaseStart(progress.event(0)), to_stdout);
+        progress
+    }
+
+    fn event(&self, value: u64) -> ProgressEvent<'_> {
+        ProgressEvent {
+            phase: self.phase,
+            message: &self.msg,
+            unit: self.len.unit(),
+            value,
+            total: self.len.total(),
+        }
+    }
+}
+
+impl ProgressTracker for JsonProgress {
+    fn inc(&self, delta: u64) {
+        let value = self.value.fetch_add(delta, Ordering::Relaxed) + delta;
+        if !self.report_progress {
+            return;
+        }
+        let now = self.start_time.elapsed().as_millis() as u64;
+        let last = self.last_report_ms.load(Ordering::Relaxed);
+        if now >= last + PROGRESS_REPORT_PERIOD_MS
+            && self
+                .last_report_ms
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            emit_event(EventKind::Progress(self.event(value)), self.to_stdout);
+        }
+    }
+}
+
+impl Drop for JsonProgress {
+    fn drop(&mut self) {
+        let event = EventKind::PhaseEnd {
+            progress: self.event(self.value.load(Ordering::Relaxed)),
+            elapsed_ms: self.start_time.elapsed().as_millis() as u64,
+        };
+        emit_event(event, self.to_stdout);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn format_log_event_with_path_and_error_kind() {
+        let path = Path::from("foo/bar");
+        let error = io::Error::from(io::ErrorKind::PermissionDenied);
+        let line = format_event(EventKind::Log {
+            level: LogLevel::Warn,
+            message: "Failed to read foo/bar",
+            path: Some(&path),
+            error_kind: Some(format!("{:?}", error_kind(&error))),
+        });
+        let json: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["event"], "log");
+        assert_eq!(json["level"], "warn");
+        assert_eq!(json["message"], "Failed to read foo/bar");
+        assert_eq!(json["path"], "foo/bar");
+        assert_eq!(json["error_kind"], "PermissionDenied");
+        assert!(json["timestamp"].is_string());
+    }
+
+    #[test]
+    fn format_phase_end_event() {
+        let line = format_event(EventKind::PhaseEnd {
+            progress: ProgressEvent {
+                phase: Some(Phase::GroupByContents),
+                message: "6/6: Grouping by contents",
+                unit: ProgressBarLength::Bytes(1000).unit(),
+                value: 1000,
+                total: Some(1000),
+            },
+            elapsed_ms: 15,
+        });
+        let json: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["event"], "phase_end");
+        assert_eq!(json["phase"], "group_by_contents");
+        assert_eq!(json["unit"], "bytes");
+        assert_eq!(json["value"], 1000);
+        assert_eq!(json["total"], 1000);
+        assert_eq!(json["elapsed_ms"], 15);
+        assert!(json.get("path").is_none());
+    }
+}
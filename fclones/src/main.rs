@@ -1,23 +1,36 @@
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::io::{stderr, stdin, IsTerminal, Write};
+use std::io::{stderr, stdin, stdout, IsTerminal, Write};
 use std::process::exit;
 use std::sync::Arc;
 use std::{fs, io};
 
+use chrono::{DateTime, Local};
 use clap::{CommandFactory, Parser};
 use console::style;
 use fallible_iterator::FallibleIterator;
 use itertools::Itertools;
 use regex::Regex;
 
-use fclones::config::{Command, Config, DedupeConfig, GroupConfig, Parallelism};
-use fclones::log::{Log, LogExt, ProgressBarLength, StdLog};
+#[cfg(target_os = "linux")]
+use fclones::config::SparsifyConfig;
+use fclones::config::{
+    CalibrateConfig, Command, Config, DedupeConfig, GroupConfig, HashConfig, OutputFormat,
+    Parallelism, VerifyConfig,
+};
+use fclones::log::{Log, LogExt, LogFormat, LogLevel, ProgressBarLength, StdLog};
 use fclones::progress::{NoProgressBar, ProgressTracker};
 use fclones::report::{open_report, ReportHeader};
-use fclones::{dedupe, log_script, run_script, DedupeOp};
-use fclones::{group_files, write_report, Error};
+use fclones::{
+    calibrate, check_checksums, dedupe, log_script, run_script, write_checksums, DedupeOp,
+};
+use fclones::{
+    file_stats, find_copies, group_files_to_ndjson, group_files_with_stats, log_run_stats,
+    verify_groups, write_report_with_header, write_report_with_stats, Calibration, Error, Path,
+};
+#[cfg(target_os = "linux")]
+use fclones::{sparsify_files, zero_filled_files, Watcher};
 
 /// Strips a red "error:" prefix and usage information added by clap.
 /// Removes ansi formatting.
@@ -63,7 +76,8 @@ fn check_input_paths_exist(config: &GroupConfig, log: &dyn Log) -> Result<(), Er
                 false
             }
             Err(e) => {
-                log.err(format!("Can't access {}: {}", p.display(), e));
+                let msg = format!("Can't access {}: {}", p.display(), e);
+                log.log_file_error(LogLevel::Error, p, &e, msg);
                 access_error = true;
                 false
             }
@@ -93,6 +107,20 @@ fn check_can_create_output_file(config: &GroupConfig) -> Result<(), Error> {
     Ok(())
 }
 
+/// Loads the device settings stored by `fclones calibrate`.
+/// The calibration is optional, so if it can't be read, the default settings are used.
+fn load_calibration(log: &dyn Log) -> Calibration {
+    Calibration::load().unwrap_or_else(|e| {
+        let path = Calibration::default_path().unwrap_or_default();
+        log.warn(format!(
+            "Failed to load device calibration from {}: {}. Using the default device settings.",
+            path.display(),
+            e
+        ));
+        Calibration::default()
+    })
+}
+
 /// Configures global thread pool to use desired number of threads
 fn configure_main_thread_pool(pool_sizes: &HashMap<OsString, Parallelism>) {
     let parallelism = pool_sizes.get(OsStr::new("main")).unwrap_or_else(|| {
@@ -112,16 +140,178 @@ fn configure_main_thread_pool(pool_sizes: &HashMap<OsString, Parallelism>) {
 
 fn run_group(mut config: GroupConfig, log: &dyn Log) -> Result<(), Error> {
     config.resolve_base_dir().map_err(|e| e.to_string())?;
+    config.calibration = load_calibration(log);
     check_input_paths_exist(&config, log)?;
     check_can_create_output_file(&config)?;
     configure_main_thread_pool(&config.thread_pool_sizes());
     log.info("Started grouping");
-    let results = group_files(&config, log).map_err(|e| Error::new(e.message))?;
+    if matches!(config.format, OutputFormat::Ndjson) {
+        let stats = group_files_to_ndjson(&config, log).map_err(|e| Error::new(e.message))?;
+        log_run_stats(log, &stats);
+        return Ok(());
+    }
+    let (results, stats) =
+        group_files_with_stats(&config, log).map_err(|e| Error::new(e.message))?;
+
+    write_report_with_stats(&config, log, &results, Some(&stats))
+        .map_err(|e| Error::new(format!("Failed to write report: {e}")))?;
+    log_run_stats(log, &stats);
+    Ok(())
+}
+
+fn run_find(mut config: GroupConfig, references: &[Path], log: &dyn Log) -> Result<(), Error> {
+    config.resolve_base_dir().map_err(|e| e.to_string())?;
+    config.calibration = load_calibration(log);
+    check_input_paths_exist(&config, log)?;
+    check_can_create_output_file(&config)?;
+    configure_main_thread_pool(&config.thread_pool_sizes());
+    log.info("Started searching for copies");
+    let (results, stats) =
+        find_copies(&config, references, log).map_err(|e| Error::new(e.message))?;
+
+    write_report_with_stats(&config, log, &results, Some(&stats))
+        .map_err(|e| Error::new(format!("Failed to write report: {e}")))?;
+    log_run_stats(log, &stats);
+    Ok(())
+}
+
+fn run_hash(mut config: HashConfig, log: &dyn Log) -> Result<(), Error> {
+    config.calibration = load_calibration(log);
+    let group_config = config.group_config(vec![], config.hash_fn());
+    configure_main_thread_pool(&group_config.thread_pool_sizes());
+    let mut out: Box<dyn Write> = match &config.output {
+        Some(path) => Box::new(io::BufWriter::new(File::create(path).map_err(|e| {
+            format!("Failed to create output file {}: {}", path.display(), e)
+        })?)),
+        None => Box::new(stdout().lock()),
+    };
+    if config.check {
+        check_checksums(&config, log, &mut out)
+    } else {
+        write_checksums(&config, log, &mut out)?;
+        out.flush()
+            .map_err(|e| Error::new(format!("Failed to write output: {e}")))
+    }
+}
+
+fn run_calibrate(config: CalibrateConfig, log: &dyn Log) -> Result<(), Error> {
+    let calibration = calibrate(&config, log)?;
+    print!("{calibration}");
+    Ok(())
+}
+
+fn run_verify(config: VerifyConfig, log: &dyn Log) -> Result<(), Error> {
+    let input_error = |e: io::Error| format!("Input error: {e}");
+    let mut reader = match &config.report {
+        Some(path) => {
+            let file = File::open(path)
+                .map_err(|e| format!("Failed to open report {}: {}", path.display(), e))?;
+            open_report(file).map_err(input_error)?
+        }
+        None => open_report(stdin()).map_err(input_error)?,
+    };
+    let header = reader.read_header().map_err(input_error)?;
+    let mut group_config = match get_command_config(&header)?.command {
+        Command::Group(c) | Command::Find { config: c, .. } => c,
+        _ => {
+            return Err(Error::from(
+                "The report was not produced by fclones group or fclones find",
+            ))
+        }
+    };
+    let groups: Vec<_> = reader
+        .read_groups()
+        .map_err(input_error)?
+        .collect()
+        .map_err(input_error)?;
 
-    write_report(&config, log, &results)
+    if !config.threads.is_empty() {
+        group_config.threads = config.threads;
+    }
+    group_config.calibration = load_calibration(log);
+    configure_main_thread_pool(&group_config.thread_pool_sizes());
+    log.info("Started verifying");
+    // Rehashed files are known to be valid at the time of verification,
+    // otherwise they are valid only at the time of the original report.
+    let timestamp = if config.rehash {
+        let now = Local::now();
+        DateTime::from_naive_utc_and_offset(now.naive_utc(), *now.offset())
+    } else {
+        header.timestamp
+    };
+    let (groups, _) = verify_groups(&group_config, header.timestamp, groups, config.rehash, log)?;
+
+    group_config.output = config.output;
+    group_config.format = config.format;
+    group_config.null = false;
+    let header = ReportHeader {
+        timestamp,
+        stats: Some(file_stats(&group_config, &groups)),
+        run_stats: None,
+        ..header
+    };
+    write_report_with_header(&group_config, log, &header, &groups)
         .map_err(|e| Error::new(format!("Failed to write report: {e}")))
 }
 
+#[cfg(target_os = "linux")]
+fn run_watch(mut config: GroupConfig, log: &dyn Log) -> Result<(), Error> {
+    config.resolve_base_dir().map_err(|e| e.to_string())?;
+    config.calibration = load_calibration(log);
+    check_input_paths_exist(&config, log)?;
+    check_can_create_output_file(&config)?;
+    configure_main_thread_pool(&config.thread_pool_sizes());
+    // Files are rehashed on every change, so caching is essential:
+    config.cache = true;
+    log.info("Started scanning");
+    let mut watcher = Watcher::new(&config, log)?;
+    let dir_count = watcher.scan();
+    log.info(format!(
+        "Found {} groups, watching {} directories for changes",
+        watcher.groups().len(),
+        dir_count
+    ));
+    watcher.run(&mut stdout().lock())
+}
+
+#[cfg(target_os = "linux")]
+fn run_sparsify(config: SparsifyConfig, log: &dyn Log) -> Result<(), Error> {
+    let input_error = |e: io::Error| format!("Input error: {e}");
+    let mut reader = open_report(stdin()).map_err(input_error)?;
+    reader.read_header().map_err(input_error)?;
+    let groups: Vec<_> = reader
+        .read_groups()
+        .map_err(input_error)?
+        .collect()
+        .map_err(input_error)?;
+    let files = zero_filled_files(groups);
+
+    if config.dry_run {
+        let output_error = |e: io::Error| format!("Output error: {e}");
+        let mut out: Box<dyn Write> = match &config.output {
+            Some(path) => Box::new(io::BufWriter::new(File::create(path).map_err(|e| {
+                format!("Failed to create output file {}: {}", path.display(), e)
+            })?)),
+            None => Box::new(stdout().lock()),
+        };
+        let mut count = 0;
+        for path in files {
+            writeln!(out, "fallocate --dig-holes {}", path.quote()).map_err(output_error)?;
+            count += 1;
+        }
+        out.flush().map_err(output_error)?;
+        log.info(format!("Would sparsify {count} zero-filled files"));
+    } else {
+        log.info("Started sparsifying");
+        let result = sparsify_files(files, !config.no_lock, log);
+        log.info(format!(
+            "Processed {} files and reclaimed {} space",
+            result.processed_count, result.reclaimed_space
+        ));
+    }
+    Ok(())
+}
+
 /// Depending on the `output` configuration field, returns either a reference to the standard
 /// output or a file opened for writing.
 /// Reports error if the output file cannot be created.
@@ -146,7 +336,12 @@ fn get_command_config(header: &ReportHeader) -> Result<Config, Error> {
 
     // Configure the same base directory as set when running the previous command.
     // This is important to get the correct input paths.
-    if let Command::Group(ref mut group_config) = command.command {
+    if let Command::Group(ref mut group_config)
+    | Command::Find {
+        config: ref mut group_config,
+        ..
+    } = command.command
+    {
         group_config.base_dir = header.base_dir.clone();
     }
     Ok(command)
@@ -157,13 +352,16 @@ pub fn run_dedupe(op: DedupeOp, config: DedupeConfig, log: &dyn Log) -> Result<(
     let mut dedupe_config = config;
     let mut reader = open_report(stdin()).map_err(input_error)?;
     let header = reader.read_header().map_err(input_error)?;
+    dedupe_config.marks = reader.read_marks().map_err(input_error)?;
     let prev_command_config = get_command_config(&header)?;
+    let mut sampled = header.sample_count.is_some();
 
-    if let Command::Group(c) = &prev_command_config.command {
+    if let Command::Group(c) | Command::Find { config: c, .. } = &prev_command_config.command {
+        sampled |= c.sample_hash.is_some();
         // we cannot check size if a transformation was applied, because the transformation
         // may change the size of the data and the recorded data size
         // would not match the physical size of the file
-        dedupe_config.no_check_size |= c.transform.is_some();
+        dedupe_config.no_check_size |= c.transforms_data();
         dedupe_config.match_links |= c.match_links;
 
         if dedupe_config.rf_over.is_none() {
@@ -181,6 +379,14 @@ pub fn run_dedupe(op: DedupeOp, config: DedupeConfig, log: &dyn Log) -> Result<(
         ));
     };
 
+    if sampled && !dedupe_config.dry_run && !dedupe_config.allow_sampled {
+        return Err(Error::from(
+            "The report was produced with --sample-hash, so large files were compared \
+            only by a sample of their contents and may be different. \
+            Please run fclones group without --sample-hash or pass --allow-sampled.",
+        ));
+    }
+
     if dedupe_config.modified_before.is_none() {
         dedupe_config.modified_before = Some(header.timestamp);
     }
@@ -262,10 +468,12 @@ fn main() {
     }
 
     let mut log = StdLog::new();
+    log.log_format = config.log_format;
     log.no_progress = match (config.quiet, config.progress.as_str()) {
         (true, _) => true,
         (_, "false") => true,
         (_, "true") => false,
+        (_, _auto) if config.log_format == LogFormat::Json => false,
         (_, _auto) => !stderr().is_terminal(),
     };
 
@@ -279,6 +487,14 @@ fn main() {
 
     let result = match config.command {
         Command::Group(config) => run_group(config, &log),
+        Command::Find { config, references } => run_find(config, &references, &log),
+        Command::Hash(config) => run_hash(config, &log),
+        Command::Verify(config) => run_verify(config, &log),
+        Command::Calibrate(config) => run_calibrate(config, &log),
+        #[cfg(target_os = "linux")]
+        Command::Watch(config) => run_watch(config, &log),
+        #[cfg(target_os = "linux")]
+        Command::Sparsify(config) => run_sparsify(config, &log),
         Command::Remove(config) => run_dedupe(DedupeOp::Remove, config, &log),
         Command::Link { config, soft: true } => run_dedupe(DedupeOp::SymbolicLink, config, &log),
         Command::Link {
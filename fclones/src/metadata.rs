@@ -0,0 +1,302 @@
+//! Selecting files by modification and status change times, ownership and permissions.
+
+use std::fmt::{Display, Formatter};
+use std::fs::Metadata;
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset, Utc};
+
+/// Describes a set of permission bits to check, with the syntax known from `find -perm`.
+///
+/// - `mode` – permission bits must be exactly `mode`
+/// - `-mode` – all of the bits in `mode` must be set
+/// - `/mode` – any of the bits in `mode` must be set
+///
+/// The mode is given in octal, e.g. `644`, `-4000` or `/o+r`.
+/// Symbolic modes like `u+s` or `o=r` are also supported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PermSpec {
+    Exact(u32),
+    All(u32),
+    Any(u32),
+}
+
+impl PermSpec {
+    pub fn matches(&self, mode: u32) -> bool {
+        let mode = mode & 0o7777;
+        match *self {
+            PermSpec::Exact(bits) => mode == bits,
+            PermSpec::All(bits) => mode & bits == bits,
+            PermSpec::Any(bits) => bits == 0 || mode & bits != 0,
+        }
+    }
+
+    /// Parses a symbolic mode like `u+s`, `go=rx` or `a+r,u+w`.
+    /// Only the bits being set are taken into account; `-` operations are not allowed.
+    fn parse_symbolic(s: &str) -> Result<u32, String> {
+        let mut result = 0;
+        for clause in s.split(',') {
+            let op_pos = clause
+                .find(['+', '='])
+                .ok_or_else(|| format!("Missing '+' or '=' in permission clause {clause}"))?;
+            let (who, perms) = (&clause[..op_pos], &clause[op_pos + 1..]);
+            let mut who_mask = 0;
+            for c in who.chars() {
+                who_mask |= match c {
+                    'u' => 0o4700,
+                    'g' => 0o2070,
+                    'o' => 0o1007,
+                    'a' => 0o7777,
+                    c => return Err(format!("Invalid permission class '{c}' in {clause}")),
+                };
+            }
+            if who.is_empty() {
+                who_mask = 0o7777;
+            }
+            let mut perm_mask = 0;
+            for c in perms.chars() {
+                perm_mask |= match c {
+                    'r' => 0o444,
+                    'w' => 0o222,
+                    'x' => 0o111,
+                    's' => 0o6000,
+                    't' => 0o1000,
+                    c => return Err(format!("Invalid permission '{c}' in {clause}")),
+                };
+            }
+            result |= who_mask & perm_mask;
+        }
+        Ok(result)
+    }
+}
+
+impl FromStr for PermSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ctor, mode): (fn(u32) -> PermSpec, &str) = match s.chars().next() {
+            Some('-') => (PermSpec::All, &s[1..]),
+            Some('/') => (PermSpec::Any, &s[1..]),
+            _ => (PermSpec::Exact, s),
+        };
+        let bits = if !mode.is_empty() && mode.chars().all(|c| c.is_ascii_digit()) {
+            u32::from_str_radix(mode, 8).map_err(|e| format!("Invalid octal mode {mode}: {e}"))?
+        } else {
+            PermSpec::parse_symbolic(mode)?
+        };
+        if bits > 0o7777 {
+            return Err(format!("Invalid mode {mode}: too many bits"));
+        }
+        Ok(ctor(bits))
+    }
+}
+
+impl Display for PermSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PermSpec::Exact(bits) => write!(f, "{bits:o}"),
+            PermSpec::All(bits) => write!(f, "-{bits:o}"),
+            PermSpec::Any(bits) => write!(f, "/{bits:o}"),
+        }
+    }
+}
+
+/// Selects files by their metadata.
+/// An empty filter matches all files.
+#[derive(Clone, Debug, Default)]
+pub struct MetadataFilter {
+    pub modified_after: Option<DateTime<FixedOffset>>,
+    pub modified_before: Option<DateTime<FixedOffset>>,
+    pub changed_after: Option<DateTime<FixedOffset>>,
+    pub changed_before: Option<DateTime<FixedOffset>>,
+    /// If not empty, the file must be owned by any of these users
+    pub uids: Vec<u32>,
+    /// If not empty, the file must belong to any of these groups
+    pub gids: Vec<u32>,
+    /// The permission bits of the file must match all of these
+    pub perms: Vec<PermSpec>,
+    /// The permission bits of the file must not match any of these
+    pub excluded_perms: Vec<PermSpec>,
+}
+
+impl MetadataFilter {
+    /// Returns true if the filter accepts all files, so there is no need to fetch the metadata.
+    pub fn is_empty(&self) -> bool {
+        self.modified_after.is_none()
+            && self.modified_before.is_none()
+            && self.changed_after.is_none()
+            && self.changed_before.is_none()
+            && self.uids.is_empty()
+            && self.gids.is_empty()
+            && self.perms.is_empty()
+            && self.excluded_perms.is_empty()
+    }
+
+    /// Returns true if the file with given metadata is accepted by this filter.
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        if self.modified_after.is_some() || self.modified_before.is_some() {
+            let mtime = metadata.modified().ok().map(DateTime::<Utc>::from);
+            if !Self::in_range(mtime, self.modified_after, self.modified_before) {
+                return false;
+            }
+        }
+        if self.changed_after.is_some() || self.changed_before.is_some() {
+            let ctime = Self::ctime(metadata);
+            if !Self::in_range(ctime, self.changed_after, self.changed_before) {
+                return false;
+            }
+        }
+        self.matches_owner_and_mode(metadata)
+    }
+
+    /// Returns true if `time` is strictly later than `after` and strictly earlier than `before`.
+    /// Unknown time never matches.
+    fn in_range(
+        time: Option<DateTime<Utc>>,
+        after: Option<DateTime<FixedOffset>>,
+        before: Option<DateTime<FixedOffset>>,
+    ) -> bool {
+        match time {
+            Some(time) => after.iter().all(|a| time > *a) && before.iter().all(|b| time < *b),
+            None => false,
+        }
+    }
+
+    #[cfg(unix)]
+    fn ctime(metadata: &Metadata) -> Option<DateTime<Utc>> {
+        use std::os::unix::fs::MetadataExt;
+        DateTime::from_timestamp(metadata.ctime(), metadata.ctime_nsec() as u32)
+    }
+
+    #[cfg(not(unix))]
+    fn ctime(_metadata: &Metadata) -> Option<DateTime<Utc>> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn matches_owner_and_mode(&self, metadata: &Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        let mode = metadata.mode();
+        (self.uids.is_empty() || self.uids.contains(&metadata.uid()))
+            && (self.gids.is_empty() || self.gids.contains(&metadata.gid()))
+            && self.perms.iter().all(|p| p.matches(mode))
+            && !self.excluded_perms.iter().any(|p| p.matches(mode))
+    }
+
+    #[cfg(not(unix))]
+    fn matches_owner_and_mode(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+}
+
+/// Returns the numeric user id of the user with given name or id.
+#[cfg(unix)]
+pub fn resolve_user(user: &str) -> Result<u32, String> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    match nix::unistd::User::from_name(user) {
+        Ok(Some(u)) => Ok(u.uid.as_raw()),
+        Ok(None) => Err(format!("Unknown user: {user}")),
+        Err(e) => Err(format!("Failed to look up user {user}: {e}")),
+    }
+}
+
+/// Returns the numeric group id of the group with given name or id.
+#[cfg(unix)]
+pub fn resolve_group(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    match nix::unistd::Group::from_name(group) {
+        Ok(Some(g)) => Ok(g.gid.as_raw()),
+        Ok(None) => Err(format!("Unknown group: {group}")),
+        Err(e) => Err(format!("Failed to look up group {group}: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+
+    use chrono::Duration;
+
+    use crate::util::test::with_dir;
+
+    use super::*;
+
+    #[test]
+    fn parse_perm_spec() {
+        assert_eq!("644".parse(), Ok(PermSpec::Exact(0o644)));
+        assert_eq!("-4000".parse(), Ok(PermSpec::All(0o4000)));
+        assert_eq!("/o+r".parse(), Ok(PermSpec::Any(0o004)));
+        assert_eq!("-u+s".parse(), Ok(PermSpec::All(0o4000)));
+        assert_eq!("/ug=w,o+x".parse(), Ok(PermSpec::Any(0o221)));
+        assert!("/10000".parse::<PermSpec>().is_err());
+        assert!("u-w".parse::<PermSpec>().is_err());
+    }
+
+    #[test]
+    fn perm_spec_matches() {
+        assert!(PermSpec::Exact(0o644).matches(0o100644));
+        assert!(!PermSpec::Exact(0o644).matches(0o100755));
+        assert!(PermSpec::All(0o4000).matches(0o104755));
+        assert!(!PermSpec::All(0o4001).matches(0o104754));
+        assert!(PermSpec::Any(0o4001).matches(0o104754));
+        assert!(!PermSpec::Any(0o006).matches(0o100640));
+    }
+
+    #[test]
+    fn filter_by_modification_time() {
+        with_dir("target/test/metadata/mtime", |root| {
+            let file = root.join("file");
+            File::create(&file).unwrap();
+            let metadata = file.metadata().unwrap();
+            let now = DateTime::<FixedOffset>::from(Utc::now());
+            let hour = Duration::hours(1);
+
+            let mut filter = MetadataFilter::default();
+            assert!(filter.is_empty());
+            filter.modified_after = Some(now - hour);
+            assert!(!filter.is_empty());
+            assert!(filter.matches(&metadata));
+            filter.modified_before = Some(now - hour / 2);
+            assert!(!filter.matches(&metadata));
+            filter.modified_before = Some(now + hour);
+            assert!(filter.matches(&metadata));
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn filter_by_owner_and_mode() {
+        use std::fs::{set_permissions, Permissions};
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        with_dir("target/test/metadata/owner", |root| {
+            let file = root.join("file");
+            File::create(&file).unwrap();
+            set_permissions(&file, Permissions::from_mode(0o640)).unwrap();
+            let metadata = file.metadata().unwrap();
+
+            let filter = MetadataFilter {
+                uids: vec![metadata.uid()],
+                perms: vec![PermSpec::All(0o600)],
+                ..MetadataFilter::default()
+            };
+            assert!(filter.matches(&metadata));
+
+            let filter = MetadataFilter {
+                uids: vec![metadata.uid() + 1],
+                ..MetadataFilter::default()
+            };
+            assert!(!filter.matches(&metadata));
+
+            let filter = MetadataFilter {
+                excluded_perms: vec![PermSpec::Any(0o040)],
+                ..MetadataFilter::default()
+            };
+            assert!(!filter.matches(&metadata));
+        });
+    }
+}
@@ -0,0 +1,613 @@
+//! Built-in normalizers of file contents.
+//!
+//! Normalizers remove the differences in file contents that are irrelevant for
+//! the user, e.g. metadata embedded in images or audio files, or line endings
+//! in text files. Unlike `--transform`, they run in-process, so they are much faster
+//! than spawning an external program for each file.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::str::FromStr;
+
+use crate::path::Path;
+use crate::pattern::{Pattern, PatternOpts};
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// PNG chunks holding metadata that doesn't affect the image
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+/// JPEG segments holding metadata that doesn't affect the image:
+/// APP1 (EXIF, XMP), APP13 (IPTC) and COM (comments)
+const JPEG_METADATA_MARKERS: [u8; 3] = [0xE1, 0xED, 0xFE];
+
+/// Receives the normalized data
+pub type Sink<'a> = dyn FnMut(&[u8]) + 'a;
+
+/// Identifies a built-in normalizer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Normalizer {
+    /// Strips EXIF, XMP, IPTC metadata and comments from JPEG and PNG images
+    Exif,
+    /// Strips ID3 and APE tags from MP3 files and metadata blocks from FLAC files
+    AudioTags,
+    /// Normalizes line endings to LF and removes trailing whitespace
+    Text,
+    /// Decompresses gzip data
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// Decompresses zstd data
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Normalizer {
+    pub fn variants() -> Vec<Normalizer> {
+        vec![
+            Normalizer::Exif,
+            Normalizer::AudioTags,
+            Normalizer::Text,
+            #[cfg(feature = "gzip")]
+            Normalizer::Gzip,
+            #[cfg(feature = "zstd")]
+            Normalizer::Zstd,
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Normalizer::Exif => "exif",
+            Normalizer::AudioTags => "audio-tags",
+            Normalizer::Text => "text",
+            #[cfg(feature = "gzip")]
+            Normalizer::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            Normalizer::Zstd => "zstd",
+        }
+    }
+
+    /// Returns the glob selecting the names of the files the normalizer is applied to,
+    /// if the user doesn't specify one.
+    pub fn default_glob(&self) -> &'static str {
+        match self {
+            Normalizer::Exif => "*.{jpg,jpeg,png}",
+            Normalizer::AudioTags => "*.{mp3,flac}",
+            Normalizer::Text => "*.{txt,md,rst,csv,tsv,log}",
+            #[cfg(feature = "gzip")]
+            Normalizer::Gzip => "*.gz",
+            #[cfg(feature = "zstd")]
+            Normalizer::Zstd => "*.zst",
+        }
+    }
+
+    /// Reads the file and sends the normalized contents to the sink.
+    pub fn run(&self, file: File, sink: &mut Sink<'_>) -> io::Result<()> {
+        match self {
+            Normalizer::Exif => strip_image_metadata(file, sink),
+            Normalizer::AudioTags => strip_audio_tags(file, sink),
+            Normalizer::Text => normalize_text(file, sink),
+            #[cfg(feature = "gzip")]
+            Normalizer::Gzip => copy(
+                &mut flate2::read::MultiGzDecoder::new(BufReader::new(file)),
+                sink,
+            ),
+            #[cfg(feature = "zstd")]
+            Normalizer::Zstd => copy(&mut zstd::stream::read::Decoder::new(file)?, sink),
+        }
+    }
+}
+
+impl FromStr for Normalizer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Normalizer::variants()
+            .into_iter()
+            .find(|n| n.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<_> = Normalizer::variants().iter().map(|n| n.name()).collect();
+                format!(
+                    "Unknown normalizer: {s}. Expected one of: {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// A normalizer given on the command line, optionally with a glob
+/// selecting the files it applies to, in the format `<name>[:<glob>]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NormalizerSpec {
+    pub normalizer: Normalizer,
+    pub pattern: Option<String>,
+}
+
+impl FromStr for NormalizerSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, pattern) = match s.split_once(':') {
+            Some((name, pattern)) => (name, Some(pattern.to_owned())),
+            None => (s, None),
+        };
+        Ok(NormalizerSpec {
+            normalizer: name.parse()?,
+            pattern,
+        })
+    }
+}
+
+impl Display for NormalizerSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.pattern {
+            Some(pattern) => write!(f, "{}:{}", self.normalizer.name(), pattern),
+            None => write!(f, "{}", self.normalizer.name()),
+        }
+    }
+}
+
+/// A list of normalizers with the patterns selecting the files they apply to.
+#[derive(Clone, Debug)]
+pub struct Normalizers {
+    normalizers: Vec<(Normalizer, Pattern)>,
+    /// Identifies the configuration, so the hashes of files
+    /// normalized differently are cached separately
+    id: String,
+}
+
+impl Normalizers {
+    /// Creates normalizers from the specs given by the user.
+    /// The `compile` function converts the user-given patterns to `Pattern` objects.
+    pub fn new<E>(
+        specs: &[NormalizerSpec],
+        compile: impl Fn(&str) -> Result<Pattern, E>,
+    ) -> Result<Normalizers, E> {
+        let mut normalizers = Vec::with_capacity(specs.len());
+        for spec in specs {
+            let pattern = match &spec.pattern {
+                Some(pattern) => compile(pattern)?,
+                None => Pattern::glob_with(
+                    spec.normalizer.default_glob(),
+                    &PatternOpts::case_insensitive(),
+                )
+                .unwrap(),
+            };
+            normalizers.push((spec.normalizer, pattern));
+        }
+        let id = specs.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        Ok(Normalizers {
+            normalizers,
+            id: format!("<normalize:{}>", id.join(",")),
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the first normalizer whose pattern matches the name of the file.
+    pub fn find(&self, path: &Path) -> Option<Normalizer> {
+        let name = path.file_name()?;
+        let name = name.to_string_lossy();
+        self.normalizers
+            .iter()
+            .find(|(_, pattern)| pattern.matches(&name))
+            .map(|(n, _)| *n)
+    }
+
+    /// Reads the file and sends its normalized contents to the sink.
+    /// If no normalizer applies to the file, the contents are sent unchanged.
+    pub fn normalize(&self, path: &Path, sink: &mut Sink<'_>) -> io::Result<()> {
+        let file = File::open(path.to_path_buf())?;
+        match self.find(path) {
+            Some(normalizer) => normalizer.run(file, sink),
+            None => copy(&mut BufReader::new(file), sink),
+        }
+    }
+}
+
+/// Sends all the remaining data from the reader to the sink.
+fn copy(r: &mut impl Read, sink: &mut Sink<'_>) -> io::Result<()> {
+    let mut buf = vec![0; 65536];
+    loop {
+        match r.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => sink(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Sends exactly `len` bytes from the reader to the sink.
+fn copy_exact(r: &mut impl Read, len: u64, sink: &mut Sink<'_>) -> io::Result<()> {
+    let mut remaining = len;
+    copy(&mut r.take(len), &mut |buf: &[u8]| {
+        remaining -= buf.len() as u64;
+        sink(buf)
+    })?;
+    if remaining > 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Skips exactly `len` bytes.
+fn skip(r: &mut impl Read, len: u64) -> io::Result<()> {
+    copy_exact(r, len, &mut |_| {})
+}
+
+/// Reads as many bytes as available, up to the size of the buffer.
+/// Returns the number of bytes read.
+fn read_up_to(r: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match r.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+fn strip_image_metadata(file: File, sink: &mut Sink<'_>) -> io::Result<()> {
+    let mut r = BufReader::new(file);
+    let mut magic = [0; 8];
+    let magic_len = read_up_to(&mut r, &mut magic)?;
+    let magic = &magic[..magic_len];
+    if magic.starts_with(&JPEG_SOI) {
+        sink(&JPEG_SOI);
+        strip_jpeg_metadata(&mut magic[2..].chain(r), sink)
+    } else if magic == PNG_SIGNATURE {
+        sink(&PNG_SIGNATURE);
+        strip_png_metadata(&mut r, sink)
+    } else {
+        sink(magic);
+        copy(&mut r, sink)
+    }
+}
+
+/// Copies the JPEG segments following the SOI marker, except the metadata segments.
+/// The image data following the SOS marker are copied as-is.
+fn strip_jpeg_metadata(r: &mut impl Read, sink: &mut Sink<'_>) -> io::Result<()> {
+    loop {
+        let mut marker = [0; 2];
+        if read_up_to(r, &mut marker)? < 2 || marker[0] != 0xFF {
+            // Not a valid segment, so we can't tell what follows
+            sink(&marker);
+            return copy(r, sink);
+        }
+        // Markers may be preceded by any number of fill bytes:
+        while marker[1] == 0xFF {
+            r.read_exact(&mut marker[1..])?;
+        }
+        match marker[1] {
+            // Start of scan or end of image
+            0xDA | 0xD9 => {
+                sink(&marker);
+                return copy(r, sink);
+            }
+            // Markers without payload
+            0x01 | 0xD0..=0xD7 => sink(&marker),
+            m => {
+                let mut len = [0; 2];
+                r.read_exact(&mut len)?;
+                let payload_len = (u16::from_be_bytes(len) as u64).saturating_sub(2);
+                if JPEG_METADATA_MARKERS.contains(&m) {
+                    skip(r, payload_len)?;
+                } else {
+                    sink(&marker);
+                    sink(&len);
+                    copy_exact(r, payload_len, sink)?;
+                }
+            }
+        }
+    }
+}
+
+/// Copies the PNG chunks following the signature, except the metadata chunks.
+fn strip_png_metadata(r: &mut impl Read, sink: &mut Sink<'_>) -> io::Result<()> {
+    loop {
+        let mut header = [0; 8];
+        match read_up_to(r, &mut header)? {
+            0 => return Ok(()),
+            8 => {}
+            n => {
+                sink(&header[..n]);
+                return Ok(());
+            }
+        }
+        let len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as u64;
+        let chunk_type = &header[4..8];
+        // Chunk data are followed by 4 bytes of CRC
+        if PNG_METADATA_CHUNKS
+            .iter()
+            .any(|t| t.as_slice() == chunk_type)
+        {
+            skip(r, len + 4)?;
+        } else {
+            sink(&header);
+            copy_exact(r, len + 4, sink)?;
+        }
+        if chunk_type == b"IEND" {
+            return copy(r, sink);
+        }
+    }
+}
+
+/// Returns the length of the ID3v2 tag at the current position of the reader,
+/// or 0 if there is no tag.
+fn id3v2_len(r: &mut impl Read) -> io::Result<u64> {
+    let mut header = [0; 10];
+    if read_up_to(r, &mut header)? < 10 || &header[0..3] != b"ID3" {
+        return Ok(0);
+    }
+    // The size is stored as 4 x 7 bits, excluding the header and the optional footer
+    let size = header[6..10]
+        .iter()
+        .fold(0u64, |acc, b| (acc << 7) | (*b & 0x7F) as u64);
+    let footer_len = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    Ok(10 + size + footer_len)
+}
+
+/// Returns the length of the ID3v1 and APEv2 tags at the end of the file.
+fn trailing_tags_len(file: &mut File, file_len: u64) -> io::Result<u64> {
+    let mut end = file_len;
+    if end >= 128 {
+        let mut tag = [0; 3];
+        file.seek(SeekFrom::Start(end - 128))?;
+        file.read_exact(&mut tag)?;
+        if &tag == b"TAG" {
+            end -= 128;
+        }
+    }
+    if end >= 32 {
+        let mut footer = [0; 32];
+        file.seek(SeekFrom::Start(end - 32))?;
+        file.read_exact(&mut footer)?;
+        if &footer[0..8] == b"APETAGEX" {
+            // The size includes the footer, but not the optional header
+            let size = u32::from_le_bytes(footer[12..16].try_into().unwrap()) as u64;
+            let flags = u32::from_le_bytes(footer[20..24].try_into().unwrap());
+            let header_len = if flags & 0x8000_0000 != 0 { 32 } else { 0 };
+            end = end.saturating_sub(size + header_len);
+        }
+    }
+    Ok(file_len - end)
+}
+
+fn strip_audio_tags(mut file: File, sink: &mut Sink<'_>) -> io::Result<()> {
+    let file_len = file.metadata()?.len();
+    let start = id3v2_len(&mut file)?.min(file_len);
+    let end = file_len - trailing_tags_len(&mut file, file_len)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut r = BufReader::new(file).take(end.saturating_sub(start));
+
+    let mut magic = [0; 4];
+    let magic_len = read_up_to(&mut r, &mut magic)?;
+    sink(&magic[..magic_len]);
+    if &magic == b"fLaC" {
+        strip_flac_metadata(&mut r, sink)
+    } else {
+        copy(&mut r, sink)
+    }
+}
+
+/// Copies the FLAC stream following the `fLaC` marker, keeping only the STREAMINFO
+/// metadata block, which is needed to decode the audio.
+fn strip_flac_metadata(r: &mut impl Read, sink: &mut Sink<'_>) -> io::Result<()> {
+    loop {
+        let mut header = [0; 4];
+        r.read_exact(&mut header)?;
+        let last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7F;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        if block_type == 0 {
+            // Other blocks are skipped, so this one must be marked as the last one
+            header[0] = 0x80;
+            sink(&header);
+            copy_exact(r, len, sink)?;
+        } else {
+            skip(r, len)?;
+        }
+        if last {
+            return copy(r, sink);
+        }
+    }
+}
+
+/// Converts line endings to LF, removes whitespace at the ends of lines
+/// and empty lines at the end of the text.
+fn normalize_text(file: File, sink: &mut Sink<'_>) -> io::Result<()> {
+    let mut r = BufReader::new(file);
+    let mut line = Vec::new();
+    let mut pending_newlines = 0;
+    loop {
+        line.clear();
+        if r.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        let trimmed_len = line
+            .iter()
+            .rposition(|b| !b" \t\r\n".contains(b))
+            .map_or(0, |i| i + 1);
+        if trimmed_len == 0 {
+            pending_newlines += 1;
+            continue;
+        }
+        for _ in 0..pending_newlines {
+            sink(b"\n");
+        }
+        sink(&line[..trimmed_len]);
+        pending_newlines = 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::write;
+
+    use crate::util::test::with_dir;
+
+    use super::*;
+
+    fn normalize(normalizer: Normalizer, path: &std::path::Path, data: &[u8]) -> Vec<u8> {
+        write(path, data).unwrap();
+        let mut result = Vec::new();
+        normalizer
+            .run(File::open(path).unwrap(), &mut |buf| {
+                result.extend_from_slice(buf)
+            })
+            .unwrap();
+        result
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn png_chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(chunk_type);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&[1, 2, 3, 4]); // CRC is not checked
+        chunk
+    }
+
+    #[test]
+    fn strip_jpeg_metadata() {
+        with_dir("normalize/jpeg", |root| {
+            let path = root.join("image.jpg");
+            let jfif = jpeg_segment(0xE0, b"JFIF\0");
+            let exif = jpeg_segment(0xE1, b"Exif\0\0foo");
+            let comment = jpeg_segment(0xFE, b"bar");
+            let image = [0xFF, 0xDA, 1, 2, 3, 0xFF, 0xD9];
+
+            let plain = [&JPEG_SOI[..], &jfif, &image].concat();
+            let tagged = [&JPEG_SOI[..], &jfif, &exif, &comment, &image].concat();
+            assert_eq!(normalize(Normalizer::Exif, &path, &tagged), plain);
+            assert_eq!(normalize(Normalizer::Exif, &path, &plain), plain);
+        });
+    }
+
+    #[test]
+    fn strip_png_metadata() {
+        with_dir("normalize/png", |root| {
+            let path = root.join("image.png");
+            let ihdr = png_chunk(b"IHDR", &[0; 13]);
+            let idat = png_chunk(b"IDAT", b"pixels");
+            let text = png_chunk(b"tEXt", b"Author\0me");
+            let iend = png_chunk(b"IEND", b"");
+
+            let plain = [&PNG_SIGNATURE[..], &ihdr, &idat, &iend].concat();
+            let tagged = [&PNG_SIGNATURE[..], &ihdr, &text, &idat, &iend].concat();
+            assert_eq!(normalize(Normalizer::Exif, &path, &tagged), plain);
+        });
+    }
+
+    #[test]
+    fn leave_unknown_images_unchanged() {
+        with_dir("normalize/unknown_image", |root| {
+            let path = root.join("image.jpg");
+            assert_eq!(normalize(Normalizer::Exif, &path, b"foo"), b"foo");
+            assert_eq!(normalize(Normalizer::Exif, &path, b""), b"");
+        });
+    }
+
+    #[test]
+    fn strip_mp3_tags() {
+        with_dir("normalize/mp3", |root| {
+            let path = root.join("song.mp3");
+            let audio = [0xFF, 0xFB, 0x90, 0x00, 1, 2, 3, 4];
+            let id3v2 = [b"ID3\x03\x00\x00\x00\x00\x00\x03".as_slice(), b"abc"].concat();
+            let mut ape = b"APETAGEX".to_vec();
+            ape.extend_from_slice(&2000u32.to_le_bytes()); // version
+            ape.extend_from_slice(&32u32.to_le_bytes()); // size
+            ape.extend_from_slice(&0u32.to_le_bytes()); // item count
+            ape.extend_from_slice(&0u32.to_le_bytes()); // flags
+            ape.extend_from_slice(&[0; 8]);
+            let mut id3v1 = b"TAG".to_vec();
+            id3v1.resize(128, b' ');
+
+            let tagged = [&id3v2[..], &audio, &ape, &id3v1].concat();
+            assert_eq!(normalize(Normalizer::AudioTags, &path, &tagged), audio);
+            assert_eq!(normalize(Normalizer::AudioTags, &path, &audio), audio);
+        });
+    }
+
+    #[test]
+    fn strip_flac_metadata() {
+        with_dir("normalize/flac", |root| {
+            let path = root.join("song.flac");
+            let stream_info = [&[0x00, 0, 0, 4][..], &[1, 2, 3, 4]].concat();
+            let comment = [&[0x84, 0, 0, 3][..], b"foo"].concat();
+            let frames = [0xFF, 0xF8, 5, 6, 7];
+
+            let tagged = [&b"fLaC"[..], &stream_info, &comment, &frames].concat();
+            let normalized = normalize(Normalizer::AudioTags, &path, &tagged);
+            let expected = [&b"fLaC"[..], &[0x80, 0, 0, 4], &[1, 2, 3, 4], &frames].concat();
+            assert_eq!(normalized, expected);
+        });
+    }
+
+    #[test]
+    fn normalize_line_endings_and_whitespace() {
+        with_dir("normalize/text", |root| {
+            let path = root.join("file.txt");
+            let unix = normalize(Normalizer::Text, &path, b"foo\n\nbar\n");
+            let windows = normalize(Normalizer::Text, &path, b"foo \r\n\r\nbar\t\r\n\r\n");
+            assert_eq!(unix, b"foo\n\nbar");
+            assert_eq!(windows, unix);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn decompress_gzip() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        with_dir("normalize/gzip", |root| {
+            let path = root.join("file.gz");
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(b"foo bar").unwrap();
+            let compressed = encoder.finish().unwrap();
+            assert_eq!(normalize(Normalizer::Gzip, &path, &compressed), b"foo bar");
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn decompress_zstd() {
+        with_dir("normalize/zstd", |root| {
+            let path = root.join("file.zst");
+            let compressed = zstd::encode_all(&b"foo bar"[..], 3).unwrap();
+            assert_eq!(normalize(Normalizer::Zstd, &path, &compressed), b"foo bar");
+        });
+    }
+
+    #[test]
+    fn select_normalizer_by_file_name() {
+        let specs: Vec<NormalizerSpec> = vec!["text:*.c".parse().unwrap(), "exif".parse().unwrap()];
+        let normalizers = Normalizers::new(&specs, Pattern::glob).unwrap();
+        assert_eq!(normalizers.id(), "<normalize:text:*.c,exif>");
+        assert_eq!(
+            normalizers.find(&Path::from("dir/main.c")),
+            Some(Normalizer::Text)
+        );
+        assert_eq!(
+            normalizers.find(&Path::from("dir/IMG_0001.JPG")),
+            Some(Normalizer::Exif)
+        );
+        assert_eq!(normalizers.find(&Path::from("dir/notes.txt")), None);
+        assert!("foo".parse::<NormalizerSpec>().is_err());
+    }
+}
@@ -1,14 +1,20 @@
+use serde::{Deserialize, Serialize};
+
 /// Identifies a phase of work.
 /// Used for reporting / progress tracking.
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Phase {
     Walk,
     FetchExtents,
     GroupBySize,
     GroupByPrefix,
     GroupBySuffix,
+    GroupBySamples,
     GroupByContents,
     TransformAndGroup,
+    Hash,
+    Verify,
 }
 
 impl Phase {
@@ -19,8 +25,11 @@ impl Phase {
             Phase::GroupBySize => "Grouping by size",
             Phase::GroupByPrefix => "Grouping by prefix",
             Phase::GroupBySuffix => "Grouping by suffix",
+            Phase::GroupBySamples => "Grouping by samples",
             Phase::GroupByContents => "Grouping by contents",
             Phase::TransformAndGroup => "Transforming and grouping",
+            Phase::Hash => "Hashing files",
+            Phase::Verify => "Verifying files",
         }
     }
 }
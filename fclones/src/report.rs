@@ -2,8 +2,9 @@
 
 use std::cell::Cell;
 use std::cmp::min;
+use std::collections::{HashMap, HashSet};
 use std::io;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::io::{BufRead, BufReader, Cursor, Error, ErrorKind, Read, Write};
 use std::str::FromStr;
 
 use chrono::{DateTime, FixedOffset};
@@ -16,10 +17,14 @@ use serde::{Deserialize, Serialize};
 
 use crate::arg;
 use crate::arg::Arg;
-use crate::config::OutputFormat;
+use crate::config::{os_string_from_bytes, OutputFormat};
 use crate::file::{FileHash, FileLen};
 use crate::group::FileGroup;
+use crate::html;
 use crate::path::Path;
+#[cfg(feature = "sqlite")]
+use crate::sqlite;
+use crate::stats::RunStats;
 use crate::util::IteratorWrapper;
 use crate::TIMESTAMP_FMT;
 
@@ -32,10 +37,24 @@ pub struct FileStats {
     pub total_file_size: FileLen,
     pub redundant_file_count: usize,
     pub redundant_file_size: FileLen,
+    /// Disk space that removing the redundant files would actually release.
+    /// Smaller than `redundant_file_size` if some files are hard-linked, reflinked or sparse.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reclaimable_size: Option<FileLen>,
     pub missing_file_count: usize,
     pub missing_file_size: FileLen,
 }
 
+/// Files marked in the report by the user to be kept or dropped by `fclones remove`,
+/// `link`, `move` or `dedupe`, regardless of other selection options.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FileMarks {
+    /// Files that must not be dropped
+    pub keep: HashSet<Path>,
+    /// If not empty, only these files can be dropped
+    pub drop: HashSet<Path>,
+}
+
 /// Data in the header of the whole report.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ReportHeader {
@@ -50,6 +69,14 @@ pub struct ReportHeader {
     /// Information on the number of duplicate files reported.
     /// This is optional to allow streaming the report out before finding all files in the future.
     pub stats: Option<FileStats>,
+    /// Number of blocks sampled from large files if the report was produced with
+    /// `--sample-hash`. Such files were not compared by their full contents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_count: Option<usize>,
+    /// Timing and I/O statistics of the search run.
+    /// Not stored in the text formats.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_stats: Option<RunStats>,
 }
 
 /// A helper struct that allows to serialize the report with serde.
@@ -61,6 +88,23 @@ struct SerializableReport<'a, G: Serialize> {
     groups: G,
 }
 
+/// The first line of a newline-delimited JSON report.
+#[derive(Serialize)]
+struct NdjsonHeader<'a> {
+    header: &'a ReportHeader,
+}
+
+/// The first line of a newline-delimited JSON report, when reading.
+#[derive(Deserialize)]
+struct DeserializedNdjsonHeader {
+    header: ReportHeader,
+}
+
+/// Prefix of the first line of a newline-delimited JSON report.
+/// Allows to tell it apart from the pretty-printed JSON report, where the header
+/// starts in the second line.
+const NDJSON_PREFIX: &str = "{\"header\":";
+
 /// A structure for holding contents of the report after fully deserializing the report.
 /// Used only by report readers that deserialize the whole report at once.
 /// Paths are represented as strings, because strings are more memory efficient than Path here,
@@ -76,11 +120,34 @@ struct DeserializedReport {
 pub struct ReportWriter<W: Write> {
     out: W,
     color: bool,
+    null: bool,
+    type_column: bool,
+    transform_column: bool,
 }
 
 impl<W: Write> ReportWriter<W> {
     pub fn new(out: W, color: bool) -> ReportWriter<W> {
-        ReportWriter { out, color }
+        ReportWriter {
+            out,
+            color,
+            null: false,
+            type_column: false,
+            transform_column: false,
+        }
+    }
+
+    /// Makes the file list formats terminate paths with NUL characters instead of newlines.
+    pub fn null_separated(mut self, null: bool) -> ReportWriter<W> {
+        self.null = null;
+        self
+    }
+
+    /// Adds the `type` and `transform` columns to the CSV format.
+    /// They are written after the file paths, so the reports without them keep their layout.
+    pub fn csv_columns(mut self, file_type: bool, transform: bool) -> ReportWriter<W> {
+        self.type_column = file_type;
+        self.transform_column = transform;
+        self
     }
 
     fn write_header_line(&mut self, line: &str) -> io::Result<()> {
@@ -95,7 +162,10 @@ impl<W: Write> ReportWriter<W> {
     ///
     /// A group of identical files starts with a group header at column 0,
     /// containing the size and hash of each file in the group.
+    /// Groups of files containing only zeros are additionally marked as `zero-filled`.
     /// Then file paths are printed in separate, indented lines.
+    /// If the writer is NUL-separated, the header ends with a `Paths: NUL-terminated` line
+    /// and each path is written verbatim and terminated by a NUL character instead of a newline.
     ///
     /// # Example
     /// ```text
@@ -105,6 +175,7 @@ impl<W: Write> ReportWriter<W> {
     /// # Base dir: /home/pkolaczk/Projekty/fclones
     /// # Total: 13589 B (13.6 KB) in 31 files in 14 groups
     /// # Redundant: 6819 B (6.8 KB) in 17 files
+    /// # Reclaimable: 4096 B (4.1 KB) on disk
     /// # Missing: 0 B (0 B) in 0 files
     /// 49165422e775f631cca3b09124f8ee89, 6274 B (6.3 KB) * 2:
     ///     /home/pkolaczk/Projekty/fclones/src/semaphore.rs
@@ -146,25 +217,44 @@ impl<W: Write> ReportWriter<W> {
                 "Redundant: {} B ({}) in {} files",
                 stats.redundant_file_size.0, stats.redundant_file_size, stats.redundant_file_count
             ))?;
+            if let Some(reclaimable_size) = stats.reclaimable_size {
+                self.write_header_line(&format!(
+                    "Reclaimable: {} B ({}) on disk",
+                    reclaimable_size.0, reclaimable_size
+                ))?;
+            }
             self.write_header_line(&format!(
                 "Missing: {} B ({}) in {} files",
                 stats.missing_file_size.0, stats.missing_file_size, stats.missing_file_count
             ))?;
         }
+        if let Some(sample_count) = header.sample_count {
+            self.write_header_line(&format!("Sampled: {sample_count} blocks per large file"))?;
+        }
+        if self.null {
+            self.write_header_line("Paths: NUL-terminated")?;
+        }
 
         for g in groups {
             let g = g.as_ref();
             let group_header = format!(
-                "{}, {} B ({}) * {}:",
+                "{}, {} B ({}){} * {}:",
                 g.file_hash,
                 g.file_len.0,
                 g.file_len,
+                if g.zero_filled { ", zero-filled" } else { "" },
                 g.files.len()
             );
             let group_header = style(group_header).yellow();
             writeln!(self.out, "{}", group_header.force_styling(self.color),)?;
             for f in g.files.iter() {
-                writeln!(self.out, "    {}", f.as_ref().to_escaped_string())?;
+                if self.null {
+                    self.out.write_all(b"    ")?;
+                    write_raw_path(&mut self.out, f.as_ref())?;
+                    self.out.write_all(b"\0")?;
+                } else {
+                    writeln!(self.out, "    {}", f.as_ref().to_escaped_string())?;
+                }
             }
         }
         Ok(())
@@ -173,6 +263,9 @@ impl<W: Write> ReportWriter<W> {
     /// Writes the report in `fdupes` compatible format.
     /// This is very similar to the TEXT format, but there are no headers
     /// for each group, and groups are separated with empty lines.
+    ///
+    /// If the writer is NUL-separated, each path is written verbatim and terminated by a NUL
+    /// character, and groups are separated by an additional NUL.
     pub fn write_as_fdupes<I, G, P>(&mut self, _header: &ReportHeader, groups: I) -> io::Result<()>
     where
         I: IntoIterator<Item = G>,
@@ -182,9 +275,18 @@ impl<W: Write> ReportWriter<W> {
         for g in groups {
             let g = g.as_ref();
             for f in g.files.iter() {
-                writeln!(self.out, "{}", f.as_ref().to_escaped_string())?;
+                if self.null {
+                    write_raw_path(&mut self.out, f.as_ref())?;
+                    self.out.write_all(b"\0")?;
+                } else {
+                    writeln!(self.out, "{}", f.as_ref().to_escaped_string())?;
+                }
+            }
+            if self.null {
+                self.out.write_all(b"\0")?;
+            } else {
+                writeln!(self.out)?;
             }
-            writeln!(self.out)?;
         }
         Ok(())
     }
@@ -198,6 +300,9 @@ impl<W: Write> ReportWriter<W> {
     /// - file hash (may be empty)
     /// - number of files in the group
     /// - file paths - each file in a separate column
+    /// - MIME type of the file contents (may be empty), if enabled by `csv_columns`
+    /// - transform applied to the files before hashing (may be empty),
+    ///   if enabled by `csv_columns`
     pub fn write_as_csv<I, G, P>(&mut self, _header: &ReportHeader, groups: I) -> io::Result<()>
     where
         I: IntoIterator<Item = G>,
@@ -210,7 +315,14 @@ impl<W: Write> ReportWriter<W> {
             .flexible(true)
             .from_writer(&mut self.out);
 
-        wtr.write_record(["size", "hash", "count", "files"])?;
+        let mut header = vec!["size", "hash", "count", "files"];
+        if self.type_column {
+            header.push("type");
+        }
+        if self.transform_column {
+            header.push("transform");
+        }
+        wtr.write_record(header)?;
         for g in groups {
             let g = g.as_ref();
             let mut record = csv::StringRecord::new();
@@ -220,6 +332,12 @@ impl<W: Write> ReportWriter<W> {
             for f in g.files.iter() {
                 record.push_field(f.as_ref().to_escaped_string().as_ref());
             }
+            if self.type_column {
+                record.push_field(g.file_type.as_deref().unwrap_or_default());
+            }
+            if self.transform_column {
+                record.push_field(g.transform.as_deref().unwrap_or_default());
+            }
             wtr.write_record(&record)?;
         }
         wtr.flush()
@@ -277,6 +395,10 @@ impl<W: Write> ReportWriter<W> {
         let groups = groups.into_iter().map(|g| FileGroup {
             file_len: g.as_ref().file_len,
             file_hash: g.as_ref().file_hash.clone(),
+            file_type: g.as_ref().file_type.clone(),
+            transform: g.as_ref().transform.clone(),
+            zero_filled: g.as_ref().zero_filled,
+            shared_extents: g.as_ref().shared_extents.clone(),
             files: g
                 .as_ref()
                 .files
@@ -293,6 +415,92 @@ impl<W: Write> ReportWriter<W> {
         Ok(())
     }
 
+    /// Writes the report as newline-delimited JSON.
+    ///
+    /// The first line holds the header wrapped in an object with a single `header` field.
+    /// Each following line holds one group of files.
+    /// Groups are written as they are pulled from the iterator, so the report can be
+    /// consumed by the next command while it is still being produced.
+    /// See [`crate::group_files_to_ndjson`] for writing the groups while they are being found.
+    ///
+    /// # Example
+    /// ```text
+    /// {"header":{"version":"0.35.0","timestamp":"2022-03-18T08:22:00.844+01:00",...}}
+    /// {"file_len":6274,"file_hash":"49165422e775f631cca3b09124f8ee89","files":["/home/..."]}
+    /// {"file_len":41,"file_hash":"dcf2e11190ccc260f2388d9a5a2ed20e","files":["/home/..."]}
+    /// ```
+    pub fn write_as_ndjson<I, G, P>(&mut self, header: &ReportHeader, groups: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = G>,
+        G: AsRef<FileGroup<P>>,
+        P: AsRef<Path>,
+    {
+        self.write_ndjson_header(header)?;
+        for g in groups {
+            self.write_ndjson_group(g.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the first line of a newline-delimited JSON report.
+    /// Use together with [`ReportWriter::write_ndjson_group`] to write the groups
+    /// one by one as they become available.
+    pub fn write_ndjson_header(&mut self, header: &ReportHeader) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, &NdjsonHeader { header })?;
+        writeln!(self.out)
+    }
+
+    /// Writes a single group as a line of a newline-delimited JSON report.
+    pub fn write_ndjson_group<P: AsRef<Path>>(&mut self, g: &FileGroup<P>) -> io::Result<()> {
+        let group = FileGroup {
+            file_len: g.file_len,
+            file_hash: g.file_hash.clone(),
+            file_type: g.file_type.clone(),
+            transform: g.transform.clone(),
+            zero_filled: g.zero_filled,
+            shared_extents: g.shared_extents.clone(),
+            files: g.files.iter().map(|f| f.as_ref()).collect_vec(),
+        };
+        serde_json::to_writer(&mut self.out, &group)?;
+        writeln!(self.out)
+    }
+
+    /// Flushes the underlying stream, so the lines written so far can be read.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Writes the report as a self-contained HTML page.
+    ///
+    /// The page shows the totals from the header, a tree of directories with the amount
+    /// of duplicate data in each, and a table of groups that can be sorted by clicking
+    /// the column headers. Each group can be expanded to show its files.
+    /// Unlike other formats, all groups are kept in memory before writing.
+    pub fn write_as_html<I, G, P>(&mut self, header: &ReportHeader, groups: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = G>,
+        G: AsRef<FileGroup<P>>,
+        P: AsRef<Path>,
+    {
+        let groups = groups.into_iter().collect_vec();
+        let groups = groups.iter().map(|g| g.as_ref()).collect_vec();
+        html::write_html(&mut self.out, header, &groups)
+    }
+
+    /// Writes the report as an SQLite database.
+    ///
+    /// The database is built in a temporary file first, and then copied to the output.
+    /// Groups and files are stored in separate tables, so they can be queried with SQL.
+    #[cfg(feature = "sqlite")]
+    pub fn write_as_sqlite<I, G, P>(&mut self, header: &ReportHeader, groups: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = G>,
+        G: AsRef<FileGroup<P>>,
+        P: AsRef<Path>,
+    {
+        sqlite::write_sqlite(&mut self.out, header, groups)
+    }
+
     /// Writes the report in the format given by `format` parameter.
     pub fn write<I, G, P>(
         &mut self,
@@ -310,10 +518,27 @@ impl<W: Write> ReportWriter<W> {
             OutputFormat::Fdupes => self.write_as_fdupes(header, groups),
             OutputFormat::Csv => self.write_as_csv(header, groups),
             OutputFormat::Json => self.write_as_json(header, groups),
+            OutputFormat::Ndjson => self.write_as_ndjson(header, groups),
+            OutputFormat::Html => self.write_as_html(header, groups),
+            #[cfg(feature = "sqlite")]
+            OutputFormat::Sqlite => self.write_as_sqlite(header, groups),
         }
     }
 }
 
+/// Writes the path as-is, without escaping.
+#[cfg(unix)]
+fn write_raw_path(out: &mut impl Write, path: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    out.write_all(path.to_path_buf().as_os_str().as_bytes())
+}
+
+/// Writes the path as-is, without escaping.
+#[cfg(not(unix))]
+fn write_raw_path(out: &mut impl Write, path: &Path) -> io::Result<()> {
+    out.write_all(path.to_string_lossy().as_bytes())
+}
+
 /// Iterator over groups of files, read form the report
 pub type GroupIterator = dyn FallibleIterator<Item = FileGroup<Path>, Error = io::Error> + Send;
 
@@ -324,6 +549,13 @@ pub trait ReportReader {
     /// if the report header is malformed.
     fn read_header(&mut self) -> io::Result<ReportHeader>;
 
+    /// Reads the files marked explicitly to be kept or dropped.
+    /// Only the reports that can be edited in place, e.g. SQLite databases, support marks.
+    /// Must be called after reading the header and before reading the groups.
+    fn read_marks(&mut self) -> io::Result<FileMarks> {
+        Ok(FileMarks::default())
+    }
+
     /// Opens an iterator over groups.
     fn read_groups(self: Box<Self>) -> io::Result<Box<GroupIterator>>;
 }
@@ -333,6 +565,8 @@ pub trait ReportReader {
 pub struct TextReportIterator<R: BufRead> {
     stream: R,
     line_buf: String,
+    /// Whether the paths are written verbatim and terminated by NUL characters
+    null: bool,
     stopped_on_error: bool,
 }
 
@@ -342,6 +576,7 @@ struct GroupHeader {
     count: usize,
     file_len: FileLen,
     file_hash: FileHash,
+    zero_filled: bool,
 }
 
 impl<R> TextReportIterator<R>
@@ -352,10 +587,17 @@ where
         TextReportIterator {
             stream: input,
             line_buf: String::new(),
+            null: false,
             stopped_on_error: false,
         }
     }
 
+    /// Makes the iterator read verbatim paths terminated by NUL characters.
+    fn null_separated(mut self, null: bool) -> TextReportIterator<R> {
+        self.null = null;
+        self
+    }
+
     fn read_first_non_comment_line(&mut self) -> io::Result<Option<&str>> {
         loop {
             self.line_buf.clear();
@@ -379,7 +621,8 @@ where
 
         lazy_static! {
             static ref GROUP_HEADER_RE: Regex =
-                Regex::new(r"^([a-f0-9]+), ([0-9]+) B [^*]* \* ([0-9]+):").unwrap();
+                Regex::new(r"^([a-f0-9]+), ([0-9]+) B [^*]*?(, zero-filled)? \* ([0-9]+):")
+                    .unwrap();
         }
 
         let captures = GROUP_HEADER_RE.captures(header_str).ok_or_else(|| {
@@ -392,13 +635,18 @@ where
         Ok(Some(GroupHeader {
             file_hash: FileHash::from_str(captures.get(1).unwrap().as_str()).unwrap(),
             file_len: FileLen(captures.get(2).unwrap().as_str().parse::<u64>().unwrap()),
-            count: captures.get(3).unwrap().as_str().parse::<usize>().unwrap(),
+            zero_filled: captures.get(3).is_some(),
+            count: captures.get(4).unwrap().as_str().parse::<usize>().unwrap(),
         }))
     }
 
     fn read_paths(&mut self, count: usize) -> io::Result<Vec<Path>> {
         let mut paths = Vec::with_capacity(min(count, 1024));
         for _ in 0..count {
+            if self.null {
+                paths.push(self.read_raw_path()?);
+                continue;
+            }
             self.line_buf.clear();
             let n = self.stream.read_line(&mut self.line_buf)?;
             let path_str = &self.line_buf;
@@ -424,6 +672,25 @@ where
         }
         Ok(paths)
     }
+
+    /// Reads an indented path written verbatim and terminated by a NUL character.
+    fn read_raw_path(&mut self) -> io::Result<Path> {
+        let mut buf = Vec::new();
+        let n = self.stream.read_until(b'\0', &mut buf)?;
+        if n == 0 || buf.last() != Some(&b'\0') {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Unexpected end of file.",
+            ));
+        }
+        match buf[..n - 1].strip_prefix(b"    ") {
+            Some(path) if !path.is_empty() => Ok(Path::from(os_string_from_bytes(path.to_vec()))),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Path expected: {}", String::from_utf8_lossy(&buf)),
+            )),
+        }
+    }
 }
 
 impl<R: BufRead + 'static> FallibleIterator for TextReportIterator<R> {
@@ -440,6 +707,10 @@ impl<R: BufRead + 'static> FallibleIterator for TextReportIterator<R> {
                 Ok(Some(FileGroup {
                     file_len: header.file_len,
                     file_hash: header.file_hash,
+                    file_type: None,
+                    transform: None,
+                    zero_filled: header.zero_filled,
+                    shared_extents: HashMap::new(),
                     files: paths,
                 }))
             }
@@ -459,34 +730,57 @@ impl<R: BufRead + 'static> FallibleIterator for TextReportIterator<R> {
 /// Allows iterating over groups of files.
 pub struct TextReportReader<R: BufRead> {
     pub stream: R,
+    /// A line read past the end of the header, because the last line of the header is optional
+    pending_line: String,
+    /// Whether the header announced NUL-terminated paths
+    null: bool,
 }
 
 impl<R: BufRead> TextReportReader<R> {
     /// Creates a new reader for reading from the given stream
     pub fn new(stream: R) -> TextReportReader<R> {
-        TextReportReader { stream }
+        TextReportReader {
+            stream,
+            pending_line: String::new(),
+            null: false,
+        }
     }
 
     fn read_line(&mut self) -> io::Result<String> {
+        if !self.pending_line.is_empty() {
+            return Ok(std::mem::take(&mut self.pending_line));
+        }
         let mut line_buf = String::new();
         self.stream.read_line(&mut line_buf)?;
         Ok(line_buf)
     }
 
     fn read_extract(&mut self, regex: &Regex, name: &str) -> io::Result<Vec<String>> {
+        self.read_optional_extract(regex)?.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Malformed header: Missing {name}"),
+            )
+        })
+    }
+
+    /// Like `read_extract`, but if the line doesn't match, returns `None`
+    /// and keeps the line to be read again.
+    fn read_optional_extract(&mut self, regex: &Regex) -> io::Result<Option<Vec<String>>> {
         let line = self.read_line()?;
-        Ok(regex
-            .captures(line.trim())
-            .ok_or_else(|| {
-                Error::new(
-                    ErrorKind::InvalidData,
-                    format!("Malformed header: Missing {name}"),
-                )
-            })?
-            .iter()
-            .skip(1)
-            .map(|c| c.unwrap().as_str().to_owned())
-            .collect())
+        match regex.captures(line.trim()) {
+            Some(captures) => Ok(Some(
+                captures
+                    .iter()
+                    .skip(1)
+                    .map(|c| c.unwrap().as_str().to_owned())
+                    .collect(),
+            )),
+            None => {
+                self.pending_line = line;
+                Ok(None)
+            }
+        }
     }
 
     fn parse_timestamp(value: &str, name: &str) -> io::Result<DateTime<FixedOffset>> {
@@ -540,8 +834,12 @@ impl<R: BufRead + Send + 'static> ReportReader for TextReportReader<R> {
                     .unwrap();
             static ref REDUNDANT_RE: Regex =
                 Regex::new(r"^# Redundant: ([0-9]+) B \([^)]+\) in ([0-9]+) files").unwrap();
+            static ref RECLAIMABLE_RE: Regex =
+                Regex::new(r"^# Reclaimable: ([0-9]+) B \([^)]+\) on disk").unwrap();
             static ref MISSING_RE: Regex =
                 Regex::new(r"^# Missing: ([0-9]+) B \([^)]+\) in ([0-9]+) files").unwrap();
+            static ref SAMPLED_RE: Regex = Regex::new(r"^# Sampled: ([0-9]+) blocks").unwrap();
+            static ref NULL_RE: Regex = Regex::new(r"^# Paths: (NUL-terminated)").unwrap();
         }
 
         let version = self
@@ -570,10 +868,21 @@ impl<R: BufRead + Send + 'static> ReportReader for TextReportReader<R> {
         let redundant_file_size = Self::parse_file_len(stats.first(), "redundant file size")?;
         let redundant_file_count = Self::parse_usize(stats.get(1), "redundant file count")?;
 
+        let reclaimable_size = match self.read_optional_extract(&RECLAIMABLE_RE)? {
+            Some(stats) => Some(Self::parse_file_len(stats.first(), "reclaimable size")?),
+            None => None,
+        };
+
         let stats = self.read_extract(&MISSING_RE, "missing file statistics")?;
         let missing_file_size = Self::parse_file_len(stats.first(), "missing file size")?;
         let missing_file_count = Self::parse_usize(stats.get(1), "missing file count")?;
 
+        let sample_count = match self.read_optional_extract(&SAMPLED_RE)? {
+            Some(captures) => Some(Self::parse_usize(captures.first(), "sample count")?),
+            None => None,
+        };
+        self.null = self.read_optional_extract(&NULL_RE)?.is_some();
+
         Ok(ReportHeader {
             version,
             timestamp,
@@ -585,16 +894,22 @@ impl<R: BufRead + Send + 'static> ReportReader for TextReportReader<R> {
                 total_file_size,
                 redundant_file_count,
                 redundant_file_size,
+                reclaimable_size,
                 missing_file_count,
                 missing_file_size,
             }),
+            sample_count,
+            run_stats: None,
         })
     }
 
     fn read_groups(
         self: Box<Self>,
     ) -> io::Result<Box<dyn FallibleIterator<Item = FileGroup<Path>, Error = Error> + Send>> {
-        Ok(Box::new(TextReportIterator::new(self.stream)))
+        let pending_line = Cursor::new(self.pending_line.into_bytes());
+        Ok(Box::new(
+            TextReportIterator::new(pending_line.chain(self.stream)).null_separated(self.null),
+        ))
     }
 }
 
@@ -623,36 +938,110 @@ impl ReportReader for JsonReportReader {
     }
 
     fn read_groups(self: Box<Self>) -> io::Result<Box<GroupIterator>> {
-        let iter = self.report.groups.into_iter().map(|g| {
-            Ok(FileGroup {
-                file_len: g.file_len,
-                file_hash: g.file_hash,
-                files: g
-                    .files
-                    .iter()
-                    .map(|s| {
-                        Path::from_escaped_string(s.as_str()).map_err(|e| {
-                            io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                format!("Invalid path {s}: {e}"),
-                            )
-                        })
-                    })
-                    .try_collect()?,
-            })
-        });
+        let iter = self.report.groups.into_iter().map(decode_paths);
         let iter = fallible_iterator::convert(iter);
         Ok(Box::new(iter))
     }
 }
 
+/// Converts escaped path strings read from a JSON report into paths.
+fn decode_paths(g: FileGroup<String>) -> io::Result<FileGroup<Path>> {
+    Ok(FileGroup {
+        file_len: g.file_len,
+        file_hash: g.file_hash,
+        file_type: g.file_type,
+        transform: g.transform,
+        zero_filled: g.zero_filled,
+        shared_extents: g.shared_extents,
+        files: g
+            .files
+            .iter()
+            .map(|s| {
+                Path::from_escaped_string(s.as_str()).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid path {s}: {e}"))
+                })
+            })
+            .try_collect()?,
+    })
+}
+
+/// Reads a report from a newline-delimited JSON file.
+/// Unlike [`JsonReportReader`], groups are read lazily one line at a time,
+/// so the memory use does not depend on the size of the report.
+pub struct NdjsonReportReader<R: BufRead> {
+    stream: R,
+}
+
+impl<R: BufRead> NdjsonReportReader<R> {
+    pub fn new(stream: R) -> NdjsonReportReader<R> {
+        NdjsonReportReader { stream }
+    }
+}
+
+impl<R: BufRead + Send + 'static> ReportReader for NdjsonReportReader<R> {
+    fn read_header(&mut self) -> io::Result<ReportHeader> {
+        let mut line = String::new();
+        self.stream.read_line(&mut line)?;
+        let header: DeserializedNdjsonHeader = serde_json::from_str(&line).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to deserialize NDJSON report header: {e}"),
+            )
+        })?;
+        Ok(header.header)
+    }
+
+    fn read_groups(self: Box<Self>) -> io::Result<Box<GroupIterator>> {
+        Ok(Box::new(NdjsonReportIterator {
+            lines: self.stream.lines(),
+            line_no: 1,
+        }))
+    }
+}
+
+/// Iterates over groups of a newline-delimited JSON report.
+/// Empty lines are skipped.
+struct NdjsonReportIterator<R: BufRead> {
+    lines: io::Lines<R>,
+    line_no: usize,
+}
+
+impl<R: BufRead> FallibleIterator for NdjsonReportIterator<R> {
+    type Item = FileGroup<Path>;
+    type Error = io::Error;
+
+    fn next(&mut self) -> Result<Option<Self::Item>, Self::Error> {
+        for line in self.lines.by_ref() {
+            let line = line?;
+            self.line_no += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let group: FileGroup<String> = serde_json::from_str(&line).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed group in line {}: {e}", self.line_no),
+                )
+            })?;
+            return decode_paths(group).map(Some);
+        }
+        Ok(None)
+    }
+}
+
 /// Returns a `ReportReader` that can read and decode the report from the given stream.
 /// Automatically detects the type of the report.
 pub fn open_report(r: impl Read + Send + 'static) -> io::Result<Box<dyn ReportReader>> {
     let mut buf_reader = BufReader::with_capacity(16 * 1024, r);
     let preview = buf_reader.fill_buf()?;
+    #[cfg(feature = "sqlite")]
+    if preview.starts_with(sqlite::SQLITE_MAGIC) {
+        return Ok(Box::new(sqlite::SqliteReportReader::new(buf_reader)?));
+    }
     let preview = String::from_utf8_lossy(preview);
-    if preview.starts_with('{') {
+    if preview.starts_with(NDJSON_PREFIX) {
+        Ok(Box::new(NdjsonReportReader::new(buf_reader)))
+    } else if preview.starts_with('{') {
         Ok(Box::new(JsonReportReader::new(buf_reader)?))
     } else if preview.starts_with('#') {
         Ok(Box::new(TextReportReader::new(buf_reader)))
@@ -660,9 +1049,15 @@ pub fn open_report(r: impl Read + Send + 'static) -> io::Result<Box<dyn ReportRe
         Err(io::Error::new(
             ErrorKind::InvalidData,
             format!(
-                "Unknown report format. Supported formats are: {}, {}",
+                "Unknown report format. Supported formats are: {}, {}, {}{}",
                 OutputFormat::Default,
-                OutputFormat::Json
+                OutputFormat::Json,
+                OutputFormat::Ndjson,
+                if cfg!(feature = "sqlite") {
+                    ", sqlite"
+                } else {
+                    ""
+                }
             ),
         ))
     }
@@ -677,6 +1072,8 @@ mod test {
 
     use crate::file::{FileHash, FileLen};
     use crate::path::Path;
+    use crate::phase::Phase;
+    use crate::stats::{IoStats, PhaseStats, SkippedFile};
 
     use super::*;
 
@@ -693,9 +1090,12 @@ mod test {
                 total_file_size: FileLen(2500),
                 redundant_file_count: 234,
                 redundant_file_size: FileLen(1000),
+                reclaimable_size: Some(FileLen(400)),
                 missing_file_count: 93,
                 missing_file_size: FileLen(300),
             }),
+            sample_count: None,
+            run_stats: None,
         }
     }
 
@@ -718,6 +1118,92 @@ mod test {
         assert_eq!(header2.stats, header1.stats);
     }
 
+    #[test]
+    fn test_text_report_reader_reads_header_without_reclaimable_size() {
+        let mut header = dummy_report_header();
+        header.stats.as_mut().unwrap().reclaimable_size = None;
+        let output = NamedTempFile::new().unwrap();
+        let input = output.reopen().unwrap();
+        let mut writer = ReportWriter::new(output, false);
+        writer
+            .write_as_text(&header, Vec::<FileGroup<Path>>::new().iter())
+            .unwrap();
+
+        let mut reader = TextReportReader::new(BufReader::new(input));
+        let header2 = reader.read_header().unwrap();
+        assert_eq!(header2.stats, header.stats);
+    }
+
+    #[test]
+    fn test_text_report_reader_reads_sampled_header() {
+        let mut header = dummy_report_header();
+        header.sample_count = Some(8);
+        let groups = vec![FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+            files: vec![Path::from("a"), Path::from("b")],
+            ..Default::default()
+        }];
+        let output = NamedTempFile::new().unwrap();
+        let input = output.reopen().unwrap();
+        let mut writer = ReportWriter::new(output, false);
+        writer.write_as_text(&header, groups.iter()).unwrap();
+
+        let mut reader = Box::new(TextReportReader::new(BufReader::new(input)));
+        assert_eq!(reader.read_header().unwrap().sample_count, Some(8));
+        let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups, groups2);
+    }
+
+    #[test]
+    fn test_text_report_reader_reads_zero_filled_groups() {
+        let header = dummy_report_header();
+        let groups = vec![
+            FileGroup {
+                file_len: FileLen(1000),
+                file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+                file_type: None,
+                transform: None,
+                zero_filled: true,
+                shared_extents: HashMap::new(),
+                files: vec![Path::from("a"), Path::from("b")],
+            },
+            FileGroup {
+                file_len: FileLen(100),
+                file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+                files: vec![Path::from("c"), Path::from("d")],
+                ..Default::default()
+            },
+        ];
+        roundtrip_groups_text(&header, groups);
+    }
+
+    #[test]
+    fn test_json_report_header_with_run_stats() {
+        let mut header = dummy_report_header();
+        header.run_stats = Some(RunStats {
+            wall_time_ms: 1500,
+            phases: vec![PhaseStats {
+                phase: Phase::GroupByContents,
+                wall_time_ms: 1000,
+                io: IoStats {
+                    files_read: 10,
+                    bytes_read: FileLen(10000),
+                    cache_hits: 2,
+                    cache_misses: 10,
+                },
+                devices: vec![],
+            }],
+            devices: vec![],
+            skipped_files: vec![SkippedFile {
+                path: Path::from("/skipped"),
+                error: "exiv2 timed out after 10s and was killed".to_owned(),
+            }],
+        });
+        let reread_header = roundtrip_header(&header, OutputFormat::Json);
+        assert_eq!(header, reread_header);
+    }
+
     fn roundtrip_groups_text(header: &ReportHeader, groups: Vec<FileGroup<Path>>) {
         let output = NamedTempFile::new().unwrap();
         let input = output.reopen().unwrap();
@@ -739,11 +1225,13 @@ mod test {
                 file_len: FileLen(100),
                 file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
                 files: vec![Path::from("a"), Path::from("b")],
+                ..Default::default()
             },
             FileGroup {
                 file_len: FileLen(40),
                 file_hash: FileHash::from(0x0000000000000555555555ffffffffff),
                 files: vec![Path::from("c"), Path::from("d")],
+                ..Default::default()
             },
         ];
 
@@ -758,11 +1246,13 @@ mod test {
                 file_len: FileLen(100),
                 file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
                 files: vec![Path::from("\t\r\n/foo"), Path::from("ąę/ść/żź/óń/")],
+                ..Default::default()
             },
             FileGroup {
                 file_len: FileLen(40),
                 file_hash: FileHash::from(0x0000000000000555555555ffffffffff),
                 files: vec![Path::from("c\u{7f}"), Path::from("😀/😋")],
+                ..Default::default()
             },
         ];
 
@@ -780,6 +1270,7 @@ mod test {
             files: vec![Path::from(OsString::from_vec(vec![
                 0xED, 0xA0, 0xBD, 0xED, 0xB8, 0x8D,
             ]))],
+            ..Default::default()
         }];
 
         roundtrip_groups_text(&header, groups);
@@ -872,17 +1363,35 @@ mod test {
                 file_len: FileLen(100),
                 file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
                 files: vec![Path::from("a"), Path::from("b")],
+                ..Default::default()
             },
             FileGroup {
                 file_len: FileLen(40),
                 file_hash: FileHash::from(0x0000000000000555555555ffffffffff),
                 files: vec![Path::from("c"), Path::from("d")],
+                ..Default::default()
             },
         ];
 
         roundtrip_groups_json(&header, groups);
     }
 
+    #[test]
+    fn test_json_report_reader_reads_file_types_and_transforms() {
+        let header = dummy_report_header();
+        let groups = vec![FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+            file_type: Some("image/png".to_owned()),
+            transform: Some("exiv2 -d a $IN".to_owned()),
+            zero_filled: false,
+            shared_extents: HashMap::new(),
+            files: vec![Path::from("a.png"), Path::from("b.png")],
+        }];
+
+        roundtrip_groups_json(&header, groups);
+    }
+
     #[test]
     fn test_json_report_reader_reads_files_with_control_chars_in_names() {
         let header = dummy_report_header();
@@ -891,11 +1400,13 @@ mod test {
                 file_len: FileLen(100),
                 file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
                 files: vec![Path::from("\t\r\n/foo"), Path::from("ąę/ść/żź/óń/")],
+                ..Default::default()
             },
             FileGroup {
                 file_len: FileLen(40),
                 file_hash: FileHash::from(0x0000000000000555555555ffffffffff),
                 files: vec![Path::from("c\u{7f}"), Path::from("😀/😋")],
+                ..Default::default()
             },
         ];
 
@@ -913,11 +1424,151 @@ mod test {
             files: vec![Path::from(OsString::from_vec(vec![
                 0xED, 0xA0, 0xBD, 0xED, 0xB8, 0x8D,
             ]))],
+            ..Default::default()
         }];
 
         roundtrip_groups_json(&header, groups);
     }
 
+    fn roundtrip_groups_ndjson(header: &ReportHeader, groups: Vec<FileGroup<Path>>) {
+        let output = NamedTempFile::new().unwrap();
+        let input = output.reopen().unwrap();
+
+        let mut writer = ReportWriter::new(output, false);
+        writer.write_as_ndjson(header, groups.iter()).unwrap();
+        let mut reader = open_report(input).unwrap();
+        assert_eq!(&reader.read_header().unwrap(), header);
+
+        let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups, groups2);
+    }
+
+    #[test]
+    fn test_ndjson_report_reader_reads_files() {
+        let header = dummy_report_header();
+        let groups = vec![
+            FileGroup {
+                file_len: FileLen(100),
+                file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+                files: vec![Path::from("a\nb"), Path::from("b")],
+                ..Default::default()
+            },
+            FileGroup {
+                file_len: FileLen(40),
+                file_hash: FileHash::from(0x0000000000000555555555ffffffffff),
+                files: vec![Path::from("c"), Path::from("😀/😋")],
+                ..Default::default()
+            },
+        ];
+
+        roundtrip_groups_ndjson(&header, groups);
+    }
+
+    #[test]
+    fn test_ndjson_report_reader_reads_groups_lazily() {
+        let header = dummy_report_header();
+        let mut report = Vec::new();
+        let group = FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+            files: vec![Path::from("a"), Path::from("b")],
+            ..Default::default()
+        };
+        ReportWriter::new(&mut report, false)
+            .write_as_ndjson(&header, [&group])
+            .unwrap();
+        report.extend_from_slice(b"not json\n");
+
+        let mut reader = open_report(io::Cursor::new(report)).unwrap();
+        reader.read_header().unwrap();
+        let mut groups = reader.read_groups().unwrap();
+        assert_eq!(groups.next().unwrap(), Some(group));
+        let err = groups.next().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("line 3"), "{err}");
+    }
+
+    #[test]
+    fn test_fdupes_null_separated() {
+        let header = dummy_report_header();
+        let groups = [
+            FileGroup {
+                file_len: FileLen(100),
+                file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+                files: vec![Path::from("/a\nb"), Path::from("/b")],
+                ..Default::default()
+            },
+            FileGroup {
+                file_len: FileLen(40),
+                file_hash: FileHash::from(0x0000000000000555555555ffffffffff),
+                files: vec![Path::from("/c"), Path::from("/d")],
+                ..Default::default()
+            },
+        ];
+        let mut out = Vec::new();
+        ReportWriter::new(&mut out, false)
+            .null_separated(true)
+            .write_as_fdupes(&header, groups.iter())
+            .unwrap();
+        assert_eq!(out, b"/a\nb\0/b\0\0/c\0/d\0\0");
+    }
+
+    #[test]
+    fn test_csv_extra_columns_follow_files() {
+        let header = dummy_report_header();
+        let group = FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+            file_type: Some("image/png".to_owned()),
+            transform: Some("exiv2 -d a $IN".to_owned()),
+            zero_filled: false,
+            shared_extents: HashMap::new(),
+            files: vec![Path::from("/a"), Path::from("/b")],
+        };
+        let mut out = Vec::new();
+        ReportWriter::new(&mut out, false)
+            .write_as_csv(&header, [&group])
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "size,hash,count,files\n100,ffeeddccbbaa99887766554433221100,2,/a,/b\n"
+        );
+
+        let mut out = Vec::new();
+        ReportWriter::new(&mut out, false)
+            .csv_columns(true, true)
+            .write_as_csv(&header, [&group])
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "size,hash,count,files,type,transform\n\
+             100,ffeeddccbbaa99887766554433221100,2,/a,/b,image/png,exiv2 -d a $IN\n"
+        );
+    }
+
+    #[test]
+    fn test_text_report_null_separated() {
+        let header = dummy_report_header();
+        let group = FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+            files: vec![Path::from("/a\nb"), Path::from("/ c")],
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        ReportWriter::new(&mut out, false)
+            .null_separated(true)
+            .write_as_text(&header, [&group, &group])
+            .unwrap();
+        let out_str = String::from_utf8_lossy(&out);
+        assert!(out_str.contains(":\n    /a\nb\0    / c\0"), "{out_str}");
+
+        let mut reader = open_report(io::Cursor::new(out)).unwrap();
+        reader.read_header().unwrap();
+        let groups: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups, vec![group.clone(), group]);
+    }
+
     fn roundtrip_header(header: &ReportHeader, format: OutputFormat) -> ReportHeader {
         let groups: Vec<FileGroup<Path>> = vec![];
         let output = NamedTempFile::new().unwrap();
@@ -933,7 +1584,18 @@ mod test {
         let header = dummy_report_header();
         let reread_header_1 = roundtrip_header(&header, OutputFormat::Default);
         let reread_header_2 = roundtrip_header(&header, OutputFormat::Json);
+        let reread_header_3 = roundtrip_header(&header, OutputFormat::Ndjson);
         assert_eq!(header, reread_header_1);
         assert_eq!(header, reread_header_2);
+        assert_eq!(header, reread_header_3);
+    }
+
+    #[test]
+    fn test_sampled_report_header_roundtrip() {
+        let mut header = dummy_report_header();
+        header.sample_count = Some(4);
+        assert_eq!(header, roundtrip_header(&header, OutputFormat::Default));
+        assert_eq!(header, roundtrip_header(&header, OutputFormat::Json));
+        assert_eq!(header, roundtrip_header(&header, OutputFormat::Ndjson));
     }
 }
@@ -48,6 +48,57 @@ lazy_static! {
     )));
 }
 
+/// Sets the limits of the memory and CPU time available to the process launched
+/// by the command. Exceeding the CPU time gets the process killed.
+/// Exceeding the memory limit makes memory allocations in the process fail.
+#[cfg(unix)]
+pub fn limit_child_resources(
+    command: &mut std::process::Command,
+    max_memory: Option<u64>,
+    max_cpu_secs: Option<u64>,
+) {
+    use std::os::unix::process::CommandExt;
+
+    if max_memory.is_none() && max_cpu_secs.is_none() {
+        return;
+    }
+    // Safety: the closure runs in the forked child and calls only setrlimit,
+    // which is async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(bytes) = max_memory {
+                let limit = libc::rlimit {
+                    rlim_cur: bytes as libc::rlim_t,
+                    rlim_max: bytes as libc::rlim_t,
+                };
+                if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(secs) = max_cpu_secs {
+                // The soft limit sends SIGXCPU, the hard limit sends SIGKILL
+                // in case the process ignores SIGXCPU
+                let limit = libc::rlimit {
+                    rlim_cur: secs as libc::rlim_t,
+                    rlim_max: secs.saturating_add(1) as libc::rlim_t,
+                };
+                if libc::setrlimit(libc::RLIMIT_CPU, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub fn limit_child_resources(
+    _command: &mut std::process::Command,
+    _max_memory: Option<u64>,
+    _max_cpu_secs: Option<u64>,
+) {
+}
+
 #[cfg(not(unix))]
 pub mod not_unix {
     #[derive(Clone, Copy)]
@@ -0,0 +1,196 @@
+//! Releasing the disk space taken by zero-filled files.
+//!
+//! Files found by `fclones group` to contain only zeros don't need to be linked together
+//! to save space. Instead, holes can be punched in them, so they stop occupying data blocks,
+//! while each file stays independent and reads back exactly the same.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+
+use filetime::FileTime;
+use nix::fcntl::{fallocate, FallocateFlags};
+use rayon::iter::{ParallelBridge, ParallelIterator};
+
+use crate::dedupe::DedupeResult;
+use crate::error::error_kind;
+use crate::file::FileLen;
+use crate::group::FileGroup;
+use crate::lock::FileLock;
+use crate::log::{Log, LogExt};
+use crate::path::Path;
+
+/// Size of the blocks checked for zeros.
+/// Must be a multiple of the file system block size, otherwise punching holes
+/// would only zero the partial blocks without releasing them.
+const BLOCK_LEN: usize = 64 * 1024;
+
+/// Returns the paths of the files in the groups marked as zero-filled.
+pub fn zero_filled_files<I>(groups: I) -> impl Iterator<Item = Path>
+where
+    I: IntoIterator<Item = FileGroup<Path>>,
+{
+    groups
+        .into_iter()
+        .filter(|g| g.zero_filled)
+        .flat_map(|g| g.files)
+}
+
+/// Punches holes in each of the given files in parallel.
+/// Errors are logged and the files that failed are not counted in the result.
+pub fn sparsify_files<I>(files: I, should_lock: bool, log: &dyn Log) -> DedupeResult
+where
+    I: Iterator<Item = Path> + Send,
+{
+    files
+        .par_bridge()
+        .map(|path| sparsify_file(&path, should_lock))
+        .inspect(|res| {
+            if let Err(e) = res {
+                log.warn(e);
+            }
+        })
+        .filter_map(|res| res.ok())
+        .map(|len| DedupeResult {
+            processed_count: 1,
+            reclaimed_space: len,
+        })
+        .reduce(DedupeResult::default, |a, b| a + b)
+}
+
+/// Punches holes over the blocks of the file that contain only zeros.
+///
+/// The blocks are read again before punching holes and the blocks containing any data are
+/// left intact, so the file is never damaged even if it was modified after grouping.
+/// The modification time of the file is preserved.
+/// Returns the amount of disk space released.
+pub fn sparsify_file(path: &Path, should_lock: bool) -> io::Result<FileLen> {
+    let with_path = |e: io::Error, action: &str| {
+        io::Error::new(
+            error_kind(&e),
+            format!("Failed to {} {}: {}", action, path.display(), e),
+        )
+    };
+    let _lock = if should_lock {
+        Some(FileLock::new(path)?)
+    } else {
+        None
+    };
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path.to_path_buf())
+        .map_err(|e| with_path(e, "open file"))?;
+    let metadata = file
+        .metadata()
+        .map_err(|e| with_path(e, "read metadata of"))?;
+
+    let mut buf = vec![0; BLOCK_LEN];
+    let mut offset = 0;
+    let mut hole_start = None;
+    let fd = file.as_raw_fd();
+    let punch = |start: u64, end: u64| {
+        let flags = FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE;
+        fallocate(fd, flags, start as i64, (end - start) as i64)
+            .map_err(|e| with_path(e.into(), "punch holes in"))
+    };
+    loop {
+        let n = read_block(&mut file, &mut buf).map_err(|e| with_path(e, "read"))?;
+        if n == 0 {
+            break;
+        }
+        let zero = buf[..n].iter().all(|&b| b == 0);
+        match hole_start {
+            None if zero => hole_start = Some(offset),
+            Some(start) if !zero => {
+                punch(start, offset)?;
+                hole_start = None;
+            }
+            _ => {}
+        }
+        offset += n as u64;
+    }
+    if let Some(start) = hole_start {
+        punch(start, offset)?;
+    }
+
+    let mtime = FileTime::from_last_modification_time(&metadata);
+    filetime::set_file_handle_times(&file, None, Some(mtime))
+        .map_err(|e| with_path(e, "set modification time of"))?;
+    let new_metadata = file
+        .metadata()
+        .map_err(|e| with_path(e, "read metadata of"))?;
+    Ok(FileLen(
+        allocated_len(&metadata).saturating_sub(allocated_len(&new_metadata)),
+    ))
+}
+
+/// Returns the amount of disk space allocated to the file
+fn allocated_len(metadata: &std::fs::Metadata) -> u64 {
+    // Unix always reports the number of blocks in 512-byte units
+    metadata.blocks() * 512
+}
+
+/// Reads until the buffer is full or the end of file is reached.
+/// Returns the number of bytes read.
+fn read_block(file: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::{Seek, SeekFrom, Write};
+
+    use crate::util::test::with_dir;
+
+    use super::*;
+
+    #[test]
+    fn sparsify_keeps_contents() {
+        with_dir("sparsify/keeps_contents", |root| {
+            let path = root.join("file");
+            let mut data = vec![0; 4 * BLOCK_LEN];
+            data[BLOCK_LEN + 1] = 1;
+            File::create(&path).unwrap().write_all(&data).unwrap();
+            let mtime = FileTime::from_unix_time(1_000_000_000, 0);
+            filetime::set_file_mtime(&path, mtime).unwrap();
+
+            let released = sparsify_file(&Path::from(&path), true).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), data);
+            let metadata = fs::metadata(&path).unwrap();
+            assert_eq!(FileTime::from_last_modification_time(&metadata), mtime);
+            // Some file systems like tmpfs don't support punching holes
+            if released > FileLen(0) {
+                assert!(allocated_len(&metadata) <= 2 * BLOCK_LEN as u64);
+            }
+        });
+    }
+
+    #[test]
+    fn sparsify_sparse_file() {
+        with_dir("sparsify/sparse_file", |root| {
+            let path = root.join("file");
+            let mut file = File::create(&path).unwrap();
+            file.seek(SeekFrom::Start(10 * BLOCK_LEN as u64)).unwrap();
+            file.write_all(&[0; 10]).unwrap();
+            drop(file);
+
+            sparsify_file(&Path::from(&path), false).unwrap();
+            let data = fs::read(&path).unwrap();
+            assert_eq!(data.len(), 10 * BLOCK_LEN + 10);
+            assert!(data.iter().all(|&b| b == 0));
+        });
+    }
+}
@@ -0,0 +1,595 @@
+//! Writing and reading reports stored in an SQLite database.
+//!
+//! The database contains three tables:
+//! - `header` with a single row holding the report header,
+//! - `groups` with one row per group of identical files, with the content type, transform
+//!   and zero-filled flag of the group, if any,
+//! - `files` with one row per file, referencing its group by `group_id`.
+//!
+//! Besides the path, each file row has its directory, name, extension, owner and modification
+//! time, so files can be conveniently selected with SQL queries.
+//! The database can be edited before passing it to `fclones remove`, `link`, `move`
+//! or `dedupe`: files deleted from the `files` table are not touched by those commands,
+//! and neither are the files of groups deleted from the `groups` table.
+//! Setting the `action` column of a file to `'keep'` or `'drop'` marks the file to be kept
+//! or dropped. If any files are marked to be dropped, no other files are dropped.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+
+use chrono::{DateTime, Local};
+use rusqlite::{params, Connection, OptionalExtension};
+use tempfile::NamedTempFile;
+
+use crate::arg::Arg;
+use crate::file::{FileHash, FileLen};
+use crate::group::FileGroup;
+use crate::path::Path;
+use crate::report::{FileMarks, FileStats, GroupIterator, ReportHeader, ReportReader};
+
+/// The first bytes of every SQLite database file.
+pub(crate) const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+const SCHEMA: &str = "
+    CREATE TABLE header (
+        version TEXT NOT NULL,
+        timestamp TEXT NOT NULL,
+        command TEXT NOT NULL,
+        base_dir TEXT NOT NULL,
+        group_count INTEGER,
+        total_file_count INTEGER,
+        total_file_size INTEGER,
+        redundant_file_count INTEGER,
+        redundant_file_size INTEGER,
+        reclaimable_size INTEGER,
+        missing_file_count INTEGER,
+        missing_file_size INTEGER,
+        sample_count INTEGER,
+        run_stats TEXT
+    );
+    CREATE TABLE groups (
+        id INTEGER PRIMARY KEY,
+        file_len INTEGER NOT NULL,
+        file_hash TEXT NOT NULL,
+        file_type TEXT,
+        transform TEXT,
+        zero_filled INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE files (
+        id INTEGER PRIMARY KEY,
+        group_id INTEGER NOT NULL,
+        path TEXT NOT NULL,
+        dir TEXT,
+        name TEXT,
+        extension TEXT,
+        uid INTEGER,
+        gid INTEGER,
+        modified TEXT,
+        action TEXT CHECK (action IN ('keep', 'drop'))
+    );
+    CREATE INDEX files_group_id ON files(group_id);
+";
+
+fn to_io_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(format!("SQLite error: {e}"))
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn write_header(conn: &Connection, header: &ReportHeader) -> io::Result<()> {
+    let command = serde_json::to_string(&header.command)?;
+    let run_stats = header
+        .run_stats
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let stats = header.stats.as_ref();
+    conn.execute(
+        "INSERT INTO header VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        params![
+            header.version,
+            header.timestamp.to_rfc3339(),
+            command,
+            header.base_dir.to_escaped_string(),
+            stats.map(|s| s.group_count as i64),
+            stats.map(|s| s.total_file_count as i64),
+            stats.map(|s| s.total_file_size.0 as i64),
+            stats.map(|s| s.redundant_file_count as i64),
+            stats.map(|s| s.redundant_file_size.0 as i64),
+            stats.and_then(|s| s.reclaimable_size).map(|n| n.0 as i64),
+            stats.map(|s| s.missing_file_count as i64),
+            stats.map(|s| s.missing_file_size.0 as i64),
+            header.sample_count.map(|n| n as i64),
+            run_stats,
+        ],
+    )
+    .map_err(to_io_error)?;
+    Ok(())
+}
+
+/// Returns owner user id, group id and the modification time of the file.
+/// Returns `None` values if the file cannot be accessed.
+fn file_attributes(path: &Path) -> (Option<i64>, Option<i64>, Option<String>) {
+    let metadata = match fs::symlink_metadata(path.to_path_buf()) {
+        Ok(m) => m,
+        Err(_) => return (None, None, None),
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .map(|t| DateTime::<Local>::from(t).to_rfc3339());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        (
+            Some(metadata.uid() as i64),
+            Some(metadata.gid() as i64),
+            modified,
+        )
+    }
+    #[cfg(not(unix))]
+    {
+        (None, None, modified)
+    }
+}
+
+/// Writes the report into a new SQLite database and then copies the database file
+/// to the output stream.
+pub(crate) fn write_sqlite<G, P>(
+    out: &mut impl Write,
+    header: &ReportHeader,
+    groups: impl IntoIterator<Item = G>,
+) -> io::Result<()>
+where
+    G: AsRef<FileGroup<P>>,
+    P: AsRef<Path>,
+{
+    let db_file = NamedTempFile::new()?;
+    let mut conn = Connection::open(db_file.path()).map_err(to_io_error)?;
+    let tx = conn.transaction().map_err(to_io_error)?;
+    tx.execute_batch(SCHEMA).map_err(to_io_error)?;
+    write_header(&tx, header)?;
+    {
+        let mut insert_group = tx
+            .prepare(
+                "INSERT INTO groups (file_len, file_hash, file_type, transform, zero_filled) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .map_err(to_io_error)?;
+        let mut insert_file = tx
+            .prepare(
+                "INSERT INTO files (group_id, path, dir, name, extension, uid, gid, modified) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )
+            .map_err(to_io_error)?;
+
+        for g in groups {
+            let g = g.as_ref();
+            let group_id = insert_group
+                .insert(params![
+                    g.file_len.0 as i64,
+                    g.file_hash.to_string(),
+                    g.file_type,
+                    g.transform,
+                    g.zero_filled
+                ])
+                .map_err(to_io_error)?;
+            for f in &g.files {
+                let f = f.as_ref();
+                let path_buf = f.to_path_buf();
+                let dir = path_buf.parent().map(|p| p.to_string_lossy().to_string());
+                let name = path_buf
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string());
+                let extension = path_buf
+                    .extension()
+                    .map(|e| e.to_string_lossy().to_string());
+                let (uid, gid, modified) = file_attributes(f);
+                insert_file
+                    .execute(params![
+                        group_id,
+                        f.to_escaped_string(),
+                        dir,
+                        name,
+                        extension,
+                        uid,
+                        gid,
+                        modified
+                    ])
+                    .map_err(to_io_error)?;
+            }
+        }
+    }
+    tx.commit().map_err(to_io_error)?;
+    conn.close().map_err(|(_, e)| to_io_error(e))?;
+
+    let mut db_file = db_file.reopen()?;
+    io::copy(&mut db_file, out)?;
+    Ok(())
+}
+
+/// A file joined with the attributes of its group
+struct FileRow {
+    group_id: i64,
+    file_len: i64,
+    file_hash: String,
+    file_type: Option<String>,
+    transform: Option<String>,
+    zero_filled: bool,
+    path: String,
+}
+
+/// Reads a report from an SQLite database created by `fclones group --format sqlite`.
+///
+/// Because SQLite needs random access to the database file, the stream is first copied to
+/// a temporary file. All groups are loaded into memory at once.
+pub struct SqliteReportReader {
+    conn: Connection,
+    // must be kept alive as long as the connection is open
+    _db_file: NamedTempFile,
+}
+
+impl SqliteReportReader {
+    pub fn new<R: Read>(mut stream: R) -> io::Result<SqliteReportReader> {
+        let mut db_file = NamedTempFile::new()?;
+        io::copy(&mut stream, &mut db_file)?;
+        db_file.flush()?;
+        let conn = Connection::open(db_file.path()).map_err(to_io_error)?;
+        Ok(SqliteReportReader {
+            conn,
+            _db_file: db_file,
+        })
+    }
+
+    fn read_all_groups(&self) -> rusqlite::Result<Vec<FileRow>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT g.id, g.file_len, g.file_hash, g.file_type, g.transform, g.zero_filled, \
+             f.path \
+             FROM groups g JOIN files f ON f.group_id = g.id \
+             ORDER BY g.id, f.id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(FileRow {
+                group_id: row.get(0)?,
+                file_len: row.get(1)?,
+                file_hash: row.get(2)?,
+                file_type: row.get(3)?,
+                transform: row.get(4)?,
+                zero_filled: row.get(5)?,
+                path: row.get(6)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    fn read_all_marks(&self) -> rusqlite::Result<Vec<(String, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT path, action FROM files WHERE action IS NOT NULL")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+}
+
+impl ReportReader for SqliteReportReader {
+    fn read_header(&mut self) -> io::Result<ReportHeader> {
+        let row = self
+            .conn
+            .query_row("SELECT * FROM header", [], |row| {
+                let stats = match row.get::<_, Option<i64>>("group_count")? {
+                    Some(group_count) => Some(FileStats {
+                        group_count: group_count as usize,
+                        total_file_count: row.get::<_, i64>("total_file_count")? as usize,
+                        total_file_size: FileLen(row.get::<_, i64>("total_file_size")? as u64),
+                        redundant_file_count: row.get::<_, i64>("redundant_file_count")? as usize,
+                        redundant_file_size: FileLen(
+                            row.get::<_, i64>("redundant_file_size")? as u64
+                        ),
+                        reclaimable_size: row
+                            .get::<_, Option<i64>>("reclaimable_size")?
+                            .map(|n| FileLen(n as u64)),
+                        missing_file_count: row.get::<_, i64>("missing_file_count")? as usize,
+                        missing_file_size: FileLen(row.get::<_, i64>("missing_file_size")? as u64),
+                    }),
+                    None => None,
+                };
+                Ok((
+                    row.get::<_, String>("version")?,
+                    row.get::<_, String>("timestamp")?,
+                    row.get::<_, String>("command")?,
+                    row.get::<_, String>("base_dir")?,
+                    stats,
+                    row.get::<_, Option<i64>>("sample_count")?,
+                    row.get::<_, Option<String>>("run_stats")?,
+                ))
+            })
+            .optional()
+            .map_err(to_io_error)?;
+
+        let (version, timestamp, command, base_dir, stats, sample_count, run_stats) = row
+            .ok_or_else(|| {
+                invalid_data("Malformed header: The header table is empty".to_owned())
+            })?;
+        let timestamp = DateTime::parse_from_rfc3339(&timestamp).map_err(|e| {
+            invalid_data(format!("Malformed header: Failed to parse timestamp: {e}"))
+        })?;
+        let command: Vec<Arg> = serde_json::from_str(&command)
+            .map_err(|e| invalid_data(format!("Malformed header: Failed to parse command: {e}")))?;
+        let base_dir = Path::from_escaped_string(&base_dir).map_err(|e| {
+            invalid_data(format!(
+                "Malformed header: Invalid base dir {base_dir}: {e}"
+            ))
+        })?;
+        let run_stats = run_stats
+            .map(|s| serde_json::from_str(&s))
+            .transpose()
+            .map_err(|e| {
+                invalid_data(format!("Malformed header: Failed to parse run stats: {e}"))
+            })?;
+
+        Ok(ReportHeader {
+            version,
+            timestamp,
+            command,
+            base_dir,
+            stats,
+            sample_count: sample_count.map(|n| n as usize),
+            run_stats,
+        })
+    }
+
+    fn read_marks(&mut self) -> io::Result<FileMarks> {
+        let mut marks = FileMarks::default();
+        for (path, action) in self.read_all_marks().map_err(to_io_error)? {
+            let path = Path::from_escaped_string(&path)
+                .map_err(|e| invalid_data(format!("Invalid path {path}: {e}")))?;
+            match action.as_str() {
+                "keep" => marks.keep.insert(path),
+                "drop" => marks.drop.insert(path),
+                _ => return Err(invalid_data(format!("Invalid file action: {action}"))),
+            };
+        }
+        Ok(marks)
+    }
+
+    fn read_groups(self: Box<Self>) -> io::Result<Box<GroupIterator>> {
+        let rows = self.read_all_groups().map_err(to_io_error)?;
+        let mut groups: Vec<FileGroup<Path>> = Vec::new();
+        let mut last_group_id = None;
+        for row in rows {
+            let path = Path::from_escaped_string(&row.path)
+                .map_err(|e| invalid_data(format!("Invalid path {}: {e}", row.path)))?;
+            if last_group_id != Some(row.group_id) {
+                let file_hash = row
+                    .file_hash
+                    .parse::<FileHash>()
+                    .map_err(|e| invalid_data(format!("Invalid hash {}: {e}", row.file_hash)))?;
+                groups.push(FileGroup {
+                    file_len: FileLen(row.file_len as u64),
+                    file_hash,
+                    file_type: row.file_type,
+                    transform: row.transform,
+                    zero_filled: row.zero_filled,
+                    shared_extents: HashMap::new(),
+                    files: Vec::new(),
+                });
+                last_group_id = Some(row.group_id);
+            }
+            groups.last_mut().unwrap().files.push(path);
+        }
+        let iter = fallible_iterator::convert(groups.into_iter().map(Ok));
+        Ok(Box::new(iter))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::DateTime;
+    use fallible_iterator::FallibleIterator;
+    use rusqlite::Connection;
+    use tempfile::NamedTempFile;
+
+    use crate::arg::Arg;
+    use crate::file::{FileHash, FileLen};
+    use crate::group::FileGroup;
+    use crate::path::Path;
+    use crate::report::{open_report, FileStats, ReportHeader};
+    use crate::TIMESTAMP_FMT;
+
+    use crate::config::DedupeConfig;
+    use crate::dedupe::{dedupe, run_script, DedupeOp};
+    use crate::log::StdLog;
+    use crate::util::test::{with_dir, write_file};
+
+    use super::write_sqlite;
+
+    fn header() -> ReportHeader {
+        ReportHeader {
+            version: "0.35.0".to_owned(),
+            timestamp: DateTime::parse_from_str("2021-08-27 12:11:23.456 +0000", TIMESTAMP_FMT)
+                .unwrap(),
+            command: vec![Arg::from("fclones"), Arg::from("group"), Arg::from(".")],
+            base_dir: Path::from("/base"),
+            stats: Some(FileStats {
+                group_count: 2,
+                total_file_count: 5,
+                total_file_size: FileLen(700),
+                redundant_file_count: 3,
+                redundant_file_size: FileLen(400),
+                reclaimable_size: Some(FileLen(100)),
+                missing_file_count: 0,
+                missing_file_size: FileLen(0),
+            }),
+            sample_count: None,
+            run_stats: None,
+        }
+    }
+
+    fn groups() -> Vec<FileGroup<Path>> {
+        vec![
+            FileGroup {
+                file_len: FileLen(100),
+                file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+                files: vec![
+                    Path::from("/file1.txt"),
+                    Path::from("/file2.txt"),
+                    Path::from("/file3.txt"),
+                ],
+                ..Default::default()
+            },
+            FileGroup {
+                file_len: FileLen(200),
+                file_hash: FileHash::from(0x0102030405),
+                files: vec![Path::from("/dir/file4.jpg"), Path::from("/dir/file5.jpg")],
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn write_db() -> NamedTempFile {
+        let mut output = NamedTempFile::new().unwrap();
+        write_sqlite(output.as_file_mut(), &header(), groups().iter()).unwrap();
+        output
+    }
+
+    #[test]
+    fn roundtrip() {
+        let db = write_db();
+        let mut reader = open_report(db.reopen().unwrap()).unwrap();
+        assert_eq!(reader.read_header().unwrap(), header());
+        let groups: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups, self::groups());
+    }
+
+    #[test]
+    fn roundtrip_group_attributes() {
+        let groups = vec![
+            FileGroup {
+                file_len: FileLen(200),
+                file_hash: FileHash::from(0x0102030405),
+                file_type: Some("image/jpeg".to_owned()),
+                transform: Some("exiv2 -d a $IN".to_owned()),
+                files: vec![Path::from("/dir/file4.jpg"), Path::from("/dir/file5.jpg")],
+                ..Default::default()
+            },
+            FileGroup {
+                file_len: FileLen(4096),
+                file_hash: FileHash::from(0x0607),
+                zero_filled: true,
+                files: vec![Path::from("/disk1.img"), Path::from("/disk2.img")],
+                ..Default::default()
+            },
+        ];
+        let mut db = NamedTempFile::new().unwrap();
+        write_sqlite(db.as_file_mut(), &header(), groups.iter()).unwrap();
+        let mut reader = open_report(db.reopen().unwrap()).unwrap();
+        reader.read_header().unwrap();
+        let read_groups: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert_eq!(read_groups, groups);
+    }
+
+    #[test]
+    fn deleted_files_are_not_read() {
+        let db = write_db();
+        let conn = Connection::open(db.path()).unwrap();
+        conn.execute(
+            "DELETE FROM files WHERE extension = 'txt' AND name <> 'file1.txt'",
+            [],
+        )
+        .unwrap();
+        conn.execute("DELETE FROM files WHERE dir = '/dir'", [])
+            .unwrap();
+        drop(conn);
+
+        let mut reader = open_report(db.reopen().unwrap()).unwrap();
+        reader.read_header().unwrap();
+        let groups: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].files, vec![Path::from("/file1.txt")]);
+    }
+
+    #[test]
+    fn files_of_deleted_groups_are_not_read() {
+        let db = write_db();
+        let conn = Connection::open(db.path()).unwrap();
+        conn.execute("DELETE FROM groups WHERE file_len = 100", [])
+            .unwrap();
+        drop(conn);
+
+        let mut reader = open_report(db.reopen().unwrap()).unwrap();
+        reader.read_header().unwrap();
+        let groups: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].file_len, FileLen(200));
+    }
+
+    /// Writes a report of three identical files into a database, runs the given SQL on it
+    /// and then removes the redundant files listed in the database.
+    fn remove_with_sql(root: &std::path::Path, sql: &str) {
+        let paths: Vec<_> = (1..=3).map(|i| root.join(format!("file_{i}"))).collect();
+        for p in &paths {
+            write_file(p, "foo");
+        }
+        let group = FileGroup {
+            file_len: FileLen(3),
+            file_hash: FileHash::from(0x1234),
+            files: paths.iter().map(Path::from).collect(),
+            ..Default::default()
+        };
+        let mut db = NamedTempFile::new().unwrap();
+        write_sqlite(db.as_file_mut(), &header(), [group]).unwrap();
+        let conn = Connection::open(db.path()).unwrap();
+        conn.execute_batch(sql).unwrap();
+        drop(conn);
+
+        let mut log = StdLog::new();
+        log.no_progress = true;
+        let mut reader = open_report(db.reopen().unwrap()).unwrap();
+        reader.read_header().unwrap();
+        let config = DedupeConfig {
+            marks: reader.read_marks().unwrap(),
+            ..DedupeConfig::default()
+        };
+        let groups: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        let script = dedupe(groups, DedupeOp::Remove, &config, &log);
+        run_script(script, false, &log);
+    }
+
+    #[test]
+    fn remove_drops_only_files_marked_to_drop() {
+        with_dir("sqlite/remove_marked_drop", |root| {
+            remove_with_sql(
+                root,
+                "UPDATE files SET action = 'drop' WHERE name = 'file_3'",
+            );
+            assert!(root.join("file_1").exists());
+            assert!(root.join("file_2").exists());
+            assert!(!root.join("file_3").exists());
+        });
+    }
+
+    #[test]
+    fn remove_keeps_files_marked_to_keep() {
+        with_dir("sqlite/remove_marked_keep", |root| {
+            remove_with_sql(
+                root,
+                "UPDATE files SET action = 'keep' WHERE name = 'file_2'",
+            );
+            assert!(!root.join("file_1").exists());
+            assert!(root.join("file_2").exists());
+            assert!(!root.join("file_3").exists());
+        });
+    }
+
+    #[test]
+    fn invalid_action_is_rejected() {
+        let db = write_db();
+        let conn = Connection::open(db.path()).unwrap();
+        let result = conn.execute("UPDATE files SET action = 'delete'", []);
+        assert!(result.is_err());
+    }
+}
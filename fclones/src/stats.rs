@@ -0,0 +1,309 @@
+//! Timing and I/O statistics of a run.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::device::DiskDevices;
+use crate::file::FileLen;
+use crate::log::{Log, LogExt};
+use crate::path::Path;
+use crate::phase::Phase;
+
+/// Amount of I/O done while hashing files.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct IoStats {
+    pub files_read: u64,
+    pub bytes_read: FileLen,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+impl IoStats {
+    fn since(&self, earlier: &IoStats) -> IoStats {
+        IoStats {
+            files_read: self.files_read - earlier.files_read,
+            bytes_read: FileLen(self.bytes_read.0 - earlier.bytes_read.0),
+            cache_hits: self.cache_hits - earlier.cache_hits,
+            cache_misses: self.cache_misses - earlier.cache_misses,
+        }
+    }
+}
+
+/// Amount of data read from a single device and the read throughput.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DeviceStats {
+    pub name: String,
+    pub files_read: u64,
+    pub bytes_read: FileLen,
+    /// Wall time of the phases that read data from this device
+    pub wall_time_ms: u64,
+    /// Bytes read per second of `wall_time_ms`
+    pub throughput: FileLen,
+}
+
+impl DeviceStats {
+    fn new(name: String, io: &IoStats, wall_time: Duration) -> DeviceStats {
+        let wall_time_ms = wall_time.as_millis() as u64;
+        DeviceStats {
+            name,
+            files_read: io.files_read,
+            bytes_read: io.bytes_read,
+            wall_time_ms,
+            throughput: FileLen(io.bytes_read.0 * 1000 / wall_time_ms.max(1)),
+        }
+    }
+}
+
+/// Statistics of a single phase of work.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PhaseStats {
+    pub phase: Phase,
+    pub wall_time_ms: u64,
+    #[serde(flatten)]
+    pub io: IoStats,
+    /// Devices that were read from during the phase
+    pub devices: Vec<DeviceStats>,
+}
+
+/// Timing and I/O statistics of the whole run.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RunStats {
+    pub wall_time_ms: u64,
+    pub phases: Vec<PhaseStats>,
+    /// Totals per device, summed over all phases
+    pub devices: Vec<DeviceStats>,
+    /// Files left out of the groups because the transform program failed on them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped_files: Vec<SkippedFile>,
+}
+
+/// A file that could not be compared, e.g. because the transform program
+/// was killed after exceeding the timeout.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SkippedFile {
+    pub path: Path,
+    pub error: String,
+}
+
+/// Counts I/O operations.
+/// Can be updated concurrently from many threads.
+#[derive(Debug, Default)]
+pub(crate) struct IoCounters {
+    files_read: AtomicU64,
+    bytes_read: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+impl IoCounters {
+    pub fn add_read(&self, files: u64, bytes: u64) {
+        self.files_read.fetch_add(files, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> IoStats {
+        IoStats {
+            files_read: self.files_read.load(Ordering::Relaxed),
+            bytes_read: FileLen(self.bytes_read.load(Ordering::Relaxed)),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+thread_local! {
+    static THREAD_READS: Cell<(u64, u64)> = const { Cell::new((0, 0)) }
+}
+
+/// Records a file read by the current thread.
+/// The hasher calls it, so the caller that knows which device the file
+/// belongs to can attribute the read with [`take_thread_reads`].
+pub(crate) fn record_thread_read(bytes: u64) {
+    THREAD_READS.with(|r| {
+        let (files, total_bytes) = r.get();
+        r.set((files + 1, total_bytes.saturating_add(bytes)))
+    })
+}
+
+/// Returns the number of files and bytes read by the current thread since the last call.
+pub(crate) fn take_thread_reads() -> (u64, u64) {
+    THREAD_READS.with(|r| r.replace((0, 0)))
+}
+
+/// State captured at the beginning of a phase.
+pub(crate) struct PhaseStart {
+    phase: Phase,
+    start_time: Instant,
+    io: IoStats,
+    device_io: Vec<IoStats>,
+}
+
+/// Collects statistics of consecutive phases.
+pub(crate) struct StatsCollector {
+    start_time: Instant,
+    phases: Mutex<Vec<(PhaseStats, Duration)>>,
+}
+
+impl StatsCollector {
+    pub fn new() -> StatsCollector {
+        StatsCollector {
+            start_time: Instant::now(),
+            phases: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn begin_phase(&self, phase: Phase, io: &IoCounters, devices: &DiskDevices) -> PhaseStart {
+        PhaseStart {
+            phase,
+            start_time: Instant::now(),
+            io: io.get(),
+            device_io: devices.iter().map(|d| d.io.get()).collect(),
+        }
+    }
+
+    pub fn end_phase(&self, start: PhaseStart, io: &IoCounters, devices: &DiskDevices) {
+        let wall_time = start.start_time.elapsed();
+        let device_stats = devices
+            .iter()
+            .zip(start.device_io.iter())
+            .map(|(d, io_start)| (d, d.io.get().since(io_start)))
+            .filter(|(_, io)| io.files_read > 0)
+            .map(|(d, io)| DeviceStats::new(d.name.to_string_lossy().to_string(), &io, wall_time))
+            .collect();
+        let stats = PhaseStats {
+            phase: start.phase,
+            wall_time_ms: wall_time.as_millis() as u64,
+            io: io.get().since(&start.io),
+            devices: device_stats,
+        };
+        self.phases.lock().unwrap().push((stats, wall_time));
+    }
+
+    pub fn finish(&self) -> RunStats {
+        let phases = self.phases.lock().unwrap();
+        let mut devices: Vec<(String, IoStats, Duration)> = Vec::new();
+        for (phase, wall_time) in phases.iter() {
+            for d in &phase.devices {
+                let pos = match devices.iter().position(|(name, _, _)| *name == d.name) {
+                    Some(pos) => pos,
+                    None => {
+                        devices.push((d.name.clone(), IoStats::default(), Duration::ZERO));
+                        devices.len() - 1
+                    }
+                };
+                let (_, io, time) = &mut devices[pos];
+                io.files_read += d.files_read;
+                io.bytes_read += d.bytes_read;
+                *time += *wall_time;
+            }
+        }
+        RunStats {
+            wall_time_ms: self.start_time.elapsed().as_millis() as u64,
+            phases: phases.iter().map(|(p, _)| p.clone()).collect(),
+            devices: devices
+                .into_iter()
+                .map(|(name, io, time)| DeviceStats::new(name, &io, time))
+                .collect(),
+            skipped_files: Vec::new(),
+        }
+    }
+}
+
+/// Logs a human-readable summary of the run statistics.
+pub fn log_run_stats(log: &dyn Log, stats: &RunStats) {
+    for p in &stats.phases {
+        let mut msg = format!("{} took {}", p.phase.name(), format_millis(p.wall_time_ms));
+        if p.io.files_read > 0 {
+            msg += &format!(", read {} from {} files", p.io.bytes_read, p.io.files_read);
+        }
+        if p.io.cache_hits + p.io.cache_misses > 0 {
+            msg += &format!(
+                ", cache hits: {}, misses: {}",
+                p.io.cache_hits, p.io.cache_misses
+            );
+        }
+        log.info(msg);
+    }
+    for d in &stats.devices {
+        log.info(format!(
+            "Read {} from {} files on device {} in {} ({}/s)",
+            d.bytes_read,
+            d.files_read,
+            d.name,
+            format_millis(d.wall_time_ms),
+            d.throughput
+        ));
+    }
+    if !stats.skipped_files.is_empty() {
+        log.warn(format!(
+            "Skipped {} files the transform program failed on",
+            stats.skipped_files.len()
+        ));
+    }
+    log.info(format!("Total time: {}", format_millis(stats.wall_time_ms)));
+}
+
+fn format_millis(ms: u64) -> String {
+    format!("{:.3} s", ms as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn device_throughput() {
+        let io = IoStats {
+            files_read: 10,
+            bytes_read: FileLen(5000),
+            ..Default::default()
+        };
+        let stats = DeviceStats::new("sda".to_owned(), &io, Duration::from_millis(500));
+        assert_eq!(stats.throughput, FileLen(10000));
+        assert_eq!(stats.wall_time_ms, 500);
+    }
+
+    #[test]
+    fn thread_reads_are_reset_after_take() {
+        record_thread_read(100);
+        record_thread_read(20);
+        assert_eq!(take_thread_reads(), (2, 120));
+        assert_eq!(take_thread_reads(), (0, 0));
+    }
+
+    #[test]
+    fn phase_stats_are_differences_of_counters() {
+        let devices = DiskDevices::single(sysinfo::DiskKind::SSD, 1);
+        let io = IoCounters::default();
+        io.add_read(3, 300);
+        devices[0].io.add_read(3, 300);
+
+        let collector = StatsCollector::new();
+        let start = collector.begin_phase(Phase::GroupByPrefix, &io, &devices);
+        io.add_read(2, 50);
+        io.add_cache_hit();
+        devices[0].io.add_read(2, 50);
+        collector.end_phase(start, &io, &devices);
+
+        let stats = collector.finish();
+        assert_eq!(stats.phases.len(), 1);
+        assert_eq!(stats.phases[0].io.files_read, 2);
+        assert_eq!(stats.phases[0].io.bytes_read, FileLen(50));
+        assert_eq!(stats.phases[0].io.cache_hits, 1);
+        assert_eq!(stats.phases[0].devices.len(), 1);
+        assert_eq!(stats.devices.len(), 1);
+        assert_eq!(stats.devices[0].bytes_read, FileLen(50));
+    }
+}
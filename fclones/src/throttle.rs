@@ -0,0 +1,138 @@
+//! Limiting the rate of I/O operations, so scans can run in the background
+//! without hurting other workloads.
+
+use std::cell::RefCell;
+use std::cmp::max;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How many bytes a metadata operation like `stat` or `read_dir` is charged for
+/// against the bandwidth limit. Such operations usually need to read at least one block.
+const METADATA_OP_COST: u64 = 4096;
+
+/// Spreads the requests evenly in time so that the long-term rate of them
+/// doesn't exceed the configured amount per second.
+/// Shared by all threads accessing the same device.
+#[derive(Debug)]
+pub struct RateLimiter {
+    units_per_sec: u64,
+    /// The earliest time the next request may start
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(units_per_sec: u64) -> RateLimiter {
+        assert!(units_per_sec > 0);
+        RateLimiter {
+            units_per_sec,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Reserves the given amount of units and blocks the current thread until
+    /// the reservation can be honored without exceeding the rate.
+    pub fn acquire(&self, units: u64) {
+        let cost = Duration::from_secs_f64(units as f64 / self.units_per_sec as f64);
+        let now = Instant::now();
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = max(*next, now);
+            *next = start + cost;
+            start
+        };
+        if start > now {
+            sleep(start - now)
+        }
+    }
+}
+
+/// A set of limits applied to the I/O done on a single device.
+/// Usually contains both the limits of the device and the global limits
+/// shared with other devices.
+#[derive(Clone, Debug, Default)]
+pub struct Throttle {
+    bytes: Vec<Arc<RateLimiter>>,
+    ops: Vec<Arc<RateLimiter>>,
+}
+
+impl Throttle {
+    /// Adds a limit of the number of bytes read per second.
+    pub fn limit_bytes(&mut self, limiter: Arc<RateLimiter>) {
+        self.bytes.push(limiter)
+    }
+
+    /// Adds a limit of the number of I/O operations per second.
+    pub fn limit_ops(&mut self, limiter: Arc<RateLimiter>) {
+        self.ops.push(limiter)
+    }
+
+    /// Returns true if there are no limits set.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty() && self.ops.is_empty()
+    }
+
+    /// Accounts for reading a block of file data and waits if needed.
+    pub fn read(&self, bytes: u64) {
+        self.ops.iter().for_each(|l| l.acquire(1));
+        self.bytes.iter().for_each(|l| l.acquire(bytes));
+    }
+
+    /// Accounts for a metadata operation like `stat` or `read_dir` and waits if needed.
+    pub fn metadata_op(&self) {
+        self.read(METADATA_OP_COST)
+    }
+}
+
+thread_local! {
+    static THREAD_THROTTLE: RefCell<Option<Throttle>> = const { RefCell::new(None) }
+}
+
+/// Sets the throttle applied to the reads done by the current thread.
+/// Called when starting the threads of the thread pools dedicated to a device.
+pub(crate) fn set_thread_throttle(throttle: Throttle) {
+    if !throttle.is_empty() {
+        THREAD_THROTTLE.with(|t| t.replace(Some(throttle)));
+    }
+}
+
+/// Accounts for reading a block of file data by the current thread.
+/// Blocks if the thread runs out of its I/O budget.
+/// Does nothing if there is no throttle set for the thread.
+pub(crate) fn throttle_thread_read(bytes: u64) {
+    THREAD_THROTTLE.with(|t| {
+        if let Some(throttle) = t.borrow().as_ref() {
+            throttle.read(bytes)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_delays_requests_exceeding_the_rate() {
+        let limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.acquire(50);
+        }
+        // The first request goes through immediately, the next ones wait 50 ms each
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn throttle_applies_all_limits() {
+        let mut throttle = Throttle::default();
+        assert!(throttle.is_empty());
+        throttle.limit_bytes(Arc::new(RateLimiter::new(1_000_000)));
+        throttle.limit_ops(Arc::new(RateLimiter::new(100)));
+        let start = Instant::now();
+        for _ in 0..4 {
+            throttle.read(10);
+        }
+        // Bandwidth limit is far away, but IOPS limit should kick in
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+}
@@ -4,9 +4,12 @@ use std::fs::{create_dir_all, remove_dir_all, File, OpenOptions};
 use std::io;
 use std::io::Read;
 use std::path::PathBuf;
-use std::process::{Child, Command, Stdio};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use nom::branch::alt;
 use nom::bytes::complete::tag;
@@ -20,6 +23,7 @@ use regex::Regex;
 use uuid::Uuid;
 
 use crate::path::Path;
+use crate::rlimit::limit_child_resources;
 
 /// Controls how we pass data to the child process.
 /// By default, the file to process is sent to the standard input of the child process.
@@ -101,6 +105,20 @@ impl Drop for Output {
     }
 }
 
+/// Limits the time and resources the transform program may use for a single file.
+/// If the program exceeds the limits, it gets killed and the file is skipped.
+#[derive(Clone, Debug, Default)]
+pub struct TransformLimits {
+    /// Maximum wall-clock time of processing a single file
+    pub timeout: Option<Duration>,
+    /// Maximum number of bytes the program may output
+    pub max_output: Option<u64>,
+    /// Maximum size of the virtual memory of the program, in bytes
+    pub max_memory: Option<u64>,
+    /// Maximum CPU time of the program, in seconds
+    pub max_cpu_secs: Option<u64>,
+}
+
 /// Transforms files through an external program.
 /// The `command_str` field contains a path to a program and its space separated arguments.
 /// The command takes a file given in the `$IN` variable and produces an `$OUT` file.
@@ -116,6 +134,8 @@ pub struct Transform {
     pub in_place: bool,
     /// will be set to the name of the program, extracted from the command_str
     pub program: String,
+    /// limits of the time and resources used by the program
+    pub limits: TransformLimits,
 }
 
 impl Transform {
@@ -168,7 +188,12 @@ impl Transform {
         };
 
         // Check if the program is runnable, fail fast if it is not.
-        match Command::new(&program).spawn() {
+        match Command::new(&program)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
             Ok(mut child) => {
                 let _ignore = child.kill();
             }
@@ -186,6 +211,7 @@ impl Transform {
             tmp_dir: Transform::create_temp_dir()?,
             copy: has_in,
             in_place,
+            limits: TransformLimits::default(),
         })
     }
 
@@ -220,7 +246,12 @@ impl Transform {
     pub fn run(&self, input: &Path) -> io::Result<Execution> {
         let (args, input_conf, output_conf) = self.make_args(input);
         let mut command = build_command(&args, &input_conf, &output_conf)?;
-        let result = execute(&mut command, input_conf, output_conf)?;
+        limit_child_resources(
+            &mut command,
+            self.limits.max_memory,
+            self.limits.max_cpu_secs,
+        );
+        let result = execute(&mut command, input_conf, output_conf, &self.limits)?;
         Ok(result)
     }
 
@@ -272,15 +303,92 @@ pub struct Execution {
     pub(crate) child: Arc<Mutex<Child>>,
     pub(crate) out_stream: Box<dyn Read>,
     pub(crate) err_stream: Option<JoinHandle<String>>,
-    _input: Input,   // holds the temporary input file(s) until execution is done
-    _output: Output, // holds the temporary output file(s) until execution is done
+    timed_out: Arc<AtomicBool>,
+    killable: bool,
+    _watchdog: Option<Sender<()>>, // cancels the timeout when dropped
+    _input: Input,                 // holds the temporary input file(s) until execution is done
+    _output: Output,               // holds the temporary output file(s) until execution is done
+}
+
+impl Execution {
+    /// Waits for the program to finish.
+    pub fn wait(&self) -> io::Result<ExitStatus> {
+        wait(&self.child, self.killable)
+    }
+
+    /// Returns true if the program was killed because it exceeded the timeout.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::Relaxed)
+    }
 }
 
 impl Drop for Execution {
     fn drop(&mut self) {
         let mut buf = [0; 4096];
         while let Ok(1..) = self.out_stream.read(&mut buf) {}
-        let _ = self.child.lock().unwrap().wait();
+        let _ = self.wait();
+    }
+}
+
+/// Waits for the child process to finish.
+/// If the child may be killed by another thread in the meantime, polls it instead of
+/// calling `Child::wait`, so the child doesn't stay locked while waiting.
+fn wait(child: &Mutex<Child>, killable: bool) -> io::Result<ExitStatus> {
+    if !killable {
+        return child.lock().unwrap().wait();
+    }
+    let mut delay = Duration::from_millis(1);
+    loop {
+        if let Some(status) = child.lock().unwrap().try_wait()? {
+            return Ok(status);
+        }
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(Duration::from_millis(50));
+    }
+}
+
+/// Kills the child if it doesn't finish in the given time.
+/// Returns the sender that cancels the timeout when dropped.
+fn start_watchdog(
+    child: Arc<Mutex<Child>>,
+    timeout: Duration,
+    timed_out: Arc<AtomicBool>,
+) -> Sender<()> {
+    let (tx, rx) = channel::<()>();
+    std::thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(timeout) {
+            let mut child = child.lock().unwrap();
+            // Don't report timeout if the child managed to finish
+            if let Ok(None) = child.try_wait() {
+                timed_out.store(true, Ordering::Relaxed);
+                let _ = child.kill();
+            }
+        }
+    });
+    tx
+}
+
+/// Reads the output of the child and kills the child when it produces more data than allowed.
+struct LimitedOutput {
+    inner: Box<dyn Read>,
+    child: Arc<Mutex<Child>>,
+    remaining: u64,
+    limit: u64,
+}
+
+impl Read for LimitedOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n as u64 > self.remaining {
+            self.remaining = 0;
+            let _ = self.child.lock().unwrap().kill();
+            return Err(io::Error::other(format!(
+                "output exceeded the limit of {} bytes",
+                self.limit
+            )));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
     }
 }
 
@@ -333,8 +441,19 @@ fn create_named_pipe(_path: &PathBuf) -> io::Result<()> {
 
 /// Spawns the command process, and returns its output as a stream.
 /// The standard error is captured by a background thread and read to a string.
-fn execute(command: &mut Command, input: Input, output: Output) -> io::Result<Execution> {
+fn execute(
+    command: &mut Command,
+    input: Input,
+    output: Output,
+    limits: &TransformLimits,
+) -> io::Result<Execution> {
     let child = Arc::new(Mutex::new(command.spawn()?));
+    // The watchdog and the output limit kill the child from another thread
+    let killable = limits.timeout.is_some() || limits.max_output.is_some();
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let watchdog = limits
+        .timeout
+        .map(|timeout| start_watchdog(child.clone(), timeout, timed_out.clone()));
 
     // We call 'take' to avoid borrowing `child` for longer than a single line.
     // We can't reference stdout/stderr directly, because a mutable borrow of a field
@@ -364,7 +483,7 @@ fn execute(command: &mut Command, input: Input, output: Output) -> io::Result<Ex
             // If those fail, we have no way to report the failure.
             // However if waiting fails here, the child process likely doesn't run, so that's not
             // a problem.
-            let _ignore = child_ref.lock().unwrap().wait();
+            let _ignore = wait(&child_ref, killable);
             let _ignore = OpenOptions::new().write(true).open(output_pipe);
         }
         str
@@ -374,15 +493,27 @@ fn execute(command: &mut Command, input: Input, output: Output) -> io::Result<Ex
         Output::StdOut => Box::new(child_out.unwrap()),
         Output::Named(output) => Box::new(File::open(output)?),
         Output::InPlace(output) => {
-            child.lock().unwrap().wait()?;
+            wait(&child, killable)?;
             Box::new(File::open(output)?)
         }
     };
+    let child_out: Box<dyn Read> = match limits.max_output {
+        Some(limit) => Box::new(LimitedOutput {
+            inner: child_out,
+            child: child.clone(),
+            remaining: limit,
+            limit,
+        }),
+        None => child_out,
+    };
 
     Ok(Execution {
         child,
         out_stream: child_out,
         err_stream: Some(stderr_reaper),
+        timed_out,
+        killable,
+        _watchdog: watchdog,
         _input: input,
         _output: output,
     })
@@ -391,7 +522,7 @@ fn execute(command: &mut Command, input: Input, output: Output) -> io::Result<Ex
 /// Compares the input with a regular expression and returns the first match.
 /// Backported from nom 6.0.0-alpha1. We can't use nom 6.0.0-alpha1 directly,
 /// because it had some issues with our use of functions in pattern.rs.
-fn re_find<'s, E>(re: Regex) -> impl Fn(&'s str) -> IResult<&'s str, &'s str, E>
+pub(crate) fn re_find<'s, E>(re: Regex) -> impl Fn(&'s str) -> IResult<&'s str, &'s str, E>
 where
     E: ParseError<&'s str>,
 {
@@ -501,6 +632,50 @@ mod test {
         })
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn kill_on_timeout() {
+        with_dir("target/test/transform/timeout/", |root| {
+            let mut transform = Transform::new(String::from("sleep 10"), false).unwrap();
+            transform.limits.timeout = Some(Duration::from_millis(100));
+            let input_path = root.join("input.txt");
+            File::create(&input_path)
+                .unwrap()
+                .write_all(b"foo")
+                .unwrap();
+
+            let log = StdLog::default();
+            let hasher = FileHasher::new(HashFn::default(), Some(transform), &log);
+            let input_path = Path::from(input_path);
+            let chunk = FileChunk::new(&input_path, FilePos(0), FileLen::MAX);
+            let start = std::time::Instant::now();
+            let result = hasher.hash_transformed(&chunk, |_| {});
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+            assert!(start.elapsed() < Duration::from_secs(5));
+        })
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn kill_on_output_limit() {
+        with_dir("target/test/transform/output_limit/", |root| {
+            let mut transform = Transform::new(String::from("cat /dev/zero"), false).unwrap();
+            transform.limits.max_output = Some(1000);
+            let input_path = root.join("input.txt");
+            File::create(&input_path)
+                .unwrap()
+                .write_all(b"foo")
+                .unwrap();
+
+            let log = StdLog::default();
+            let hasher = FileHasher::new(HashFn::default(), Some(transform), &log);
+            let input_path = Path::from(input_path);
+            let chunk = FileChunk::new(&input_path, FilePos(0), FileLen(3));
+            let err = hasher.hash_transformed(&chunk, |_| {}).unwrap_err();
+            assert!(err.to_string().contains("exceeded the limit of 1000 bytes"));
+        })
+    }
+
     #[test]
     fn parse_command() {
         let result = super::parse_command("foo bar", |s| OsString::from(s));
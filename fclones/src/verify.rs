@@ -0,0 +1,357 @@
+//! Checking whether the files listed in a report are still the same as when the report
+//! was produced, so that the report can be safely used by `remove`, `link` etc.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use chrono::{DateTime, FixedOffset, Utc};
+use rayon::prelude::*;
+
+use crate::config::GroupConfig;
+use crate::error::Error;
+use crate::file::{FileAccess, FileChunk, FileHash, FileInfo, FileLen, FileMetadata, FilePos};
+use crate::group::{rehash, FileGroup, GroupCtx};
+use crate::log::{Log, LogExt, ProgressBarLength};
+use crate::path::Path;
+use crate::phase::Phase;
+use crate::progress::ProgressTracker;
+
+/// Describes how many files and groups were dropped from the report, and why.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyStats {
+    /// Number of the files checked
+    pub file_count: usize,
+    /// Files that don't exist anymore or can't be accessed
+    pub missing: usize,
+    /// Files of a different size than recorded in the report
+    pub resized: usize,
+    /// Files modified after the report was produced
+    pub modified: usize,
+    /// Files whose contents hash differs from the one in the report
+    pub changed: usize,
+    /// Groups that don't satisfy the replication requirements anymore
+    pub dropped_groups: usize,
+}
+
+impl Display for VerifyStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Verified {} files: {} missing, {} resized, {} modified, {} changed; \
+             dropped {} groups",
+            self.file_count,
+            self.missing,
+            self.resized,
+            self.modified,
+            self.changed,
+            self.dropped_groups
+        )
+    }
+}
+
+/// The result of checking a single file
+enum FileStatus {
+    Valid(FileInfo),
+    Missing,
+    Resized,
+    Modified,
+}
+
+/// Checks whether the files in the groups read from a report are still the same as when
+/// the report was produced, and drops the files that are not.
+/// Then removes the groups that don't match the replication requirements
+/// given by the config anymore.
+///
+/// # Parameters
+/// - `config`: configuration of the `group` run that produced the report
+/// - `timestamp`: time when the report was produced
+/// - `groups`: the groups read from the report
+/// - `rehash_files`: if set, the contents of the files are hashed again and compared to
+///   the hashes in the report; otherwise only the sizes and modification times are checked
+///
+/// Returns the groups that are still valid.
+pub fn verify_groups(
+    config: &GroupConfig,
+    timestamp: DateTime<FixedOffset>,
+    groups: Vec<FileGroup<Path>>,
+    rehash_files: bool,
+    log: &dyn Log,
+) -> Result<(Vec<FileGroup<FileInfo>>, VerifyStats), Error> {
+    if rehash_files && config.skip_content_hash {
+        return Err(Error::from(
+            "Cannot rehash files of a report produced with --skip-content-hash",
+        ));
+    }
+    if rehash_files && config.sample_hash.is_some() {
+        return Err(Error::from(
+            "Cannot rehash files of a report produced with --sample-hash",
+        ));
+    }
+    let mut ctx = GroupCtx::new(config, log)?;
+    let phases = if rehash_files {
+        vec![Phase::Verify, Phase::Hash]
+    } else {
+        vec![Phase::Verify]
+    };
+    ctx.set_phases(phases);
+
+    // If the files were transformed, the lengths in the report are the lengths of the
+    // transformed data, so they can't be compared with the file lengths:
+    let check_len = !config.transforms_data();
+    let mut stats = VerifyStats {
+        file_count: groups.iter().map(|g| g.file_count()).sum(),
+        ..VerifyStats::default()
+    };
+
+    let progress = ctx.phase_progress_bar(
+        Phase::Verify,
+        ProgressBarLength::Items(stats.file_count as u64),
+    );
+    let checked: Vec<(FileGroup<Path>, Vec<FileStatus>)> = groups
+        .into_par_iter()
+        .map(|g| {
+            let status = g
+                .files
+                .iter()
+                .map(|path| {
+                    progress.inc(1);
+                    check_file(&ctx, path, g.file_len, check_len, timestamp, rehash_files)
+                })
+                .collect();
+            (g, status)
+        })
+        .collect();
+    drop(progress);
+
+    let mut groups = Vec::with_capacity(checked.len());
+    for (g, status) in checked {
+        let mut files = Vec::with_capacity(status.len());
+        for s in status {
+            match s {
+                FileStatus::Valid(f) => files.push(f),
+                FileStatus::Missing => stats.missing += 1,
+                FileStatus::Resized => stats.resized += 1,
+                FileStatus::Modified => stats.modified += 1,
+            }
+        }
+        // Only the hashes of groups with more than one file are guaranteed to be computed
+        // from the full contents. Other groups may be skipped early when searching
+        // for unique files.
+        let rehash_group = rehash_files && g.file_count() > 1;
+        groups.push((
+            FileGroup {
+                file_len: g.file_len,
+                file_hash: g.file_hash,
+                file_type: g.file_type,
+                transform: g.transform,
+                zero_filled: g.zero_filled,
+                shared_extents: g.shared_extents,
+                files,
+            },
+            rehash_group,
+        ));
+    }
+
+    if rehash_files {
+        let hashes = hash_files(&ctx, groups.iter().filter(|(_, r)| *r).map(|(g, _)| g));
+        for (g, rehashed) in groups.iter_mut() {
+            if *rehashed {
+                let count = g.files.len();
+                g.files
+                    .retain(|f| hashes.get(&f.path).is_some_and(|h| *h == g.file_hash));
+                stats.changed += count - g.files.len();
+            }
+        }
+    }
+
+    let group_count = groups.len();
+    let groups: Vec<_> = groups
+        .into_iter()
+        .map(|(g, _)| g)
+        .filter(|g| g.matches_strictly(&ctx.group_filter))
+        .collect();
+    stats.dropped_groups = group_count - groups.len();
+    log.info(stats.to_string());
+    Ok((groups, stats))
+}
+
+/// Checks the metadata of a single file listed in the report.
+fn check_file(
+    ctx: &GroupCtx<'_>,
+    path: &Path,
+    len: FileLen,
+    check_len: bool,
+    timestamp: DateTime<FixedOffset>,
+    rehash_files: bool,
+) -> FileStatus {
+    let metadata = match FileMetadata::new(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return FileStatus::Missing,
+        Err(e) => {
+            ctx.log.warn_file(path, &e, &e);
+            return FileStatus::Missing;
+        }
+    };
+    if check_len && metadata.len() != len {
+        return FileStatus::Resized;
+    }
+    // If we rehash, there is no need to look at the modification time.
+    // Files touched after the report was made, but with the same contents are fine.
+    if !rehash_files {
+        let modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+        if modified.map_or(true, |m| m > timestamp) {
+            return FileStatus::Modified;
+        }
+    }
+    FileStatus::Valid(FileInfo::with_metadata(
+        path.clone(),
+        &metadata,
+        &ctx.devices,
+    ))
+}
+
+/// Computes the hashes of the files in the given groups, the same way as `group` does.
+/// Files that can't be read are not included in the result.
+fn hash_files<'a>(
+    ctx: &GroupCtx<'_>,
+    groups: impl Iterator<Item = &'a FileGroup<FileInfo>>,
+) -> HashMap<Path, FileHash> {
+    let files: Vec<FileInfo> = groups.flat_map(|g| g.files.iter().cloned()).collect();
+    let total_len = files.iter().map(|f| f.len).sum::<FileLen>();
+    let progress = &ctx.phase_progress_bar(Phase::Hash, ProgressBarLength::Bytes(total_len.0));
+
+    let groups = files
+        .into_iter()
+        .map(|f| FileGroup {
+            file_len: f.len,
+            file_hash: FileHash::default(),
+            files: vec![f],
+            ..Default::default()
+        })
+        .collect();
+    let transform = ctx.config.transforms_data();
+    let groups = rehash(
+        groups,
+        |_| true,
+        |_| true,
+        &ctx.devices,
+        FileAccess::Sequential,
+        |(fi, _)| {
+            let chunk = FileChunk::new(&fi.path, FilePos(0), fi.len);
+            let progress = |bytes_read: usize| progress.inc(bytes_read as u64);
+            if transform {
+                ctx.hasher
+                    .hash_transformed_or_log_err(&chunk, progress)
+                    .map(|(_, hash)| hash)
+            } else {
+                ctx.hasher.hash_file_or_log_err(&chunk, progress)
+            }
+        },
+    );
+    groups
+        .into_iter()
+        .flat_map(|g| {
+            let hash = g.file_hash;
+            g.files.into_iter().map(move |f| (f.path, hash.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{remove_file, write};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::group::group_files;
+    use crate::log::StdLog;
+    use crate::util::test::with_dir;
+    use chrono::Local;
+
+    use super::*;
+
+    fn test_log() -> StdLog {
+        let mut log = StdLog::new();
+        log.no_progress = true;
+        log
+    }
+
+    fn now() -> DateTime<FixedOffset> {
+        let now = Local::now();
+        DateTime::from_naive_utc_and_offset(now.naive_utc(), *now.offset())
+    }
+
+    #[test]
+    fn verify_drops_missing_resized_and_modified_files() {
+        with_dir("verify/metadata", |root| {
+            let files: Vec<_> = (0..6).map(|i| root.join(format!("file{i}"))).collect();
+            for f in &files[0..3] {
+                write(f, b"foo").unwrap();
+            }
+            for f in &files[3..6] {
+                write(f, b"bar").unwrap();
+            }
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                ..GroupConfig::default()
+            };
+            let groups: Vec<_> = group_files(&config, &log)
+                .unwrap()
+                .into_iter()
+                .map(|g| g.map(|f| f.path))
+                .collect();
+            assert_eq!(groups.len(), 2);
+            sleep(Duration::from_millis(10));
+            let timestamp = now();
+            sleep(Duration::from_millis(10));
+
+            remove_file(&files[0]).unwrap();
+            write(&files[1], b"foobar").unwrap();
+            write(&files[3], b"baz").unwrap();
+            write(&files[4], b"baz").unwrap();
+
+            let (result, stats) = verify_groups(&config, timestamp, groups, false, &log).unwrap();
+            assert_eq!(result.len(), 0);
+            assert_eq!(stats.missing, 1);
+            assert_eq!(stats.resized, 1);
+            assert_eq!(stats.modified, 2);
+            assert_eq!(stats.dropped_groups, 2);
+        });
+    }
+
+    #[test]
+    fn verify_with_rehash_drops_changed_files() {
+        with_dir("verify/rehash", |root| {
+            let files: Vec<_> = (0..3).map(|i| root.join(format!("file{i}"))).collect();
+            for f in &files {
+                write(f, b"foo").unwrap();
+            }
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                ..GroupConfig::default()
+            };
+            let groups: Vec<_> = group_files(&config, &log)
+                .unwrap()
+                .into_iter()
+                .map(|g| g.map(|f| f.path))
+                .collect();
+            let timestamp = now();
+            sleep(Duration::from_millis(10));
+
+            // Touched, but not changed:
+            write(&files[0], b"foo").unwrap();
+            // Changed, but the length is the same:
+            write(&files[1], b"bar").unwrap();
+
+            let (result, stats) = verify_groups(&config, timestamp, groups, true, &log).unwrap();
+            assert_eq!(result.len(), 1);
+            assert_eq!(result[0].files.len(), 2);
+            assert_eq!(stats.changed, 1);
+            assert_eq!(stats.modified, 0);
+            assert_eq!(stats.dropped_groups, 0);
+        });
+    }
+}
@@ -1,6 +1,7 @@
 use std::default::Default;
 use std::env::current_dir;
 use std::fs::{read_link, symlink_metadata, DirEntry, FileType, ReadDir};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::{fs, io};
 
@@ -9,7 +10,9 @@ use dashmap::DashSet;
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use rayon::Scope;
 
+use crate::expr::{FileAttrs, FileExpr};
 use crate::log::{Log, LogExt};
+use crate::metadata::MetadataFilter;
 use crate::path::Path;
 use crate::selector::PathSelector;
 
@@ -61,11 +64,15 @@ impl Entry {
     }
 }
 
+/// Name of the fclones-specific ignore file, looked up in every visited directory
+const IGNORE_FILE_NAME: &str = ".fclonesignore";
+
 #[derive(Clone)]
 struct IgnoreStack(Arc<Vec<Gitignore>>);
 
 impl IgnoreStack {
-    /// Returns ignore stack initialized with global gitignore settings.
+    /// Returns ignore stack initialized with global gitignore settings
+    /// and the global fclones ignore file.
     fn new(log: Option<&dyn Log>) -> Self {
         let gitignore = GitignoreBuilder::new("/").build_global();
         if let Some(err) = gitignore.1 {
@@ -73,7 +80,32 @@ impl IgnoreStack {
                 log.warn(format!("Error loading global gitignore rules: {err}"))
             }
         }
-        IgnoreStack(Arc::new(vec![gitignore.0]))
+        let mut stack = vec![gitignore.0];
+        if let Some(path) = Self::global_ignore_file().filter(|p| p.is_file()) {
+            let mut builder = GitignoreBuilder::new("/");
+            let result = match builder.add(&path) {
+                Some(err) => Err(err),
+                None => builder.build(),
+            };
+            match result {
+                Ok(gitignore) => stack.push(gitignore),
+                Err(err) => {
+                    if let Some(log) = log {
+                        log.warn(format!(
+                            "Error while loading ignore file {}: {}",
+                            path.display(),
+                            err
+                        ))
+                    }
+                }
+            }
+        }
+        IgnoreStack(Arc::new(stack))
+    }
+
+    /// Returns the location of the global ignore file: `fclones/ignore` in the user config dir.
+    pub fn global_ignore_file() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("fclones").join("ignore"))
     }
 
     /// Returns an empty gitignore stack that ignores no files.
@@ -81,17 +113,12 @@ impl IgnoreStack {
         IgnoreStack(Arc::new(vec![]))
     }
 
-    /// If .gitignore file exists in given dir, creates a `Gitignore` struct for it
-    /// and returns the stack with the new `Gitignore` appended. Otherwise returns a cloned self.
-    pub fn push(&self, dir: &Path, log: Option<&dyn Log>) -> IgnoreStack {
-        let mut path = Arc::new(dir.clone()).resolve(Path::from(".gitignore"));
-        let mut path_buf = path.to_path_buf();
+    /// Loads the ignore rules from given file, if the file exists.
+    fn load(dir: &Path, name: &str, log: Option<&dyn Log>) -> Option<Gitignore> {
+        let path = Arc::new(dir.clone()).resolve(Path::from(name));
+        let path_buf = path.to_path_buf();
         if !path_buf.is_file() {
-            path = Arc::new(dir.clone()).resolve(Path::from(".fdignore"));
-            path_buf = path.to_path_buf();
-        }
-        if !path_buf.is_file() {
-            return self.clone();
+            return None;
         }
         let gitignore = Gitignore::new(&path_buf);
         if let Some(err) = gitignore.1 {
@@ -103,8 +130,22 @@ impl IgnoreStack {
                 ))
             }
         }
+        Some(gitignore.0)
+    }
+
+    /// If .gitignore (or .fdignore) or .fclonesignore files exist in given dir,
+    /// creates `Gitignore` structs for them and returns the stack with them appended.
+    /// Otherwise returns a cloned self.
+    pub fn push(&self, dir: &Path, log: Option<&dyn Log>) -> IgnoreStack {
+        let gitignore =
+            Self::load(dir, ".gitignore", log).or_else(|| Self::load(dir, ".fdignore", log));
+        let fclonesignore = Self::load(dir, IGNORE_FILE_NAME, log);
+        if gitignore.is_none() && fclonesignore.is_none() {
+            return self.clone();
+        }
         let mut stack = self.0.as_ref().clone();
-        stack.push(gitignore.0);
+        stack.extend(gitignore);
+        stack.extend(fclonesignore);
         IgnoreStack(Arc::new(stack))
     }
 
@@ -137,14 +178,23 @@ pub struct Walk<'a> {
     pub follow_links: bool,
     /// Don't follow symlinks to files, but report them.
     pub report_links: bool,
-    /// Don't honor .gitignore and .fdignore.
+    /// Don't honor .gitignore, .fdignore, .fclonesignore and the global ignore file.
     pub no_ignore: bool,
     /// Don't leave the fs of the root paths.
     pub one_fs: bool,
     /// Controls selecting or ignoring files by matching file and path names with regexes / globs.
     pub path_selector: PathSelector,
+    /// Selects files by metadata like modification time, owner or permissions.
+    pub metadata_filter: MetadataFilter,
+    /// Selects files matching an expression given with `--where`.
+    pub file_expr: Option<FileExpr>,
     /// The function to call for each visited file. The directories are not reported.
     pub on_visit: &'a (dyn Fn(&Path) + Sync + Send),
+    /// The function to call for each visited directory, before reading its entries.
+    pub on_dir: &'a (dyn Fn(&Path) + Sync + Send),
+    /// The function to call before reading a directory or metadata of a file.
+    /// May block to limit the rate of I/O.
+    pub throttle: &'a (dyn Fn(&Path) + Sync + Send),
     /// Warnings about inaccessible files or dirs are logged here, if defined.
     pub log: Option<&'a dyn Log>,
 }
@@ -168,7 +218,11 @@ impl<'a> Walk<'a> {
             no_ignore: false,
             one_fs: false,
             path_selector: PathSelector::new(base_dir),
+            metadata_filter: MetadataFilter::default(),
+            file_expr: None,
             on_visit: &|_| {},
+            on_dir: &|_| {},
+            throttle: &|_| {},
             log: None,
         }
     }
@@ -213,11 +267,15 @@ impl<'a> Walk<'a> {
                     Ok(_) => {
                         let dev = FileId::new(&p).map(|f| f.device);
                         match dev {
-                            Err(err) if self.one_fs => self.log_warn(format!(
-                                "Failed to get device information for {}: {}",
-                                p.display(),
-                                err
-                            )),
+                            Err(err) if self.one_fs => self.log_warn_file(
+                                &p,
+                                &err,
+                                format!(
+                                    "Failed to get device information for {}: {}",
+                                    p.display(),
+                                    err
+                                ),
+                            ),
                             _ => {
                                 let dev = dev.unwrap_or_default();
                                 let state = &state;
@@ -228,7 +286,11 @@ impl<'a> Walk<'a> {
                         }
                     }
                     Err(err) => {
-                        self.log_warn(format!("Cannot stat {}: {}", p.display(), err));
+                        self.log_warn_file(
+                            &p,
+                            &err,
+                            format!("Cannot stat {}: {}", p.display(), err),
+                        );
                     }
                 }
             }
@@ -249,8 +311,15 @@ impl<'a> Walk<'a> {
         's: 'w,
     {
         if self.path_selector.matches_dir(&path) {
+            (self.throttle)(&path);
             Entry::from_path(path.clone())
-                .map_err(|e| self.log_warn(format!("Failed to stat {}: {}", path.display(), e)))
+                .map_err(|e| {
+                    self.log_warn_file(
+                        &path,
+                        &e,
+                        format!("Failed to stat {}: {}", path.display(), e),
+                    )
+                })
                 .into_iter()
                 .for_each(|entry| {
                     self.visit_entry(entry, dev, scope, level, gitignore.clone(), state)
@@ -296,7 +365,7 @@ impl<'a> Walk<'a> {
         }
 
         match entry.tpe {
-            EntryType::File => self.visit_file(entry.path, state),
+            EntryType::File => self.visit_file(entry.path, level, state),
             EntryType::Dir => self.visit_dir(entry.path, dev, scope, level, gitignore, state),
             EntryType::SymLink => self.visit_link(entry.path, dev, scope, level, gitignore, state),
             EntryType::Other => {}
@@ -304,15 +373,46 @@ impl<'a> Walk<'a> {
     }
 
     /// If file matches selection criteria, sends it to the consumer
-    fn visit_file<F>(&self, path: Path, state: &WalkState<F>)
+    fn visit_file<F>(&self, path: Path, level: usize, state: &WalkState<F>)
     where
         F: Fn(Path) + Sync + Send,
     {
-        if self.path_selector.matches_full_path(&path) {
+        if self.path_selector.matches_full_path(&path) && self.matches_attrs(&path, level) {
             (state.consumer)(path)
         }
     }
 
+    /// Returns true if the file matches `metadata_filter` and `file_expr`.
+    /// Doesn't fetch the metadata if none of the filters need it.
+    fn matches_attrs(&self, path: &Path, level: usize) -> bool {
+        let needs_metadata = !self.metadata_filter.is_empty()
+            || self.file_expr.as_ref().is_some_and(|e| e.needs_metadata());
+        let metadata = if needs_metadata {
+            (self.throttle)(path);
+            match fs::metadata(path.to_path_buf()) {
+                Ok(metadata) => Some(metadata),
+                Err(e) => {
+                    self.log_warn_file(
+                        path,
+                        &e,
+                        format!("Failed to stat {}: {}", path.display(), e),
+                    );
+                    return false;
+                }
+            }
+        } else {
+            None
+        };
+        metadata.iter().all(|m| self.metadata_filter.matches(m))
+            && self.file_expr.iter().all(|e| {
+                e.matches(&FileAttrs {
+                    path,
+                    depth: level,
+                    metadata: metadata.as_ref(),
+                })
+            })
+    }
+
     /// Resolves a symbolic link.
     /// If `follow_links` is set to false, does nothing.
     fn visit_link<'s, 'w, F>(
@@ -329,13 +429,19 @@ impl<'a> Walk<'a> {
     {
         if self.follow_links || self.report_links {
             match self.resolve_link(&path) {
-                Ok((_, EntryType::File)) if self.report_links => self.visit_file(path, state),
+                Ok((_, EntryType::File)) if self.report_links => {
+                    self.visit_file(path, level, state)
+                }
                 Ok((target, _)) => {
                     if self.follow_links && (!self.one_fs || self.same_fs(&target, dev)) {
                         self.visit_path(target, dev, scope, level, gitignore, state);
                     }
                 }
-                Err(e) => self.log_warn(format!("Failed to read link {}: {}", path.display(), e)),
+                Err(e) => self.log_warn_file(
+                    &path,
+                    &e,
+                    format!("Failed to read link {}: {}", path.display(), e),
+                ),
             }
         }
     }
@@ -370,6 +476,8 @@ impl<'a> Walk<'a> {
             gitignore.push(&path, self.log)
         };
 
+        (self.on_dir)(&path);
+        (self.throttle)(&path);
         match fs::read_dir(path.to_path_buf()) {
             Ok(rd) => {
                 for entry in Self::sorted_entries(path, rd) {
@@ -379,7 +487,11 @@ impl<'a> Walk<'a> {
                     })
                 }
             }
-            Err(e) => self.log_warn(format!("Failed to read dir {}: {}", path.display(), e)),
+            Err(e) => self.log_warn_file(
+                &path,
+                &e,
+                format!("Failed to read dir {}: {}", path.display(), e),
+            ),
         }
     }
 
@@ -420,6 +532,7 @@ impl<'a> Walk<'a> {
 
     /// Returns the absolute target path of a symbolic link with the type of the target
     fn resolve_link(&self, link: &Path) -> io::Result<(Path, EntryType)> {
+        (self.throttle)(link);
         let link_buf = link.to_path_buf();
         let target = read_link(&link_buf)?;
         let entry_type = EntryType::from(link_buf.metadata()?.file_type());
@@ -437,11 +550,11 @@ impl<'a> Walk<'a> {
         match FileId::new(path) {
             Ok(file_id) => file_id.device == device,
             Err(err) => {
-                self.log_warn(format!(
-                    "Cannot read device id of {}: {}",
-                    path.display(),
-                    err
-                ));
+                self.log_warn_file(
+                    path,
+                    &err,
+                    format!("Cannot read device id of {}: {}", path.display(), err),
+                );
                 false
             }
         }
@@ -469,6 +582,11 @@ impl<'a> Walk<'a> {
     fn log_warn(&self, msg: String) {
         self.log.iter().for_each(|l| l.warn(&msg))
     }
+
+    /// Logs a warning caused by a failed operation on the given path
+    fn log_warn_file(&self, path: &Path, error: &io::Error, msg: String) {
+        self.log.iter().for_each(|l| l.warn_file(path, error, &msg))
+    }
 }
 
 impl Default for Walk<'_> {
@@ -662,6 +780,47 @@ mod test {
         respect_ignore("target/test/walk/fdignore/", ".fdignore")
     }
 
+    #[test]
+    fn respect_fclonesignore() {
+        respect_ignore("target/test/walk/fclonesignore/", ".fclonesignore")
+    }
+
+    #[test]
+    fn respect_fclonesignore_together_with_gitignore() {
+        with_dir("target/test/walk/fclonesignore_gitignore/", |test_root| {
+            std::fs::write(test_root.join(".gitignore"), "*.log\n").unwrap();
+            std::fs::write(test_root.join(".fclonesignore"), "*.tmp\n").unwrap();
+            create_file(&test_root.join("file.log"));
+            create_file(&test_root.join("file.tmp"));
+            create_file(&test_root.join("file.txt"));
+
+            let walk = Walk::new();
+            assert_eq!(
+                run_walk(walk, test_root.clone()),
+                vec![test_root.join("file.txt")]
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn filter_by_metadata() {
+        use crate::metadata::PermSpec;
+        use std::fs::{set_permissions, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        with_dir("target/test/walk/metadata/", |test_root| {
+            let file1 = test_root.join("file1.txt");
+            let file2 = test_root.join("file2.txt");
+            File::create(&file1).unwrap();
+            File::create(&file2).unwrap();
+            set_permissions(&file2, Permissions::from_mode(0o4755)).unwrap();
+            let mut walk = Walk::new();
+            walk.metadata_filter.excluded_perms = vec![PermSpec::All(0o4000)];
+            assert_eq!(run_walk(walk, test_root.clone()), vec![file1]);
+        });
+    }
+
     fn run_walk(walk: Walk, root: PathBuf) -> Vec<PathBuf> {
         let results = Mutex::new(Vec::new());
         walk.run(vec![Path::from(root)], |path| {
@@ -0,0 +1,541 @@
+//! Watching directories for changes and reporting new duplicates as they appear.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::rename;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::config::GroupConfig;
+use crate::error::Error;
+use crate::file::{file_info_or_log_err, FileChunk, FileHash, FileInfo, FileLen, FilePos};
+use crate::group::{write_report, FileGroup, GroupCtx};
+use crate::log::{Log, LogExt};
+use crate::path::Path;
+
+/// Events we need to keep the index up-to-date.
+/// New files are added after they are closed, so we don't hash files that are still written.
+fn watch_flags() -> AddWatchFlags {
+    AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_ONLYDIR
+}
+
+/// Emitted when a new or modified file becomes a member of a reported group,
+/// e.g. when it duplicates an existing file.
+#[derive(Debug, Serialize)]
+pub struct WatchEvent {
+    pub event: &'static str,
+    pub path: Path,
+    pub group: FileGroup<Path>,
+}
+
+struct IndexedFile {
+    info: FileInfo,
+    /// Hash of the whole file, computed only if there are other files of the same size
+    hash: Option<FileHash>,
+}
+
+/// Keeps the files found so far grouped by size.
+#[derive(Default)]
+struct Index {
+    by_len: HashMap<FileLen, Vec<IndexedFile>>,
+    /// Sizes of the files by their paths.
+    /// Ordered by `PathBuf`, so the files under the same directory are next to each other.
+    lens: BTreeMap<PathBuf, (Path, FileLen)>,
+}
+
+impl Index {
+    fn insert(&mut self, info: FileInfo) {
+        self.lens
+            .insert(info.path.to_path_buf(), (info.path.clone(), info.len));
+        self.by_len
+            .entry(info.len)
+            .or_default()
+            .push(IndexedFile { info, hash: None });
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some((_, len)) = self.lens.remove(&path.to_path_buf()) {
+            if let Some(files) = self.by_len.get_mut(&len) {
+                files.retain(|f| &f.info.path != path);
+                if files.is_empty() {
+                    self.by_len.remove(&len);
+                }
+            }
+        }
+    }
+
+    /// Returns the sizes of the file at `path` or of all files under `path` if it is a directory.
+    fn lens_under(&self, path: &Path, is_dir: bool) -> Vec<(Path, FileLen)> {
+        let path = path.to_path_buf();
+        if !is_dir {
+            return self.lens.get(&path).cloned().into_iter().collect();
+        }
+        self.lens
+            .range(path.clone()..)
+            .take_while(|(p, _)| p.starts_with(&path))
+            .map(|(_, f)| f.clone())
+            .collect()
+    }
+
+    /// Returns the groups of files of given size with the same hash.
+    /// If there is only one file of given size, its hash is not computed and set to 0.
+    fn groups(&self, len: FileLen) -> Vec<FileGroup<&FileInfo>> {
+        let mut groups: HashMap<FileHash, Vec<&FileInfo>> = HashMap::new();
+        for f in self.by_len.get(&len).into_iter().flatten() {
+            let hash = f.hash.clone().unwrap_or_else(|| FileHash::from(0));
+            groups.entry(hash).or_default().push(&f.info);
+        }
+        groups
+            .into_iter()
+            .map(|(file_hash, files)| FileGroup {
+                file_len: len,
+                file_hash,
+                files,
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+/// Keeps an index of files in the watched directories and updates it on file system events.
+pub struct Watcher<'a> {
+    ctx: GroupCtx<'a>,
+    inotify: Inotify,
+    dirs: Mutex<HashMap<WatchDescriptor, Arc<Path>>>,
+    index: Index,
+    /// Paths in the reported groups of the sizes affected by the current batch of changes,
+    /// as they were before the changes
+    before: HashMap<FileLen, HashSet<Path>>,
+    /// Files added or modified in the current batch of changes
+    added: HashSet<Path>,
+    /// Set if the index changed since the report was last written
+    dirty: bool,
+}
+
+impl<'a> Watcher<'a> {
+    pub fn new(config: &'a GroupConfig, log: &'a dyn Log) -> Result<Watcher<'a>, Error> {
+        let ctx = GroupCtx::new(config, log)?;
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC)
+            .map_err(|e| Error::new(format!("Failed to initialize inotify: {e}")))?;
+        Ok(Watcher {
+            ctx,
+            inotify,
+            dirs: Mutex::new(HashMap::new()),
+            index: Index::default(),
+            before: HashMap::new(),
+            added: HashSet::new(),
+            dirty: true,
+        })
+    }
+
+    /// Scans the input directories and starts watching them.
+    /// Returns the number of watched directories.
+    pub fn scan(&mut self) -> usize {
+        let roots = self.ctx.config.input_paths().collect();
+        self.add(roots);
+        self.update_hashes();
+        self.before.clear();
+        self.added.clear();
+        self.dirs.lock().unwrap().len()
+    }
+
+    /// Returns the groups of files selected by the replication options given in the config
+    pub fn groups(&self) -> Vec<FileGroup<FileInfo>> {
+        let filter = &self.ctx.group_filter;
+        let mut groups: Vec<_> = self
+            .index
+            .by_len
+            .keys()
+            .flat_map(|&len| self.index.groups(len))
+            .filter(|g| g.matches_strictly(filter))
+            .map(|g| g.map(|f| f.clone()))
+            .collect();
+        groups.sort_by_key(|g| Reverse((g.file_len, g.file_hash.u128_prefix())));
+        groups
+            .iter_mut()
+            .for_each(|g| g.sort_by_path(&filter.root_paths));
+        groups
+    }
+
+    /// Blocks until some changes happen in the watched directories,
+    /// then updates the index and returns the files that became members of reported groups.
+    pub fn process_events(&mut self) -> Result<Vec<WatchEvent>, Error> {
+        let events = self
+            .inotify
+            .read_events()
+            .map_err(|e| Error::new(format!("Failed to read file system events: {e}")))?;
+        let overflow = events
+            .iter()
+            .any(|e| e.mask.contains(AddWatchFlags::IN_Q_OVERFLOW));
+        for event in events {
+            self.handle_event(event)
+        }
+        if overflow {
+            self.rescan();
+        }
+        self.update_hashes();
+        self.dirty |= !self.before.is_empty();
+        Ok(self.finish_batch())
+    }
+
+    /// Processes the events forever.
+    /// Writes the events to `out` and rewrites the report after each batch of changes,
+    /// if the output file is configured.
+    pub fn run(&mut self, out: &mut impl Write) -> Result<(), Error> {
+        self.write_report()?;
+        loop {
+            let events = self.process_events()?;
+            for e in events.iter() {
+                self.ctx.log.info(format!(
+                    "New duplicate: {} ({} files)",
+                    e.path.display(),
+                    e.group.files.len()
+                ));
+                let line = serde_json::to_string(e).map_err(|e| Error::new(e.to_string()))?;
+                writeln!(out, "{line}")
+                    .and_then(|_| out.flush())
+                    .map_err(|e| Error::new(format!("Failed to write event: {e}")))?;
+            }
+            self.write_report()?;
+        }
+    }
+
+    /// Rewrites the report file, if configured.
+    /// The report is written to a temporary file first, so readers never see a partial report.
+    fn write_report(&mut self) -> Result<(), Error> {
+        let Some(output) = &self.ctx.config.output else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        self.dirty = false;
+        let mut tmp = output.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut config = self.ctx.config.clone();
+        config.output = Some(tmp.clone().into());
+        write_report(&config, self.ctx.log, &self.groups())
+            .and_then(|_| rename(&tmp, output))
+            .map_err(|e| Error::new(format!("Failed to write report: {e}")))
+    }
+
+    fn handle_event(&mut self, event: InotifyEvent) {
+        if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+            return;
+        }
+        if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+            self.dirs.lock().unwrap().remove(&event.wd);
+            return;
+        }
+        let dir = match self.dirs.lock().unwrap().get(&event.wd) {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        let Some(name) = event.name else {
+            return;
+        };
+        let path = dir.join(Path::from(name));
+        let is_dir = event.mask.contains(AddWatchFlags::IN_ISDIR);
+        self.remove(&path, is_dir);
+
+        // Regular files are added when they are closed after writing, or moved in.
+        // New directories must be scanned immediately, because files might have been
+        // created in them before we started watching them.
+        if event.mask.contains(AddWatchFlags::IN_CLOSE_WRITE)
+            || event.mask.contains(AddWatchFlags::IN_MOVED_TO)
+            || (is_dir && event.mask.contains(AddWatchFlags::IN_CREATE))
+        {
+            self.add(vec![path]);
+        }
+    }
+
+    /// Recovers from an overflow of the event queue, which means some events were lost.
+    ///
+    /// The kernel doesn't tell which directories the lost events belong to,
+    /// so all input directories are scanned again.
+    fn rescan(&mut self) {
+        self.ctx
+            .log
+            .warn("Event queue overflow. Rescanning all directories.");
+        let lens: Vec<_> = self.index.by_len.keys().copied().collect();
+        lens.into_iter().for_each(|len| self.touch(len));
+        self.index = Index::default();
+        let roots = self.ctx.config.input_paths().collect();
+        self.add(roots);
+    }
+
+    /// Walks the given paths, adds the matching files to the index
+    /// and starts watching the directories.
+    fn add(&mut self, paths: Vec<Path>) {
+        let found = Mutex::new(Vec::new());
+        {
+            let on_dir = |dir: &Path| self.watch_dir(dir);
+            let mut walk = self.ctx.walk();
+            walk.on_dir = &on_dir;
+            walk.run(paths, |path| {
+                if let Some(info) = file_info_or_log_err(path, &self.ctx.devices, self.ctx.log) {
+                    if self.ctx.matches_size(info.len) {
+                        found.lock().unwrap().push(info)
+                    }
+                }
+            });
+        }
+        for info in found.into_inner().unwrap() {
+            self.index.remove(&info.path);
+            self.touch(info.len);
+            self.added.insert(info.path.clone());
+            self.index.insert(info);
+        }
+    }
+
+    /// Removes the file or all files in the directory from the index.
+    fn remove(&mut self, path: &Path, is_dir: bool) {
+        for (path, len) in self.index.lens_under(path, is_dir) {
+            self.touch(len);
+            self.index.remove(&path);
+        }
+        if is_dir {
+            // The directory might have been moved elsewhere, so the old watches
+            // would report wrong paths:
+            let mut dirs = self.dirs.lock().unwrap();
+            dirs.retain(|wd, dir| {
+                let remove = path.is_prefix_of(dir);
+                if remove {
+                    let _ = self.inotify.rm_watch(*wd);
+                }
+                !remove
+            });
+        }
+    }
+
+    fn watch_dir(&self, dir: &Path) {
+        match self.inotify.add_watch(&dir.to_path_buf(), watch_flags()) {
+            Ok(wd) => {
+                self.dirs.lock().unwrap().insert(wd, Arc::new(dir.clone()));
+            }
+            Err(e) => self.ctx.log.warn_file(
+                dir,
+                &io::Error::from(e),
+                format!("Failed to watch directory {}: {}", dir.display(), e),
+            ),
+        }
+    }
+
+    /// Remembers which paths of given size were reported before the current batch of changes.
+    fn touch(&mut self, len: FileLen) {
+        if !self.before.contains_key(&len) {
+            let paths = self.reported_paths(len);
+            self.before.insert(len, paths);
+        }
+    }
+
+    fn reported_paths(&self, len: FileLen) -> HashSet<Path> {
+        self.index
+            .groups(len)
+            .into_iter()
+            .filter(|g| g.matches_strictly(&self.ctx.group_filter))
+            .flat_map(|g| g.files.into_iter().map(|f| f.path.clone()))
+            .collect()
+    }
+
+    /// Computes the missing hashes of the files of the sizes affected by the current batch.
+    /// Files that can't be hashed are removed from the index.
+    fn update_hashes(&mut self) {
+        let hasher = &self.ctx.hasher;
+        for len in self.before.keys() {
+            let Some(files) = self.index.by_len.get_mut(len) else {
+                continue;
+            };
+            if files.len() < 2 {
+                continue;
+            }
+            files
+                .par_iter_mut()
+                .filter(|f| f.hash.is_none())
+                .for_each(|f| {
+                    let chunk = FileChunk::new(&f.info.path, FilePos(0), f.info.len);
+                    f.hash = hasher.hash_file_or_log_err(&chunk, |_| {});
+                });
+            let failed: Vec<_> = files
+                .iter()
+                .filter(|f| f.hash.is_none())
+                .map(|f| f.info.path.clone())
+                .collect();
+            failed.iter().for_each(|p| self.index.remove(p));
+        }
+    }
+
+    /// Returns the events for the new or modified files that became members
+    /// of the reported groups in the current batch of changes.
+    fn finish_batch(&mut self) -> Vec<WatchEvent> {
+        let added = std::mem::take(&mut self.added);
+        let mut events = Vec::new();
+        for (len, before) in std::mem::take(&mut self.before) {
+            for g in self.index.groups(len) {
+                if !g.matches_strictly(&self.ctx.group_filter) {
+                    continue;
+                }
+                let group = g.map(|f| f.path.clone());
+                for path in group.files.iter() {
+                    if added.contains(path) && !before.contains(path) {
+                        events.push(WatchEvent {
+                            event: "duplicate",
+                            path: path.clone(),
+                            group: group.clone(),
+                        })
+                    }
+                }
+            }
+        }
+        events.sort_by(|e1, e2| e1.path.cmp(&e2.path));
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{create_dir, remove_file, write};
+
+    use crate::file::FileId;
+    use crate::log::StdLog;
+    use crate::util::test::with_dir;
+
+    use super::*;
+
+    fn test_log() -> StdLog {
+        let mut log = StdLog::new();
+        log.no_progress = true;
+        log
+    }
+
+    #[test]
+    fn index_finds_files_under_directory() {
+        let mut index = Index::default();
+        for (path, len) in [
+            ("dir/a", 1),
+            ("dir/sub/b", 2),
+            ("dir-2/c", 3),
+            ("dir2/d", 4),
+        ] {
+            index.insert(FileInfo {
+                path: Path::from(path),
+                id: FileId {
+                    device: 0,
+                    inode: len,
+                },
+                len: FileLen(len),
+                location: 0,
+            });
+        }
+        let mut lens = index.lens_under(&Path::from("dir"), true);
+        lens.sort();
+        assert_eq!(
+            lens,
+            vec![
+                (Path::from("dir/a"), FileLen(1)),
+                (Path::from("dir/sub/b"), FileLen(2))
+            ]
+        );
+        let lens = index.lens_under(&Path::from("dir-2/c"), false);
+        assert_eq!(lens, vec![(Path::from("dir-2/c"), FileLen(3))]);
+        assert!(index.lens_under(&Path::from("dir"), false).is_empty());
+    }
+
+    #[test]
+    fn reports_new_duplicates() {
+        with_dir("target/test/watch/new_duplicates", |root| {
+            write(root.join("a"), b"foo").unwrap();
+            write(root.join("b"), b"bar").unwrap();
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                ..GroupConfig::default()
+            };
+            let mut watcher = Watcher::new(&config, &log).unwrap();
+            assert_eq!(watcher.scan(), 1);
+            assert!(watcher.groups().is_empty());
+
+            let c = root.join("c");
+            write(&c, b"foo").unwrap();
+            let events = watcher.process_events().unwrap();
+            let paths: Vec<_> = events.iter().map(|e| e.path.to_path_buf()).collect();
+            assert_eq!(paths, vec![c.clone()]);
+            assert_eq!(watcher.groups().len(), 1);
+
+            remove_file(&c).unwrap();
+            assert!(watcher.process_events().unwrap().is_empty());
+            assert!(watcher.groups().is_empty());
+        });
+    }
+
+    #[test]
+    fn scans_new_directories() {
+        with_dir("target/test/watch/new_dirs", |root| {
+            write(root.join("a"), b"foo").unwrap();
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                ..GroupConfig::default()
+            };
+            let mut watcher = Watcher::new(&config, &log).unwrap();
+            watcher.scan();
+
+            let dir = root.join("dir");
+            create_dir(&dir).unwrap();
+            write(dir.join("a"), b"foo").unwrap();
+            let events = watcher.process_events().unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(watcher.groups().len(), 1);
+            assert_eq!(watcher.groups()[0].files.len(), 2);
+        });
+    }
+
+    #[test]
+    fn rescans_all_directories_after_overflow() {
+        with_dir("target/test/watch/rescan", |root| {
+            let busy = root.join("busy");
+            let quiet = root.join("quiet");
+            create_dir(&busy).unwrap();
+            create_dir(&quiet).unwrap();
+            write(busy.join("a"), b"foo").unwrap();
+            write(quiet.join("a"), b"bar").unwrap();
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                ..GroupConfig::default()
+            };
+            let mut watcher = Watcher::new(&config, &log).unwrap();
+            assert_eq!(watcher.scan(), 3);
+
+            // Pretend the events of these files were lost:
+            write(busy.join("b"), b"foo").unwrap();
+            write(quiet.join("b"), b"bar").unwrap();
+            watcher.rescan();
+            watcher.update_hashes();
+
+            let paths: Vec<Vec<_>> = watcher
+                .groups()
+                .iter()
+                .map(|g| g.files.iter().map(|f| f.path.to_path_buf()).collect())
+                .collect();
+            assert_eq!(paths.len(), 2);
+            assert!(paths.contains(&vec![busy.join("a"), busy.join("b")]));
+            assert!(paths.contains(&vec![quiet.join("a"), quiet.join("b")]));
+            assert_eq!(watcher.dirs.lock().unwrap().len(), 3);
+        });
+    }
+}